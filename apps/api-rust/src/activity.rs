@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+
+use crate::models::{ActivityRecord, AiReport};
+
+struct ActivityEntry {
+    owner: String,
+    report: AiReport,
+}
+
+/// Bounded ring buffer of recent requests, each tagged with the caller that
+/// made it. Reads are always filtered by owner unless the reader is an admin.
+pub struct ActivityLog {
+    entries: VecDeque<ActivityEntry>,
+    capacity: usize,
+}
+
+impl ActivityLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, owner: String, report: AiReport) {
+        self.entries.push_back(ActivityEntry { owner, report });
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    /// `owner` of `None` means an admin view across all callers.
+    pub fn report(&self, owner: Option<&str>, request_id: &str) -> Option<AiReport> {
+        self.visible(owner)
+            .find(|e| e.report.request_id == request_id)
+            .map(|e| e.report.clone())
+    }
+
+    pub fn latest_report(&self, owner: Option<&str>) -> Option<AiReport> {
+        self.visible(owner).next().map(|e| e.report.clone())
+    }
+
+    /// Newest first.
+    pub fn records(&self, owner: Option<&str>, limit: usize) -> Vec<ActivityRecord> {
+        self.visible(owner)
+            .take(limit)
+            .map(|e| ActivityRecord {
+                request_id: e.report.request_id.clone(),
+                owner: e.owner.clone(),
+                created_at: e.report.created_at,
+                query: e.report.last_query.clone(),
+                route: e.report.route.clone(),
+                cache_hit: e.report.cache_hit,
+                fallback: e.report.fallback,
+                confidence: e.report.confidence,
+                transparency_score: e.report.transparency_score,
+            })
+            .collect()
+    }
+
+    fn visible<'a>(&'a self, owner: Option<&'a str>) -> impl Iterator<Item = &'a ActivityEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(move |e| owner.map_or(true, |o| e.owner == o))
+    }
+}
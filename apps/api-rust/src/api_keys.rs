@@ -0,0 +1,164 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auth::{Identity, Role};
+use crate::report::unix_seconds;
+
+const KEY_PREFIX: &str = "cak_";
+
+/// An API key as shown to admins. The key itself is never stored.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyRecord {
+    pub id: String,
+    pub name: String,
+    pub role: Role,
+    pub tenant: Option<String>,
+    pub created_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    keys: Vec<StoredKey>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct StoredKey {
+    id: String,
+    name: String,
+    role: Role,
+    #[serde(default)]
+    tenant: Option<String>,
+    created_at: u64,
+    hash: String,
+}
+
+impl StoredKey {
+    fn record(&self) -> ApiKeyRecord {
+        ApiKeyRecord {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            role: self.role,
+            tenant: self.tenant.clone(),
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// API keys persisted as SHA-256 hashes in `DATA_DIR/api_keys.json`.
+pub struct ApiKeyStore {
+    path: PathBuf,
+    keys: Vec<StoredKey>,
+}
+
+impl ApiKeyStore {
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let keys = match fs::read_to_string(&path) {
+            Ok(raw) => {
+                let file: KeyFile = serde_json::from_str(&raw)
+                    .map_err(|e| format!("invalid API key store {}: {e}", path.display()))?;
+                file.keys
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("cannot read API key store {}: {e}", path.display())),
+        };
+
+        Ok(Self { path, keys })
+    }
+
+    pub fn authenticate(&self, key: &str) -> Option<Identity> {
+        let hash = hash_key(key);
+        self.keys.iter().find(|k| k.hash == hash).map(|k| Identity {
+            principal: format!("key:{}", k.id),
+            name: k.name.clone(),
+            role: k.role,
+            tenant: k.tenant.clone(),
+        })
+    }
+
+    pub fn list(&self) -> Vec<ApiKeyRecord> {
+        self.keys.iter().map(StoredKey::record).collect()
+    }
+
+    /// Creates a key and returns its record together with the plaintext key,
+    /// which is only ever available at this point.
+    pub fn create(
+        &mut self,
+        name: &str,
+        role: Role,
+        tenant: Option<String>,
+    ) -> Result<(ApiKeyRecord, String), String> {
+        let key = format!("{KEY_PREFIX}{}", random_hex(24));
+        let stored = StoredKey {
+            id: format!("key-{}", random_hex(4)),
+            name: name.to_string(),
+            role,
+            tenant,
+            created_at: unix_seconds(),
+            hash: hash_key(&key),
+        };
+        let record = stored.record();
+        self.keys.push(stored);
+        if let Err(err) = self.persist() {
+            self.keys.pop();
+            return Err(err);
+        }
+        Ok((record, key))
+    }
+
+    /// Returns false when no key has the given id.
+    pub fn revoke(&mut self, id: &str) -> Result<bool, String> {
+        let Some(idx) = self.keys.iter().position(|k| k.id == id) else {
+            return Ok(false);
+        };
+        let removed = self.keys.remove(idx);
+        if let Err(err) = self.persist() {
+            self.keys.insert(idx, removed);
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    fn persist(&self) -> Result<(), String> {
+        let file = KeyFile {
+            keys: self.keys.clone(),
+        };
+        let raw = serde_json::to_string_pretty(&file).map_err(|e| e.to_string())?;
+        write_atomic(&self.path, &raw)
+    }
+}
+
+/// Writes through a temporary file so a crash never leaves a truncated store.
+pub fn write_atomic(path: &Path, contents: &str) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {e}", dir.display()))?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents).map_err(|e| format!("cannot write {}: {e}", tmp.display()))?;
+    fs::rename(&tmp, path).map_err(|e| format!("cannot replace {}: {e}", path.display()))
+}
+
+fn hash_key(key: &str) -> String {
+    to_hex(&Sha256::digest(key.trim().as_bytes()))
+}
+
+pub fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut buf);
+    to_hex(&buf)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut out, b| {
+            let _ = write!(out, "{b:02x}");
+            out
+        })
+}
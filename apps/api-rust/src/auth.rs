@@ -0,0 +1,179 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::models::ErrorResponse;
+use crate::oidc::{looks_like_jwt, OidcError, SESSION_COOKIE};
+use crate::state::AppState;
+
+const API_KEY_HEADER: &str = "X-Api-Key";
+const ADMIN_TOKEN_HEADER: &str = "X-Admin-Token";
+
+/// Paths that stay reachable without credentials when `AUTH_REQUIRED` is on.
+const PUBLIC_PATHS: &[&str] = &[
+    "/health",
+    "/ready",
+    "/api/auth/login",
+    "/api/auth/callback",
+    "/api/auth/logout",
+];
+
+/// Ordered so `role >= Role::Staff` reads as "staff or above".
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Student,
+    Staff,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "student" => Some(Role::Student),
+            "staff" => Some(Role::Staff),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Role::Student => "student",
+            Role::Staff => "staff",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// Authenticated principal, stored in request extensions by [`authenticate`].
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Identity {
+    /// Stable id such as `key:key-1a2b3c4d`; used to scope activity.
+    pub principal: String,
+    pub name: String,
+    pub role: Role,
+    /// Tenant for data residency policy, from the API key or the
+    /// `OIDC_TENANT_CLAIM` claim.
+    pub tenant: Option<String>,
+}
+
+/// Resolves the caller's credentials before any handler runs: a bearer JWT
+/// from the OIDC provider, an API key, the login session cookie or the
+/// bootstrap admin token. Presented but invalid credentials are always
+/// rejected; missing ones only when `AUTH_REQUIRED`.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(data) = req.app_data::<web::Data<AppState>>().cloned() else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+
+    let identity = if let Some(token) = presented_key(req.headers()) {
+        let found = if looks_like_jwt(&token) {
+            match &data.oidc {
+                Some(oidc) => oidc.authenticate_bearer(&data.client, &token).await,
+                None => Err(OidcError::Rejected(
+                    "bearer tokens require OIDC to be configured".to_string(),
+                )),
+            }
+        } else {
+            data.api_keys
+                .lock()
+                .ok()
+                .and_then(|store| store.authenticate(&token))
+                .ok_or_else(|| OidcError::Rejected("invalid API key".to_string()))
+        };
+        match found {
+            Ok(identity) => Some(identity),
+            Err(OidcError::Unavailable(message)) => {
+                let response = HttpResponse::ServiceUnavailable().json(ErrorResponse {
+                    error: format!("cannot verify the token right now: {message}"),
+                });
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            Err(OidcError::Rejected(message)) => {
+                data.metrics.incr_auth_failure();
+                return Ok(reject(req, &message));
+            }
+        }
+    } else {
+        // A stale session cookie just means "logged out", not an error.
+        let session = req.cookie(SESSION_COOKIE).and_then(|cookie| {
+            data.oidc
+                .as_ref()
+                .and_then(|oidc| oidc.session(cookie.value()))
+        });
+        session.or_else(|| admin_token_identity(&data, req.headers()))
+    };
+
+    if identity.is_none() && data.cfg.auth_required && !PUBLIC_PATHS.contains(&req.path()) {
+        data.metrics.incr_auth_failure();
+        return Ok(reject(req, "authentication required"));
+    }
+
+    data.metrics
+        .incr_requests_by_role(identity.as_ref().map(|i| i.role));
+    if let Some(identity) = identity {
+        req.extensions_mut().insert(identity);
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// `Authorization: Bearer <key or JWT>` or `X-Api-Key: <key>`.
+fn presented_key(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let key = bearer.or_else(|| headers.get(API_KEY_HEADER).and_then(|v| v.to_str().ok()))?;
+    let key = key.trim();
+    (!key.is_empty()).then(|| key.to_string())
+}
+
+/// `ADMIN_TOKEN` is kept as a bootstrap credential for creating the first keys.
+fn admin_token_identity(data: &AppState, headers: &HeaderMap) -> Option<Identity> {
+    let token = &data.cfg.admin_token;
+    let presented = headers
+        .get(ADMIN_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())?;
+    (!token.is_empty() && same_secret(presented, token)).then(|| Identity {
+        principal: "admin-token".to_string(),
+        name: "bootstrap admin".to_string(),
+        role: Role::Admin,
+        tenant: None,
+    })
+}
+
+/// Compares digests in constant time so response timing does not reveal
+/// how much of the token matched.
+fn same_secret(presented: &str, expected: &str) -> bool {
+    let presented = Sha256::digest(presented.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    presented
+        .iter()
+        .zip(expected.iter())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+fn reject<B>(req: ServiceRequest, message: &str) -> ServiceResponse<EitherBody<B>> {
+    let response = HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+        .json(ErrorResponse {
+            error: message.to_string(),
+        });
+    req.into_response(response).map_into_right_body()
+}
@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::api_keys::write_atomic;
+use crate::config::AppConfig;
+use crate::providers::TokenUsage;
+use crate::report::unix_seconds;
+use crate::state::AppState;
+use crate::usage::utc_date;
+
+/// Enough history to cover the current and the previous month.
+const RETAINED_DAYS: usize = 62;
+
+/// USD per million tokens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl ModelPrice {
+    fn cost(self, usage: &TokenUsage) -> f64 {
+        (usage.prompt_tokens as f64 * self.input + usage.completion_tokens as f64 * self.output)
+            / 1_000_000.0
+    }
+}
+
+/// Parses `gpt-4.1-mini=0.40:1.60,gpt-4.1=2.00:8.00` (input:output USD per
+/// million tokens).
+pub fn parse_prices(raw: &str) -> Result<HashMap<String, ModelPrice>, String> {
+    let mut prices = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let parsed = entry.split_once('=').and_then(|(model, price)| {
+            let (input, output) = price.split_once(':')?;
+            let input: f64 = input.trim().parse().ok()?;
+            let output: f64 = output.trim().parse().ok()?;
+            (input >= 0.0 && output >= 0.0)
+                .then(|| (model.trim().to_string(), ModelPrice { input, output }))
+        });
+        let (model, price) = parsed
+            .ok_or_else(|| format!("price entry '{entry}' must look like model=input:output"))?;
+        prices.insert(model, price);
+    }
+    Ok(prices)
+}
+
+fn parse_budget(name: &str, raw: &str) -> Result<Option<f64>, String> {
+    if raw.trim().is_empty() {
+        return Ok(None);
+    }
+    raw.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| *v > 0.0)
+        .map(Some)
+        .ok_or_else(|| format!("{name} must be a positive amount in USD"))
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct DaySpend {
+    requests: u64,
+    input_tokens: u64,
+    output_tokens: u64,
+    cost_usd: f64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SpendFile {
+    days: BTreeMap<String, DaySpend>,
+}
+
+/// Cloud spend so far against the configured budgets.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BudgetStatus {
+    pub today_usd: f64,
+    pub month_usd: f64,
+    pub today_tokens: u64,
+    pub daily_budget_usd: Option<f64>,
+    pub monthly_budget_usd: Option<f64>,
+    pub daily_remaining_usd: Option<f64>,
+    pub monthly_remaining_usd: Option<f64>,
+    pub cutoff_percent: f64,
+    /// True once either budget has reached the cut-off.
+    pub escalation_disabled: bool,
+}
+
+impl BudgetStatus {
+    /// The tighter of the two remaining budgets.
+    pub fn remaining_usd(&self) -> Option<f64> {
+        match (self.daily_remaining_usd, self.monthly_remaining_usd) {
+            (Some(d), Some(m)) => Some(d.min(m)),
+            (d, m) => d.or(m),
+        }
+    }
+}
+
+/// Daily cloud spend, priced from the provider's reported token usage and
+/// saved to `DATA_DIR/cloud_spend.json` by [`save_periodically`].
+///
+/// [`save_periodically`]: crate::usage::save_periodically
+pub struct CloudBudget {
+    path: PathBuf,
+    file: SpendFile,
+    unsaved: bool,
+    prices: HashMap<String, ModelPrice>,
+    daily_budget: Option<f64>,
+    monthly_budget: Option<f64>,
+    cutoff_percent: f64,
+}
+
+impl CloudBudget {
+    pub fn load(cfg: &AppConfig, path: PathBuf) -> Result<Self, String> {
+        let file = match fs::read_to_string(&path) {
+            Ok(raw) => serde_json::from_str(&raw)
+                .map_err(|e| format!("invalid cloud spend store {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => SpendFile::default(),
+            Err(e) => {
+                return Err(format!(
+                    "cannot read cloud spend store {}: {e}",
+                    path.display()
+                ))
+            }
+        };
+
+        let prices = parse_prices(&cfg.cloud_prices).map_err(|e| format!("CLOUD_PRICES: {e}"))?;
+        if !prices.contains_key(&cfg.cloud_model) {
+            warn!(
+                "CLOUD_PRICES has no entry for {}; it will be priced at the most expensive listed model",
+                cfg.cloud_model
+            );
+        }
+        if !(1.0..=100.0).contains(&cfg.cloud_budget_cutoff_percent) {
+            return Err("CLOUD_BUDGET_CUTOFF_PERCENT must be between 1 and 100".to_string());
+        }
+
+        Ok(Self {
+            path,
+            file,
+            unsaved: false,
+            prices,
+            daily_budget: parse_budget("CLOUD_BUDGET_DAILY_USD", &cfg.cloud_budget_daily_usd)?,
+            monthly_budget: parse_budget(
+                "CLOUD_BUDGET_MONTHLY_USD",
+                &cfg.cloud_budget_monthly_usd,
+            )?,
+            cutoff_percent: cfg.cloud_budget_cutoff_percent,
+        })
+    }
+
+    /// Unknown models are priced at the most expensive listed rates so a
+    /// missing entry never under-counts spend.
+    fn price_for(&self, model: &str) -> ModelPrice {
+        self.prices.get(model).copied().unwrap_or_else(|| {
+            self.prices.values().fold(
+                ModelPrice {
+                    input: 0.0,
+                    output: 0.0,
+                },
+                |max, p| ModelPrice {
+                    input: max.input.max(p.input),
+                    output: max.output.max(p.output),
+                },
+            )
+        })
+    }
+
+    pub fn status(&self) -> BudgetStatus {
+        let today = utc_date(unix_seconds());
+        let month = &today[..7];
+        let day = self.file.days.get(&today).copied().unwrap_or_default();
+        let month_usd: f64 = self
+            .file
+            .days
+            .iter()
+            .filter(|(date, _)| date.starts_with(month))
+            .map(|(_, spend)| spend.cost_usd)
+            .sum();
+
+        BudgetStatus {
+            today_usd: day.cost_usd,
+            month_usd,
+            today_tokens: day.input_tokens + day.output_tokens,
+            daily_budget_usd: self.daily_budget,
+            monthly_budget_usd: self.monthly_budget,
+            daily_remaining_usd: self.daily_budget.map(|b| (b - day.cost_usd).max(0.0)),
+            monthly_remaining_usd: self.monthly_budget.map(|b| (b - month_usd).max(0.0)),
+            cutoff_percent: self.cutoff_percent,
+            escalation_disabled: self.reached(day.cost_usd, self.daily_budget)
+                || self.reached(month_usd, self.monthly_budget),
+        }
+    }
+
+    fn reached(&self, spent: f64, budget: Option<f64>) -> bool {
+        budget.is_some_and(|b| spent >= b * self.cutoff_percent / 100.0)
+    }
+
+    pub fn escalation_allowed(&self) -> bool {
+        !self.status().escalation_disabled
+    }
+
+    /// `daily` or `monthly`, whichever budget has reached the cut-off.
+    pub fn exhausted(&self) -> Option<&'static str> {
+        let status = self.status();
+        if self.reached(status.today_usd, self.daily_budget) {
+            Some("daily")
+        } else if self.reached(status.month_usd, self.monthly_budget) {
+            Some("monthly")
+        } else {
+            None
+        }
+    }
+
+    /// Adds one cloud call to today's spend and returns its cost in USD.
+    pub fn record(&mut self, model: &str, usage: &TokenUsage) -> f64 {
+        let cost = self.price_for(model).cost(usage);
+        let day = self.file.days.entry(utc_date(unix_seconds())).or_default();
+        day.requests += 1;
+        day.input_tokens += usage.prompt_tokens;
+        day.output_tokens += usage.completion_tokens;
+        day.cost_usd += cost;
+        while self.file.days.len() > RETAINED_DAYS {
+            self.file.days.pop_first();
+        }
+
+        self.unsaved = true;
+        cost
+    }
+
+    /// The file contents to write, when spend changed since the last call.
+    fn take_unsaved(&mut self) -> Option<(PathBuf, String)> {
+        if !std::mem::take(&mut self.unsaved) {
+            return None;
+        }
+        match serde_json::to_string(&self.file) {
+            Ok(raw) => Some((self.path.clone(), raw)),
+            Err(err) => {
+                warn!("cloud spend store not saved: {}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Writes changed spend outside the budget lock; called by
+/// [`save_periodically`](crate::usage::save_periodically).
+pub async fn save(data: &AppState) {
+    let Some((path, raw)) = data.budget.lock().ok().and_then(|mut b| b.take_unsaved()) else {
+        return;
+    };
+    let result = tokio::task::spawn_blocking(move || write_atomic(&path, &raw))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    if let Err(err) = result {
+        warn!("cloud spend store not saved: {}", err);
+        // Retried on the next tick.
+        if let Ok(mut budget) = data.budget.lock() {
+            budget.unsaved = true;
+        }
+    }
+}
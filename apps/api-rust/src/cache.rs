@@ -1,9 +1,18 @@
 use std::collections::{HashMap, VecDeque};
 use std::time::{Duration, Instant};
 
+use crate::providers::TokenUsage;
+
+/// A cached answer with the usage of the generation that produced it.
+#[derive(Clone)]
+pub struct CachedAnswer {
+    pub text: String,
+    pub usage: Option<TokenUsage>,
+}
+
 #[derive(Clone)]
 struct CacheEntry {
-    value: String,
+    value: CachedAnswer,
     expires_at: Instant,
 }
 
@@ -24,7 +33,7 @@ impl LruTtlCache {
         }
     }
 
-    pub fn get(&mut self, key: &str) -> Option<String> {
+    pub fn get(&mut self, key: &str) -> Option<CachedAnswer> {
         self.evict_expired();
 
         if let Some(entry) = self.map.get(key).cloned() {
@@ -39,7 +48,7 @@ impl LruTtlCache {
         None
     }
 
-    pub fn put(&mut self, key: String, value: String) {
+    pub fn put(&mut self, key: String, value: CachedAnswer) {
         self.evict_expired();
 
         let entry = CacheEntry {
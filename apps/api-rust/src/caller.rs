@@ -0,0 +1,170 @@
+use std::future::{ready, Ready};
+use std::sync::OnceLock;
+
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::api_keys::{random_hex, to_hex};
+use crate::auth::{Identity, Role};
+use crate::client_ip::client_ip;
+use crate::state::AppState;
+
+pub const SESSION_HEADER: &str = "X-Session-Id";
+const TENANT_HEADER: &str = "X-Tenant-Id";
+
+/// Who is making a request. Activity and reports are scoped to this.
+#[derive(Clone, Debug)]
+pub struct Caller {
+    /// `X-Session-Id` when this server minted it, otherwise a fresh one that
+    /// is echoed back so the client can keep using it.
+    pub session_id: String,
+    /// Set by the auth middleware when credentials were presented.
+    pub identity: Option<Identity>,
+    /// Used to apply per-tenant data residency policy. Taken from the
+    /// identity; `X-Tenant-Id` only counts for anonymous callers while
+    /// `AUTH_REQUIRED` is off.
+    pub tenant: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl Caller {
+    /// Authenticated callers own their activity across sessions.
+    pub fn owner_key(&self) -> String {
+        match &self.identity {
+            Some(identity) => identity.principal.clone(),
+            None => format!("session:{}", self.session_id),
+        }
+    }
+
+    /// Quotas follow the authenticated principal, falling back to the client
+    /// address since anonymous session ids are free to mint.
+    pub fn quota_key(&self) -> String {
+        match (&self.identity, &self.ip) {
+            (Some(identity), _) => identity.principal.clone(),
+            (None, Some(ip)) => format!("ip:{ip}"),
+            (None, None) => self.owner_key(),
+        }
+    }
+
+    pub fn role(&self) -> Option<Role> {
+        self.identity.as_ref().map(|i| i.role)
+    }
+
+    pub fn has_role(&self, min: Role) -> bool {
+        self.role().is_some_and(|role| role >= min)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.has_role(Role::Admin)
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let supplied = req
+            .headers()
+            .get(SESSION_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|v| valid_session_id(v));
+
+        let session_id = supplied.map(str::to_string).unwrap_or_else(new_session_id);
+
+        let identity = req.extensions().get::<Identity>().cloned();
+
+        let auth_required = req
+            .app_data::<web::Data<AppState>>()
+            .map_or(true, |data| data.cfg.auth_required);
+        let tenant = match &identity {
+            Some(identity) => identity.tenant.clone(),
+            None if auth_required => None,
+            None => req
+                .headers()
+                .get(TENANT_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string),
+        };
+
+        let ip = client_ip(req);
+
+        ready(Ok(Caller {
+            session_id,
+            identity,
+            tenant,
+            ip,
+        }))
+    }
+}
+
+/// Signs session ids for this process; sessions, like the activity log they
+/// scope, do not outlive a restart.
+fn session_key() -> &'static [u8; 32] {
+    static KEY: OnceLock<[u8; 32]> = OnceLock::new();
+    KEY.get_or_init(|| {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        key
+    })
+}
+
+fn session_tag(nonce: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(session_key());
+    hasher.update(nonce.as_bytes());
+    to_hex(&hasher.finalize()[..8])
+}
+
+/// `sess-<128 random bits>-<tag>`; the tag lets a returned id be checked
+/// without keeping a list of issued ones.
+fn new_session_id() -> String {
+    let nonce = random_hex(16);
+    let tag = session_tag(&nonce);
+    format!("sess-{nonce}-{tag}")
+}
+
+/// Only ids this server minted are accepted, so a client cannot pick or
+/// guess another caller's session.
+fn valid_session_id(id: &str) -> bool {
+    let Some((nonce, tag)) = id
+        .strip_prefix("sess-")
+        .and_then(|rest| rest.split_once('-'))
+    else {
+        return false;
+    };
+    nonce.len() == 32
+        && nonce.chars().all(|c| c.is_ascii_hexdigit())
+        && session_tag(nonce)
+            .bytes()
+            .zip(tag.bytes())
+            .fold(tag.len() ^ 16, |diff, (a, b)| diff | usize::from(a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minted_session_ids_are_accepted() {
+        let id = new_session_id();
+        assert!(valid_session_id(&id));
+        assert_ne!(id, new_session_id());
+    }
+
+    #[test]
+    fn client_chosen_session_ids_are_rejected() {
+        let id = new_session_id();
+        let forged = format!("{}0000000000000000", &id[..id.len() - 16]);
+        assert!(!valid_session_id(&forged));
+        assert!(!valid_session_id("sess-1a150c39246-0"));
+        assert!(!valid_session_id("my-own-session-id"));
+        assert!(!valid_session_id(&format!("{id}0")));
+    }
+}
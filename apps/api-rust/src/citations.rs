@@ -0,0 +1,224 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::documents::{DocumentStore, Hit};
+
+/// Appended to the excerpts so the model marks where it used them.
+pub const CITATION_INSTRUCTION: &str = "Cite the excerpts you use with their number in square brackets, such as [1] or [2][3], right after the sentence they support. Only cite the excerpts above.";
+
+/// A source the answer cites. Citations of excerpts that were not part of
+/// the prompt have `retrieved: false`; for unknown sources only `marker`
+/// is set.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Citation {
+    /// As written in the answer: an excerpt number or a citation id.
+    pub marker: String,
+    pub citation_id: Option<String>,
+    pub document_id: Option<String>,
+    pub title: Option<String>,
+    pub page: Option<u32>,
+    pub offset: Option<usize>,
+    pub snippet: Option<String>,
+    pub retrieved: bool,
+}
+
+impl Citation {
+    fn from_hit(marker: String, hit: &Hit, retrieved: bool) -> Self {
+        Citation {
+            marker,
+            citation_id: Some(hit.chunk.citation_id()),
+            document_id: Some(hit.chunk.document_id.clone()),
+            title: Some(hit.title.clone()),
+            page: hit.chunk.page,
+            offset: Some(hit.chunk.offset),
+            snippet: Some(hit.chunk.snippet()),
+            retrieved,
+        }
+    }
+
+    fn unknown(marker: String) -> Self {
+        Citation {
+            marker,
+            citation_id: None,
+            document_id: None,
+            title: None,
+            page: None,
+            offset: None,
+            snippet: None,
+            retrieved: false,
+        }
+    }
+}
+
+/// An excerpt number or a chunk's citation id.
+const SOURCE: &str = r"\d+|doc-[0-9a-f]+-[0-9a-f]+#(?:p\d+:)?\d+";
+
+/// `[1]`, `[1, 3]` and `[doc-…#p2:140]`.
+fn markers() -> &'static Regex {
+    static MARKERS: OnceLock<Regex> = OnceLock::new();
+    MARKERS.get_or_init(|| {
+        Regex::new(&format!(
+            r"\[\s*((?:{SOURCE})(?:\s*[,;]\s*(?:{SOURCE}))*)\s*\]"
+        ))
+        .expect("valid regex")
+    })
+}
+
+/// The citations in `answer`, in order of first use. `sources` are the
+/// excerpts given to the model, numbered from 1; citation ids of other
+/// chunks are looked up in `store` and flagged as not retrieved.
+pub fn extract_citations(answer: &str, sources: &[Hit], store: &DocumentStore) -> Vec<Citation> {
+    let mut citations: Vec<Citation> = Vec::new();
+    for found in markers().captures_iter(answer) {
+        let whole = found.get(0).expect("match");
+        // `list[0]` is an index, not a citation.
+        if answer[..whole.start()]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_')
+        {
+            continue;
+        }
+        for marker in found[1].split([',', ';']).map(str::trim) {
+            if citations.iter().any(|c| c.marker == marker) {
+                continue;
+            }
+            let citation = if let Ok(number) = marker.parse::<usize>() {
+                match number.checked_sub(1).and_then(|i| sources.get(i)) {
+                    Some(hit) => Citation::from_hit(marker.to_string(), hit, true),
+                    None => Citation::unknown(marker.to_string()),
+                }
+            } else if let Some(hit) = sources.iter().find(|h| h.chunk.citation_id() == marker) {
+                Citation::from_hit(marker.to_string(), hit, true)
+            } else if let Some(hit) = store.find_citation(marker) {
+                Citation::from_hit(marker.to_string(), &hit, false)
+            } else {
+                Citation::unknown(marker.to_string())
+            };
+            citations.push(citation);
+        }
+    }
+    citations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::random_hex;
+    use crate::config::AppConfig;
+    use crate::documents::{Chunk, DocumentFormat, DocumentRecord};
+
+    const DOC: &str = "doc-0a1b-2c3d";
+
+    fn chunk(index: usize, page: Option<u32>, offset: usize, text: &str) -> Chunk {
+        Chunk {
+            id: format!("{DOC}:{index}"),
+            document_id: DOC.to_string(),
+            page,
+            offset,
+            text: text.to_string(),
+            embedding: vec![1.0],
+        }
+    }
+
+    fn hit(chunk: Chunk) -> Hit {
+        Hit {
+            chunk,
+            title: "Handbook".to_string(),
+            score: 0.9,
+        }
+    }
+
+    /// A store holding the handbook's three chunks.
+    async fn handbook() -> (DocumentStore, String) {
+        let mut cfg = AppConfig::from_env();
+        let dir = std::env::temp_dir().join(format!("campus-citations-{}", random_hex(8)));
+        cfg.documents_dir = dir.display().to_string();
+        let store = DocumentStore::load(&cfg).unwrap();
+        let record = DocumentRecord {
+            id: DOC.to_string(),
+            collection: "policies".to_string(),
+            title: "Handbook".to_string(),
+            format: DocumentFormat::Pdf,
+            bytes: 100,
+            pages: Some(3),
+            chunks: 3,
+            embedding_model: "test-embed".to_string(),
+            uploaded_by: "key:test".to_string(),
+            uploaded_at: 0,
+        };
+        let chunks = vec![
+            chunk(0, Some(1), 0, "Parking permits renew in August."),
+            chunk(1, Some(2), 140, "Library fines are waived during exams."),
+            chunk(2, Some(3), 0, "Labs close at 10pm."),
+        ];
+        store.add(record, chunks).await.unwrap();
+        (store, cfg.documents_dir)
+    }
+
+    #[test]
+    fn citation_ids_name_the_document_page_and_offset() {
+        assert_eq!(
+            chunk(0, Some(2), 140, "").citation_id(),
+            format!("{DOC}#p2:140")
+        );
+        assert_eq!(chunk(0, None, 7, "").citation_id(), format!("{DOC}#7"));
+
+        let long = format!("{}  \n tail", "word ".repeat(100));
+        let snippet = chunk(0, None, 0, &long).snippet();
+        assert!(snippet.ends_with("word…"), "{snippet}");
+        assert!(snippet.chars().count() <= 281);
+        assert_eq!(chunk(0, None, 0, " a\n b ").snippet(), "a b");
+    }
+
+    #[actix_web::test]
+    async fn markers_resolve_to_retrieved_excerpts_or_are_flagged() {
+        let (store, dir) = handbook().await;
+        let sources = [
+            hit(chunk(0, Some(1), 0, "Parking permits renew in August.")),
+            hit(chunk(
+                1,
+                Some(2),
+                140,
+                "Library fines are waived during exams.",
+            )),
+        ];
+        let answer = format!(
+            "Permits renew in August [1]. Fines are waived [2; 1], see list[0] and \
+             [{DOC}#p2:140]. Labs close early [{DOC}#p3:0]. Also [7] and [{DOC}#p9:0]."
+        );
+
+        let citations = extract_citations(&answer, &sources, &store);
+        let summary: Vec<(&str, bool, Option<u32>)> = citations
+            .iter()
+            .map(|c| (c.marker.as_str(), c.retrieved, c.page))
+            .collect();
+        let p2 = format!("{DOC}#p2:140");
+        let p3 = format!("{DOC}#p3:0");
+        let p9 = format!("{DOC}#p9:0");
+        assert_eq!(
+            summary,
+            [
+                ("1", true, Some(1)),
+                ("2", true, Some(2)),
+                (p2.as_str(), true, Some(2)),
+                (p3.as_str(), false, Some(3)),
+                ("7", false, None),
+                (p9.as_str(), false, None),
+            ]
+        );
+        assert_eq!(
+            citations[0].citation_id.as_deref(),
+            Some(&*format!("{DOC}#p1:0"))
+        );
+        assert_eq!(citations[0].title.as_deref(), Some("Handbook"));
+        assert_eq!(citations[3].snippet.as_deref(), Some("Labs close at 10pm."));
+        assert!(citations[4].document_id.is_none());
+
+        assert!(extract_citations("No sources [a] or [ ].", &sources, &store).is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
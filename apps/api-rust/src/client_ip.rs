@@ -0,0 +1,132 @@
+use std::net::IpAddr;
+
+use actix_web::{web, HttpRequest};
+
+use crate::config::AppConfig;
+use crate::state::AppState;
+
+/// Reverse proxies allowed to name the client in `X-Forwarded-For`.
+pub struct TrustedProxies {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    /// Parses `TRUSTED_PROXIES`, a comma-separated list of addresses and
+    /// CIDR ranges such as `10.0.0.0/8,::1`.
+    pub fn from_config(cfg: &AppConfig) -> Result<Self, String> {
+        let mut networks = Vec::new();
+        for entry in cfg
+            .trusted_proxies
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let (addr, prefix) = entry.split_once('/').unwrap_or((entry, ""));
+            let addr: IpAddr = addr
+                .parse()
+                .map_err(|_| format!("TRUSTED_PROXIES entry '{entry}' is not an IP address"))?;
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix =
+                match prefix {
+                    "" => max,
+                    p => p.parse().ok().filter(|p| *p <= max).ok_or_else(|| {
+                        format!("TRUSTED_PROXIES entry '{entry}' has a bad prefix")
+                    })?,
+                };
+            networks.push((addr, prefix));
+        }
+        Ok(Self { networks })
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|(network, prefix)| match (network, ip) {
+                (IpAddr::V4(n), IpAddr::V4(ip)) => {
+                    same_prefix(u32::from(*n).into(), u32::from(ip).into(), 32, *prefix)
+                }
+                (IpAddr::V6(n), IpAddr::V6(ip)) => {
+                    same_prefix(u128::from(*n), u128::from(ip), 128, *prefix)
+                }
+                _ => false,
+            })
+    }
+
+    /// The connecting address, unless it is a trusted proxy: then the last
+    /// `X-Forwarded-For` hop that is not one. Headers from anyone else are
+    /// ignored, so clients cannot pick the address they are counted under.
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        if !self.trusts(peer) {
+            return peer;
+        }
+        let hops: Vec<IpAddr> = forwarded_for
+            .unwrap_or("")
+            .split(',')
+            .filter_map(|hop| hop.trim().parse().ok())
+            .collect();
+        hops.iter()
+            .rev()
+            .find(|hop| !self.trusts(**hop))
+            .or(hops.first())
+            .copied()
+            .unwrap_or(peer)
+    }
+}
+
+fn same_prefix(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    let shift = u32::from(bits - prefix);
+    shift >= 128 || (a >> shift) == (b >> shift)
+}
+
+/// Address quotas and rate limits count anonymous callers under.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    let peer = req.peer_addr()?.ip();
+    let forwarded_for = req
+        .headers()
+        .get("X-Forwarded-For")
+        .and_then(|v| v.to_str().ok());
+    let ip = match req.app_data::<web::Data<AppState>>() {
+        Some(data) => data.trusted_proxies.client_ip(peer, forwarded_for),
+        None => peer,
+    };
+    Some(ip.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxies(list: &str) -> TrustedProxies {
+        let mut networks = Vec::new();
+        for entry in list.split(',') {
+            let (addr, prefix) = entry.split_once('/').unwrap();
+            networks.push((addr.parse().unwrap(), prefix.parse().unwrap()));
+        }
+        TrustedProxies { networks }
+    }
+
+    fn ip(raw: &str) -> IpAddr {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_counts_only_from_trusted_proxies() {
+        let trusted = proxies("10.0.0.0/8,::1/128");
+        let header = Some("6.6.6.6, 192.0.2.7, 10.1.2.3");
+
+        assert_eq!(
+            trusted.client_ip(ip("203.0.113.9"), header),
+            ip("203.0.113.9")
+        );
+        assert_eq!(trusted.client_ip(ip("10.9.9.9"), header), ip("192.0.2.7"));
+        assert_eq!(
+            trusted.client_ip(ip("::1"), Some("192.0.2.8")),
+            ip("192.0.2.8")
+        );
+        assert_eq!(trusted.client_ip(ip("10.9.9.9"), None), ip("10.9.9.9"));
+        assert_eq!(
+            trusted.client_ip(ip("10.9.9.9"), Some("10.0.0.1, 10.0.0.2")),
+            ip("10.0.0.1")
+        );
+    }
+}
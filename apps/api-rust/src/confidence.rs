@@ -0,0 +1,127 @@
+use serde_json::Value;
+
+use crate::models::ConfidenceMetrics;
+
+/// Running aggregate of token log-probabilities seen while streaming.
+#[derive(Clone, Debug, Default)]
+pub struct LogprobStats {
+    count: usize,
+    sum_logprob: f64,
+    sum_prob: f64,
+    min_logprob: f64,
+}
+
+impl LogprobStats {
+    pub fn record(&mut self, logprob: f64) {
+        if !logprob.is_finite() {
+            return;
+        }
+        if self.count == 0 || logprob < self.min_logprob {
+            self.min_logprob = logprob;
+        }
+        self.count += 1;
+        self.sum_logprob += logprob;
+        self.sum_prob += logprob.exp();
+    }
+
+    /// Records every `logprob` in an array of `{ "token", "logprob", ... }`
+    /// objects, the shape both Ollama and the Responses API use.
+    pub fn record_all(&mut self, entries: Option<&Value>) {
+        let Some(entries) = entries.and_then(Value::as_array) else {
+            return;
+        };
+        for entry in entries {
+            if let Some(lp) = entry.get("logprob").and_then(Value::as_f64) {
+                self.record(lp);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// `unavailable_note` explains why there is nothing to report when no
+    /// log-probabilities were collected.
+    pub fn metrics(&self, unavailable_note: &str) -> ConfidenceMetrics {
+        if self.is_empty() {
+            return ConfidenceMetrics::unavailable(unavailable_note);
+        }
+
+        let n = self.count as f64;
+        ConfidenceMetrics {
+            available: true,
+            token_count: self.count,
+            mean_token_prob: Some((self.sum_prob / n) as f32),
+            min_token_prob: Some(self.min_logprob.exp() as f32),
+            geometric_mean_prob: Some((self.sum_logprob / n).exp() as f32),
+            note: None,
+        }
+    }
+}
+
+impl ConfidenceMetrics {
+    pub fn unavailable(note: &str) -> Self {
+        Self {
+            available: false,
+            token_count: 0,
+            mean_token_prob: None,
+            min_token_prob: None,
+            geometric_mean_prob: None,
+            note: Some(note.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn close(actual: Option<f32>, expected: f64) -> bool {
+        actual.is_some_and(|a| (f64::from(a) - expected).abs() < 1e-6)
+    }
+
+    #[test]
+    fn log_probabilities_are_aggregated() {
+        let mut stats = LogprobStats::default();
+        for p in [0.9_f64, 0.5, 0.8] {
+            stats.record(p.ln());
+        }
+        stats.record(f64::NEG_INFINITY);
+        stats.record(f64::NAN);
+
+        let metrics = stats.metrics("unused");
+        assert!(metrics.available);
+        assert_eq!(metrics.token_count, 3);
+        assert!(close(metrics.mean_token_prob, (0.9 + 0.5 + 0.8) / 3.0));
+        assert!(close(metrics.min_token_prob, 0.5));
+        assert!(close(
+            metrics.geometric_mean_prob,
+            (0.9_f64 * 0.5 * 0.8).cbrt()
+        ));
+        assert_eq!(metrics.note, None);
+    }
+
+    #[test]
+    fn provider_entries_are_read_and_missing_ones_reported() {
+        let mut stats = LogprobStats::default();
+        stats.record_all(Some(&json!([
+            {"token": "Hi", "logprob": -0.1, "top_logprobs": []},
+            {"token": "!", "bytes": [33]},
+            {"token": " there", "logprob": 0.0},
+        ])));
+        stats.record_all(Some(&json!({"logprob": -5.0})));
+        stats.record_all(None);
+        assert_eq!(stats.metrics("unused").token_count, 2);
+
+        let empty = LogprobStats::default().metrics("model sent no log-probabilities");
+        assert!(!empty.available);
+        assert_eq!(empty.token_count, 0);
+        assert_eq!(empty.mean_token_prob, None);
+        assert_eq!(
+            empty.note.as_deref(),
+            Some("model sent no log-probabilities")
+        );
+    }
+}
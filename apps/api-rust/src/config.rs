@@ -17,6 +17,7 @@ pub struct AppConfig {
 
     pub smart_routing: bool,
     pub cloud_escalation: bool,
+    pub cloud_failover: bool,
 
     pub max_input_chars: usize,
     pub local_temperature: f32,
@@ -28,6 +29,97 @@ pub struct AppConfig {
 
     pub upstream_timeout_ms: u64,
     pub quality_system_prompt: String,
+    pub personas_dir: String,
+    pub utility_templates_dir: String,
+    pub utility_templates_reload_seconds: u64,
+    pub request_logprobs: bool,
+    pub structured_output_native: bool,
+    pub structured_output_retries: u32,
+    pub tools_enabled: bool,
+    pub tool_max_iterations: u32,
+    pub tool_timeout_ms: u64,
+    pub documents_dir: String,
+    pub document_max_bytes: usize,
+    pub embedding_model: String,
+    pub chunk_tokens: usize,
+    pub chunk_overlap_tokens: usize,
+    pub rag_top_k: usize,
+    pub rag_context_tokens: u64,
+    pub rag_min_score: f32,
+    pub retrieval_mode: String,
+    pub rerank_provider: String,
+    pub rerank_model: String,
+    pub rerank_base_url: String,
+    pub rerank_api_key: String,
+    pub rerank_candidates: usize,
+    pub rerank_min_score: f32,
+    pub rerank_timeout_ms: u64,
+    pub embeddings_cloud_model: String,
+    pub embeddings_max_inputs: usize,
+    pub embeddings_cache_size: usize,
+    pub embeddings_cache_ttl_seconds: u64,
+
+    pub moderation_enabled: bool,
+    pub moderation_rules_path: String,
+    pub moderation_classifier_model: String,
+    pub moderation_classifier_action: String,
+
+    pub pii_redaction: bool,
+    pub pii_restore: bool,
+    pub pii_campus_id_patterns: String,
+
+    pub route_disclosure: bool,
+    /// `ACTIVITY_LOG_SIZE`, or the deprecated `REPORT_HISTORY_SIZE` it
+    /// replaced when only that is set.
+    pub activity_log_size: usize,
+    pub admin_token: String,
+    pub auth_required: bool,
+    pub cors_allowed_origins: String,
+    pub trusted_proxies: String,
+    pub data_dir: String,
+
+    pub quota_anonymous: String,
+    pub quota_student: String,
+    pub quota_staff: String,
+    pub quota_admin: String,
+
+    pub rate_limit_anonymous: String,
+    pub rate_limit_student: String,
+    pub rate_limit_staff: String,
+    pub rate_limit_admin: String,
+    pub max_concurrent_streams: usize,
+    pub local_parallel: usize,
+    pub cloud_context_tokens: u64,
+    pub reserved_completion_tokens: u64,
+    pub token_chars_per_token: String,
+    pub token_calibration: bool,
+    pub history_summary: bool,
+    pub summary_max_tokens: u64,
+    pub summary_cache_size: usize,
+    pub summary_cache_ttl_seconds: u64,
+    pub cloud_prices: String,
+    pub cloud_budget_daily_usd: String,
+    pub cloud_budget_monthly_usd: String,
+    pub cloud_budget_cutoff_percent: f64,
+    pub cloud_parallel: usize,
+    pub queue_timeout_ms: u64,
+
+    pub oidc_issuer: String,
+    pub oidc_client_id: String,
+    pub oidc_client_secret: String,
+    pub oidc_redirect_uri: String,
+    pub oidc_scopes: String,
+    pub oidc_audience: String,
+    pub oidc_role_claim: String,
+    pub oidc_tenant_claim: String,
+    pub oidc_role_map: String,
+    pub oidc_default_role: String,
+    pub oidc_post_login_url: String,
+    pub oidc_session_ttl_seconds: u64,
+    pub oidc_timeout_ms: u64,
+
+    pub residency_local_only_tenants: String,
+    pub residency_local_only_paths: String,
 }
 
 impl AppConfig {
@@ -50,6 +142,7 @@ impl AppConfig {
 
             smart_routing: env_bool("SMART_ROUTING", true),
             cloud_escalation: env_bool("CLOUD_ESCALATION", false),
+            cloud_failover: env_bool("CLOUD_FAILOVER", false),
 
             max_input_chars: env_var("MAX_INPUT_CHARS", "12000")
                 .parse()
@@ -72,6 +165,145 @@ impl AppConfig {
                 "QUALITY_SYSTEM_PROMPT",
                 "You are a precise, practical assistant. Prioritize correctness over verbosity. When uncertain, clearly state assumptions. For technical tasks, produce structured and actionable responses. Avoid hallucinations.",
             ),
+
+            personas_dir: env_var("PERSONAS_DIR", "personas"),
+            utility_templates_dir: env_var("UTILITY_TEMPLATES_DIR", ""),
+            utility_templates_reload_seconds: env_var("UTILITY_TEMPLATES_RELOAD_SECONDS", "2")
+                .parse()
+                .unwrap_or(2),
+
+            request_logprobs: env_bool("REQUEST_LOGPROBS", true),
+            structured_output_native: env_bool("STRUCTURED_OUTPUT_NATIVE", true),
+            structured_output_retries: env_var("STRUCTURED_OUTPUT_RETRIES", "2")
+                .parse()
+                .unwrap_or(2),
+            tools_enabled: env_bool("TOOLS_ENABLED", true),
+            tool_max_iterations: env_var("TOOL_MAX_ITERATIONS", "4").parse().unwrap_or(4),
+            tool_timeout_ms: env_var("TOOL_TIMEOUT_MS", "5000").parse().unwrap_or(5000),
+            documents_dir: env_var("DOCUMENTS_DIR", ""),
+            document_max_bytes: env_var("DOCUMENT_MAX_BYTES", "10485760")
+                .parse()
+                .unwrap_or(10_485_760),
+            embedding_model: env_var("EMBEDDING_MODEL", "nomic-embed-text"),
+            chunk_tokens: env_var("CHUNK_TOKENS", "300").parse().unwrap_or(300),
+            chunk_overlap_tokens: env_var("CHUNK_OVERLAP_TOKENS", "40")
+                .parse()
+                .unwrap_or(40),
+            rag_top_k: env_var("RAG_TOP_K", "4").parse().unwrap_or(4),
+            rag_context_tokens: env_var("RAG_CONTEXT_TOKENS", "1200")
+                .parse()
+                .unwrap_or(1200),
+            rag_min_score: env_var("RAG_MIN_SCORE", "0.2").parse().unwrap_or(0.2),
+            retrieval_mode: env_var("RETRIEVAL_MODE", "hybrid").to_lowercase(),
+            rerank_provider: env_var("RERANK_PROVIDER", "").to_lowercase(),
+            rerank_model: env_var("RERANK_MODEL", ""),
+            rerank_base_url: env_var("RERANK_BASE_URL", ""),
+            rerank_api_key: env_var("RERANK_API_KEY", ""),
+            rerank_candidates: env_var("RERANK_CANDIDATES", "12").parse().unwrap_or(12),
+            rerank_min_score: env_var("RERANK_MIN_SCORE", "0.3").parse().unwrap_or(0.3),
+            rerank_timeout_ms: env_var("RERANK_TIMEOUT_MS", "5000")
+                .parse()
+                .unwrap_or(5000),
+            embeddings_cloud_model: env_var("EMBEDDINGS_CLOUD_MODEL", ""),
+            embeddings_max_inputs: env_var("EMBEDDINGS_MAX_INPUTS", "256")
+                .parse()
+                .unwrap_or(256),
+            embeddings_cache_size: env_var("EMBEDDINGS_CACHE_SIZE", "2048")
+                .parse()
+                .unwrap_or(2048),
+            embeddings_cache_ttl_seconds: env_var("EMBEDDINGS_CACHE_TTL_SECONDS", "86400")
+                .parse()
+                .unwrap_or(86400),
+
+            moderation_enabled: env_bool("MODERATION_ENABLED", true),
+            moderation_rules_path: env_var("MODERATION_RULES_PATH", ""),
+            moderation_classifier_model: env_var("MODERATION_CLASSIFIER_MODEL", ""),
+            moderation_classifier_action: env_var("MODERATION_CLASSIFIER_ACTION", "block"),
+
+            pii_redaction: env_bool("PII_REDACTION", true),
+            pii_restore: env_bool("PII_RESTORE", true),
+            pii_campus_id_patterns: env_var(
+                "PII_CAMPUS_ID_PATTERNS",
+                r"\b[Ss]\d{7}\b \b\d{2}[A-Z]{2,4}\d{3,5}\b",
+            ),
+
+            route_disclosure: env_bool("ROUTE_DISCLOSURE", true),
+            activity_log_size: env_var("ACTIVITY_LOG_SIZE", &env_var("REPORT_HISTORY_SIZE", "500"))
+                .parse()
+                .unwrap_or(500),
+            admin_token: env_var("ADMIN_TOKEN", ""),
+            auth_required: env_bool("AUTH_REQUIRED", false),
+            cors_allowed_origins: env_var("CORS_ALLOWED_ORIGINS", ""),
+            trusted_proxies: env_var("TRUSTED_PROXIES", ""),
+            data_dir: env_var("DATA_DIR", "data"),
+
+            quota_anonymous: env_var("QUOTA_ANONYMOUS", ""),
+            quota_student: env_var("QUOTA_STUDENT", ""),
+            quota_staff: env_var("QUOTA_STAFF", ""),
+            quota_admin: env_var("QUOTA_ADMIN", ""),
+
+            rate_limit_anonymous: env_var("RATE_LIMIT_ANONYMOUS", "burst=20,per_minute=60"),
+            rate_limit_student: env_var("RATE_LIMIT_STUDENT", "burst=20,per_minute=60"),
+            rate_limit_staff: env_var("RATE_LIMIT_STAFF", "burst=60,per_minute=300"),
+            rate_limit_admin: env_var("RATE_LIMIT_ADMIN", ""),
+            max_concurrent_streams: env_var("MAX_CONCURRENT_STREAMS", "32")
+                .parse()
+                .unwrap_or(32),
+            local_parallel: env_var("LOCAL_PARALLEL", &env_var("OLLAMA_NUM_PARALLEL", "1"))
+                .parse()
+                .unwrap_or(1),
+            cloud_parallel: env_var("CLOUD_PARALLEL", "8").parse().unwrap_or(8),
+            cloud_context_tokens: env_var("CLOUD_CONTEXT_TOKENS", "128000")
+                .parse()
+                .unwrap_or(128_000),
+            reserved_completion_tokens: env_var("RESERVED_COMPLETION_TOKENS", "512")
+                .parse()
+                .unwrap_or(512),
+            token_chars_per_token: env_var("TOKEN_CHARS_PER_TOKEN", "default=4.0"),
+            token_calibration: env_bool("TOKEN_CALIBRATION", true),
+            history_summary: env_bool("HISTORY_SUMMARY", false),
+            summary_max_tokens: env_var("SUMMARY_MAX_TOKENS", "256")
+                .parse()
+                .unwrap_or(256),
+            summary_cache_size: env_var("SUMMARY_CACHE_SIZE", "256")
+                .parse()
+                .unwrap_or(256),
+            summary_cache_ttl_seconds: env_var("SUMMARY_CACHE_TTL_SECONDS", "3600")
+                .parse()
+                .unwrap_or(3600),
+            cloud_prices: env_var(
+                "CLOUD_PRICES",
+                "gpt-4.1-mini=0.40:1.60,gpt-4.1=2.00:8.00,gpt-4o-mini=0.15:0.60,gpt-4o=2.50:10.00",
+            ),
+            cloud_budget_daily_usd: env_var("CLOUD_BUDGET_DAILY_USD", ""),
+            cloud_budget_monthly_usd: env_var("CLOUD_BUDGET_MONTHLY_USD", ""),
+            cloud_budget_cutoff_percent: env_var("CLOUD_BUDGET_CUTOFF_PERCENT", "100")
+                .parse()
+                .unwrap_or(100.0),
+            queue_timeout_ms: env_var("QUEUE_TIMEOUT_MS", "60000")
+                .parse()
+                .unwrap_or(60_000),
+
+            oidc_issuer: env_var("OIDC_ISSUER", ""),
+            oidc_client_id: env_var("OIDC_CLIENT_ID", ""),
+            oidc_client_secret: env_var("OIDC_CLIENT_SECRET", ""),
+            oidc_redirect_uri: env_var("OIDC_REDIRECT_URI", ""),
+            oidc_scopes: env_var("OIDC_SCOPES", "openid profile email"),
+            oidc_audience: env_var("OIDC_AUDIENCE", ""),
+            oidc_role_claim: env_var("OIDC_ROLE_CLAIM", "roles"),
+            oidc_tenant_claim: env_var("OIDC_TENANT_CLAIM", ""),
+            oidc_role_map: env_var("OIDC_ROLE_MAP", ""),
+            oidc_default_role: env_var("OIDC_DEFAULT_ROLE", "student"),
+            oidc_post_login_url: env_var("OIDC_POST_LOGIN_URL", "/"),
+            oidc_session_ttl_seconds: env_var("OIDC_SESSION_TTL_SECONDS", "28800")
+                .parse()
+                .unwrap_or(28800),
+            oidc_timeout_ms: env_var("OIDC_TIMEOUT_MS", "5000")
+                .parse()
+                .unwrap_or(5000),
+
+            residency_local_only_tenants: env_var("RESIDENCY_LOCAL_ONLY_TENANTS", ""),
+            residency_local_only_paths: env_var("RESIDENCY_LOCAL_ONLY_PATHS", ""),
         }
     }
 
@@ -92,6 +324,94 @@ impl AppConfig {
             return Err("RESPONSE_CACHE_SIZE must be greater than 0".to_string());
         }
 
+        if self.reserved_completion_tokens >= u64::from(self.local_num_ctx) {
+            return Err("RESERVED_COMPLETION_TOKENS must be below LOCAL_NUM_CTX".to_string());
+        }
+
+        if self.local_parallel == 0 || self.cloud_parallel == 0 {
+            return Err("LOCAL_PARALLEL and CLOUD_PARALLEL must be greater than 0".to_string());
+        }
+
+        if self.max_concurrent_streams == 0 {
+            return Err("MAX_CONCURRENT_STREAMS must be greater than 0".to_string());
+        }
+
+        if self.structured_output_retries > 5 {
+            return Err("STRUCTURED_OUTPUT_RETRIES must be 5 or less".to_string());
+        }
+
+        if !(1..=10).contains(&self.tool_max_iterations) {
+            return Err("TOOL_MAX_ITERATIONS must be between 1 and 10".to_string());
+        }
+
+        if self.tool_timeout_ms == 0 {
+            return Err("TOOL_TIMEOUT_MS must be greater than 0".to_string());
+        }
+
+        if self.chunk_tokens < 50 || self.chunk_overlap_tokens >= self.chunk_tokens / 2 {
+            return Err(
+                "CHUNK_TOKENS must be >= 50 and CHUNK_OVERLAP_TOKENS below half of it".to_string(),
+            );
+        }
+
+        if !(1..=20).contains(&self.rag_top_k) {
+            return Err("RAG_TOP_K must be between 1 and 20".to_string());
+        }
+
+        if !(-1.0..=1.0).contains(&self.rag_min_score) {
+            return Err("RAG_MIN_SCORE must be between -1 and 1".to_string());
+        }
+
+        if !["vector", "keyword", "hybrid"].contains(&self.retrieval_mode.as_str()) {
+            return Err("RETRIEVAL_MODE must be 'vector', 'keyword' or 'hybrid'".to_string());
+        }
+
+        if !["", "ollama", "openai", "stub"].contains(&self.rerank_provider.as_str()) {
+            return Err("RERANK_PROVIDER must be empty, 'ollama', 'openai' or 'stub'".to_string());
+        }
+
+        if ["ollama", "openai"].contains(&self.rerank_provider.as_str())
+            && self.rerank_model.is_empty()
+        {
+            return Err(
+                "RERANK_MODEL is required when RERANK_PROVIDER is 'ollama' or 'openai'".to_string(),
+            );
+        }
+
+        if self.rerank_provider == "openai" && self.rerank_base_url.is_empty() {
+            return Err("RERANK_BASE_URL is required when RERANK_PROVIDER=openai".to_string());
+        }
+
+        if !(self.rag_top_k..=50).contains(&self.rerank_candidates) {
+            return Err("RERANK_CANDIDATES must be between RAG_TOP_K and 50".to_string());
+        }
+
+        if !(0.0..=1.0).contains(&self.rerank_min_score) {
+            return Err("RERANK_MIN_SCORE must be between 0 and 1".to_string());
+        }
+
+        if self.rerank_timeout_ms == 0 {
+            return Err("RERANK_TIMEOUT_MS must be greater than 0".to_string());
+        }
+
+        if self.oidc_timeout_ms == 0 {
+            return Err("OIDC_TIMEOUT_MS must be greater than 0".to_string());
+        }
+
+        if !(1..=2048).contains(&self.embeddings_max_inputs) {
+            return Err("EMBEDDINGS_MAX_INPUTS must be between 1 and 2048".to_string());
+        }
+
+        if !self.embeddings_cloud_model.is_empty()
+            && self.embeddings_cloud_model == self.embedding_model
+        {
+            return Err("EMBEDDINGS_CLOUD_MODEL must differ from EMBEDDING_MODEL".to_string());
+        }
+
+        if self.activity_log_size == 0 {
+            return Err("ACTIVITY_LOG_SIZE must be greater than 0".to_string());
+        }
+
         if self.upstream_timeout_ms < 1000 {
             return Err("UPSTREAM_TIMEOUT_MS must be >= 1000".to_string());
         }
@@ -0,0 +1,685 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::api_keys::write_atomic;
+use crate::citations::CITATION_INSTRUCTION;
+use crate::config::AppConfig;
+use crate::pdf;
+use crate::search::Bm25Index;
+use crate::usage::estimate_tokens;
+
+/// Characters per token assumed when sizing chunks, matching
+/// `usage::estimate_tokens`.
+const CHARS_PER_TOKEN: usize = 4;
+/// Characters of a chunk shown as its snippet.
+const SNIPPET_CHARS: usize = 280;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentFormat {
+    Text,
+    Markdown,
+    Pdf,
+}
+
+impl DocumentFormat {
+    /// From the upload's content type, falling back to the title's file
+    /// extension for generic types such as `application/octet-stream`.
+    pub fn detect(content_type: &str, title: &str) -> Option<Self> {
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        match mime.as_str() {
+            "text/plain" => return Some(DocumentFormat::Text),
+            "text/markdown" | "text/x-markdown" => return Some(DocumentFormat::Markdown),
+            "application/pdf" => return Some(DocumentFormat::Pdf),
+            _ => {}
+        }
+        let extension = title.rsplit_once('.')?.1.to_ascii_lowercase();
+        match extension.as_str() {
+            "txt" | "text" => Some(DocumentFormat::Text),
+            "md" | "markdown" => Some(DocumentFormat::Markdown),
+            "pdf" => Some(DocumentFormat::Pdf),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentRecord {
+    pub id: String,
+    pub collection: String,
+    pub title: String,
+    pub format: DocumentFormat,
+    pub bytes: usize,
+    /// Page count for PDFs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pages: Option<u32>,
+    pub chunks: usize,
+    pub embedding_model: String,
+    pub uploaded_by: String,
+    pub uploaded_at: u64,
+}
+
+/// A retrievable passage of a document.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Chunk {
+    /// `<document id>:<index>`.
+    pub id: String,
+    pub document_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<u32>,
+    /// Character offset of the passage within its page, or within the
+    /// document when it has no pages.
+    pub offset: usize,
+    pub text: String,
+    /// Unit length, so cosine similarity is a dot product.
+    pub embedding: Vec<f32>,
+}
+
+impl Chunk {
+    /// Stable reference to the passage: `<document id>#p<page>:<offset>`,
+    /// or `<document id>#<offset>` without pages.
+    pub fn citation_id(&self) -> String {
+        match self.page {
+            Some(page) => format!("{}#p{}:{}", self.document_id, page, self.offset),
+            None => format!("{}#{}", self.document_id, self.offset),
+        }
+    }
+
+    /// The start of the text on one line, cut at a word boundary.
+    pub fn snippet(&self) -> String {
+        let text = self.text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.chars().count() <= SNIPPET_CHARS {
+            return text;
+        }
+        let cut: String = text.chars().take(SNIPPET_CHARS).collect();
+        let cut = cut.rsplit_once(' ').map_or(cut.as_str(), |(head, _)| head);
+        format!("{cut}…")
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CollectionInfo {
+    pub name: String,
+    pub documents: usize,
+    pub chunks: usize,
+}
+
+/// A chunk returned by a search, with the title of its document.
+#[derive(Clone, Debug)]
+pub struct Hit {
+    pub chunk: Chunk,
+    pub title: String,
+    pub score: f32,
+}
+
+/// Extracted text of one page, or of the whole document when it has none.
+pub struct Section {
+    pub page: Option<u32>,
+    pub text: String,
+}
+
+/// Passage boundaries produced by `chunk_sections`, before embedding.
+pub struct Passage {
+    pub page: Option<u32>,
+    pub offset: usize,
+    pub text: String,
+}
+
+pub fn extract(format: DocumentFormat, bytes: &[u8]) -> Result<Vec<Section>, String> {
+    let sections = match format {
+        DocumentFormat::Text | DocumentFormat::Markdown => {
+            let text = std::str::from_utf8(bytes)
+                .map_err(|_| "the document is not valid UTF-8 text".to_string())?;
+            vec![Section {
+                page: None,
+                text: text.trim_start_matches('\u{feff}').replace("\r\n", "\n"),
+            }]
+        }
+        DocumentFormat::Pdf => pdf::extract_pages(bytes)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, text)| Section {
+                page: Some(i as u32 + 1),
+                text,
+            })
+            .collect(),
+    };
+    if sections.iter().all(|s| s.text.trim().is_empty()) {
+        return Err(match format {
+            DocumentFormat::Pdf => {
+                "the PDF has no extractable text; scanned documents need OCR first".to_string()
+            }
+            _ => "the document is empty".to_string(),
+        });
+    }
+    Ok(sections)
+}
+
+/// Splits sections into passages of about `max_tokens`, each repeating the
+/// last `overlap_tokens` of the one before. Passages prefer to end at a
+/// paragraph break and never span pages.
+pub fn chunk_sections(
+    sections: &[Section],
+    max_tokens: usize,
+    overlap_tokens: usize,
+) -> Vec<Passage> {
+    let max_chars = max_tokens * CHARS_PER_TOKEN;
+    let overlap_chars = overlap_tokens * CHARS_PER_TOKEN;
+    let mut passages = Vec::new();
+
+    for section in sections {
+        let words = words(&section.text);
+        let mut start = 0;
+        while start < words.len() {
+            let mut end = start + 1;
+            while end < words.len() && words[end].char_end - words[start].char_start <= max_chars {
+                end += 1;
+            }
+            if end < words.len() {
+                let half = start + (end - start) / 2;
+                if let Some(cut) = (half + 1..end).rev().find(|&i| words[i].paragraph) {
+                    end = cut;
+                }
+            }
+
+            let (first, last) = (&words[start], &words[end - 1]);
+            passages.push(Passage {
+                page: section.page,
+                offset: first.char_start,
+                text: section.text[first.byte_start..last.byte_end].to_string(),
+            });
+            if end == words.len() {
+                break;
+            }
+            let resume = last.char_end.saturating_sub(overlap_chars);
+            start = (start + 1..end)
+                .find(|&i| words[i].char_start >= resume)
+                .unwrap_or(end);
+        }
+    }
+    passages
+}
+
+struct Word {
+    byte_start: usize,
+    byte_end: usize,
+    char_start: usize,
+    char_end: usize,
+    /// Preceded by a blank line.
+    paragraph: bool,
+}
+
+fn words(text: &str) -> Vec<Word> {
+    let mut words = Vec::new();
+    // Byte start, char start and paragraph flag of the word being read.
+    let mut current: Option<(usize, usize, bool)> = None;
+    let mut newlines = 0;
+    let mut chars = 0;
+
+    for (byte, c) in text.char_indices() {
+        if c.is_whitespace() {
+            if let Some((byte_start, char_start, paragraph)) = current.take() {
+                words.push(Word {
+                    byte_start,
+                    byte_end: byte,
+                    char_start,
+                    char_end: chars,
+                    paragraph,
+                });
+            }
+            if c == '\n' {
+                newlines += 1;
+            }
+        } else if current.is_none() {
+            current = Some((byte, chars, newlines >= 2));
+            newlines = 0;
+        }
+        chars += 1;
+    }
+    if let Some((byte_start, char_start, paragraph)) = current {
+        words.push(Word {
+            byte_start,
+            byte_end: text.len(),
+            char_start,
+            char_end: chars,
+            paragraph,
+        });
+    }
+    words
+}
+
+/// Scales `vector` to unit length; zero vectors are left as they are.
+pub fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+}
+
+/// Collection names double as file names.
+pub fn valid_collection(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+/// Formats retrieved passages as a system prompt section, adding hits in
+/// rank order while they fit in `max_tokens`. Returns the section and the
+/// hits it includes, which the section numbers from 1 for citing.
+pub fn context_block(collection: &str, hits: Vec<Hit>, max_tokens: u64) -> (String, Vec<Hit>) {
+    let mut block = format!(
+        "Excerpts from the '{collection}' document collection follow. Use them when they are relevant to the question, and say so when they do not contain the answer."
+    );
+    let mut used = estimate_tokens(&block) + estimate_tokens(CITATION_INSTRUCTION) + 1;
+    let mut included = Vec::new();
+
+    for hit in hits {
+        let source = match hit.chunk.page {
+            Some(page) => format!("{}, page {}", hit.title, page),
+            None => hit.title.clone(),
+        };
+        let excerpt = format!(
+            "\n\n[{}] {}:\n{}",
+            included.len() + 1,
+            source,
+            hit.chunk.text
+        );
+        let cost = estimate_tokens(&excerpt);
+        if used + cost > max_tokens {
+            continue;
+        }
+        used += cost;
+        block.push_str(&excerpt);
+        included.push(hit);
+    }
+    block.push_str("\n\n");
+    block.push_str(CITATION_INSTRUCTION);
+    (block, included)
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct Collection {
+    documents: Vec<DocumentRecord>,
+    chunks: Vec<Chunk>,
+    /// Rebuilt from the chunks on load and after every change.
+    #[serde(skip)]
+    index: Bm25Index,
+}
+
+impl Collection {
+    fn title(&self, document_id: &str) -> String {
+        self.documents
+            .iter()
+            .find(|d| d.id == document_id)
+            .map(|d| d.title.clone())
+            .unwrap_or_default()
+    }
+
+    /// Indexes each chunk with its document's title.
+    fn reindex(&mut self) {
+        let texts: Vec<String> = self
+            .chunks
+            .iter()
+            .map(|c| format!("{}\n{}", self.title(&c.document_id), c.text))
+            .collect();
+        self.index = Bm25Index::build(texts.iter().map(String::as_str));
+    }
+
+    fn hit(&self, chunk: &Chunk, score: f32) -> Hit {
+        Hit {
+            chunk: chunk.clone(),
+            title: self.title(&chunk.document_id),
+            score,
+        }
+    }
+}
+
+/// Documents and their embedded chunks, one JSON file per collection.
+/// Searches are exhaustive, which is fast enough for course and policy
+/// collections of a few thousand chunks.
+pub struct DocumentStore {
+    dir: PathBuf,
+    collections: Mutex<BTreeMap<String, Collection>>,
+    /// Held from a change until its file is written, so writes land in the
+    /// order the changes were made.
+    writes: tokio::sync::Mutex<()>,
+}
+
+impl DocumentStore {
+    pub fn load(cfg: &AppConfig) -> Result<Self, String> {
+        let dir = if cfg.documents_dir.is_empty() {
+            Path::new(&cfg.data_dir).join("documents")
+        } else {
+            PathBuf::from(&cfg.documents_dir)
+        };
+
+        let mut collections = BTreeMap::new();
+        if dir.exists() {
+            let entries = fs::read_dir(&dir)
+                .map_err(|e| format!("cannot read DOCUMENTS_DIR {}: {e}", dir.display()))?;
+            for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                let Some(name) = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .filter(|_| path.extension().is_some_and(|ext| ext == "json"))
+                    .filter(|name| valid_collection(name))
+                else {
+                    continue;
+                };
+                let raw = fs::read_to_string(&path)
+                    .map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+                let mut collection: Collection = serde_json::from_str(&raw)
+                    .map_err(|e| format!("invalid document collection {}: {e}", path.display()))?;
+                if collection
+                    .documents
+                    .iter()
+                    .any(|d| d.embedding_model != cfg.embedding_model)
+                {
+                    warn!(
+                        "collection '{}' has documents embedded with another model than {}; re-upload them for reliable retrieval",
+                        name, cfg.embedding_model
+                    );
+                }
+                collection.reindex();
+                collections.insert(name.to_string(), collection);
+            }
+        }
+        info!(
+            "loaded {} document collections from {}",
+            collections.len(),
+            dir.display()
+        );
+
+        Ok(Self {
+            dir,
+            collections: Mutex::new(collections),
+            writes: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// What to write for a collection: its JSON, or `None` to remove the
+    /// file once the last document is gone.
+    fn snapshot(&self, name: &str, collection: &Collection) -> Result<Snapshot, String> {
+        let path = self.dir.join(format!("{name}.json"));
+        if collection.documents.is_empty() {
+            return Ok((path, None));
+        }
+        let raw = serde_json::to_string(collection)
+            .map_err(|e| format!("cannot encode collection '{name}': {e}"))?;
+        Ok((path, Some(raw)))
+    }
+
+    pub async fn add(&self, record: DocumentRecord, chunks: Vec<Chunk>) -> Result<(), String> {
+        let _writing = self.writes.lock().await;
+        let name = record.collection.clone();
+        let id = record.id.clone();
+        let snapshot = {
+            let mut collections = self.lock()?;
+            let collection = collections.entry(name.clone()).or_default();
+            collection.documents.push(record);
+            collection.chunks.extend(chunks);
+            collection.reindex();
+            self.snapshot(&name, collection)
+        };
+
+        let Err(err) = persist(snapshot).await else {
+            return Ok(());
+        };
+        // Keep memory in step with disk.
+        let mut collections = self.lock()?;
+        if let Some(collection) = collections.get_mut(&name) {
+            collection.documents.retain(|d| d.id != id);
+            collection.chunks.retain(|c| c.document_id != id);
+            collection.reindex();
+            if collection.documents.is_empty() {
+                collections.remove(&name);
+            }
+        }
+        Err(err)
+    }
+
+    /// Removes a document; `Ok(None)` when no collection has it.
+    pub async fn delete(&self, id: &str) -> Result<Option<DocumentRecord>, String> {
+        let _writing = self.writes.lock().await;
+        let (name, index, record, chunks, snapshot) = {
+            let mut collections = self.lock()?;
+            let Some((name, collection)) = collections
+                .iter_mut()
+                .find(|(_, c)| c.documents.iter().any(|d| d.id == id))
+            else {
+                return Ok(None);
+            };
+            let index = collection
+                .documents
+                .iter()
+                .position(|d| d.id == id)
+                .expect("found above");
+            let record = collection.documents.remove(index);
+            let chunks: Vec<Chunk> = collection
+                .chunks
+                .iter()
+                .filter(|c| c.document_id == id)
+                .cloned()
+                .collect();
+            collection.chunks.retain(|c| c.document_id != id);
+            collection.reindex();
+            let snapshot = self.snapshot(name, collection);
+            (name.clone(), index, record, chunks, snapshot)
+        };
+
+        let result = persist(snapshot).await;
+        let mut collections = self.lock()?;
+        let Some(collection) = collections.get_mut(&name) else {
+            return result.map(|()| Some(record));
+        };
+        if let Err(err) = result {
+            collection.documents.insert(index, record);
+            collection.chunks.extend(chunks);
+            collection.reindex();
+            return Err(err);
+        }
+        if collection.documents.is_empty() {
+            collections.remove(&name);
+        }
+        Ok(Some(record))
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, BTreeMap<String, Collection>>, String> {
+        self.collections
+            .lock()
+            .map_err(|_| "document store unavailable".to_string())
+    }
+
+    pub fn contains(&self, collection: &str) -> bool {
+        self.collections
+            .lock()
+            .map(|c| c.contains_key(collection))
+            .unwrap_or(false)
+    }
+
+    /// Documents of one collection, or of all of them.
+    pub fn list(&self, collection: Option<&str>) -> Vec<DocumentRecord> {
+        let Ok(collections) = self.collections.lock() else {
+            return Vec::new();
+        };
+        collections
+            .iter()
+            .filter(|(name, _)| collection.map_or(true, |wanted| wanted == name.as_str()))
+            .flat_map(|(_, c)| c.documents.iter().cloned())
+            .collect()
+    }
+
+    pub fn collections(&self) -> Vec<CollectionInfo> {
+        let Ok(collections) = self.collections.lock() else {
+            return Vec::new();
+        };
+        collections
+            .iter()
+            .map(|(name, c)| CollectionInfo {
+                name: name.clone(),
+                documents: c.documents.len(),
+                chunks: c.chunks.len(),
+            })
+            .collect()
+    }
+
+    /// The chunk a citation id points at, from any collection.
+    pub fn find_citation(&self, citation_id: &str) -> Option<Hit> {
+        let collections = self.collections.lock().ok()?;
+        collections.values().find_map(|collection| {
+            let chunk = collection
+                .chunks
+                .iter()
+                .find(|c| c.citation_id() == citation_id)?;
+            Some(collection.hit(chunk, 0.0))
+        })
+    }
+
+    /// The `k` chunks most similar to `query` (unit length) scoring at least
+    /// `min_score`, best first.
+    pub fn search(&self, collection: &str, query: &[f32], k: usize, min_score: f32) -> Vec<Hit> {
+        let Ok(collections) = self.collections.lock() else {
+            return Vec::new();
+        };
+        let Some(collection) = collections.get(collection) else {
+            return Vec::new();
+        };
+
+        let mut scored: Vec<(f32, &Chunk)> = collection
+            .chunks
+            .iter()
+            .filter(|c| c.embedding.len() == query.len())
+            .map(|c| (dot(&c.embedding, query), c))
+            .filter(|(score, _)| *score >= min_score)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.truncate(k);
+
+        scored
+            .into_iter()
+            .map(|(score, chunk)| collection.hit(chunk, score))
+            .collect()
+    }
+
+    /// The `k` chunks scoring best for `query` under BM25, best first.
+    pub fn keyword_search(&self, collection: &str, query: &str, k: usize) -> Vec<Hit> {
+        let Ok(collections) = self.collections.lock() else {
+            return Vec::new();
+        };
+        let Some(collection) = collections.get(collection) else {
+            return Vec::new();
+        };
+        collection
+            .index
+            .search(query, k)
+            .into_iter()
+            .map(|(position, score)| collection.hit(&collection.chunks[position], score))
+            .collect()
+    }
+}
+
+/// A collection file and its new contents; see [`DocumentStore::snapshot`].
+type Snapshot = (PathBuf, Option<String>);
+
+/// Writes a snapshot on the blocking pool, outside the store lock.
+async fn persist(snapshot: Result<Snapshot, String>) -> Result<(), String> {
+    let (path, raw) = snapshot?;
+    web::block(move || match raw {
+        Some(raw) => write_atomic(&path, &raw),
+        None => match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(format!("cannot remove {}: {e}", path.display()))
+            }
+            _ => Ok(()),
+        },
+    })
+    .await
+    .map_err(|e| format!("collection not saved: {e}"))?
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::random_hex;
+
+    fn store(dir: &Path) -> DocumentStore {
+        DocumentStore {
+            dir: dir.to_path_buf(),
+            collections: Mutex::new(BTreeMap::new()),
+            writes: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn document(id: &str, collection: &str, text: &str) -> (DocumentRecord, Vec<Chunk>) {
+        let record = DocumentRecord {
+            id: id.to_string(),
+            collection: collection.to_string(),
+            title: format!("{id}.txt"),
+            format: DocumentFormat::Text,
+            bytes: text.len(),
+            pages: None,
+            chunks: 1,
+            embedding_model: "test-embed".to_string(),
+            uploaded_by: "key:test".to_string(),
+            uploaded_at: 0,
+        };
+        let chunk = Chunk {
+            id: format!("{id}:0"),
+            document_id: id.to_string(),
+            page: None,
+            offset: 0,
+            text: text.to_string(),
+            embedding: vec![1.0],
+        };
+        (record, vec![chunk])
+    }
+
+    #[actix_web::test]
+    async fn changes_are_written_and_rolled_back_when_the_write_fails() {
+        let dir = std::env::temp_dir().join(format!("campus-documents-{}", random_hex(8)));
+        let documents = store(&dir);
+
+        let (record, chunks) = document("doc-1", "policies", "parking permits renew in august");
+        documents.add(record, chunks).await.unwrap();
+        let (record, chunks) = document("doc-2", "policies", "library fines are waived");
+        documents.add(record, chunks).await.unwrap();
+        let saved = fs::read_to_string(dir.join("policies.json")).unwrap();
+        assert!(saved.contains("doc-1") && saved.contains("doc-2"));
+
+        // A directory where the file should go makes the write fail.
+        fs::create_dir_all(dir.join("courses.json")).unwrap();
+        let (record, chunks) = document("doc-3", "courses", "syllabus");
+        assert!(documents.add(record, chunks).await.is_err());
+        assert!(!documents.contains("courses"));
+
+        assert!(documents.delete("doc-1").await.unwrap().is_some());
+        let hits = documents.keyword_search("policies", "library fines", 5);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].chunk.document_id, "doc-2");
+        assert!(documents.delete("doc-1").await.unwrap().is_none());
+
+        documents.delete("doc-2").await.unwrap();
+        assert!(!dir.join("policies.json").exists());
+        assert!(!documents.contains("policies"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
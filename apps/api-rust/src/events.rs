@@ -0,0 +1,87 @@
+use bytes::Bytes;
+use serde_json::json;
+
+use crate::citations::Citation;
+use crate::models::ResponseMetadata;
+use crate::moderation::ModerationEvent;
+use crate::queue::QueuePosition;
+use crate::tools::{ToolCall, ToolResult};
+
+/// Wire format for streamed chat output, picked from the request's `Accept`
+/// header. Plain text stays the default so existing clients keep working.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamFormat {
+    Text,
+    Sse,
+}
+
+impl StreamFormat {
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        match accept {
+            Some(v) if v.contains("text/event-stream") => StreamFormat::Sse,
+            _ => StreamFormat::Text,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            StreamFormat::Text => "text/plain; charset=utf-8",
+            StreamFormat::Sse => "text/event-stream",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum StreamEvent {
+    Delta(String),
+    Moderation(ModerationEvent),
+    Queue(QueuePosition),
+    ToolCall(ToolCall),
+    ToolResult(ToolResult),
+    /// Sources cited by an answer grounded on a document collection.
+    Citations(Vec<Citation>),
+    Done(Box<ResponseMetadata>),
+}
+
+impl StreamEvent {
+    /// Returns `None` for events the chosen format has no way to carry.
+    pub fn encode(&self, format: StreamFormat) -> Option<Bytes> {
+        if let StreamEvent::Delta(text) = self {
+            if text.is_empty() {
+                return None;
+            }
+        }
+
+        match format {
+            StreamFormat::Text => match self {
+                StreamEvent::Delta(text) => Some(Bytes::from(text.clone())),
+                StreamEvent::Moderation(_)
+                | StreamEvent::Queue(_)
+                | StreamEvent::ToolCall(_)
+                | StreamEvent::ToolResult(_)
+                | StreamEvent::Citations(_)
+                | StreamEvent::Done(_) => None,
+            },
+            StreamFormat::Sse => {
+                let (name, data) = match self {
+                    StreamEvent::Delta(text) => ("delta", json!({ "text": text }).to_string()),
+                    StreamEvent::Moderation(event) => {
+                        ("moderation", serde_json::to_string(event).ok()?)
+                    }
+                    StreamEvent::Queue(position) => {
+                        ("queue", serde_json::to_string(position).ok()?)
+                    }
+                    StreamEvent::ToolCall(call) => ("tool_call", serde_json::to_string(call).ok()?),
+                    StreamEvent::ToolResult(result) => {
+                        ("tool_result", serde_json::to_string(result).ok()?)
+                    }
+                    StreamEvent::Citations(citations) => {
+                        ("citations", serde_json::to_string(citations).ok()?)
+                    }
+                    StreamEvent::Done(meta) => ("done", serde_json::to_string(meta).ok()?),
+                };
+                Some(Bytes::from(format!("event: {name}\ndata: {data}\n\n")))
+            }
+        }
+    }
+}
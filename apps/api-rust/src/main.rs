@@ -1,24 +1,71 @@
+mod activity;
+mod api_keys;
+mod auth;
+mod budget;
 mod cache;
+mod caller;
+mod citations;
+mod client_ip;
+mod confidence;
 mod config;
+mod documents;
+mod events;
 mod models;
+mod moderation;
+mod oidc;
+mod pdf;
+mod persona;
 mod providers;
+mod queue;
+mod ratelimit;
+mod redaction;
+mod report;
+mod rerank;
+mod residency;
 mod routes;
+mod schema;
+mod search;
 mod state;
+mod summary;
+mod tokens;
+mod tools;
+mod usage;
+mod utility;
 
 use std::io;
-use std::sync::Mutex;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use actix_cors::Cors;
 use actix_web::http::header;
-use actix_web::middleware::{DefaultHeaders, Logger, NormalizePath};
-use actix_web::{web, App, HttpResponse, HttpServer};
+use actix_web::middleware::{from_fn, DefaultHeaders, Logger, NormalizePath};
+use actix_web::{web, App, HttpMessage, HttpResponse, HttpServer};
 use reqwest::Client;
-use tracing::{error, info};
+use tokio::sync::{Notify, Semaphore};
+use tracing::{error, info, warn};
 
+use crate::activity::ActivityLog;
+use crate::api_keys::ApiKeyStore;
+use crate::auth::Identity;
+use crate::budget::CloudBudget;
 use crate::cache::LruTtlCache;
+use crate::client_ip::TrustedProxies;
 use crate::config::AppConfig;
+use crate::documents::DocumentStore;
 use crate::models::ErrorResponse;
+use crate::moderation::Moderator;
+use crate::oidc::OidcClient;
+use crate::persona::PersonaRegistry;
+use crate::queue::GenerationQueue;
+use crate::ratelimit::RateLimiter;
+use crate::redaction::PiiRedactor;
+use crate::residency::ResidencyPolicy;
 use crate::state::{AppState, RuntimeMetrics};
+use crate::summary::HistorySummarizer;
+use crate::tokens::TokenEstimators;
+use crate::tools::ToolRegistry;
+use crate::usage::UsageStore;
+use crate::utility::TemplateLibrary;
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
@@ -29,6 +76,77 @@ async fn main() -> io::Result<()> {
         error!("invalid configuration: {}", msg);
         return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
     }
+    if std::env::var_os("REPORT_HISTORY_SIZE").is_some() {
+        warn!("REPORT_HISTORY_SIZE is deprecated; set ACTIVITY_LOG_SIZE instead");
+    }
+
+    let moderation = Moderator::load(&cfg).map_err(|msg| {
+        error!("invalid moderation configuration: {}", msg);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
+
+    let tools = ToolRegistry::builtin();
+    let personas = PersonaRegistry::load(&cfg, &tools).map_err(|msg| {
+        error!("invalid persona configuration: {}", msg);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
+
+    let templates = TemplateLibrary::load(&cfg).map_err(|msg| {
+        error!("cannot load utility templates: {}", msg);
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    })?;
+
+    let documents = DocumentStore::load(&cfg).map_err(|msg| {
+        error!("cannot load documents: {}", msg);
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    })?;
+
+    let pii = PiiRedactor::load(&cfg).map_err(|msg| {
+        error!("invalid PII redaction configuration: {}", msg);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
+
+    let api_keys =
+        ApiKeyStore::load(Path::new(&cfg.data_dir).join("api_keys.json")).map_err(|msg| {
+            error!("cannot load API keys: {}", msg);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })?;
+
+    let data_dir = Path::new(&cfg.data_dir);
+    let usage = UsageStore::load(
+        &cfg,
+        data_dir.join("usage.json"),
+        data_dir.join("quota_overrides.json"),
+    )
+    .map_err(|msg| {
+        error!("invalid quota configuration: {}", msg);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
+
+    let budget = CloudBudget::load(&cfg, data_dir.join("cloud_spend.json")).map_err(|msg| {
+        error!("invalid cloud budget configuration: {}", msg);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
+
+    let tokens = TokenEstimators::from_config(&cfg).map_err(|msg| {
+        error!("invalid token estimate configuration: {}", msg);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
+
+    let rate_limiter = RateLimiter::from_config(&cfg).map_err(|msg| {
+        error!("invalid rate limit configuration: {}", msg);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
+
+    let trusted_proxies = TrustedProxies::from_config(&cfg).map_err(|msg| {
+        error!("invalid trusted proxy configuration: {}", msg);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
+
+    let oidc = OidcClient::from_config(&cfg).map_err(|msg| {
+        error!("invalid OIDC configuration: {}", msg);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
 
     let bind = format!("0.0.0.0:{}", cfg.port);
 
@@ -47,8 +165,28 @@ async fn main() -> io::Result<()> {
             cfg.response_cache_size,
             cfg.response_cache_ttl_seconds,
         )),
-        last_query: Mutex::new(String::new()),
-        last_route: Mutex::new(String::new()),
+        embeddings_cache: Mutex::new(LruTtlCache::new(
+            cfg.embeddings_cache_size,
+            cfg.embeddings_cache_ttl_seconds,
+        )),
+        activity: Mutex::new(ActivityLog::new(cfg.activity_log_size)),
+        moderation,
+        personas,
+        templates,
+        tools,
+        documents,
+        pii,
+        residency: ResidencyPolicy::from_config(&cfg),
+        api_keys: Mutex::new(api_keys),
+        oidc,
+        usage: Mutex::new(usage),
+        budget: Mutex::new(budget),
+        rate_limiter,
+        trusted_proxies,
+        stream_slots: Arc::new(Semaphore::new(cfg.max_concurrent_streams)),
+        queue: GenerationQueue::from_config(&cfg),
+        tokens,
+        summarizer: HistorySummarizer::new(&cfg),
         metrics: RuntimeMetrics::new(),
     });
 
@@ -57,27 +195,61 @@ async fn main() -> io::Result<()> {
         bind, cfg.mode, cfg.ollama_model, cfg.cloud_model
     );
 
+    let stop_saving = Arc::new(Notify::new());
+    let saver = actix_web::rt::spawn(usage::save_periodically(state.clone(), stop_saving.clone()));
+
+    let app_state = state.clone();
     HttpServer::new(move || {
         App::new()
-            .app_data(state.clone())
+            .app_data(app_state.clone())
             .app_data(web::JsonConfig::default().limit(1_000_000))
+            .app_data(web::PayloadConfig::new(cfg.document_max_bytes))
+            .wrap(from_fn(ratelimit::limit))
+            .wrap(from_fn(auth::authenticate))
+            .wrap(cors(&cfg))
+            .wrap(DefaultHeaders::new().add((header::X_CONTENT_TYPE_OPTIONS, "nosniff")))
             .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allow_any_method()
-                    .allow_any_header()
-                    .max_age(3600),
+                Logger::new(
+                    r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T principal=%{principal}xo"#,
+                )
+                .custom_response_replace("principal", |res| {
+                    res.request()
+                        .extensions()
+                        .get::<Identity>()
+                        .map(|i| i.principal.clone())
+                        .unwrap_or_else(|| "-".to_string())
+                }),
             )
-            .wrap(DefaultHeaders::new().add((header::X_CONTENT_TYPE_OPTIONS, "nosniff")))
-            .wrap(Logger::default())
             .wrap(NormalizePath::trim())
             .service(routes::health)
             .service(routes::ready)
             .service(routes::metrics)
+            .service(routes::personas)
+            .service(routes::list_tools)
             .service(routes::utility_templates)
+            .service(routes::create_utility_template)
+            .service(routes::update_utility_template)
+            .service(routes::delete_utility_template)
             .service(routes::utility_generate)
+            .service(routes::upload_document)
+            .service(routes::list_documents)
+            .service(routes::document_collections)
+            .service(routes::delete_document)
+            .service(routes::search)
+            .service(routes::create_embeddings)
             .service(routes::ai_report)
+            .service(routes::ai_report_by_id)
+            .service(routes::ai_activity)
             .service(routes::chat)
+            .service(routes::whoami)
+            .service(routes::user_usage)
+            .service(routes::admin_usage)
+            .service(routes::oidc_login)
+            .service(routes::oidc_callback)
+            .service(routes::logout)
+            .service(routes::list_api_keys)
+            .service(routes::create_api_key)
+            .service(routes::revoke_api_key)
             .default_service(web::route().to(|| async {
                 HttpResponse::NotFound().json(ErrorResponse {
                     error: "Not found".to_string(),
@@ -87,7 +259,35 @@ async fn main() -> io::Result<()> {
     .shutdown_timeout(15)
     .bind(bind)?
     .run()
-    .await
+    .await?;
+
+    stop_saving.notify_one();
+    let _ = saver.await;
+    Ok(())
+}
+
+/// Any origin while the API is open; with `AUTH_REQUIRED` only the
+/// `CORS_ALLOWED_ORIGINS`, or none, so other sites cannot drive it with a
+/// signed-in browser's credentials.
+fn cors(cfg: &AppConfig) -> Cors {
+    let origins: Vec<&str> = cfg
+        .cors_allowed_origins
+        .split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .collect();
+    let mut cors = Cors::default()
+        .allow_any_method()
+        .allow_any_header()
+        .expose_any_header()
+        .max_age(3600);
+    if origins.is_empty() && !cfg.auth_required {
+        cors = cors.allow_any_origin();
+    }
+    for origin in origins {
+        cors = cors.allowed_origin(origin);
+    }
+    cors
 }
 
 fn init_tracing() {
@@ -1,4 +1,20 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::api_keys::ApiKeyRecord;
+use crate::auth::Role;
+use crate::budget::BudgetStatus;
+use crate::citations::Citation;
+use crate::moderation::ModerationEvent;
+use crate::providers::TokenUsage;
+use crate::queue::{Lane, QueueStatus};
+use crate::residency::Residency;
+use crate::schema::ResponseSchema;
+use crate::search::SearchMode;
+use crate::tools::ToolResult;
+use crate::usage::{QuotaExceeded, QuotaLimits, UsageCounters};
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ChatMessage {
@@ -7,45 +23,212 @@ pub struct ChatMessage {
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct ChatRequest {
     pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub residency: Option<Residency>,
+    #[serde(default)]
+    pub priority: Lane,
+    /// Persona id from `/api/personas`; the default persona when absent.
+    #[serde(default)]
+    pub persona: Option<String>,
+    /// Constrains the answer to JSON matching this schema.
+    #[serde(default)]
+    pub response_schema: Option<ResponseSchema>,
+    /// Narrows the persona's tools; `[]` turns tool calling off.
+    #[serde(default)]
+    pub tools: Option<Vec<String>>,
+    /// Document collection to retrieve context from.
+    #[serde(default)]
+    pub collection: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct UtilityGenerateRequest {
     pub template: Option<String>,
-    pub prompt: String,
-}
-
-#[derive(Serialize)]
-pub struct UtilityTemplate {
-    pub id: &'static str,
-    pub title: &'static str,
-    pub description: &'static str,
+    /// Shorthand for the `input` variable.
+    #[serde(default)]
+    pub prompt: Option<String>,
+    #[serde(default)]
+    pub variables: BTreeMap<String, Value>,
+    /// Stream the answer like `/api/chat` instead of returning JSON.
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub residency: Option<Residency>,
+    #[serde(default)]
+    pub priority: Lane,
 }
 
 #[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct UtilityGenerateResponse {
     pub result: String,
+    /// The parsed answer of templates with a JSON output format.
+    pub data: Option<Value>,
+    pub template: String,
+    /// Same as the `done` event of a streamed answer.
+    pub metadata: Option<ResponseMetadata>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct AiReport {
+    pub request_id: String,
+    pub created_at: u64,
+    pub principal: Option<String>,
+    pub role: Option<Role>,
     pub confidence: f32,
+    pub confidence_source: String,
+    pub confidence_metrics: ConfidenceMetrics,
     pub bias_warnings: Vec<String>,
+    pub sensitive_topics: Vec<String>,
+    pub moderation_warnings: Vec<String>,
+    pub moderation_events: Vec<ModerationEvent>,
+    pub pii_redactions: usize,
+    pub residency: String,
+    pub residency_reason: String,
+    pub cloud_calls: u32,
+    pub persona: String,
+    pub queue_wait_ms: u64,
+    pub structured_output: Option<StructuredOutput>,
+    pub tool_calls: Vec<ToolResult>,
+    pub retrieval: Option<Retrieval>,
+    pub citations: Vec<Citation>,
+    /// As reported by the provider; for cache hits, the usage of the
+    /// generation that produced the cached answer.
+    pub token_usage: Option<TokenUsage>,
+    /// Priced from the provider's token usage; 0 for local answers.
+    pub cloud_cost_usd: f64,
+    /// The tighter of the daily and monthly budgets; `None` without one.
+    pub cloud_budget_remaining_usd: Option<f64>,
     pub transparency_score: u32,
     pub model_info: String,
     pub last_query: String,
     pub route: String,
+    pub route_disclosed: bool,
+    pub cache_hit: bool,
+    pub escalated: bool,
+    pub fallback: bool,
     pub cache_size: usize,
 }
 
+/// Model-grounded confidence derived from token log-probabilities.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfidenceMetrics {
+    pub available: bool,
+    pub token_count: usize,
+    pub mean_token_prob: Option<f32>,
+    pub min_token_prob: Option<f32>,
+    pub geometric_mean_prob: Option<f32>,
+    pub note: Option<String>,
+}
+
+/// Sent as the final `done` event on event-stream responses.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ResponseMetadata {
+    pub request_id: String,
+    pub provider: String,
+    pub model: String,
+    pub tier: String,
+    pub cache_hit: bool,
+    pub fallback: bool,
+    pub confidence: ConfidenceMetrics,
+    pub pii_redactions: usize,
+    pub residency: String,
+    /// Cloud requests actually made for this answer; always 0 under
+    /// local-only residency.
+    pub cloud_calls: u32,
+    pub persona: String,
+    pub queue_wait_ms: u64,
+    pub token_usage: Option<TokenUsage>,
+    pub structured_output: Option<StructuredOutput>,
+    pub tool_calls: Vec<ToolResult>,
+    pub retrieval: Option<Retrieval>,
+    pub citations: Vec<Citation>,
+}
+
+/// Document chunks injected into the prompt for a `collection` request,
+/// in the order the model cites them.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Retrieval {
+    pub collection: String,
+    pub mode: SearchMode,
+    /// Provider and model that reordered the chunks, when reranking ran.
+    pub reranker: Option<String>,
+    pub chunks: Vec<RetrievedChunk>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RetrievedChunk {
+    pub chunk_id: String,
+    pub citation_id: String,
+    pub document_id: String,
+    pub title: String,
+    pub page: Option<u32>,
+    /// Cosine similarity, BM25 score or fused rank score, by `mode`; the
+    /// reranker's score, from 0 to 1, when `reranker` is set.
+    pub score: f32,
+}
+
+/// How a schema-constrained answer fared against its schema.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StructuredOutput {
+    pub schema: String,
+    pub valid: bool,
+    /// Generations made, including re-prompts; 0 for cache hits.
+    pub attempts: u32,
+    /// Validation errors of the last attempt.
+    pub errors: Vec<String>,
+}
+
+/// Sent instead of a result when a structured answer never validated.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StructuredOutputFailedResponse {
+    pub error: String,
+    pub request_id: String,
+    pub structured_output: StructuredOutput,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ActivityRecord {
+    pub request_id: String,
+    pub owner: String,
+    pub created_at: u64,
+    pub query: String,
+    pub route: String,
+    pub cache_hit: bool,
+    pub fallback: bool,
+    pub confidence: f32,
+    pub transparency_score: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ActivityQuery {
+    pub limit: Option<usize>,
+}
+
 #[derive(Serialize)]
 pub struct ErrorResponse {
     pub error: String,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModerationBlockedResponse {
+    pub error: String,
+    pub request_id: String,
+    pub moderation: Vec<ModerationEvent>,
+}
+
 #[derive(Serialize)]
 pub struct HealthResponse {
     pub ok: bool,
@@ -69,11 +252,259 @@ pub struct RoutingHealth {
 pub struct MetricsResponse {
     pub requests_total: u64,
     pub chat_requests_total: u64,
+    pub utility_requests_total: u64,
+    pub structured_retries_total: u64,
+    pub structured_failures_total: u64,
+    pub tool_calls_total: u64,
+    pub tool_failures_total: u64,
+    pub documents_ingested_total: u64,
+    pub retrievals_total: u64,
+    pub retrieval_failures_total: u64,
+    pub searches_total: u64,
+    pub embedding_requests_total: u64,
+    pub embedding_inputs_total: u64,
+    pub reranks_total: u64,
+    pub rerank_failures_total: u64,
+    pub citations_total: u64,
+    pub citations_flagged_total: u64,
     pub cache_hits_total: u64,
     pub cache_misses_total: u64,
     pub local_routes_total: u64,
     pub cloud_routes_total: u64,
     pub fallback_responses_total: u64,
+    pub moderation_flags_total: u64,
+    pub moderation_blocks_total: u64,
+    pub pii_redactions_total: u64,
+    pub residency_blocks_total: u64,
+    pub auth_failures_total: u64,
+    pub oidc_logins_total: u64,
+    pub quota_rejections_total: u64,
+    pub quota_cloud_blocks_total: u64,
+    pub rate_limited_total: u64,
+    pub streams_rejected_total: u64,
+    pub active_streams: usize,
+    pub max_concurrent_streams: usize,
+    pub queue_timeouts_total: u64,
+    pub generation_timeouts_total: u64,
+    pub queues: Vec<QueueStatus>,
+    pub budget_cloud_blocks_total: u64,
+    pub cloud_budget: Option<BudgetStatus>,
+    pub context_trimmed_total: u64,
+    pub context_messages_dropped_total: u64,
+    pub summaries_generated_total: u64,
+    pub summary_cache_hits_total: u64,
+    pub summary_failures_total: u64,
+    /// Only generations whose provider reported usage are counted.
+    pub token_usage: TokenUsageTotals,
+    pub requests_by_role: RoleCounts,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenTotals {
+    pub generations: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub generation_ms: u64,
+}
+
+#[derive(Serialize)]
+pub struct TokenUsageTotals {
+    pub local: TokenTotals,
+    pub cloud: TokenTotals,
+}
+
+#[derive(Serialize)]
+pub struct RoleCounts {
+    pub anonymous: u64,
+    pub student: u64,
+    pub staff: u64,
+    pub admin: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaExceededResponse {
+    pub error: String,
+    pub request_id: String,
+    pub quota: QuotaExceeded,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageResponse {
+    pub principal: String,
+    pub date: String,
+    pub usage: UsageCounters,
+    pub limits: QuotaLimits,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UsageQuery {
+    /// `YYYY-MM-DD`, defaulting to today (UTC).
+    pub date: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrincipalUsage {
+    pub principal: String,
+    pub usage: UsageCounters,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminUsageResponse {
+    pub date: String,
+    pub principals: Vec<PrincipalUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DocumentUploadQuery {
+    pub collection: String,
+    /// Usually the file name; its extension picks the format when the
+    /// content type is generic.
+    pub title: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DocumentListQuery {
+    pub collection: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchQuery {
+    pub q: String,
+    /// Every collection when absent.
+    pub collection: Option<String>,
+    /// `keyword` when absent.
+    pub mode: Option<SearchMode>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResponse {
+    pub query: String,
+    /// The ranking used; hybrid falls back to keywords when embedding fails.
+    pub mode: SearchMode,
+    pub results: Vec<SearchResult>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResult {
+    pub collection: String,
+    pub chunk_id: String,
+    pub citation_id: String,
+    pub document_id: String,
+    pub title: String,
+    pub page: Option<u32>,
+    pub offset: usize,
+    pub score: f32,
+    pub snippet: String,
+}
+
+/// `POST /api/embeddings`, in the OpenAI shape.
+#[derive(Deserialize, Debug)]
+pub struct EmbeddingsRequest {
+    pub input: EmbeddingInput,
+    /// `EMBEDDING_MODEL` when absent.
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub encoding_format: EncodingFormat,
+    #[serde(default)]
+    pub residency: Option<Residency>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl EmbeddingInput {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            EmbeddingInput::One(text) => vec![text],
+            EmbeddingInput::Many(texts) => texts,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EncodingFormat {
+    #[default]
+    Float,
+    /// Little-endian `f32`s, base64-encoded.
+    Base64,
+}
+
+#[derive(Serialize)]
+pub struct EmbeddingsResponse {
+    pub object: &'static str,
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Serialize)]
+pub struct EmbeddingData {
+    pub object: &'static str,
+    pub index: usize,
+    pub embedding: EmbeddingVector,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+#[derive(Serialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: u64,
+    pub total_tokens: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub role: Role,
+    /// Residency tenant for requests made with the key.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateApiKeyResponse {
+    #[serde(flatten)]
+    pub record: ApiKeyRecord,
+    /// Shown once; only its hash is stored.
+    pub key: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WhoAmIResponse {
+    pub authenticated: bool,
+    pub session_id: String,
+    pub principal: Option<String>,
+    pub name: Option<String>,
+    pub role: Option<Role>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -82,6 +513,15 @@ pub enum Provider {
     Cloud,
 }
 
+impl Provider {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Provider::Local => "local",
+            Provider::Cloud => "cloud",
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct RouteChoice {
     pub provider: Provider,
@@ -0,0 +1,456 @@
+use std::fs;
+
+use regex::Regex;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::{timeout, Duration};
+use tracing::warn;
+
+use crate::config::AppConfig;
+
+/// Policy actions, ordered from least to most severe so the strongest action
+/// across several matches can be taken with `max`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationAction {
+    Allow,
+    Warn,
+    Redact,
+    Block,
+}
+
+impl ModerationAction {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "allow" => Some(ModerationAction::Allow),
+            "warn" => Some(ModerationAction::Warn),
+            "redact" => Some(ModerationAction::Redact),
+            "block" => Some(ModerationAction::Block),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationStage {
+    Input,
+    Output,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum RuleStage {
+    Input,
+    Output,
+    #[default]
+    Both,
+}
+
+impl RuleStage {
+    fn covers(self, stage: ModerationStage) -> bool {
+        match self {
+            RuleStage::Both => true,
+            RuleStage::Input => stage == ModerationStage::Input,
+            RuleStage::Output => stage == ModerationStage::Output,
+        }
+    }
+}
+
+/// One thing the moderation stage noticed, and what it did about it.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ModerationEvent {
+    pub stage: ModerationStage,
+    pub action: ModerationAction,
+    pub rule_id: String,
+    pub category: String,
+    pub matches: usize,
+}
+
+impl ModerationEvent {
+    pub fn describe(&self) -> String {
+        let stage = match self.stage {
+            ModerationStage::Input => "prompt",
+            ModerationStage::Output => "answer",
+        };
+        let action = match self.action {
+            ModerationAction::Allow => "allowed",
+            ModerationAction::Warn => "flagged",
+            ModerationAction::Redact => "redacted",
+            ModerationAction::Block => "blocked",
+        };
+        format!(
+            "{stage} {action} by moderation rule '{}' ({})",
+            self.rule_id, self.category
+        )
+    }
+}
+
+pub struct ModerationOutcome {
+    pub action: ModerationAction,
+    pub text: String,
+    pub events: Vec<ModerationEvent>,
+}
+
+#[derive(Deserialize)]
+struct RuleFile {
+    rules: Vec<RuleSpec>,
+}
+
+#[derive(Deserialize)]
+struct RuleSpec {
+    id: String,
+    category: String,
+    action: ModerationAction,
+    #[serde(default)]
+    stage: RuleStage,
+    #[serde(default)]
+    words: Vec<String>,
+    #[serde(default)]
+    patterns: Vec<String>,
+}
+
+struct Rule {
+    id: String,
+    category: String,
+    action: ModerationAction,
+    stage: RuleStage,
+    matchers: Vec<Regex>,
+}
+
+const DEFAULT_RULES: &str = r#"{
+  "rules": [
+    {
+      "id": "weapons-manufacture",
+      "category": "violence",
+      "action": "block",
+      "stage": "both",
+      "patterns": ["(?i)\\b(make|build|assemble|synthesi[sz]e)\\b.{0,40}\\b(bomb|explosives?|nerve agent)\\b"]
+    },
+    {
+      "id": "exam-leak",
+      "category": "academic-integrity",
+      "action": "warn",
+      "stage": "input",
+      "words": ["answer key", "leaked exam", "exam leak"]
+    },
+    {
+      "id": "inline-credentials",
+      "category": "credentials",
+      "action": "redact",
+      "stage": "both",
+      "patterns": ["(?i)\\b(password|passwd|api[_ -]?key|secret)\\s*[:=]\\s*\\S+"]
+    }
+  ]
+}"#;
+
+const REDACTED: &str = "[redacted]";
+
+/// Local, rule-based moderation with an optional classifier model served by
+/// the local runtime.
+pub struct Moderator {
+    enabled: bool,
+    rules: Vec<Rule>,
+    classifier_model: String,
+    classifier_action: ModerationAction,
+}
+
+impl Moderator {
+    pub fn load(cfg: &AppConfig) -> Result<Self, String> {
+        let raw = if cfg.moderation_rules_path.is_empty() {
+            DEFAULT_RULES.to_string()
+        } else {
+            fs::read_to_string(&cfg.moderation_rules_path).map_err(|e| {
+                format!(
+                    "cannot read MODERATION_RULES_PATH {}: {e}",
+                    cfg.moderation_rules_path
+                )
+            })?
+        };
+
+        let file: RuleFile =
+            serde_json::from_str(&raw).map_err(|e| format!("invalid moderation rules: {e}"))?;
+
+        let mut rules = Vec::new();
+        for spec in file.rules {
+            let mut matchers = Vec::new();
+            if !spec.words.is_empty() {
+                let alternation = spec
+                    .words
+                    .iter()
+                    .map(|w| regex::escape(w.trim()))
+                    .collect::<Vec<_>>()
+                    .join("|");
+                matchers.push(
+                    Regex::new(&format!(r"(?i)\b(?:{alternation})\b"))
+                        .map_err(|e| format!("moderation rule '{}': {e}", spec.id))?,
+                );
+            }
+            for pattern in &spec.patterns {
+                matchers.push(
+                    Regex::new(pattern)
+                        .map_err(|e| format!("moderation rule '{}': {e}", spec.id))?,
+                );
+            }
+            rules.push(Rule {
+                id: spec.id,
+                category: spec.category,
+                action: spec.action,
+                stage: spec.stage,
+                matchers,
+            });
+        }
+
+        let classifier_action = ModerationAction::parse(&cfg.moderation_classifier_action)
+            .ok_or_else(|| {
+                "MODERATION_CLASSIFIER_ACTION must be one of allow, warn, redact, block".to_string()
+            })?;
+
+        Ok(Self {
+            enabled: cfg.moderation_enabled,
+            rules,
+            classifier_model: cfg.moderation_classifier_model.clone(),
+            classifier_action,
+        })
+    }
+
+    pub fn has_output_rules(&self) -> bool {
+        self.enabled
+            && self
+                .rules
+                .iter()
+                .any(|r| r.stage.covers(ModerationStage::Output))
+    }
+
+    /// Applies the word and regex lists for `stage`. Redactions are applied to
+    /// the returned text; a block leaves the text untouched.
+    pub fn check(&self, stage: ModerationStage, text: &str) -> ModerationOutcome {
+        let mut outcome = ModerationOutcome {
+            action: ModerationAction::Allow,
+            text: text.to_string(),
+            events: Vec::new(),
+        };
+        if !self.enabled {
+            return outcome;
+        }
+
+        for rule in self.rules.iter().filter(|r| r.stage.covers(stage)) {
+            let matches: usize = rule
+                .matchers
+                .iter()
+                .map(|m| m.find_iter(&outcome.text).count())
+                .sum();
+            if matches == 0 {
+                continue;
+            }
+
+            if rule.action == ModerationAction::Redact {
+                for m in &rule.matchers {
+                    outcome.text = m.replace_all(&outcome.text, REDACTED).into_owned();
+                }
+            }
+
+            outcome.action = outcome.action.max(rule.action);
+            outcome.events.push(ModerationEvent {
+                stage,
+                action: rule.action,
+                rule_id: rule.id.clone(),
+                category: rule.category.clone(),
+                matches,
+            });
+        }
+
+        outcome
+    }
+
+    /// Asks the configured classifier model whether `text` is safe. Answers
+    /// in the Llama Guard style ("safe" / "unsafe" + category) are expected.
+    /// Classifier failures are logged and treated as allow.
+    pub async fn classify(
+        &self,
+        client: &Client,
+        cfg: &AppConfig,
+        text: &str,
+    ) -> Option<ModerationEvent> {
+        if !self.enabled || self.classifier_model.is_empty() {
+            return None;
+        }
+
+        let payload = serde_json::json!({
+            "model": self.classifier_model,
+            "stream": false,
+            "messages": [{ "role": "user", "content": text }],
+            "options": { "temperature": 0.0 }
+        });
+
+        let url = format!(
+            "{}/api/chat",
+            cfg.local_model_base_url.trim_end_matches('/')
+        );
+        let request = async {
+            let response = client
+                .post(url)
+                .json(&payload)
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("status {}", response.status()));
+            }
+            let body: Value = response.json().await.map_err(|e| e.to_string())?;
+            Ok(body
+                .get("message")
+                .and_then(|m| m.get("content"))
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .trim()
+                .to_lowercase())
+        };
+
+        let limit = Duration::from_millis(cfg.upstream_timeout_ms.min(10_000));
+        let verdict = match timeout(limit, request).await {
+            Ok(Ok(verdict)) => verdict,
+            Ok(Err(err)) => {
+                warn!("moderation classifier failed: {}", err);
+                return None;
+            }
+            Err(_) => {
+                warn!("moderation classifier timed out");
+                return None;
+            }
+        };
+
+        if !verdict.starts_with("unsafe") {
+            return None;
+        }
+
+        let category = verdict
+            .trim_start_matches("unsafe")
+            .trim()
+            .lines()
+            .next()
+            .filter(|c| !c.is_empty())
+            .unwrap_or("unspecified")
+            .to_string();
+
+        Some(ModerationEvent {
+            stage: ModerationStage::Input,
+            action: self.classifier_action,
+            rule_id: format!("classifier:{}", self.classifier_model),
+            category,
+            matches: 1,
+        })
+    }
+}
+
+/// Applies output-stage rules to a streamed answer. Text is released at
+/// sentence or line boundaries so matches are not split across deltas.
+pub struct OutputModeration<'a> {
+    moderator: &'a Moderator,
+    buffer: String,
+    pub events: Vec<ModerationEvent>,
+    pub blocked: bool,
+}
+
+const OUTPUT_FLUSH_CHARS: usize = 400;
+
+impl<'a> OutputModeration<'a> {
+    pub fn new(moderator: &'a Moderator) -> Self {
+        Self {
+            moderator,
+            buffer: String::new(),
+            events: Vec::new(),
+            blocked: false,
+        }
+    }
+
+    /// Returns the moderated text that is ready to be sent, if any.
+    pub fn push(&mut self, delta: &str) -> Option<String> {
+        if self.blocked {
+            return None;
+        }
+        if !self.moderator.has_output_rules() {
+            return Some(delta.to_string());
+        }
+
+        self.buffer.push_str(delta);
+        let cut = match self.buffer.rfind(['.', '!', '?', '\n']) {
+            Some(idx) => idx + 1,
+            None if self.buffer.len() >= OUTPUT_FLUSH_CHARS => {
+                match self
+                    .buffer
+                    .char_indices()
+                    .rev()
+                    .find(|(_, ch)| ch.is_whitespace())
+                {
+                    Some((idx, ch)) => idx + ch.len_utf8(),
+                    None => self.buffer.len(),
+                }
+            }
+            None => return None,
+        };
+
+        let ready: String = self.buffer.drain(..cut).collect();
+        self.release(&ready)
+    }
+
+    /// Flushes whatever is still buffered once the stream has ended.
+    pub fn finish(&mut self) -> Option<String> {
+        if self.blocked || self.buffer.is_empty() {
+            return None;
+        }
+        let rest = std::mem::take(&mut self.buffer);
+        self.release(&rest)
+    }
+
+    fn release(&mut self, text: &str) -> Option<String> {
+        let outcome = self.moderator.check(ModerationStage::Output, text);
+        self.events.extend(outcome.events);
+
+        if outcome.action == ModerationAction::Block {
+            self.blocked = true;
+            return Some("\n\n[Response withheld by the campus moderation policy.]".to_string());
+        }
+
+        Some(outcome.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moderator() -> Moderator {
+        Moderator {
+            enabled: true,
+            rules: vec![Rule {
+                id: "test".to_string(),
+                category: "test".to_string(),
+                action: ModerationAction::Redact,
+                stage: RuleStage::Output,
+                matchers: vec![Regex::new("secret").unwrap()],
+            }],
+            classifier_model: String::new(),
+            classifier_action: ModerationAction::Warn,
+        }
+    }
+
+    #[test]
+    fn flush_cuts_after_multibyte_whitespace() {
+        let moderator = moderator();
+        for space in ['\u{a0}', '\u{3000}', '\u{2003}'] {
+            let mut output = OutputModeration::new(&moderator);
+            let mut sent = String::new();
+            let word = format!("wort{space}");
+            for _ in 0..(OUTPUT_FLUSH_CHARS / word.len() + 2) {
+                sent.extend(output.push(&word));
+            }
+            sent.extend(output.push("tail secret"));
+            sent.extend(output.finish());
+            assert!(sent.starts_with("wort"));
+            assert!(sent.ends_with("tail [redacted]"), "{sent}");
+        }
+    }
+}
@@ -0,0 +1,1017 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+
+use flate2::read::ZlibDecoder;
+use regex::bytes::Regex;
+
+/// Form XObjects nested deeper than this are not followed.
+const MAX_FORM_DEPTH: usize = 3;
+/// Decompressed streams larger than this are skipped.
+const MAX_STREAM_BYTES: u64 = 32 * 1024 * 1024;
+/// Arrays and dictionaries nested deeper than this parse as null.
+const MAX_NESTING: usize = 64;
+/// Tokens read per page, form XObjects included.
+const MAX_PAGE_OPERATIONS: usize = 500_000;
+/// Form XObjects drawn per page, so repeated `Do`s cannot fan out.
+const MAX_PAGE_XOBJECTS: usize = 256;
+
+/// The text of each page, in page order. Handles the text layer of PDFs
+/// exported by word processors and browsers: Flate-compressed content and
+/// object streams, simple and composite fonts with `ToUnicode` maps, and
+/// form XObjects. Scanned pages have no text layer and come back empty.
+pub fn extract_pages(data: &[u8]) -> Result<Vec<String>, String> {
+    if !data.starts_with(b"%PDF") {
+        return Err("not a PDF file".to_string());
+    }
+    let document = Document::parse(data);
+    if document.encrypted {
+        return Err("encrypted PDFs are not supported".to_string());
+    }
+
+    let pages = document.pages();
+    if pages.is_empty() {
+        return Err("no pages found in the PDF".to_string());
+    }
+    Ok(pages
+        .iter()
+        .map(|page| {
+            let mut text = TextWriter::default();
+            let mut budget = RenderBudget {
+                operations: MAX_PAGE_OPERATIONS,
+                xobjects: MAX_PAGE_XOBJECTS,
+            };
+            document.render(&page.contents, &page.resources, &mut text, &mut budget, 0);
+            tidy(&text.out)
+        })
+        .collect())
+}
+
+#[derive(Clone, Debug)]
+enum Obj {
+    /// Also stands in for booleans, which text extraction never needs.
+    Null,
+    Num(f64),
+    Name(String),
+    Str(Vec<u8>),
+    Array(Vec<Obj>),
+    Dict(BTreeMap<String, Obj>),
+    Ref(u32),
+    /// Content stream operators; never part of a parsed object.
+    Op(String),
+}
+
+impl Obj {
+    fn as_dict(&self) -> Option<&BTreeMap<String, Obj>> {
+        match self {
+            Obj::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    fn as_num(&self) -> Option<f64> {
+        match self {
+            Obj::Num(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn as_name(&self) -> Option<&str> {
+        match self {
+            Obj::Name(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+struct Object {
+    value: Obj,
+    /// Decoded stream data; `None` for plain objects and streams with
+    /// filters other than Flate.
+    stream: Option<Vec<u8>>,
+}
+
+struct Page {
+    contents: Vec<u32>,
+    resources: Obj,
+}
+
+/// What is left of a page's rendering allowance.
+struct RenderBudget {
+    operations: usize,
+    xobjects: usize,
+}
+
+struct Document {
+    objects: HashMap<u32, Object>,
+    encrypted: bool,
+}
+
+impl Document {
+    fn parse(data: &[u8]) -> Self {
+        let header = Regex::new(r"(?-u)(\d+)\s+\d+\s+obj\b").expect("valid regex");
+        let mut objects = HashMap::new();
+        let mut pos = 0;
+
+        while let Some(found) = header.captures_at(data, pos) {
+            let whole = found.get(0).expect("match");
+            let number: u32 = std::str::from_utf8(&found[1])
+                .ok()
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(0);
+            let mut lexer = Lexer::new(data, whole.end());
+            let value = lexer.value().unwrap_or(Obj::Null);
+            pos = lexer.pos.max(whole.end());
+
+            let mut stream = None;
+            let mut rest = Lexer::new(data, pos);
+            rest.skip_space();
+            if data[rest.pos..].starts_with(b"stream") {
+                let mut start = rest.pos + b"stream".len();
+                if data[start..].starts_with(b"\r\n") {
+                    start += 2;
+                } else if data[start..].starts_with(b"\n") || data[start..].starts_with(b"\r") {
+                    start += 1;
+                }
+                let end = stream_end(data, start, &value);
+                stream = decode_stream(&value, &data[start..end]);
+                pos = end;
+            }
+            objects.insert(number, Object { value, stream });
+        }
+
+        let mut document = Self {
+            objects,
+            encrypted: data.windows(8).any(|w| w == b"/Encrypt"),
+        };
+        document.unpack_object_streams();
+        document
+    }
+
+    /// Objects stored inside compressed object streams (PDF 1.5+).
+    fn unpack_object_streams(&mut self) {
+        let mut unpacked = Vec::new();
+        for object in self.objects.values() {
+            let Some(dict) = object.value.as_dict() else {
+                continue;
+            };
+            if dict.get("Type").and_then(Obj::as_name) != Some("ObjStm") {
+                continue;
+            }
+            let (Some(data), Some(first)) =
+                (&object.stream, dict.get("First").and_then(Obj::as_num))
+            else {
+                continue;
+            };
+            // Float to integer casts saturate, so hostile values stay in range.
+            let first = first as usize;
+            let count = dict.get("N").and_then(Obj::as_num).unwrap_or(0.0) as usize;
+            let mut header = Lexer::new(&data[..first.min(data.len())], 0);
+            for _ in 0..count {
+                let (Some(Obj::Num(number)), Some(Obj::Num(offset))) =
+                    (header.value(), header.value())
+                else {
+                    break;
+                };
+                let start = first.saturating_add(offset as usize);
+                if start < data.len() {
+                    if let Some(value) = Lexer::new(data, start).value() {
+                        unpacked.push((number as u32, value));
+                    }
+                }
+            }
+        }
+        for (number, value) in unpacked {
+            self.objects.entry(number).or_insert(Object {
+                value,
+                stream: None,
+            });
+        }
+    }
+
+    fn resolve<'a>(&'a self, obj: &'a Obj) -> &'a Obj {
+        match obj {
+            Obj::Ref(number) => self
+                .objects
+                .get(number)
+                .map(|o| &o.value)
+                .unwrap_or(&Obj::Null),
+            other => other,
+        }
+    }
+
+    fn get<'a>(&'a self, dict: &'a Obj, key: &str) -> &'a Obj {
+        match self.resolve(dict) {
+            Obj::Dict(dict) => dict.get(key).map(|v| self.resolve(v)).unwrap_or(&Obj::Null),
+            _ => &Obj::Null,
+        }
+    }
+
+    fn pages(&self) -> Vec<Page> {
+        let catalog = self.objects.values().find(|o| {
+            o.value
+                .as_dict()
+                .and_then(|d| d.get("Type"))
+                .and_then(Obj::as_name)
+                == Some("Catalog")
+        });
+        let mut pages = Vec::new();
+        if let Some(Obj::Dict(catalog)) = catalog.map(|c| &c.value) {
+            if let Some(root) = catalog.get("Pages") {
+                let mut seen = HashSet::new();
+                self.collect_pages(root, &Obj::Null, &mut pages, &mut seen, 0);
+            }
+        }
+        pages
+    }
+
+    /// Each page tree node is visited once, so kids that point back up the
+    /// tree or repeat cannot multiply the work.
+    fn collect_pages(
+        &self,
+        node: &Obj,
+        inherited: &Obj,
+        pages: &mut Vec<Page>,
+        seen: &mut HashSet<u32>,
+        depth: usize,
+    ) {
+        if depth > 32 {
+            return;
+        }
+        if let Obj::Ref(number) = node {
+            if !seen.insert(*number) {
+                return;
+            }
+        }
+        let resources = match self.get(node, "Resources") {
+            Obj::Null => inherited.clone(),
+            own => own.clone(),
+        };
+        match self.get(node, "Type").as_name() {
+            Some("Pages") => {
+                if let Obj::Array(kids) = self.get(node, "Kids") {
+                    for kid in kids {
+                        self.collect_pages(kid, &resources, pages, seen, depth + 1);
+                    }
+                }
+            }
+            _ => {
+                let contents = match self.resolve(node).as_dict().and_then(|d| d.get("Contents")) {
+                    Some(Obj::Ref(number)) => match self.objects.get(number).map(|o| &o.value) {
+                        // An indirect array of content streams.
+                        Some(Obj::Array(items)) => refs(items),
+                        _ => vec![*number],
+                    },
+                    Some(Obj::Array(items)) => refs(items),
+                    _ => Vec::new(),
+                };
+                pages.push(Page {
+                    contents,
+                    resources,
+                });
+            }
+        }
+    }
+
+    fn render(
+        &self,
+        streams: &[u32],
+        resources: &Obj,
+        text: &mut TextWriter,
+        budget: &mut RenderBudget,
+        depth: usize,
+    ) {
+        let mut content = Vec::new();
+        for number in streams {
+            if let Some(data) = self.objects.get(number).and_then(|o| o.stream.as_ref()) {
+                content.extend_from_slice(data);
+                content.push(b'\n');
+            }
+        }
+
+        let mut fonts: HashMap<String, Font> = HashMap::new();
+        let mut lexer = Lexer::new(&content, 0);
+        let mut operands: Vec<Obj> = Vec::new();
+        let mut font: Option<String> = None;
+        let mut line_y: Option<f64> = None;
+
+        while let Some(token) = lexer.value() {
+            if budget.operations == 0 {
+                return;
+            }
+            budget.operations -= 1;
+            let Obj::Op(op) = token else {
+                operands.push(token);
+                continue;
+            };
+            let num = |i: usize| operands.get(i).and_then(Obj::as_num).unwrap_or(0.0);
+            match op.as_str() {
+                "Tf" => {
+                    if let Some(name) = operands.first().and_then(Obj::as_name) {
+                        let name = name.to_string();
+                        fonts
+                            .entry(name.clone())
+                            .or_insert_with(|| self.font(resources, &name));
+                        font = Some(name);
+                    }
+                }
+                "Td" | "TD" => {
+                    if num(1).abs() > 0.1 {
+                        text.newline();
+                    } else if num(0) > 0.0 {
+                        text.space();
+                    }
+                }
+                "Tm" => {
+                    let y = num(5);
+                    match line_y {
+                        Some(previous) if (previous - y).abs() > 0.1 => text.newline(),
+                        _ => text.space(),
+                    }
+                    line_y = Some(y);
+                }
+                "T*" => text.newline(),
+                "Tj" | "'" | "\"" => {
+                    if op != "Tj" {
+                        text.newline();
+                    }
+                    if let Some(Obj::Str(bytes)) = operands.last() {
+                        text.push(&decode(
+                            fonts.get(font.as_deref().unwrap_or_default()),
+                            bytes,
+                        ));
+                    }
+                }
+                "TJ" => {
+                    if let Some(Obj::Array(items)) = operands.last() {
+                        let font = fonts.get(font.as_deref().unwrap_or_default());
+                        for item in items {
+                            match item {
+                                Obj::Str(bytes) => text.push(&decode(font, bytes)),
+                                // Large negative kerning is how many
+                                // producers encode a word space.
+                                Obj::Num(n) if *n < -150.0 => text.space(),
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+                "Do" if depth < MAX_FORM_DEPTH && budget.xobjects > 0 => {
+                    if let Some(name) = operands.first().and_then(Obj::as_name) {
+                        let xobjects = self.get(resources, "XObject");
+                        if let Some(reference @ Obj::Ref(number)) =
+                            xobjects.as_dict().and_then(|d| d.get(name))
+                        {
+                            let form = self.resolve(reference);
+                            if self.get(form, "Subtype").as_name() == Some("Form") {
+                                let own = match self.get(form, "Resources") {
+                                    Obj::Null => resources.clone(),
+                                    own => own.clone(),
+                                };
+                                budget.xobjects -= 1;
+                                text.newline();
+                                self.render(&[*number], &own, text, budget, depth + 1);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+            operands.clear();
+        }
+    }
+
+    fn font(&self, resources: &Obj, name: &str) -> Font {
+        let fonts = self.get(resources, "Font");
+        let font = fonts
+            .as_dict()
+            .and_then(|d| d.get(name))
+            .map(|f| self.resolve(f))
+            .unwrap_or(&Obj::Null);
+        let composite = self.get(font, "Subtype").as_name() == Some("Type0");
+        let cmap = match font.as_dict().and_then(|d| d.get("ToUnicode")) {
+            Some(Obj::Ref(number)) => self
+                .objects
+                .get(number)
+                .and_then(|o| o.stream.as_deref())
+                .map(CMap::parse),
+            _ => None,
+        };
+        Font { composite, cmap }
+    }
+}
+
+fn refs(items: &[Obj]) -> Vec<u32> {
+    items
+        .iter()
+        .filter_map(|item| match item {
+            Obj::Ref(number) => Some(*number),
+            _ => None,
+        })
+        .collect()
+}
+
+/// End of a stream's data: trusts a direct `/Length` when `endstream`
+/// follows it, and searches for `endstream` otherwise.
+fn stream_end(data: &[u8], start: usize, dict: &Obj) -> usize {
+    let declared = dict
+        .as_dict()
+        .and_then(|d| d.get("Length"))
+        .and_then(Obj::as_num)
+        .and_then(|n| start.checked_add(n as usize));
+    if let Some(end) = declared.filter(|end| *end <= data.len()) {
+        let mut after = Lexer::new(data, end);
+        after.skip_space();
+        if data[after.pos..].starts_with(b"endstream") {
+            return end;
+        }
+    }
+    let found = data[start..]
+        .windows(9)
+        .position(|w| w == b"endstream")
+        .map_or(data.len(), |i| start + i);
+    let mut end = found;
+    while end > start && matches!(data[end - 1], b'\r' | b'\n') {
+        end -= 1;
+    }
+    end
+}
+
+fn decode_stream(dict: &Obj, raw: &[u8]) -> Option<Vec<u8>> {
+    let filters: Vec<&str> = match dict.as_dict().and_then(|d| d.get("Filter")) {
+        None => Vec::new(),
+        Some(Obj::Name(name)) => vec![name.as_str()],
+        Some(Obj::Array(names)) => names.iter().filter_map(Obj::as_name).collect(),
+        Some(_) => return None,
+    };
+    match filters.as_slice() {
+        [] => Some(raw.to_vec()),
+        ["FlateDecode" | "Fl"] => {
+            let mut decoded = Vec::new();
+            // Truncated streams are common; keep whatever inflated.
+            let _ = ZlibDecoder::new(raw)
+                .take(MAX_STREAM_BYTES)
+                .read_to_end(&mut decoded);
+            (!decoded.is_empty()).then_some(decoded)
+        }
+        _ => None,
+    }
+}
+
+struct Font {
+    /// Type0 fonts use two-byte codes unless their CMap says otherwise.
+    composite: bool,
+    cmap: Option<CMap>,
+}
+
+fn decode(font: Option<&Font>, bytes: &[u8]) -> String {
+    match font {
+        Some(Font {
+            cmap: Some(cmap), ..
+        }) => cmap.decode(bytes),
+        // Composite fonts without a ToUnicode map carry glyph ids, which
+        // cannot be turned back into text.
+        Some(Font {
+            composite: true, ..
+        }) => String::new(),
+        _ => bytes.iter().map(|b| win_ansi(*b)).collect(),
+    }
+}
+
+/// WinAnsiEncoding, which matches Latin-1 outside 0x80-0x9F.
+fn win_ansi(byte: u8) -> char {
+    match byte {
+        0x80 => '€',
+        0x82 => '‚',
+        0x84 => '„',
+        0x85 => '…',
+        0x91 => '‘',
+        0x92 => '’',
+        0x93 => '“',
+        0x94 => '”',
+        0x95 => '•',
+        0x96 => '–',
+        0x97 => '—',
+        0x99 => '™',
+        other => char::from(other),
+    }
+}
+
+/// A `ToUnicode` CMap: character codes to the text they stand for.
+struct CMap {
+    code_bytes: usize,
+    map: HashMap<u32, String>,
+}
+
+impl CMap {
+    fn parse(data: &[u8]) -> Self {
+        let mut cmap = CMap {
+            code_bytes: 0,
+            map: HashMap::new(),
+        };
+        let mut lexer = Lexer::new(data, 0);
+        let mut section: Option<String> = None;
+        let mut pending: Vec<Obj> = Vec::new();
+
+        while let Some(token) = lexer.value() {
+            if let Obj::Op(op) = &token {
+                match op.as_str() {
+                    "begincodespacerange" | "beginbfchar" | "beginbfrange" => {
+                        section = Some(op.clone());
+                        pending.clear();
+                    }
+                    _ => section = None,
+                }
+                continue;
+            }
+            let Some(kind) = section.as_deref() else {
+                continue;
+            };
+            pending.push(token);
+            match (kind, pending.as_slice()) {
+                ("begincodespacerange", [Obj::Str(low), Obj::Str(_)]) => {
+                    cmap.code_bytes = cmap.code_bytes.max(low.len());
+                    pending.clear();
+                }
+                ("beginbfchar", [Obj::Str(code), Obj::Str(target)]) => {
+                    cmap.map.insert(code_value(code), utf16(target));
+                    cmap.code_bytes = cmap.code_bytes.max(code.len());
+                    pending.clear();
+                }
+                ("beginbfrange", [Obj::Str(low), Obj::Str(high), target]) => {
+                    let (low_code, high_code) = (code_value(low), code_value(high));
+                    cmap.code_bytes = cmap.code_bytes.max(low.len());
+                    let last = high_code.min(low_code.saturating_add(0xFFFF));
+                    for (i, code) in (low_code..=last).enumerate() {
+                        let text = match target {
+                            Obj::Str(start) => Some(offset_utf16(start, i as u16)),
+                            Obj::Array(targets) => match targets.get(i) {
+                                Some(Obj::Str(each)) => Some(utf16(each)),
+                                _ => None,
+                            },
+                            _ => None,
+                        };
+                        if let Some(text) = text {
+                            cmap.map.insert(code, text);
+                        }
+                    }
+                    pending.clear();
+                }
+                (_, items) if items.len() >= 3 => pending.clear(),
+                _ => {}
+            }
+        }
+        if cmap.code_bytes == 0 {
+            cmap.code_bytes = 1;
+        }
+        cmap
+    }
+
+    fn decode(&self, bytes: &[u8]) -> String {
+        bytes
+            .chunks(self.code_bytes)
+            .filter_map(|code| {
+                let value = code_value(code);
+                match self.map.get(&value) {
+                    Some(text) => Some(text.clone()),
+                    None if self.code_bytes == 1 => Some(win_ansi(code[0]).to_string()),
+                    None => None,
+                }
+            })
+            .collect()
+    }
+}
+
+fn code_value(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, b| (acc << 8) | u32::from(*b))
+}
+
+fn utf16(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// The `start` text with its last UTF-16 unit advanced by `offset`, as
+/// `bfrange` entries define consecutive codes.
+fn offset_utf16(start: &[u8], offset: u16) -> String {
+    let mut units: Vec<u16> = start
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+        .collect();
+    if let Some(last) = units.last_mut() {
+        *last = last.wrapping_add(offset);
+    }
+    String::from_utf16_lossy(&units)
+}
+
+/// Collects shown text with the line and word breaks implied by text
+/// positioning.
+#[derive(Default)]
+struct TextWriter {
+    out: String,
+}
+
+impl TextWriter {
+    fn push(&mut self, text: &str) {
+        self.out.push_str(text);
+    }
+
+    fn space(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with(char::is_whitespace) {
+            self.out.push(' ');
+        }
+    }
+
+    fn newline(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with('\n') {
+            self.out.push('\n');
+        }
+    }
+}
+
+/// Trims lines, collapses runs of spaces and keeps at most one blank line.
+fn tidy(text: &str) -> String {
+    let mut out = String::new();
+    let mut blank = false;
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str(if blank { "\n\n" } else { "\n" });
+        }
+        out.push_str(&line);
+        blank = false;
+    }
+    out
+}
+
+/// Tokenizer and object parser shared by object bodies, content streams
+/// and CMaps.
+struct Lexer<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Arrays and dictionaries currently open.
+    nesting: usize,
+}
+
+fn is_delimiter(b: u8) -> bool {
+    matches!(
+        b,
+        b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%'
+    )
+}
+
+fn is_space(b: u8) -> bool {
+    matches!(b, b' ' | b'\t' | b'\n' | b'\r' | b'\x0c' | b'\0')
+}
+
+impl<'a> Lexer<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self {
+            data,
+            pos,
+            nesting: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(b) = self.peek() {
+            if is_space(b) {
+                self.pos += 1;
+            } else if b == b'%' {
+                while self.peek().is_some_and(|b| b != b'\n' && b != b'\r') {
+                    self.pos += 1;
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// The next complete value, with `n g R` turned into a reference.
+    /// Returns `None` at the end of input and at `endobj`/`stream`.
+    fn value(&mut self) -> Option<Obj> {
+        let value = self.token()?;
+        match value {
+            Obj::Op(op) if op == "endobj" || op == "stream" => {
+                self.pos -= op.len();
+                None
+            }
+            Obj::Num(number) if number.fract() == 0.0 && number >= 0.0 => {
+                let save = self.pos;
+                if let (Some(Obj::Num(_)), Some(Obj::Op(r))) = (self.token(), self.token()) {
+                    if r == "R" {
+                        return Some(Obj::Ref(number as u32));
+                    }
+                }
+                self.pos = save;
+                Some(Obj::Num(number))
+            }
+            other => Some(other),
+        }
+    }
+
+    fn token(&mut self) -> Option<Obj> {
+        loop {
+            self.skip_space();
+            let b = self.peek()?;
+            match b {
+                b'[' | b'<'
+                    if self.nesting >= MAX_NESTING
+                        && (b == b'[' || self.data.get(self.pos + 1) == Some(&b'<')) =>
+                {
+                    // Skipping the opening delimiter keeps recursion bounded;
+                    // what follows parses as siblings.
+                    self.pos += if b == b'[' { 1 } else { 2 };
+                    return Some(Obj::Null);
+                }
+                b'[' => {
+                    self.pos += 1;
+                    self.nesting += 1;
+                    let mut items = Vec::new();
+                    loop {
+                        self.skip_space();
+                        match self.peek() {
+                            Some(b']') => {
+                                self.pos += 1;
+                                break;
+                            }
+                            None => break,
+                            _ => match self.value() {
+                                Some(item) => items.push(item),
+                                None => break,
+                            },
+                        }
+                    }
+                    self.nesting -= 1;
+                    return Some(Obj::Array(items));
+                }
+                b'<' if self.data.get(self.pos + 1) == Some(&b'<') => {
+                    self.pos += 2;
+                    self.nesting += 1;
+                    let mut dict = BTreeMap::new();
+                    loop {
+                        self.skip_space();
+                        if self.data[self.pos..].starts_with(b">>") {
+                            self.pos += 2;
+                            break;
+                        }
+                        match self.token() {
+                            Some(Obj::Name(key)) => {
+                                let value = self.value().unwrap_or(Obj::Null);
+                                dict.insert(key, value);
+                            }
+                            Some(_) => {}
+                            None => break,
+                        }
+                    }
+                    self.nesting -= 1;
+                    return Some(Obj::Dict(dict));
+                }
+                b'<' => return Some(Obj::Str(self.hex_string())),
+                b'(' => return Some(Obj::Str(self.literal_string())),
+                b'/' => {
+                    self.pos += 1;
+                    return Some(Obj::Name(self.name()));
+                }
+                b']' | b'>' | b')' | b'{' | b'}' => {
+                    // Stray delimiters; skip them.
+                    self.pos += 1;
+                }
+                _ => {
+                    let start = self.pos;
+                    while self
+                        .peek()
+                        .is_some_and(|b| !is_space(b) && !is_delimiter(b))
+                    {
+                        self.pos += 1;
+                    }
+                    let word = String::from_utf8_lossy(&self.data[start..self.pos]).into_owned();
+                    if let Ok(number) = word.parse::<f64>() {
+                        return Some(Obj::Num(number));
+                    }
+                    return Some(match word.as_str() {
+                        "true" | "false" | "null" => Obj::Null,
+                        "ID" => {
+                            self.skip_inline_image();
+                            continue;
+                        }
+                        _ => Obj::Op(word),
+                    });
+                }
+            }
+        }
+    }
+
+    fn name(&mut self) -> String {
+        let mut name = Vec::new();
+        while let Some(b) = self.peek() {
+            if is_space(b) || is_delimiter(b) {
+                break;
+            }
+            self.pos += 1;
+            if b == b'#' {
+                let hex = self.data.get(self.pos..self.pos + 2).unwrap_or_default();
+                if let Some(byte) = std::str::from_utf8(hex)
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                {
+                    name.push(byte);
+                    self.pos += 2;
+                    continue;
+                }
+            }
+            name.push(b);
+        }
+        String::from_utf8_lossy(&name).into_owned()
+    }
+
+    fn hex_string(&mut self) -> Vec<u8> {
+        self.pos += 1;
+        let mut digits = Vec::new();
+        while let Some(b) = self.peek() {
+            self.pos += 1;
+            if b == b'>' {
+                break;
+            }
+            if let Some(d) = char::from(b).to_digit(16) {
+                digits.push(d as u8);
+            }
+        }
+        if digits.len() % 2 == 1 {
+            digits.push(0);
+        }
+        digits
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect()
+    }
+
+    fn literal_string(&mut self) -> Vec<u8> {
+        self.pos += 1;
+        let mut out = Vec::new();
+        let mut depth = 1;
+        while let Some(b) = self.peek() {
+            self.pos += 1;
+            match b {
+                b'(' => {
+                    depth += 1;
+                    out.push(b);
+                }
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                    out.push(b);
+                }
+                b'\\' => {
+                    let Some(next) = self.peek() else {
+                        break;
+                    };
+                    self.pos += 1;
+                    match next {
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'b' => out.push(8),
+                        b'f' => out.push(12),
+                        b'0'..=b'7' => {
+                            let mut value = u32::from(next - b'0');
+                            for _ in 0..2 {
+                                match self.peek() {
+                                    Some(d @ b'0'..=b'7') => {
+                                        value = value * 8 + u32::from(d - b'0');
+                                        self.pos += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            out.push(value as u8);
+                        }
+                        b'\r' => {
+                            if self.peek() == Some(b'\n') {
+                                self.pos += 1;
+                            }
+                        }
+                        b'\n' => {}
+                        other => out.push(other),
+                    }
+                }
+                other => out.push(other),
+            }
+        }
+        out
+    }
+
+    /// Inline image data is binary; skip to the `EI` that ends it.
+    fn skip_inline_image(&mut self) {
+        let rest = &self.data[self.pos..];
+        let end = rest
+            .windows(4)
+            .position(|w| is_space(w[0]) && &w[1..3] == b"EI" && (is_space(w[3]) || w[3] == b'Q'))
+            .map_or(rest.len(), |i| i + 3);
+        self.pos += end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    /// A PDF with one page drawing `content`, plus any `extra` objects. No
+    /// cross-reference table; the parser does not need one.
+    fn pdf(content: &str, page_extra: &str, extra: &str) -> Vec<u8> {
+        format!(
+            "%PDF-1.4\n\
+             1 0 obj << /Type /Catalog /Pages 2 0 R >> endobj\n\
+             2 0 obj << /Type /Pages /Kids [3 0 R] /Count 1 >> endobj\n\
+             3 0 obj << /Type /Page /Parent 2 0 R /Contents 4 0 R \
+             /Resources << /Font << /F1 5 0 R >> {page_extra} >> >> endobj\n\
+             4 0 obj << /Length {} >>\nstream\n{content}\nendstream\nendobj\n\
+             5 0 obj << /Type /Font /Subtype /Type1 /BaseFont /Helvetica >> endobj\n\
+             {extra}",
+            content.len()
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn extracts_page_text() {
+        let data = pdf("BT /F1 12 Tf 72 720 Td (Hello campus) Tj ET", "", "");
+        assert_eq!(extract_pages(&data).unwrap(), vec!["Hello campus"]);
+    }
+
+    #[test]
+    fn deep_nesting_parses_as_null() {
+        let deep = "[".repeat(100_000) + &"<<".repeat(100_000);
+        let mut lexer = Lexer::new(deep.as_bytes(), 0);
+        assert!(lexer.value().is_some());
+
+        let data = pdf(&format!("BT {deep} (after) Tj ET"), "", "");
+        assert!(extract_pages(&data).is_ok());
+    }
+
+    #[test]
+    fn huge_lengths_and_offsets_do_not_overflow() {
+        let data = b"%PDF-1.4\n1 0 obj << /Length 1e30 >>\nstream\nabc\nendstream\nendobj\n";
+        assert!(extract_pages(data).is_err());
+        let data = b"%PDF-1.4\n1 0 obj << /Length -5 >>\nstream\nabc\nendstream\nendobj\n";
+        assert!(extract_pages(data).is_err());
+
+        let objstm = "6 0 obj << /Type /ObjStm /N 1e30 /First 1e30 /Length 9 >>\n\
+                      stream\n7 1e30 8 \nendstream\nendobj\n";
+        let data = pdf("BT /F1 12 Tf (ok) Tj ET", "", objstm);
+        assert_eq!(extract_pages(&data).unwrap(), vec!["ok"]);
+    }
+
+    #[test]
+    fn cmap_ranges_near_the_top_of_the_code_space() {
+        let cmap = CMap::parse(b"1 beginbfrange <FFFFFFFE> <FFFFFFFF> <0041> endbfrange");
+        assert_eq!(cmap.map.len(), 2);
+        assert_eq!(cmap.map[&0xFFFF_FFFF], "B");
+    }
+
+    #[test]
+    fn repeated_form_xobjects_stay_within_budget() {
+        // Each form draws itself many times, which would otherwise fan out to
+        // 1000^MAX_FORM_DEPTH renders.
+        let draws = "/X Do ".repeat(1000);
+        let form = format!(
+            "6 0 obj << /Type /XObject /Subtype /Form /Length {} >>\n\
+             stream\nBT /F1 12 Tf (x) Tj ET {draws}\nendstream\nendobj\n",
+            draws.len() + 23
+        );
+        let data = pdf(&draws, "/XObject << /X 6 0 R >>", &form);
+
+        let started = Instant::now();
+        let pages = extract_pages(&data).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let drawn = pages[0].matches('x').count();
+        assert!(
+            drawn > 0 && drawn <= MAX_PAGE_XOBJECTS,
+            "{drawn} forms drawn"
+        );
+    }
+
+    #[test]
+    fn page_trees_that_loop_are_visited_once() {
+        let data = b"%PDF-1.4\n\
+            1 0 obj << /Type /Catalog /Pages 2 0 R >> endobj\n\
+            2 0 obj << /Type /Pages /Kids [2 0 R 2 0 R 2 0 R 3 0 R 3 0 R] >> endobj\n\
+            3 0 obj << /Type /Page /Contents 4 0 R >> endobj\n\
+            4 0 obj << /Length 17 >>\nstream\nBT (page) Tj ET\n\nendstream\nendobj\n";
+        assert_eq!(extract_pages(data).unwrap(), vec!["page"]);
+    }
+}
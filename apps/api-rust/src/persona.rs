@@ -0,0 +1,320 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config::AppConfig;
+use crate::models::ChatMessage;
+use crate::tools::ToolRegistry;
+
+pub const DEFAULT_PERSONA: &str = "default";
+
+/// Local routing tiers, lowest first, so a persona's tier can act as a floor.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    Fast,
+    Balanced,
+    Quality,
+}
+
+impl Tier {
+    pub fn label(self) -> &'static str {
+        match self {
+            Tier::Fast => "fast",
+            Tier::Balanced => "balanced",
+            Tier::Quality => "quality",
+        }
+    }
+}
+
+/// What happens to system messages sent by the client.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientSystem {
+    /// Dropped; only the persona's prompt applies.
+    #[default]
+    Ignore,
+    /// Appended below the persona's prompt as additional instructions.
+    Append,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Persona {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub system_prompt: String,
+    /// Lowest local tier requests with this persona are routed to.
+    #[serde(default)]
+    pub default_tier: Option<Tier>,
+    /// Overrides `LOCAL_TEMPERATURE` for local models.
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// Overrides `LOCAL_TOP_P` for local models.
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub allowed_tools: Vec<String>,
+    #[serde(default)]
+    pub client_system: ClientSystem,
+}
+
+impl Persona {
+    fn validate(&self, tools: &ToolRegistry) -> Result<(), String> {
+        let valid_id = !self.id.is_empty()
+            && self
+                .id
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !valid_id {
+            return Err(format!(
+                "persona id '{}' must be lowercase letters, digits and dashes",
+                self.id
+            ));
+        }
+        if self.system_prompt.trim().is_empty() {
+            return Err(format!("persona '{}' has an empty systemPrompt", self.id));
+        }
+        if self.temperature.is_some_and(|t| !(0.0..=2.0).contains(&t)) {
+            return Err(format!(
+                "persona '{}' temperature must be between 0 and 2",
+                self.id
+            ));
+        }
+        if self.top_p.is_some_and(|p| !(0.0..=1.0).contains(&p)) {
+            return Err(format!(
+                "persona '{}' topP must be between 0 and 1",
+                self.id
+            ));
+        }
+        if let Some(name) = self.allowed_tools.iter().find(|name| !tools.contains(name)) {
+            return Err(format!(
+                "persona '{}' allows unknown tool '{name}'",
+                self.id
+            ));
+        }
+        Ok(())
+    }
+
+    /// The single system message for a request: the persona's prompt first,
+    /// then the client's system messages if the persona accepts them.
+    pub fn system_prompt_with(&self, client_system: &[ChatMessage]) -> String {
+        if self.client_system == ClientSystem::Ignore || client_system.is_empty() {
+            return self.system_prompt.clone();
+        }
+        let mut prompt = self.system_prompt.clone();
+        prompt.push_str("\n\nAdditional instructions from the client:");
+        for message in client_system {
+            prompt.push('\n');
+            prompt.push_str(message.content.trim());
+        }
+        prompt
+    }
+
+    /// Applies this persona's sampling parameters to a request's config.
+    pub fn apply(&self, cfg: &mut AppConfig) {
+        if let Some(temperature) = self.temperature {
+            cfg.local_temperature = temperature;
+        }
+        if let Some(top_p) = self.top_p {
+            cfg.local_top_p = top_p;
+        }
+    }
+}
+
+/// Personas loaded from `PERSONAS_DIR`, one JSON file each. A `default`
+/// persona built from `QUALITY_SYSTEM_PROMPT` always exists unless a file
+/// replaces it.
+pub struct PersonaRegistry {
+    personas: BTreeMap<String, Persona>,
+}
+
+impl PersonaRegistry {
+    pub fn load(cfg: &AppConfig, tools: &ToolRegistry) -> Result<Self, String> {
+        let mut personas = BTreeMap::new();
+        personas.insert(
+            DEFAULT_PERSONA.to_string(),
+            Persona {
+                id: DEFAULT_PERSONA.to_string(),
+                name: "Campus Assistant".to_string(),
+                description: "General-purpose assistant.".to_string(),
+                system_prompt: cfg.quality_system_prompt.clone(),
+                default_tier: None,
+                temperature: None,
+                top_p: None,
+                allowed_tools: ["calculator", "datetime", "unit_convert"]
+                    .map(String::from)
+                    .to_vec(),
+                client_system: ClientSystem::Append,
+            },
+        );
+
+        let dir = Path::new(&cfg.personas_dir);
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!(
+                    "PERSONAS_DIR {} not found; only the default persona is available",
+                    dir.display()
+                );
+                return Ok(Self { personas });
+            }
+            Err(e) => return Err(format!("cannot read PERSONAS_DIR {}: {e}", dir.display())),
+        };
+
+        let mut paths: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+
+        let mut loaded = Vec::new();
+        for path in paths {
+            let raw = fs::read_to_string(&path)
+                .map_err(|e| format!("cannot read persona {}: {e}", path.display()))?;
+            let persona: Persona = serde_json::from_str(&raw)
+                .map_err(|e| format!("invalid persona {}: {e}", path.display()))?;
+            persona
+                .validate(tools)
+                .map_err(|e| format!("{}: {e}", path.display()))?;
+            if loaded.contains(&persona.id) {
+                return Err(format!(
+                    "{}: duplicate persona id '{}'",
+                    path.display(),
+                    persona.id
+                ));
+            }
+            loaded.push(persona.id.clone());
+            personas.insert(persona.id.clone(), persona);
+        }
+        info!("loaded {} personas from {}", loaded.len(), dir.display());
+
+        Ok(Self { personas })
+    }
+
+    /// `None` selects the default persona.
+    pub fn get(&self, id: Option<&str>) -> Option<&Persona> {
+        self.personas.get(id.unwrap_or(DEFAULT_PERSONA))
+    }
+
+    pub fn list(&self) -> Vec<&Persona> {
+        self.personas.values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::random_hex;
+
+    fn persona(id: &str) -> Persona {
+        Persona {
+            id: id.to_string(),
+            name: "Tutor".to_string(),
+            description: String::new(),
+            system_prompt: "You are a tutor.".to_string(),
+            default_tier: Some(Tier::Balanced),
+            temperature: None,
+            top_p: None,
+            allowed_tools: vec!["calculator".to_string()],
+            client_system: ClientSystem::Ignore,
+        }
+    }
+
+    fn system(content: &str) -> ChatMessage {
+        ChatMessage {
+            role: "system".to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn client_system_messages_follow_the_persona_prompt_only_when_accepted() {
+        let mut tutor = persona("tutor");
+        let client = [system("  Answer in French. "), system("Be brief.")];
+        assert_eq!(tutor.system_prompt_with(&client), "You are a tutor.");
+
+        tutor.client_system = ClientSystem::Append;
+        assert_eq!(tutor.system_prompt_with(&[]), "You are a tutor.");
+        assert_eq!(
+            tutor.system_prompt_with(&client),
+            "You are a tutor.\n\nAdditional instructions from the client:\nAnswer in French.\nBe brief."
+        );
+    }
+
+    #[test]
+    fn sampling_overrides_replace_only_what_is_set() {
+        let mut cfg = AppConfig::from_env();
+        cfg.local_temperature = 0.7;
+        cfg.local_top_p = 0.9;
+        let mut tutor = persona("tutor");
+        tutor.temperature = Some(0.2);
+        tutor.apply(&mut cfg);
+        assert_eq!((cfg.local_temperature, cfg.local_top_p), (0.2, 0.9));
+        assert!(Tier::Fast < Tier::Balanced && Tier::Balanced < Tier::Quality);
+    }
+
+    #[test]
+    fn invalid_personas_are_rejected() {
+        let tools = ToolRegistry::builtin();
+        assert!(persona("tutor").validate(&tools).is_ok());
+
+        let rejects = |edit: fn(&mut Persona), expected: &str| {
+            let mut p = persona("tutor");
+            edit(&mut p);
+            let err = p.validate(&tools).unwrap_err();
+            assert!(err.contains(expected), "{err}");
+        };
+        rejects(|p| p.id = "Tutor".to_string(), "lowercase letters");
+        rejects(|p| p.system_prompt = "  ".to_string(), "empty systemPrompt");
+        rejects(|p| p.temperature = Some(2.5), "temperature");
+        rejects(|p| p.top_p = Some(-0.1), "topP");
+        rejects(
+            |p| p.allowed_tools.push("shell".to_string()),
+            "unknown tool 'shell'",
+        );
+    }
+
+    #[test]
+    fn personas_load_from_the_directory() {
+        let tools = ToolRegistry::builtin();
+        let mut cfg = AppConfig::from_env();
+        cfg.personas_dir = "personas".to_string();
+        let shipped = PersonaRegistry::load(&cfg, &tools).unwrap();
+        assert!(shipped.get(None).is_some());
+        assert!(shipped.get(Some("tutor")).is_some());
+        assert!(shipped.get(Some("missing")).is_none());
+
+        let dir = std::env::temp_dir().join(format!("campus-personas-{}", random_hex(8)));
+        fs::create_dir_all(&dir).unwrap();
+        cfg.personas_dir = dir.display().to_string();
+        let write = |file: &str, persona: &Persona| {
+            fs::write(dir.join(file), serde_json::to_string(persona).unwrap()).unwrap();
+        };
+
+        let mut default = persona(DEFAULT_PERSONA);
+        default.system_prompt = "Replaced.".to_string();
+        write("a.json", &default);
+        let registry = PersonaRegistry::load(&cfg, &tools).unwrap();
+        assert_eq!(registry.list().len(), 1);
+        assert_eq!(registry.get(None).unwrap().system_prompt, "Replaced.");
+
+        write("b.json", &default);
+        let err = PersonaRegistry::load(&cfg, &tools).err().unwrap();
+        assert!(err.contains("duplicate persona id 'default'"), "{err}");
+
+        fs::write(
+            dir.join("b.json"),
+            r#"{"id": "x", "name": "X", "systemPrompt": "Hi", "mood": 1}"#,
+        )
+        .unwrap();
+        let err = PersonaRegistry::load(&cfg, &tools).err().unwrap();
+        assert!(err.contains("unknown field `mood`"), "{err}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,28 +1,341 @@
-use bytes::Bytes;
+use std::sync::Arc;
+use std::time::Instant;
+
 use reqwest::Client;
-use serde_json::Value;
+use serde::Serialize;
+use serde_json::{json, Value};
 use tokio::sync::mpsc;
 
+use crate::confidence::LogprobStats;
 use crate::config::AppConfig;
+use crate::events::StreamEvent;
 use crate::models::ChatMessage;
+use crate::residency::CloudGuard;
+use crate::schema::ResponseSchema;
+use crate::tools::{Tool, ToolCall, ToolResult, ToolRound};
+
+/// Token counts and timings as reported by the provider. Ollama reports its
+/// own durations; for the cloud they are measured here, split at the first
+/// streamed token.
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub prompt_ms: Option<u64>,
+    pub generation_ms: Option<u64>,
+    pub total_ms: Option<u64>,
+}
+
+impl TokenUsage {
+    /// Usage of two calls made for one answer.
+    pub fn combined(self, other: TokenUsage) -> TokenUsage {
+        let sum = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+        TokenUsage {
+            prompt_tokens: self.prompt_tokens + other.prompt_tokens,
+            completion_tokens: self.completion_tokens + other.completion_tokens,
+            prompt_ms: sum(self.prompt_ms, other.prompt_ms),
+            generation_ms: sum(self.generation_ms, other.generation_ms),
+            total_ms: sum(self.total_ms, other.total_ms),
+        }
+    }
+}
+
+fn nanos_to_ms(value: Option<&Value>) -> Option<u64> {
+    value.and_then(Value::as_u64).map(|ns| ns / 1_000_000)
+}
+
+/// Usage from Ollama's final `done` chunk or non-streaming reply.
+fn ollama_usage(event: &Value) -> TokenUsage {
+    TokenUsage {
+        prompt_tokens: event
+            .get("prompt_eval_count")
+            .and_then(Value::as_u64)
+            .unwrap_or(0),
+        completion_tokens: event.get("eval_count").and_then(Value::as_u64).unwrap_or(0),
+        prompt_ms: nanos_to_ms(event.get("prompt_eval_duration")),
+        generation_ms: nanos_to_ms(event.get("eval_duration")),
+        total_ms: nanos_to_ms(event.get("total_duration")),
+    }
+}
+
+/// Non-streaming Ollama chat call for internal helpers; `max_tokens` caps
+/// the answer via `num_predict`.
+pub async fn complete_ollama(
+    client: &Client,
+    cfg: &AppConfig,
+    model: &str,
+    messages: &[ChatMessage],
+    max_tokens: u64,
+) -> Result<Completion, String> {
+    let payload = serde_json::json!({
+        "model": model,
+        "stream": false,
+        "messages": messages,
+        "options": {
+            "temperature": cfg.local_temperature,
+            "top_p": cfg.local_top_p,
+            "num_ctx": cfg.local_num_ctx,
+            "num_predict": max_tokens,
+        }
+    });
+
+    let response = client
+        .post(format!("{}/api/chat", cfg.local_model_base_url.trim_end_matches('/')))
+        .json(&payload)
+        .send()
+        .await
+        .map_err(|e| format!("ollama send error: {e}"))?;
+
+    if !response.status().is_success() {
+        return Err(format!("ollama status error: {}", response.status()));
+    }
+
+    let body: Value = response
+        .json()
+        .await
+        .map_err(|e| format!("ollama response error: {e}"))?;
+    let text = body
+        .pointer("/message/content")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+
+    Ok(Completion {
+        text,
+        logprobs: LogprobStats::default(),
+        usage: Some(ollama_usage(&body)),
+        tool_calls: Vec::new(),
+        tool_results: Vec::new(),
+    })
+}
+
+/// Inputs sent to Ollama's embed endpoint per request.
+const EMBED_BATCH: usize = 16;
+
+/// Embeddings for `inputs`, in order, with the prompt tokens Ollama counted.
+pub async fn embed_ollama(
+    client: &Client,
+    cfg: &AppConfig,
+    model: &str,
+    inputs: &[String],
+) -> Result<(Vec<Vec<f32>>, u64), String> {
+    let url = format!(
+        "{}/api/embed",
+        cfg.local_model_base_url.trim_end_matches('/')
+    );
+    let mut embeddings = Vec::with_capacity(inputs.len());
+    let mut prompt_tokens = 0;
+
+    for batch in inputs.chunks(EMBED_BATCH) {
+        let response = client
+            .post(&url)
+            .json(&json!({ "model": model, "input": batch }))
+            .send()
+            .await
+            .map_err(|e| format!("ollama embed send error: {e}"))?;
+
+        if !response.status().is_success() {
+            return Err(format!("ollama embed status error: {}", response.status()));
+        }
+
+        let body: Value = response
+            .json()
+            .await
+            .map_err(|e| format!("ollama embed response error: {e}"))?;
+        let vectors = body
+            .get("embeddings")
+            .and_then(Value::as_array)
+            .ok_or("ollama embed response has no embeddings")?;
+        if vectors.len() != batch.len() {
+            return Err(format!(
+                "ollama returned {} embeddings for {} inputs",
+                vectors.len(),
+                batch.len()
+            ));
+        }
+        for vector in vectors {
+            let values = vector
+                .as_array()
+                .ok_or("ollama embedding is not an array")?
+                .iter()
+                .map(|v| v.as_f64().unwrap_or(0.0) as f32)
+                .collect();
+            embeddings.push(values);
+        }
+        prompt_tokens += body
+            .get("prompt_eval_count")
+            .and_then(Value::as_u64)
+            .unwrap_or(0);
+    }
+
+    Ok((embeddings, prompt_tokens))
+}
+
+/// Inputs sent to the cloud embeddings endpoint per request.
+const CLOUD_EMBED_BATCH: usize = 128;
+
+/// Embeddings from the cloud provider's OpenAI-style `/embeddings`
+/// endpoint, in order, with the prompt tokens it reported.
+pub async fn embed_cloud(
+    client: &Client,
+    cfg: &AppConfig,
+    model: &str,
+    inputs: &[String],
+    guard: &CloudGuard,
+) -> Result<(Vec<Vec<f32>>, u64), String> {
+    if cfg.cloud_api_key.is_empty() {
+        return Err("cloud api key missing".to_string());
+    }
+    let url = format!(
+        "{}/embeddings",
+        cfg.cloud_api_base_url.trim_end_matches('/')
+    );
+    let mut embeddings = Vec::with_capacity(inputs.len());
+    let mut prompt_tokens = 0;
+
+    for batch in inputs.chunks(CLOUD_EMBED_BATCH) {
+        guard.record_call()?;
+        let response = client
+            .post(&url)
+            .bearer_auth(&cfg.cloud_api_key)
+            .json(&json!({ "model": model, "input": batch }))
+            .send()
+            .await
+            .map_err(|e| format!("cloud embed send error: {e}"))?;
+
+        if !response.status().is_success() {
+            return Err(format!("cloud embed status error: {}", response.status()));
+        }
+
+        let body: Value = response
+            .json()
+            .await
+            .map_err(|e| format!("cloud embed response error: {e}"))?;
+        let mut data: Vec<&Value> = body
+            .get("data")
+            .and_then(Value::as_array)
+            .ok_or("cloud embed response has no data")?
+            .iter()
+            .collect();
+        if data.len() != batch.len() {
+            return Err(format!(
+                "cloud returned {} embeddings for {} inputs",
+                data.len(),
+                batch.len()
+            ));
+        }
+        data.sort_by_key(|item| item.get("index").and_then(Value::as_u64));
+        for item in data {
+            let values = item
+                .get("embedding")
+                .and_then(Value::as_array)
+                .ok_or("cloud embedding is not an array")?
+                .iter()
+                .map(|v| v.as_f64().unwrap_or(0.0) as f32)
+                .collect();
+            embeddings.push(values);
+        }
+        prompt_tokens += body
+            .pointer("/usage/prompt_tokens")
+            .and_then(Value::as_u64)
+            .unwrap_or(0);
+    }
+
+    Ok((embeddings, prompt_tokens))
+}
+
+/// Everything a provider produced for one request once its stream has ended.
+pub struct Completion {
+    pub text: String,
+    pub logprobs: LogprobStats,
+    /// `None` when the provider did not report usage.
+    pub usage: Option<TokenUsage>,
+    /// Calls the model asked for instead of answering.
+    pub tool_calls: Vec<ToolCall>,
+    /// Calls run on the way to this answer, filled in by the tool loop.
+    pub tool_results: Vec<ToolResult>,
+}
+
+/// What a streaming call asks of the model beyond answering the messages.
+#[derive(Clone, Copy, Default)]
+pub struct CallOptions<'a> {
+    pub schema: Option<&'a ResponseSchema>,
+    /// Tools the model may call in this turn.
+    pub tools: &'a [Arc<dyn Tool>],
+    /// Earlier tool calls for this answer and their results.
+    pub rounds: &'a [ToolRound],
+}
+
+/// Ollama sends tool arguments as an object; some models emit a string.
+fn tool_arguments(raw: Option<&Value>) -> Value {
+    match raw {
+        Some(Value::String(text)) => {
+            serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.clone()))
+        }
+        Some(value) => value.clone(),
+        None => json!({}),
+    }
+}
 
 pub async fn stream_ollama(
     client: Client,
     cfg: AppConfig,
     model: String,
     messages: Vec<ChatMessage>,
-    tx: mpsc::Sender<Bytes>,
-) -> Result<String, String> {
-    let payload = serde_json::json!({
+    options: CallOptions<'_>,
+    tx: mpsc::Sender<StreamEvent>,
+) -> Result<Completion, String> {
+    let mut history: Vec<Value> = messages.iter().map(|m| json!(m)).collect();
+    for round in options.rounds {
+        let calls: Vec<Value> = round
+            .calls
+            .iter()
+            .map(|call| json!({ "function": { "name": call.name, "arguments": call.arguments } }))
+            .collect();
+        history.push(json!({ "role": "assistant", "content": "", "tool_calls": calls }));
+        for (call, output) in round.calls.iter().zip(&round.outputs) {
+            history.push(json!({ "role": "tool", "tool_name": call.name, "content": output }));
+        }
+    }
+
+    let mut payload = serde_json::json!({
         "model": model,
         "stream": true,
-        "messages": messages,
+        "messages": history,
         "options": {
             "temperature": cfg.local_temperature,
             "top_p": cfg.local_top_p,
             "num_ctx": cfg.local_num_ctx,
         }
     });
+    if cfg.request_logprobs {
+        payload["logprobs"] = Value::Bool(true);
+    }
+    if let Some(schema) = options.schema.filter(|_| cfg.structured_output_native) {
+        payload["format"] = schema.schema.clone();
+    }
+    if !options.tools.is_empty() {
+        let tools: Vec<Value> = options
+            .tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool.name(),
+                        "description": tool.description(),
+                        "parameters": tool.parameters(),
+                    }
+                })
+            })
+            .collect();
+        payload["tools"] = Value::Array(tools);
+    }
 
     let response = client
         .post(format!("{}/api/chat", cfg.local_model_base_url.trim_end_matches('/')))
@@ -38,6 +351,9 @@ pub async fn stream_ollama(
     let mut stream = response.bytes_stream();
     let mut buffer = String::new();
     let mut full = String::new();
+    let mut logprobs = LogprobStats::default();
+    let mut usage = None;
+    let mut tool_calls = Vec::new();
 
     while let Some(chunk_result) = futures_util::StreamExt::next(&mut stream).await {
         let chunk = chunk_result.map_err(|e| format!("ollama stream chunk error: {e}"))?;
@@ -52,19 +368,52 @@ pub async fn stream_ollama(
             }
 
             if let Ok(event) = serde_json::from_str::<Value>(&line) {
+                logprobs.record_all(event.get("logprobs"));
                 if let Some(part) = event
                     .get("message")
                     .and_then(|m| m.get("content"))
                     .and_then(Value::as_str)
                 {
                     full.push_str(part);
-                    let _ = tx.send(Bytes::from(part.to_string())).await;
+                    let _ = tx.send(StreamEvent::Delta(part.to_string())).await;
+                }
+                for call in event
+                    .pointer("/message/tool_calls")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                {
+                    let id = call
+                        .get("id")
+                        .and_then(Value::as_str)
+                        .map(str::to_string)
+                        .unwrap_or_else(|| {
+                            format!("call_{}_{}", options.rounds.len(), tool_calls.len())
+                        });
+                    tool_calls.push(ToolCall {
+                        id,
+                        name: call
+                            .pointer("/function/name")
+                            .and_then(Value::as_str)
+                            .unwrap_or_default()
+                            .to_string(),
+                        arguments: tool_arguments(call.pointer("/function/arguments")),
+                    });
+                }
+                if event.get("done").and_then(Value::as_bool) == Some(true) {
+                    usage = Some(ollama_usage(&event));
                 }
             }
         }
     }
 
-    Ok(full)
+    Ok(Completion {
+        text: full,
+        logprobs,
+        usage,
+        tool_calls,
+        tool_results: Vec::new(),
+    })
 }
 
 pub async fn stream_cloud(
@@ -72,13 +421,16 @@ pub async fn stream_cloud(
     cfg: AppConfig,
     model: String,
     messages: Vec<ChatMessage>,
-    tx: mpsc::Sender<Bytes>,
-) -> Result<String, String> {
+    options: CallOptions<'_>,
+    tx: mpsc::Sender<StreamEvent>,
+    guard: &CloudGuard,
+) -> Result<Completion, String> {
     if cfg.cloud_api_key.is_empty() {
         return Err("cloud api key missing".to_string());
     }
+    guard.record_call()?;
 
-    let input: Vec<Value> = messages
+    let mut input: Vec<Value> = messages
         .into_iter()
         .map(|m| {
             serde_json::json!({
@@ -87,15 +439,64 @@ pub async fn stream_cloud(
             })
         })
         .collect();
+    for round in options.rounds {
+        for (call, output) in round.calls.iter().zip(&round.outputs) {
+            input.push(json!({
+                "type": "function_call",
+                "call_id": call.id,
+                "name": call.name,
+                "arguments": call.arguments.to_string(),
+            }));
+            input.push(json!({
+                "type": "function_call_output",
+                "call_id": call.id,
+                "output": output,
+            }));
+        }
+    }
+
+    let mut body = serde_json::json!({
+        "model": model,
+        "input": input,
+        "stream": true
+    });
+    if cfg.request_logprobs {
+        body["include"] = serde_json::json!(["message.output_text.logprobs"]);
+    }
+    if !options.tools.is_empty() {
+        let tools: Vec<Value> = options
+            .tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "type": "function",
+                    "name": tool.name(),
+                    "description": tool.description(),
+                    "parameters": tool.parameters(),
+                    "strict": false,
+                })
+            })
+            .collect();
+        body["tools"] = Value::Array(tools);
+    }
+    if let Some(schema) = options.schema.filter(|_| cfg.structured_output_native) {
+        // Not strict: strict mode rejects schemas with optional properties,
+        // and the answer is validated here anyway.
+        body["text"] = serde_json::json!({
+            "format": {
+                "type": "json_schema",
+                "name": schema.name,
+                "schema": schema.schema,
+                "strict": false,
+            }
+        });
+    }
 
+    let started = Instant::now();
     let response = client
         .post(format!("{}/responses", cfg.cloud_api_base_url.trim_end_matches('/')))
         .bearer_auth(cfg.cloud_api_key)
-        .json(&serde_json::json!({
-            "model": model,
-            "input": input,
-            "stream": true
-        }))
+        .json(&body)
         .send()
         .await
         .map_err(|e| format!("cloud send error: {e}"))?;
@@ -107,6 +508,10 @@ pub async fn stream_cloud(
     let mut stream = response.bytes_stream();
     let mut buffer = String::new();
     let mut full = String::new();
+    let mut logprobs = LogprobStats::default();
+    let mut usage = None;
+    let mut first_token_ms = None;
+    let mut tool_calls = Vec::new();
 
     while let Some(chunk_result) = futures_util::StreamExt::next(&mut stream).await {
         let chunk = chunk_result.map_err(|e| format!("cloud stream chunk error: {e}"))?;
@@ -128,9 +533,49 @@ pub async fn stream_cloud(
 
                 if let Ok(json) = serde_json::from_str::<Value>(payload) {
                     if json.get("type") == Some(&Value::String("response.output_text.delta".to_string())) {
+                        logprobs.record_all(json.get("logprobs"));
                         if let Some(delta) = json.get("delta").and_then(Value::as_str) {
+                            first_token_ms.get_or_insert(started.elapsed().as_millis() as u64);
                             full.push_str(delta);
-                            let _ = tx.send(Bytes::from(delta.to_string())).await;
+                            let _ = tx.send(StreamEvent::Delta(delta.to_string())).await;
+                        }
+                    } else if json.get("type").and_then(Value::as_str)
+                        == Some("response.output_item.done")
+                    {
+                        let item = &json["item"];
+                        if item.get("type").and_then(Value::as_str) == Some("function_call") {
+                            tool_calls.push(ToolCall {
+                                id: item
+                                    .get("call_id")
+                                    .and_then(Value::as_str)
+                                    .unwrap_or_default()
+                                    .to_string(),
+                                name: item
+                                    .get("name")
+                                    .and_then(Value::as_str)
+                                    .unwrap_or_default()
+                                    .to_string(),
+                                arguments: tool_arguments(item.get("arguments")),
+                            });
+                        }
+                    } else if matches!(
+                        json.get("type").and_then(Value::as_str),
+                        Some("response.completed" | "response.incomplete" | "response.failed")
+                    ) {
+                        // Unfinished responses are billed too.
+                        usage = json.pointer("/response/usage").map(|u| TokenUsage {
+                            prompt_tokens: u
+                                .get("input_tokens")
+                                .and_then(Value::as_u64)
+                                .unwrap_or(0),
+                            completion_tokens: u
+                                .get("output_tokens")
+                                .and_then(Value::as_u64)
+                                .unwrap_or(0),
+                            ..TokenUsage::default()
+                        });
+                        if let Some(usage) = usage {
+                            guard.record_usage(usage);
                         }
                     }
                 }
@@ -138,5 +583,181 @@ pub async fn stream_cloud(
         }
     }
 
-    Ok(full)
+    let total_ms = started.elapsed().as_millis() as u64;
+    let usage = usage.map(|usage| TokenUsage {
+        prompt_ms: first_token_ms,
+        generation_ms: first_token_ms.map(|first| total_ms.saturating_sub(first)),
+        total_ms: Some(total_ms),
+        ..usage
+    });
+
+    Ok(Completion {
+        text: full,
+        logprobs,
+        usage,
+        tool_calls,
+        tool_results: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    use super::*;
+    use crate::residency::Residency;
+
+    /// Serves canned Ollama and Responses streams, with the usage each
+    /// provider reports in its final event.
+    fn provider() -> AppConfig {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = HttpServer::new(|| {
+            App::new()
+                .route(
+                    "/api/chat",
+                    web::post().to(|| async {
+                        let lines = [
+                            json!({"message": {"content": "Hel"}, "done": false}),
+                            json!({"message": {"content": "lo"}, "done": false}),
+                            json!({
+                                "message": {"content": ""}, "done": true,
+                                "prompt_eval_count": 12, "eval_count": 2,
+                                "prompt_eval_duration": 30_000_000_u64,
+                                "eval_duration": 45_500_000_u64,
+                                "total_duration": 90_000_000_u64,
+                            }),
+                        ];
+                        let body = lines.map(|l| format!("{l}\n")).concat();
+                        HttpResponse::Ok().body(body)
+                    }),
+                )
+                .route(
+                    "/responses",
+                    web::post().to(|| async {
+                        let events = [
+                            json!({"type": "response.output_text.delta", "delta": "Hi"}),
+                            json!({"type": "response.output_text.delta", "delta": " there"}),
+                            json!({"type": "response.completed", "response": {
+                                "usage": {"input_tokens": 20, "output_tokens": 3}
+                            }}),
+                        ];
+                        let body = events
+                            .map(|e| format!("event: message\ndata: {e}\n\n"))
+                            .concat();
+                        HttpResponse::Ok()
+                            .content_type("text/event-stream")
+                            .body(body + "data: [DONE]\n\n")
+                    }),
+                )
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+
+        let mut cfg = AppConfig::from_env();
+        cfg.local_model_base_url.clone_from(&base);
+        cfg.cloud_api_base_url = base;
+        cfg.cloud_api_key = "test-key".to_string();
+        cfg
+    }
+
+    #[actix_web::test]
+    async fn ollama_reports_counts_and_durations() {
+        let cfg = provider();
+        let (tx, mut rx) = mpsc::channel(8);
+        let completion = stream_ollama(
+            Client::new(),
+            cfg,
+            "llama".to_string(),
+            Vec::new(),
+            CallOptions::default(),
+            tx,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(completion.text, "Hello");
+        assert!(matches!(rx.recv().await, Some(StreamEvent::Delta(d)) if d == "Hel"));
+        let usage = completion.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 2));
+        assert_eq!(usage.prompt_ms, Some(30));
+        assert_eq!(usage.generation_ms, Some(45));
+        assert_eq!(usage.total_ms, Some(90));
+    }
+
+    #[actix_web::test]
+    async fn cloud_usage_is_reported_and_charged_to_the_guard() {
+        let cfg = provider();
+        let (tx, _rx) = mpsc::channel(8);
+        let guard = CloudGuard::new(Residency::Any);
+        let completion = stream_cloud(
+            Client::new(),
+            cfg,
+            "gpt".to_string(),
+            Vec::new(),
+            CallOptions::default(),
+            tx,
+            &guard,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(completion.text, "Hi there");
+        let usage = completion.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (20, 3));
+        assert!(usage.prompt_ms.is_some());
+        assert_eq!(
+            usage.total_ms,
+            usage.prompt_ms.zip(usage.generation_ms).map(|(p, g)| p + g)
+        );
+        assert_eq!(guard.calls(), 1);
+        assert_eq!(guard.take_usage().map(|u| u.prompt_tokens), Some(20));
+    }
+
+    #[actix_web::test]
+    async fn local_only_requests_never_reach_the_cloud() {
+        let cfg = provider();
+        let (tx, _rx) = mpsc::channel(8);
+        let guard = CloudGuard::new(Residency::LocalOnly);
+        let result = stream_cloud(
+            Client::new(),
+            cfg,
+            "gpt".to_string(),
+            Vec::new(),
+            CallOptions::default(),
+            tx,
+            &guard,
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(guard.calls(), 0);
+    }
+
+    #[test]
+    fn usage_of_several_calls_is_summed() {
+        let first = TokenUsage {
+            prompt_tokens: 10,
+            completion_tokens: 4,
+            prompt_ms: Some(5),
+            generation_ms: None,
+            total_ms: Some(50),
+        };
+        let second = TokenUsage {
+            prompt_tokens: 7,
+            completion_tokens: 1,
+            prompt_ms: None,
+            generation_ms: Some(8),
+            total_ms: Some(20),
+        };
+        let sum = first.combined(second);
+        assert_eq!((sum.prompt_tokens, sum.completion_tokens), (17, 5));
+        assert_eq!(sum.prompt_ms, Some(5));
+        assert_eq!(sum.generation_ms, Some(8));
+        assert_eq!(sum.total_ms, Some(70));
+    }
 }
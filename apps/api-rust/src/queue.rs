@@ -0,0 +1,553 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::sleep;
+
+use crate::config::AppConfig;
+use crate::events::StreamEvent;
+use crate::models::Provider;
+
+/// How often a waiting client is told its position.
+const POSITION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Interactive requests are always served before batch ones.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Lane {
+    #[default]
+    Interactive,
+    Batch,
+}
+
+impl Lane {
+    fn index(self) -> usize {
+        match self {
+            Lane::Interactive => 0,
+            Lane::Batch => 1,
+        }
+    }
+}
+
+/// Sent to streaming clients while they wait for a generation slot.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuePosition {
+    pub model: String,
+    /// 1 means next in line.
+    pub position: usize,
+    /// Unknown until the model has finished at least one generation.
+    pub estimated_wait_seconds: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueStatus {
+    pub model: String,
+    pub capacity: usize,
+    pub active: usize,
+    pub interactive_waiting: usize,
+    pub batch_waiting: usize,
+    pub avg_generation_ms: Option<u64>,
+}
+
+/// The queue gave up before a slot was free; distinct from a generation
+/// timeout so clients and metrics can tell "busy" from "slow".
+#[derive(Debug)]
+pub struct QueueTimeout;
+
+struct Waiter {
+    id: u64,
+    grant: oneshot::Sender<QueueSlot>,
+}
+
+/// Waiters grouped by user and served round-robin, so one user's burst
+/// cannot starve everyone else in the lane.
+#[derive(Default)]
+struct LaneQueue {
+    turns: VecDeque<String>,
+    waiting: HashMap<String, VecDeque<Waiter>>,
+}
+
+impl LaneQueue {
+    fn push(&mut self, user: &str, waiter: Waiter) {
+        let queue = self.waiting.entry(user.to_string()).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(user.to_string());
+        }
+        queue.push_back(waiter);
+    }
+
+    fn pop(&mut self) -> Option<Waiter> {
+        let user = self.turns.pop_front()?;
+        let queue = self.waiting.get_mut(&user)?;
+        let waiter = queue.pop_front();
+        if queue.is_empty() {
+            self.waiting.remove(&user);
+        } else {
+            self.turns.push_back(user);
+        }
+        waiter
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        let Some((user, queue)) = self
+            .waiting
+            .iter_mut()
+            .find(|(_, q)| q.iter().any(|w| w.id == id))
+        else {
+            return false;
+        };
+        queue.retain(|w| w.id != id);
+        if queue.is_empty() {
+            let user = user.clone();
+            self.waiting.remove(&user);
+            self.turns.retain(|u| *u != user);
+        }
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.waiting.values().map(VecDeque::len).sum()
+    }
+
+    /// Waiters in the order they will be served.
+    fn order(&self) -> impl Iterator<Item = u64> + '_ {
+        let deepest = self.waiting.values().map(VecDeque::len).max().unwrap_or(0);
+        (0..deepest).flat_map(move |round| {
+            self.turns
+                .iter()
+                .filter_map(move |user| self.waiting.get(user)?.get(round).map(|w| w.id))
+        })
+    }
+}
+
+struct ModelQueue {
+    capacity: usize,
+    active: usize,
+    lanes: [LaneQueue; 2],
+    avg_generation_ms: Option<f64>,
+}
+
+impl ModelQueue {
+    fn next_waiter(&mut self) -> Option<Waiter> {
+        self.lanes.iter_mut().find_map(LaneQueue::pop)
+    }
+
+    fn position(&self, id: u64) -> Option<usize> {
+        self.lanes
+            .iter()
+            .flat_map(LaneQueue::order)
+            .position(|w| w == id)
+            .map(|ahead| ahead + 1)
+    }
+
+    fn estimate_wait(&self, position: usize) -> Option<u64> {
+        let rounds = position.div_ceil(self.capacity) as f64;
+        self.avg_generation_ms
+            .map(|avg| (rounds * avg / 1000.0).ceil() as u64)
+    }
+}
+
+struct Shared {
+    models: Mutex<HashMap<String, ModelQueue>>,
+    next_id: AtomicU64,
+}
+
+impl Shared {
+    /// `ran_for` is `None` for slots that were granted to a waiter who had
+    /// already left, so they do not skew the average.
+    fn release(self: &Arc<Self>, model: &str, ran_for: Option<Duration>) {
+        let mut granted = Vec::new();
+        if let Ok(mut models) = self.models.lock() {
+            let Some(queue) = models.get_mut(model) else {
+                return;
+            };
+            queue.active = queue.active.saturating_sub(1);
+            if let Some(ran_for) = ran_for {
+                let ms = ran_for.as_millis() as f64;
+                queue.avg_generation_ms = Some(match queue.avg_generation_ms {
+                    Some(avg) => avg * 0.8 + ms * 0.2,
+                    None => ms,
+                });
+            }
+            while queue.active < queue.capacity {
+                let Some(waiter) = queue.next_waiter() else {
+                    break;
+                };
+                queue.active += 1;
+                granted.push(waiter);
+            }
+        }
+
+        // Sent outside the lock: a waiter that gave up drops its slot, which
+        // re-enters `release`.
+        for waiter in granted {
+            let slot = QueueSlot::new(self.clone(), model, Duration::ZERO);
+            if let Err(mut slot) = waiter.grant.send(slot) {
+                slot.unused = true;
+            }
+        }
+    }
+
+    fn withdraw(&self, model: &str, id: u64) {
+        if let Ok(mut models) = self.models.lock() {
+            if let Some(queue) = models.get_mut(model) {
+                for lane in &mut queue.lanes {
+                    if lane.remove(id) {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Held for the duration of one generation; dropping it admits the next
+/// waiter for the same model.
+pub struct QueueSlot {
+    shared: Arc<Shared>,
+    model: String,
+    started: Instant,
+    unused: bool,
+    /// Time spent queued before the slot was granted.
+    pub waited: Duration,
+}
+
+impl QueueSlot {
+    fn new(shared: Arc<Shared>, model: &str, waited: Duration) -> Self {
+        Self {
+            shared,
+            model: model.to_string(),
+            started: Instant::now(),
+            unused: false,
+            waited,
+        }
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        let ran_for = (!self.unused).then(|| self.started.elapsed());
+        self.shared.release(&self.model, ran_for);
+    }
+}
+
+/// Removes a waiter that timed out or whose client went away.
+struct PendingTicket<'a> {
+    shared: &'a Shared,
+    model: &'a str,
+    id: u64,
+}
+
+impl Drop for PendingTicket<'_> {
+    fn drop(&mut self) {
+        self.shared.withdraw(self.model, self.id);
+    }
+}
+
+/// Per-model generation queue. Local models get `LOCAL_PARALLEL` slots
+/// (defaulting to `OLLAMA_NUM_PARALLEL`), cloud models `CLOUD_PARALLEL`.
+pub struct GenerationQueue {
+    shared: Arc<Shared>,
+    local_capacity: usize,
+    cloud_capacity: usize,
+}
+
+impl GenerationQueue {
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                models: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(1),
+            }),
+            local_capacity: cfg.local_parallel,
+            cloud_capacity: cfg.cloud_parallel,
+        }
+    }
+
+    /// Waits for a slot on `model`, sending queue events to `events` while
+    /// queued. Gives up after `max_wait`.
+    pub async fn acquire(
+        &self,
+        model: &str,
+        provider: &Provider,
+        user: &str,
+        lane: Lane,
+        events: &mpsc::Sender<StreamEvent>,
+        max_wait: Duration,
+    ) -> Result<QueueSlot, QueueTimeout> {
+        let capacity = match provider {
+            Provider::Local => self.local_capacity,
+            Provider::Cloud => self.cloud_capacity,
+        };
+        let enqueued = Instant::now();
+        let (grant, mut granted) = oneshot::channel();
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        {
+            let Ok(mut models) = self.shared.models.lock() else {
+                return Ok(QueueSlot::new(self.shared.clone(), model, Duration::ZERO));
+            };
+            let queue = models
+                .entry(model.to_string())
+                .or_insert_with(|| ModelQueue {
+                    capacity,
+                    active: 0,
+                    lanes: Default::default(),
+                    avg_generation_ms: None,
+                });
+            if queue.active < queue.capacity {
+                queue.active += 1;
+                return Ok(QueueSlot::new(self.shared.clone(), model, Duration::ZERO));
+            }
+            queue.lanes[lane.index()].push(user, Waiter { id, grant });
+        }
+
+        let _ticket = PendingTicket {
+            shared: &self.shared,
+            model,
+            id,
+        };
+        let deadline = enqueued + max_wait;
+        let mut last_position = None;
+        loop {
+            let update = self.shared.models.lock().ok().and_then(|models| {
+                let queue = models.get(model)?;
+                let position = queue.position(id)?;
+                Some(QueuePosition {
+                    model: model.to_string(),
+                    position,
+                    estimated_wait_seconds: queue.estimate_wait(position),
+                })
+            });
+            if let Some(update) = update {
+                if last_position != Some(update.position) {
+                    last_position = Some(update.position);
+                    let _ = events.send(StreamEvent::Queue(update)).await;
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(QueueTimeout);
+            }
+            tokio::select! {
+                slot = &mut granted => {
+                    return match slot {
+                        Ok(mut slot) => {
+                            slot.waited = enqueued.elapsed();
+                            Ok(slot)
+                        }
+                        Err(_) => Err(QueueTimeout),
+                    };
+                }
+                _ = sleep(POSITION_INTERVAL.min(deadline - now)) => {}
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<QueueStatus> {
+        let Ok(models) = self.shared.models.lock() else {
+            return Vec::new();
+        };
+        let mut statuses: Vec<QueueStatus> = models
+            .iter()
+            .map(|(model, queue)| QueueStatus {
+                model: model.clone(),
+                capacity: queue.capacity,
+                active: queue.active,
+                interactive_waiting: queue.lanes[0].len(),
+                batch_waiting: queue.lanes[1].len(),
+                avg_generation_ms: queue.avg_generation_ms.map(|ms| ms as u64),
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.model.cmp(&b.model));
+        statuses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: Duration = Duration::from_secs(30);
+
+    fn queue(capacity: usize) -> Arc<GenerationQueue> {
+        Arc::new(GenerationQueue {
+            shared: Arc::new(Shared {
+                models: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(1),
+            }),
+            local_capacity: capacity,
+            cloud_capacity: capacity,
+        })
+    }
+
+    fn waiting(queue: &GenerationQueue) -> usize {
+        queue
+            .snapshot()
+            .iter()
+            .map(|s| s.interactive_waiting + s.batch_waiting)
+            .sum()
+    }
+
+    fn active(queue: &GenerationQueue) -> usize {
+        queue.snapshot().iter().map(|s| s.active).sum()
+    }
+
+    /// Queues `label` behind whatever holds the model and records the order
+    /// slots are granted in; each slot is released as soon as it is granted.
+    async fn enqueue(
+        queue: &Arc<GenerationQueue>,
+        served: &Arc<Mutex<Vec<&'static str>>>,
+        label: &'static str,
+        user: &'static str,
+        lane: Lane,
+    ) {
+        let before = waiting(queue);
+        let (queue_ref, served) = (queue.clone(), served.clone());
+        actix_web::rt::spawn(async move {
+            let (events, _rx) = mpsc::channel(8);
+            let slot = queue_ref
+                .acquire("m", &Provider::Local, user, lane, &events, LONG)
+                .await
+                .unwrap();
+            served.lock().unwrap().push(label);
+            drop(slot);
+        });
+        while waiting(queue) == before {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    async fn hold(queue: &GenerationQueue) -> QueueSlot {
+        let (events, _rx) = mpsc::channel(8);
+        queue
+            .acquire(
+                "m",
+                &Provider::Local,
+                "holder",
+                Lane::Interactive,
+                &events,
+                LONG,
+            )
+            .await
+            .unwrap()
+    }
+
+    async fn drain(queue: &GenerationQueue) {
+        while waiting(queue) > 0 || active(queue) > 0 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn users_take_turns_within_a_lane() {
+        let queue = queue(1);
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let holder = hold(&queue).await;
+        for label in ["alice-1", "alice-2", "alice-3"] {
+            enqueue(&queue, &served, label, "alice", Lane::Interactive).await;
+        }
+        enqueue(&queue, &served, "bob-1", "bob", Lane::Interactive).await;
+        enqueue(&queue, &served, "bob-2", "bob", Lane::Interactive).await;
+
+        drop(holder);
+        drain(&queue).await;
+        assert_eq!(
+            *served.lock().unwrap(),
+            ["alice-1", "bob-1", "alice-2", "bob-2", "alice-3"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn interactive_requests_overtake_batch_ones() {
+        let queue = queue(1);
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let holder = hold(&queue).await;
+        enqueue(&queue, &served, "batch-1", "alice", Lane::Batch).await;
+        enqueue(&queue, &served, "batch-2", "bob", Lane::Batch).await;
+        enqueue(&queue, &served, "chat", "carol", Lane::Interactive).await;
+
+        drop(holder);
+        drain(&queue).await;
+        assert_eq!(*served.lock().unwrap(), ["chat", "batch-1", "batch-2"]);
+    }
+
+    #[actix_web::test]
+    async fn waiters_see_their_position_and_leave_on_timeout() {
+        let queue = queue(1);
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let holder = hold(&queue).await;
+        enqueue(&queue, &served, "first", "alice", Lane::Interactive).await;
+
+        let (events, mut rx) = mpsc::channel(8);
+        let result = queue
+            .acquire(
+                "m",
+                &Provider::Local,
+                "bob",
+                Lane::Interactive,
+                &events,
+                Duration::from_millis(50),
+            )
+            .await;
+        assert!(matches!(result, Err(QueueTimeout)));
+        match rx.try_recv() {
+            Ok(StreamEvent::Queue(update)) => {
+                assert_eq!(update.position, 2);
+                assert_eq!(update.estimated_wait_seconds, None);
+            }
+            _ => panic!("expected a queue position event"),
+        }
+        assert_eq!(waiting(&queue), 1);
+
+        drop(holder);
+        drain(&queue).await;
+        assert_eq!(*served.lock().unwrap(), ["first"]);
+        assert!(queue.snapshot()[0].avg_generation_ms.is_some());
+    }
+
+    #[actix_web::test]
+    async fn a_dropped_waiter_does_not_keep_a_slot() {
+        let queue = queue(1);
+        let holder = hold(&queue).await;
+
+        let queue_ref = queue.clone();
+        let abandoned = actix_web::rt::spawn(async move {
+            hold(&queue_ref).await;
+        });
+        while waiting(&queue) == 0 {
+            tokio::task::yield_now().await;
+        }
+        abandoned.abort();
+        let _ = abandoned.await;
+        assert_eq!(waiting(&queue), 0);
+
+        drop(holder);
+        assert_eq!(active(&queue), 0);
+        let next = tokio::time::timeout(Duration::from_millis(100), hold(&queue)).await;
+        assert!(next.is_ok(), "the slot was not released");
+    }
+
+    #[actix_web::test]
+    async fn a_slot_granted_to_a_departed_waiter_is_passed_on() {
+        let queue = queue(1);
+        let shared = &queue.shared;
+        let holder = hold(&queue).await;
+
+        // A waiter whose receiver is gone, as when its client disconnects
+        // between the grant and the wake-up.
+        let (grant, granted) = oneshot::channel();
+        drop(granted);
+        shared.models.lock().unwrap().get_mut("m").unwrap().lanes[0]
+            .push("gone", Waiter { id: 999, grant });
+
+        drop(holder);
+        assert_eq!(active(&queue), 0);
+        assert_eq!(waiting(&queue), 0);
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+
+use crate::auth::{Identity, Role};
+use crate::client_ip::client_ip;
+use crate::config::AppConfig;
+use crate::models::ErrorResponse;
+use crate::state::AppState;
+
+/// Probes and metrics scrapes never count against a caller.
+const EXEMPT_PATHS: &[&str] = &["/health", "/ready", "/metrics"];
+
+/// Buckets are pruned once this many callers are tracked, down to half so
+/// the sweep runs at most once per `MAX_TRACKED / 2` new callers.
+const MAX_TRACKED: usize = 10_000;
+
+/// Token bucket parameters: `burst` requests at once, refilled at
+/// `per_minute`.
+#[derive(Clone, Copy, Debug)]
+pub struct BucketSpec {
+    pub burst: f64,
+    pub per_minute: f64,
+}
+
+impl BucketSpec {
+    /// Parses `burst=20,per_minute=60`; an empty string means unlimited.
+    pub fn parse(raw: &str) -> Result<Option<Self>, String> {
+        if raw.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let (mut burst, mut per_minute) = (None, None);
+        for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("rate limit entry '{entry}' must look like name=value"))?;
+            let value: f64 = value
+                .trim()
+                .parse()
+                .ok()
+                .filter(|v: &f64| *v > 0.0)
+                .ok_or_else(|| format!("rate limit entry '{entry}' needs a positive number"))?;
+            match name.trim() {
+                "burst" => burst = Some(value),
+                "per_minute" => per_minute = Some(value),
+                other => {
+                    return Err(format!(
+                        "unknown rate limit '{other}'; expected burst or per_minute"
+                    ))
+                }
+            }
+        }
+
+        let per_minute = per_minute.ok_or_else(|| "rate limit needs per_minute".to_string())?;
+        Ok(Some(Self {
+            burst: burst.unwrap_or(per_minute),
+            per_minute,
+        }))
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    spec: BucketSpec,
+}
+
+impl Bucket {
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.spec.per_minute / 60.0).min(self.spec.burst)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.tokens = self.tokens_at(now);
+        self.updated = now;
+    }
+}
+
+/// Drops full buckets, which carry no state worth keeping, then forgets the
+/// least recently seen callers until half of `MAX_TRACKED` remain.
+fn prune(buckets: &mut HashMap<String, Bucket>, now: Instant) {
+    buckets.retain(|_, b| b.tokens_at(now) < b.spec.burst);
+
+    let keep = MAX_TRACKED / 2;
+    if buckets.len() > keep {
+        let mut seen: Vec<Instant> = buckets.values().map(|b| b.updated).collect();
+        let oldest_kept = seen.len() - keep;
+        let (_, cutoff, _) = seen.select_nth_unstable(oldest_kept);
+        let cutoff = *cutoff;
+        buckets.retain(|_, b| b.updated >= cutoff);
+    }
+}
+
+/// Per-caller token buckets, with limits chosen by role.
+pub struct RateLimiter {
+    specs: HashMap<Option<Role>, BucketSpec>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn from_config(cfg: &AppConfig) -> Result<Self, String> {
+        let mut specs = HashMap::new();
+        for (role, name, raw) in [
+            (None, "RATE_LIMIT_ANONYMOUS", &cfg.rate_limit_anonymous),
+            (
+                Some(Role::Student),
+                "RATE_LIMIT_STUDENT",
+                &cfg.rate_limit_student,
+            ),
+            (Some(Role::Staff), "RATE_LIMIT_STAFF", &cfg.rate_limit_staff),
+            (Some(Role::Admin), "RATE_LIMIT_ADMIN", &cfg.rate_limit_admin),
+        ] {
+            if let Some(spec) = BucketSpec::parse(raw).map_err(|e| format!("{name}: {e}"))? {
+                specs.insert(role, spec);
+            }
+        }
+
+        Ok(Self {
+            specs,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Takes one token for `key`, or returns the seconds until one is free.
+    pub fn check(&self, key: &str, role: Option<Role>) -> Result<(), u64> {
+        let Some(spec) = self.specs.get(&role) else {
+            return Ok(());
+        };
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        if buckets.len() >= MAX_TRACKED && !buckets.contains_key(key) {
+            prune(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: spec.burst,
+            updated: now,
+            spec: *spec,
+        });
+        // A caller whose role changed gets the new limits on their next call.
+        bucket.spec = *spec;
+        bucket.refill(now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let rate = spec.per_minute / 60.0;
+            Err(((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64)
+        }
+    }
+}
+
+/// Rejects bursts with 429 and `Retry-After`. Runs after [`crate::auth`] so
+/// authenticated callers are limited by principal, everyone else by address.
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let data = req.app_data::<web::Data<AppState>>().cloned();
+    let Some(data) = data.filter(|_| !EXEMPT_PATHS.contains(&req.path())) else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+
+    let identity = req.extensions().get::<Identity>().cloned();
+    let key = match &identity {
+        Some(identity) => identity.principal.clone(),
+        None => format!(
+            "ip:{}",
+            client_ip(req.request()).as_deref().unwrap_or("unknown")
+        ),
+    };
+
+    if let Err(retry_after) = data.rate_limiter.check(&key, identity.map(|i| i.role)) {
+        data.metrics.incr_rate_limited();
+        let response = HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+            .json(ErrorResponse {
+                error: format!("rate limit exceeded; retry in {retry_after}s"),
+            });
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_stay_bounded_and_keep_recent_callers() {
+        let limiter = RateLimiter {
+            specs: HashMap::from([(
+                None,
+                BucketSpec {
+                    burst: 1.0,
+                    per_minute: 1.0,
+                },
+            )]),
+            buckets: Mutex::new(HashMap::new()),
+        };
+
+        for n in 0..MAX_TRACKED * 3 {
+            assert!(limiter.check(&format!("ip:{n}"), None).is_ok());
+            assert!(limiter.buckets.lock().unwrap().len() <= MAX_TRACKED);
+        }
+
+        let latest = format!("ip:{}", MAX_TRACKED * 3 - 1);
+        assert!(limiter.check(&latest, None).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use regex::Regex;
+
+use crate::config::AppConfig;
+use crate::models::ChatMessage;
+
+struct Detector {
+    label: String,
+    pattern: Regex,
+    validate: fn(&str) -> bool,
+}
+
+/// Replaces personal data with stable placeholders before a conversation
+/// leaves campus. Only applied to cloud-bound requests.
+pub struct PiiRedactor {
+    enabled: bool,
+    restore: bool,
+    detectors: Vec<Detector>,
+}
+
+/// Placeholder to original value mapping for one request.
+#[derive(Default)]
+pub struct PiiMap {
+    entries: Vec<(String, String)>,
+    pub redactions: usize,
+}
+
+impl PiiMap {
+    fn placeholder_for(&mut self, label: &str, original: &str) -> String {
+        if let Some((placeholder, _)) = self.entries.iter().find(|(_, o)| o == original) {
+            return placeholder.clone();
+        }
+        let n = self
+            .entries
+            .iter()
+            .filter(|(p, _)| p.starts_with(&format!("[{label}_")))
+            .count()
+            + 1;
+        let placeholder = format!("[{label}_{n}]");
+        self.entries
+            .push((placeholder.clone(), original.to_string()));
+        placeholder
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl PiiRedactor {
+    pub fn load(cfg: &AppConfig) -> Result<Self, String> {
+        let mut detectors = vec![
+            detector(
+                "EMAIL",
+                r"(?i)\b[a-z0-9._%+-]+@[a-z0-9.-]+\.[a-z]{2,}\b",
+                any,
+            )?,
+            detector("SSN", r"\b\d{3}-\d{2}-\d{4}\b", any)?,
+            detector("CARD", r"\b(?:\d[ -]?){12,18}\d\b", luhn_valid)?,
+        ];
+
+        for (i, pattern) in cfg.pii_campus_id_patterns.split_whitespace().enumerate() {
+            detectors.push(
+                detector("CAMPUS_ID", pattern, any)
+                    .map_err(|e| format!("PII_CAMPUS_ID_PATTERNS entry {}: {e}", i + 1))?,
+            );
+        }
+
+        // Phone numbers last: the pattern is loose and would otherwise eat
+        // digits that belong to card numbers or campus ids.
+        detectors.push(detector("PHONE", r"\+?\(?\d[\d\s().-]{7,}\d", phone_like)?);
+
+        Ok(Self {
+            enabled: cfg.pii_redaction,
+            restore: cfg.pii_restore,
+            detectors,
+        })
+    }
+
+    /// Redacts every message in place. Only `persona_prompt`, the server-owned
+    /// text the leading system message starts with, is left alone; client instructions,
+    /// history summaries and document excerpts after it are redacted too.
+    pub fn redact(&self, messages: &mut [ChatMessage], persona_prompt: &str) -> PiiMap {
+        let mut map = PiiMap::default();
+        if !self.enabled {
+            return map;
+        }
+
+        for (i, message) in messages.iter_mut().enumerate() {
+            let leads = i == 0 && message.role == "system";
+            let kept = if leads && message.content.starts_with(persona_prompt) {
+                persona_prompt.len()
+            } else {
+                0
+            };
+            let (head, tail) = message.content.split_at(kept);
+            let redacted = self.redact_text(tail, &mut map);
+            message.content = format!("{head}{redacted}");
+        }
+
+        map
+    }
+
+    fn redact_text(&self, text: &str, map: &mut PiiMap) -> String {
+        let mut text = text.to_string();
+        for detector in &self.detectors {
+            let mut out = String::with_capacity(text.len());
+            let mut last = 0;
+            for m in detector.pattern.find_iter(&text) {
+                if !(detector.validate)(m.as_str()) {
+                    continue;
+                }
+                out.push_str(&text[last..m.start()]);
+                out.push_str(&map.placeholder_for(&detector.label, m.as_str()));
+                map.redactions += 1;
+                last = m.end();
+            }
+            if last > 0 {
+                out.push_str(&text[last..]);
+                text = out;
+            }
+        }
+        text
+    }
+
+    pub fn restores(&self) -> bool {
+        self.restore
+    }
+}
+
+fn detector(label: &str, pattern: &str, validate: fn(&str) -> bool) -> Result<Detector, String> {
+    Ok(Detector {
+        label: label.to_string(),
+        pattern: Regex::new(pattern).map_err(|e| e.to_string())?,
+        validate,
+    })
+}
+
+fn any(_: &str) -> bool {
+    true
+}
+
+fn phone_like(candidate: &str) -> bool {
+    let digits = candidate.chars().filter(char::is_ascii_digit).count();
+    (9..=15).contains(&digits)
+}
+
+fn luhn_valid(candidate: &str) -> bool {
+    let digits: Vec<u32> = candidate.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| {
+            if i % 2 == 1 {
+                let doubled = d * 2;
+                if doubled > 9 {
+                    doubled - 9
+                } else {
+                    doubled
+                }
+            } else {
+                d
+            }
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+/// Puts original values back into a streamed answer. Only placeholders that
+/// were issued for this request are touched, and a partial placeholder at the
+/// end of a delta is held back until the next one arrives.
+pub struct PiiRestorer<'a> {
+    map: &'a PiiMap,
+    pending: String,
+    max_placeholder_len: usize,
+}
+
+impl<'a> PiiRestorer<'a> {
+    pub fn new(map: &'a PiiMap) -> Self {
+        let max_placeholder_len = map.entries.iter().map(|(p, _)| p.len()).max().unwrap_or(0);
+        Self {
+            map,
+            pending: String::new(),
+            max_placeholder_len,
+        }
+    }
+
+    pub fn push(&mut self, delta: &str) -> String {
+        if self.map.is_empty() {
+            return delta.to_string();
+        }
+
+        self.pending.push_str(delta);
+        let hold_from = match self.pending.rfind('[') {
+            Some(idx)
+                if !self.pending[idx..].contains(']')
+                    && self.pending.len() - idx < self.max_placeholder_len =>
+            {
+                idx
+            }
+            _ => self.pending.len(),
+        };
+
+        let ready: String = self.pending.drain(..hold_from).collect();
+        self.restore(&ready)
+    }
+
+    pub fn finish(&mut self) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        self.restore(&rest)
+    }
+
+    fn restore(&self, text: &str) -> String {
+        let mut out = text.to_string();
+        for (placeholder, original) in &self.map.entries {
+            if out.contains(placeholder.as_str()) {
+                out = out.replace(placeholder.as_str(), original);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::summary::summary_message;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn redacts_system_context_but_not_the_persona_prompt() {
+        let mut cfg = AppConfig::from_env();
+        cfg.pii_redaction = true;
+        let redactor = PiiRedactor::load(&cfg).unwrap();
+        let persona = "You are the IT helpdesk. Escalate to helpdesk@campus.edu.";
+
+        // As trimmed for a cloud escalation: the system prompt with client
+        // instructions and excerpts, then the summary of dropped turns.
+        let mut messages = vec![
+            message(
+                "system",
+                &format!("{persona}\n\nAdditional instructions from the client:\nCC dana@campus.edu\n\n[1] handbook.md:\nAsk sam@campus.edu."),
+            ),
+            summary_message("Alex (alex.kim@campus.edu, +1 555 010 7788) asked about VPN access.".to_string()),
+            message("user", "Can you email me at alex.kim@campus.edu?"),
+        ];
+        let map = redactor.redact(&mut messages, persona);
+
+        assert!(messages[0].content.starts_with(persona));
+        assert!(!messages[0].content.contains("dana@campus.edu"));
+        assert!(!messages[0].content.contains("sam@campus.edu"));
+        assert!(!messages[1].content.contains("alex.kim@campus.edu"));
+        assert!(!messages[1].content.contains("555 010 7788"));
+        assert!(messages[1].content.contains("[EMAIL_"));
+        assert_eq!(messages[2].content, "Can you email me at [EMAIL_3]?");
+        assert_eq!(map.redactions, 5);
+    }
+}
@@ -0,0 +1,322 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::auth::Identity;
+use crate::citations::Citation;
+use crate::models::{
+    AiReport, ConfidenceMetrics, Provider, Retrieval, RouteChoice, StructuredOutput,
+};
+use crate::moderation::{ModerationAction, ModerationEvent};
+use crate::providers::TokenUsage;
+use crate::residency::Residency;
+use crate::tools::ToolResult;
+
+const GROUP_TERMS: [&str; 24] = [
+    "women",
+    "men",
+    "girls",
+    "boys",
+    "females",
+    "males",
+    "immigrants",
+    "foreigners",
+    "muslims",
+    "christians",
+    "hindus",
+    "jews",
+    "atheists",
+    "black people",
+    "white people",
+    "asians",
+    "africans",
+    "indians",
+    "disabled people",
+    "old people",
+    "young people",
+    "poor people",
+    "rich people",
+    "international students",
+];
+
+const GENERALIZATION_TERMS: [&str; 11] = [
+    "always",
+    "never",
+    "naturally",
+    "inherently",
+    "all of them",
+    "bad at",
+    "better at",
+    "worse at",
+    "can't",
+    "cannot",
+    "shouldn't",
+];
+
+const SENSITIVE_TOPICS: [(&str, &[&str]); 6] = [
+    (
+        "self-harm",
+        &[
+            "suicide",
+            "kill myself",
+            "self harm",
+            "self-harm",
+            "end my life",
+        ],
+    ),
+    (
+        "mental-health",
+        &[
+            "depression",
+            "anxiety",
+            "panic attack",
+            "counselling",
+            "counseling",
+            "therapy",
+        ],
+    ),
+    (
+        "medical",
+        &[
+            "diagnosis",
+            "symptom",
+            "medication",
+            "dosage",
+            "prescription",
+        ],
+    ),
+    (
+        "legal",
+        &[
+            "lawsuit",
+            "lawyer",
+            "illegal",
+            "visa status",
+            "immigration status",
+        ],
+    ),
+    (
+        "academic-integrity",
+        &["cheat", "plagiari", "write my exam", "do my assignment"],
+    ),
+    (
+        "personal-data",
+        &[
+            "student id",
+            "password",
+            "social security",
+            "home address",
+            "phone number",
+        ],
+    ),
+];
+
+const HEDGE_TERMS: [&str; 8] = [
+    "i'm not sure",
+    "i am not sure",
+    "i don't know",
+    "i do not know",
+    "might be",
+    "possibly",
+    "it is unclear",
+    "i cannot verify",
+];
+
+/// Everything observed about a single chat request that feeds its report.
+/// When `confidence` carries no token log-probabilities the report falls back
+/// to a heuristic estimate and says so in `confidenceSource`.
+pub struct ReportInput<'a> {
+    pub request_id: &'a str,
+    pub prompt: &'a str,
+    pub answer: &'a str,
+    pub route: &'a RouteChoice,
+    pub cache_hit: bool,
+    pub fallback: bool,
+    pub route_disclosed: bool,
+    pub cache_size: usize,
+    pub confidence: ConfidenceMetrics,
+    pub moderation: &'a [ModerationEvent],
+    pub pii_redactions: usize,
+    pub residency: Residency,
+    pub residency_reason: &'a str,
+    pub cloud_calls: u32,
+    pub persona: &'a str,
+    pub queue_wait_ms: u64,
+    pub structured_output: Option<StructuredOutput>,
+    pub tool_calls: Vec<ToolResult>,
+    pub retrieval: Option<Retrieval>,
+    pub citations: Vec<Citation>,
+    pub token_usage: Option<TokenUsage>,
+    pub cloud_cost_usd: f64,
+    pub cloud_budget_remaining_usd: Option<f64>,
+    pub identity: Option<&'a Identity>,
+}
+
+pub fn build_report(input: ReportInput<'_>) -> AiReport {
+    let mut bias_warnings = detect_bias("prompt", input.prompt);
+    bias_warnings.extend(detect_bias("answer", input.answer));
+
+    let mut sensitive_topics = detect_sensitive_topics(input.prompt);
+    for topic in detect_sensitive_topics(input.answer) {
+        if !sensitive_topics.contains(&topic) {
+            sensitive_topics.push(topic);
+        }
+    }
+
+    let escalated = input.route.provider == Provider::Cloud;
+    let (confidence, confidence_source) = match input.confidence.mean_token_prob {
+        Some(p) => (p, "logprobs"),
+        None => (
+            estimate_confidence(input.route, input.answer, input.fallback),
+            "heuristic",
+        ),
+    };
+
+    AiReport {
+        request_id: input.request_id.to_string(),
+        created_at: unix_seconds(),
+        principal: input.identity.map(|i| i.principal.clone()),
+        role: input.identity.map(|i| i.role),
+        confidence,
+        confidence_source: confidence_source.to_string(),
+        confidence_metrics: input.confidence.clone(),
+        bias_warnings,
+        sensitive_topics,
+        moderation_warnings: input
+            .moderation
+            .iter()
+            .filter(|e| e.action != ModerationAction::Allow)
+            .map(ModerationEvent::describe)
+            .collect(),
+        moderation_events: input.moderation.to_vec(),
+        pii_redactions: input.pii_redactions,
+        residency: input.residency.label().to_string(),
+        residency_reason: input.residency_reason.to_string(),
+        cloud_calls: input.cloud_calls,
+        persona: input.persona.to_string(),
+        queue_wait_ms: input.queue_wait_ms,
+        structured_output: input.structured_output.clone(),
+        tool_calls: input.tool_calls.clone(),
+        retrieval: input.retrieval.clone(),
+        citations: input.citations.clone(),
+        token_usage: input.token_usage,
+        cloud_cost_usd: input.cloud_cost_usd,
+        cloud_budget_remaining_usd: input.cloud_budget_remaining_usd,
+        transparency_score: transparency_score(&input, escalated),
+        model_info: format!("{}:{}", input.route.provider.label(), input.route.model),
+        last_query: input.prompt.chars().take(120).collect(),
+        route: format!(
+            "{}:{}:{}:{}",
+            input.route.provider.label(),
+            input.route.model,
+            input.route.tier,
+            input.route.reason
+        ),
+        route_disclosed: input.route_disclosed,
+        cache_hit: input.cache_hit,
+        escalated,
+        fallback: input.fallback,
+        cache_size: input.cache_size,
+    }
+}
+
+fn detect_bias(source: &str, text: &str) -> Vec<String> {
+    let lower = text.to_lowercase();
+    let mut warnings = Vec::new();
+
+    for sentence in lower.split(['.', '!', '?', '\n']) {
+        for group in GROUP_TERMS {
+            if !contains_word(sentence, group) {
+                continue;
+            }
+            if let Some(term) = GENERALIZATION_TERMS
+                .iter()
+                .find(|term| contains_word(sentence, term))
+            {
+                warnings.push(format!(
+                    "{source} contains a generalization about {group} (\"{term}\")"
+                ));
+            }
+        }
+    }
+
+    warnings.dedup();
+    warnings
+}
+
+fn detect_sensitive_topics(text: &str) -> Vec<String> {
+    let lower = text.to_lowercase();
+    SENSITIVE_TOPICS
+        .iter()
+        .filter(|(_, terms)| terms.iter().any(|t| lower.contains(t)))
+        .map(|(topic, _)| topic.to_string())
+        .collect()
+}
+
+fn contains_word(haystack: &str, needle: &str) -> bool {
+    haystack.match_indices(needle).any(|(idx, _)| {
+        let before = haystack[..idx].chars().next_back();
+        let after = haystack[idx + needle.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// Starts from full marks and deducts for anything that hides how the answer
+/// was produced or where the data went.
+fn transparency_score(input: &ReportInput<'_>, escalated: bool) -> u32 {
+    let mut score: i32 = 100;
+
+    if !input.route_disclosed {
+        score -= 35;
+    }
+    if input.route.reason.is_empty() {
+        score -= 10;
+    }
+    if input.cache_hit {
+        score -= 10;
+    }
+    if escalated {
+        score -= 15;
+    }
+    if input.fallback {
+        score -= 20;
+    }
+
+    score.clamp(0, 100) as u32
+}
+
+fn estimate_confidence(route: &RouteChoice, answer: &str, fallback: bool) -> f32 {
+    if fallback || answer.trim().is_empty() {
+        return 0.1;
+    }
+
+    let base: f32 = match route.tier.as_str() {
+        "escalated" | "forced-cloud" => 0.85,
+        "quality" => 0.8,
+        "balanced" => 0.75,
+        "fast" => 0.7,
+        _ => 0.72,
+    };
+
+    let lower = answer.to_lowercase();
+    let hedges = HEDGE_TERMS.iter().filter(|t| lower.contains(*t)).count() as f32;
+
+    (base - hedges * 0.08).clamp(0.05, 0.99)
+}
+
+pub fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub fn new_id(prefix: &str) -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let seq = ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{prefix}-{millis:x}-{seq:x}")
+}
@@ -0,0 +1,293 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use reqwest::Client;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::config::AppConfig;
+use crate::documents::Hit;
+use crate::models::{ChatMessage, Provider};
+use crate::providers::complete_ollama;
+use crate::queue::Lane;
+use crate::search::terms;
+use crate::state::AppState;
+
+const RELEVANCE_PROMPT: &str = "You rate search results. Rate how relevant the passage is to the query on a scale from 0 (unrelated) to 10 (answers it directly). Reply with the number only.";
+
+/// Where passages are scored against the query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RerankProvider {
+    /// A local chat model asked for a relevance rating per passage.
+    Ollama,
+    /// A `/rerank` endpoint in the Cohere and Jina shape, as served by vLLM
+    /// or llama.cpp.
+    OpenAi,
+    /// Share of the query terms found in the passage; for tests.
+    Stub,
+}
+
+impl RerankProvider {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ollama" => Some(RerankProvider::Ollama),
+            "openai" => Some(RerankProvider::OpenAi),
+            "stub" => Some(RerankProvider::Stub),
+            _ => None,
+        }
+    }
+
+    /// Provider and model, as reported with the retrieval.
+    pub fn label(self, cfg: &AppConfig) -> String {
+        match self {
+            RerankProvider::Ollama => format!("ollama:{}", cfg.rerank_model),
+            RerankProvider::OpenAi => format!("openai:{}", cfg.rerank_model),
+            RerankProvider::Stub => "stub".to_string(),
+        }
+    }
+}
+
+/// Passages after reranking, each scored between 0 and 1.
+pub struct Reranked {
+    /// At most `k` passages scoring at least `RERANK_MIN_SCORE`, best first.
+    pub kept: Vec<Hit>,
+    pub dropped: Vec<Hit>,
+}
+
+/// Scores `hits` against `query` with `provider` and keeps the best `k`
+/// that reach `RERANK_MIN_SCORE`. Their scores replace the first-stage ones.
+/// Local model calls queue for slots as `user` on `lane`, like generations.
+pub async fn rerank(
+    app_state: &AppState,
+    provider: RerankProvider,
+    query: &str,
+    hits: Vec<Hit>,
+    k: usize,
+    user: &str,
+    lane: Lane,
+) -> Result<Reranked, String> {
+    let (client, cfg) = (&app_state.client, &app_state.cfg);
+    let passages: Vec<String> = hits
+        .iter()
+        .map(|hit| format!("{}\n{}", hit.title, hit.chunk.text))
+        .collect();
+    let scores = match provider {
+        RerankProvider::Ollama => score_ollama(app_state, query, &passages, user, lane).await?,
+        RerankProvider::OpenAi => score_openai(client, cfg, query, &passages).await?,
+        RerankProvider::Stub => passages.iter().map(|p| score_stub(query, p)).collect(),
+    };
+    Ok(keep_best(hits, scores, k, cfg.rerank_min_score))
+}
+
+/// Applies `scores`, then keeps at most `k` hits scoring `min_score` or more.
+fn keep_best(hits: Vec<Hit>, scores: Vec<f32>, k: usize, min_score: f32) -> Reranked {
+    let mut scored: Vec<Hit> = hits
+        .into_iter()
+        .zip(scores)
+        .map(|(mut hit, score)| {
+            hit.score = score;
+            hit
+        })
+        .collect();
+    scored.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    let (mut kept, mut dropped): (Vec<Hit>, Vec<Hit>) =
+        scored.into_iter().partition(|hit| hit.score >= min_score);
+    if kept.len() > k {
+        let mut rest = kept.split_off(k);
+        rest.append(&mut dropped);
+        dropped = rest;
+    }
+    Reranked { kept, dropped }
+}
+
+/// One rating per passage, each waiting its turn in the generation queue so
+/// reranking cannot crowd out other callers' generations.
+async fn score_ollama(
+    app_state: &AppState,
+    query: &str,
+    passages: &[String],
+    user: &str,
+    lane: Lane,
+) -> Result<Vec<f32>, String> {
+    let (client, cfg) = (&app_state.client, &app_state.cfg);
+    // Reranking happens before the answer stream opens, so queue positions
+    // have nowhere to go.
+    let (events, _) = mpsc::channel(1);
+    let max_wait = Duration::from_millis(cfg.rerank_timeout_ms);
+    let events = &events;
+    let ratings = passages.iter().map(|passage| async move {
+        let _slot = app_state
+            .queue
+            .acquire(
+                &cfg.rerank_model,
+                &Provider::Local,
+                user,
+                lane,
+                events,
+                max_wait,
+            )
+            .await
+            .map_err(|_| "rerank queue timed out".to_string())?;
+        let messages = [
+            ChatMessage {
+                role: "system".to_string(),
+                content: RELEVANCE_PROMPT.to_string(),
+            },
+            ChatMessage {
+                role: "user".to_string(),
+                content: format!("Query: {query}\n\nPassage:\n{passage}"),
+            },
+        ];
+        let completion = complete_ollama(client, cfg, &cfg.rerank_model, &messages, 8).await?;
+        rating(&completion.text)
+            .ok_or_else(|| format!("rerank model answered '{}'", completion.text))
+    });
+    join_all(ratings).await.into_iter().collect()
+}
+
+/// The first number in `answer`, scaled from 0–10 to 0–1. A fraction such
+/// as `7/10` or `3/5` is taken as written.
+fn rating(answer: &str) -> Option<f32> {
+    let start = answer.find(|c: char| c.is_ascii_digit())?;
+    let (value, rest) = leading_number(&answer[start..])?;
+    let scale = rest
+        .trim_start()
+        .strip_prefix('/')
+        .and_then(|rest| leading_number(rest.trim_start()))
+        .map(|(denominator, _)| denominator)
+        .filter(|d| *d > 0.0)
+        .unwrap_or(10.0);
+    Some((value / scale).clamp(0.0, 1.0))
+}
+
+/// Digits with at most one decimal point, and the text after them.
+fn leading_number(text: &str) -> Option<(f32, &str)> {
+    let mut end = 0;
+    let mut seen_point = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '0'..='9' => end = i + 1,
+            '.' if !seen_point => seen_point = true,
+            _ => break,
+        }
+    }
+    Some((text[..end].parse().ok()?, &text[end..]))
+}
+
+async fn score_openai(
+    client: &Client,
+    cfg: &AppConfig,
+    query: &str,
+    passages: &[String],
+) -> Result<Vec<f32>, String> {
+    let mut request = client
+        .post(format!(
+            "{}/rerank",
+            cfg.rerank_base_url.trim_end_matches('/')
+        ))
+        .json(&json!({
+            "model": cfg.rerank_model,
+            "query": query,
+            "documents": passages,
+            "top_n": passages.len(),
+        }));
+    if !cfg.rerank_api_key.is_empty() {
+        request = request.bearer_auth(&cfg.rerank_api_key);
+    }
+
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("rerank send error: {e}"))?;
+    if !response.status().is_success() {
+        return Err(format!("rerank status error: {}", response.status()));
+    }
+    let body: Value = response
+        .json()
+        .await
+        .map_err(|e| format!("rerank response error: {e}"))?;
+    let results = body
+        .get("results")
+        .and_then(Value::as_array)
+        .ok_or("rerank response has no results")?;
+
+    // Passages the endpoint leaves out score 0.
+    let mut scores = vec![0.0; passages.len()];
+    for result in results {
+        let index = result
+            .get("index")
+            .and_then(Value::as_u64)
+            .ok_or("rerank result has no index")? as usize;
+        let score = result
+            .get("relevance_score")
+            .and_then(Value::as_f64)
+            .ok_or("rerank result has no relevance_score")?;
+        *scores
+            .get_mut(index)
+            .ok_or_else(|| format!("rerank result index {index} is out of range"))? = score as f32;
+    }
+    Ok(scores)
+}
+
+fn score_stub(query: &str, passage: &str) -> f32 {
+    let wanted: HashSet<String> = terms(query).into_iter().collect();
+    if wanted.is_empty() {
+        return 0.0;
+    }
+    let present: HashSet<String> = terms(passage).into_iter().collect();
+    wanted.intersection(&present).count() as f32 / wanted.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::documents::Chunk;
+
+    fn hit(id: &str) -> Hit {
+        Hit {
+            chunk: Chunk {
+                id: format!("doc:{id}"),
+                document_id: "doc".to_string(),
+                page: None,
+                offset: 0,
+                text: id.to_string(),
+                embedding: Vec::new(),
+            },
+            title: "doc".to_string(),
+            score: 0.0,
+        }
+    }
+
+    fn ids(hits: &[Hit]) -> Vec<&str> {
+        hits.iter().map(|h| h.chunk.text.as_str()).collect()
+    }
+
+    #[test]
+    fn keeps_the_best_k_above_the_threshold() {
+        let hits = ["a", "b", "c", "d", "e"].map(hit).to_vec();
+        let reranked = keep_best(hits, vec![0.2, 0.9, 0.5, 0.3, 0.7], 2, 0.3);
+        assert_eq!(ids(&reranked.kept), ["b", "e"]);
+        assert_eq!(ids(&reranked.dropped), ["c", "d", "a"]);
+        assert_eq!(reranked.kept[0].score, 0.9);
+
+        let hits = ["a", "b", "c"].map(hit).to_vec();
+        let reranked = keep_best(hits, vec![0.1, 0.29, 0.3], 5, 0.3);
+        assert_eq!(ids(&reranked.kept), ["c"]);
+        assert_eq!(ids(&reranked.dropped), ["b", "a"]);
+    }
+
+    #[test]
+    fn parses_ratings() {
+        assert_eq!(rating("7"), Some(0.7));
+        assert_eq!(rating("Relevance: 8."), Some(0.8));
+        assert_eq!(rating("10/10"), Some(1.0));
+        assert_eq!(rating("3 / 5"), Some(0.6));
+        assert_eq!(rating("1.2.3"), Some(1.2 / 10.0));
+        assert_eq!(rating("42"), Some(1.0));
+        assert_eq!(rating("5/0"), Some(0.5));
+        assert_eq!(rating("not relevant"), None);
+        assert_eq!(rating(".5"), Some(0.5));
+    }
+}
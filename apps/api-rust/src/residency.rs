@@ -0,0 +1,122 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppConfig;
+use crate::providers::TokenUsage;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Residency {
+    #[default]
+    Any,
+    LocalOnly,
+}
+
+impl Residency {
+    pub fn label(self) -> &'static str {
+        match self {
+            Residency::Any => "any",
+            Residency::LocalOnly => "local-only",
+        }
+    }
+}
+
+/// Tenants and API paths whose data must never leave campus, regardless of
+/// what an individual request asks for.
+pub struct ResidencyPolicy {
+    local_only_tenants: Vec<String>,
+    local_only_paths: Vec<String>,
+}
+
+impl ResidencyPolicy {
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        Self {
+            local_only_tenants: split_list(&cfg.residency_local_only_tenants),
+            local_only_paths: split_list(&cfg.residency_local_only_paths),
+        }
+    }
+
+    /// Returns the effective residency and why it was chosen. Policy can only
+    /// tighten a request, never loosen it.
+    pub fn resolve(
+        &self,
+        requested: Option<Residency>,
+        tenant: Option<&str>,
+        path: &str,
+    ) -> (Residency, String) {
+        if let Some(tenant) = tenant {
+            if self.local_only_tenants.iter().any(|t| t == tenant) {
+                return (Residency::LocalOnly, format!("tenant-policy:{tenant}"));
+            }
+        }
+        if self.local_only_paths.iter().any(|p| p == path) {
+            return (Residency::LocalOnly, format!("route-policy:{path}"));
+        }
+        match requested {
+            Some(Residency::LocalOnly) => (Residency::LocalOnly, "request".to_string()),
+            _ => (Residency::Any, "default".to_string()),
+        }
+    }
+}
+
+fn split_list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Per-request gate shared by every code path that can reach the cloud.
+/// `stream_cloud` records each outbound call here, so `calls()` is the ground
+/// truth for the residency marker returned to the client, and the usage
+/// they reported is what the cloud budget is charged, failed calls included.
+pub struct CloudGuard {
+    residency: Residency,
+    calls: AtomicU32,
+    usage: Mutex<Option<TokenUsage>>,
+}
+
+impl CloudGuard {
+    pub fn new(residency: Residency) -> Self {
+        Self {
+            residency,
+            calls: AtomicU32::new(0),
+            usage: Mutex::new(None),
+        }
+    }
+
+    pub fn residency(&self) -> Residency {
+        self.residency
+    }
+
+    pub fn allows_cloud(&self) -> bool {
+        self.residency != Residency::LocalOnly
+    }
+
+    /// Must be called immediately before any request to the cloud provider.
+    pub fn record_call(&self) -> Result<(), String> {
+        if !self.allows_cloud() {
+            return Err("cloud call blocked by local-only data residency".to_string());
+        }
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn calls(&self) -> u32 {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    /// Adds usage a cloud call reported, as soon as it is reported.
+    pub fn record_usage(&self, reported: TokenUsage) {
+        if let Ok(mut usage) = self.usage.lock() {
+            *usage = Some(usage.map_or(reported, |u| u.combined(reported)));
+        }
+    }
+
+    /// Usage reported since the last call, summed over every cloud call.
+    pub fn take_usage(&self) -> Option<TokenUsage> {
+        self.usage.lock().ok().and_then(|mut usage| usage.take())
+    }
+}
@@ -1,17 +1,63 @@
-use actix_web::{get, post, web, HttpResponse, Responder};
+use std::sync::Arc;
+
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header;
+use actix_web::{
+    delete, get, post, put, web, HttpRequest, HttpResponse, HttpResponseBuilder, Responder,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use bytes::Bytes;
+use futures_util::stream::BoxStream;
 use futures_util::StreamExt;
-use serde_json::json;
-use tokio::sync::mpsc;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+use tokio::time::error::Elapsed;
 use tokio::time::{timeout, Duration};
 use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, info, warn};
 
+use crate::api_keys::to_hex;
+use crate::auth::{Identity, Role};
+use crate::cache::CachedAnswer;
+use crate::caller::{Caller, SESSION_HEADER};
+use crate::citations::{extract_citations, Citation};
+use crate::config::AppConfig;
+use crate::documents::{
+    chunk_sections, context_block, extract, normalize, valid_collection, Chunk, DocumentFormat,
+    DocumentRecord, Hit,
+};
+use crate::events::{StreamEvent, StreamFormat};
 use crate::models::{
-    AiReport, ChatMessage, ChatRequest, ErrorResponse, HealthResponse, MetricsResponse, Provider,
-    RouteChoice, RoutingHealth, UtilityGenerateRequest, UtilityGenerateResponse, UtilityTemplate,
+    ActivityQuery, AdminUsageResponse, AiReport, ChatMessage, ChatRequest, ConfidenceMetrics,
+    CreateApiKeyRequest, CreateApiKeyResponse, DocumentListQuery, DocumentUploadQuery,
+    EmbeddingData, EmbeddingUsage, EmbeddingVector, EmbeddingsRequest, EmbeddingsResponse,
+    EncodingFormat, ErrorResponse, HealthResponse, MetricsResponse, ModerationBlockedResponse,
+    OidcCallbackQuery, PrincipalUsage, Provider, QuotaExceededResponse, ResponseMetadata,
+    Retrieval, RetrievedChunk, RoleCounts, RouteChoice, RoutingHealth, SearchQuery, SearchResponse,
+    SearchResult, StructuredOutput, StructuredOutputFailedResponse, TokenUsageTotals, UsageQuery,
+    UsageResponse, UtilityGenerateRequest, UtilityGenerateResponse, WhoAmIResponse,
+};
+use crate::moderation::{ModerationAction, ModerationEvent, ModerationStage, OutputModeration};
+use crate::oidc::{OidcError, SESSION_COOKIE};
+use crate::persona::{Persona, Tier};
+use crate::providers::{
+    embed_cloud, embed_ollama, stream_cloud, stream_ollama, CallOptions, Completion, TokenUsage,
 };
-use crate::providers::{stream_cloud, stream_ollama};
+use crate::queue::{Lane, QueueTimeout};
+use crate::redaction::{PiiMap, PiiRestorer};
+use crate::report::{build_report, new_id, unix_seconds, ReportInput};
+use crate::rerank::{rerank, RerankProvider};
+use crate::residency::{CloudGuard, Residency};
+use crate::schema::ResponseSchema;
+use crate::search::{fuse, SearchMode};
 use crate::state::AppState;
+use crate::tokens::{prompt_budget, TokenEstimators};
+use crate::tools::{run_tool_loop, Tool, ToolLimits, ToolRound};
+use crate::usage::{estimate_tokens, utc_date};
+use crate::utility::{TemplateError, UtilityTemplate};
 
 #[get("/health")]
 pub async fn health(data: web::Data<AppState>) -> impl Responder {
@@ -67,363 +113,3107 @@ pub async fn ready(data: web::Data<AppState>) -> impl Responder {
 }
 
 #[get("/metrics")]
-pub async fn metrics(data: web::Data<AppState>) -> impl Responder {
+pub async fn metrics(data: web::Data<AppState>, caller: Caller) -> impl Responder {
     use std::sync::atomic::Ordering;
 
+    if data.cfg.auth_required {
+        if let Err(denied) = require_role(&caller, Role::Staff) {
+            return denied;
+        }
+    }
+
     HttpResponse::Ok().json(MetricsResponse {
         requests_total: data.metrics.requests_total.load(Ordering::Relaxed),
         chat_requests_total: data.metrics.chat_requests_total.load(Ordering::Relaxed),
+        utility_requests_total: data.metrics.utility_requests_total.load(Ordering::Relaxed),
+        structured_retries_total: data
+            .metrics
+            .structured_retries_total
+            .load(Ordering::Relaxed),
+        structured_failures_total: data
+            .metrics
+            .structured_failures_total
+            .load(Ordering::Relaxed),
+        tool_calls_total: data.metrics.tool_calls_total.load(Ordering::Relaxed),
+        tool_failures_total: data.metrics.tool_failures_total.load(Ordering::Relaxed),
+        documents_ingested_total: data
+            .metrics
+            .documents_ingested_total
+            .load(Ordering::Relaxed),
+        retrievals_total: data.metrics.retrievals_total.load(Ordering::Relaxed),
+        retrieval_failures_total: data
+            .metrics
+            .retrieval_failures_total
+            .load(Ordering::Relaxed),
+        searches_total: data.metrics.searches_total.load(Ordering::Relaxed),
+        embedding_requests_total: data
+            .metrics
+            .embedding_requests_total
+            .load(Ordering::Relaxed),
+        embedding_inputs_total: data.metrics.embedding_inputs_total.load(Ordering::Relaxed),
+        reranks_total: data.metrics.reranks_total.load(Ordering::Relaxed),
+        rerank_failures_total: data.metrics.rerank_failures_total.load(Ordering::Relaxed),
+        citations_total: data.metrics.citations_total.load(Ordering::Relaxed),
+        citations_flagged_total: data.metrics.citations_flagged_total.load(Ordering::Relaxed),
         cache_hits_total: data.metrics.cache_hits_total.load(Ordering::Relaxed),
         cache_misses_total: data.metrics.cache_misses_total.load(Ordering::Relaxed),
         local_routes_total: data.metrics.local_routes_total.load(Ordering::Relaxed),
         cloud_routes_total: data.metrics.cloud_routes_total.load(Ordering::Relaxed),
         fallback_responses_total: data.metrics.fallback_responses_total.load(Ordering::Relaxed),
+        moderation_flags_total: data.metrics.moderation_flags_total.load(Ordering::Relaxed),
+        moderation_blocks_total: data.metrics.moderation_blocks_total.load(Ordering::Relaxed),
+        pii_redactions_total: data.metrics.pii_redactions_total.load(Ordering::Relaxed),
+        residency_blocks_total: data.metrics.residency_blocks_total.load(Ordering::Relaxed),
+        auth_failures_total: data.metrics.auth_failures_total.load(Ordering::Relaxed),
+        oidc_logins_total: data.metrics.oidc_logins_total.load(Ordering::Relaxed),
+        quota_rejections_total: data.metrics.quota_rejections_total.load(Ordering::Relaxed),
+        quota_cloud_blocks_total: data
+            .metrics
+            .quota_cloud_blocks_total
+            .load(Ordering::Relaxed),
+        rate_limited_total: data.metrics.rate_limited_total.load(Ordering::Relaxed),
+        streams_rejected_total: data.metrics.streams_rejected_total.load(Ordering::Relaxed),
+        active_streams: data.cfg.max_concurrent_streams - data.stream_slots.available_permits(),
+        max_concurrent_streams: data.cfg.max_concurrent_streams,
+        queue_timeouts_total: data.metrics.queue_timeouts_total.load(Ordering::Relaxed),
+        generation_timeouts_total: data
+            .metrics
+            .generation_timeouts_total
+            .load(Ordering::Relaxed),
+        queues: data.queue.snapshot(),
+        budget_cloud_blocks_total: data
+            .metrics
+            .budget_cloud_blocks_total
+            .load(Ordering::Relaxed),
+        cloud_budget: data.budget.lock().ok().map(|budget| budget.status()),
+        context_trimmed_total: data.metrics.context_trimmed_total.load(Ordering::Relaxed),
+        context_messages_dropped_total: data
+            .metrics
+            .context_messages_dropped_total
+            .load(Ordering::Relaxed),
+        summaries_generated_total: data
+            .metrics
+            .summaries_generated_total
+            .load(Ordering::Relaxed),
+        summary_cache_hits_total: data
+            .metrics
+            .summary_cache_hits_total
+            .load(Ordering::Relaxed),
+        summary_failures_total: data.metrics.summary_failures_total.load(Ordering::Relaxed),
+        token_usage: TokenUsageTotals {
+            local: data.metrics.local_tokens.snapshot(),
+            cloud: data.metrics.cloud_tokens.snapshot(),
+        },
+        requests_by_role: RoleCounts {
+            anonymous: data
+                .metrics
+                .anonymous_requests_total
+                .load(Ordering::Relaxed),
+            student: data.metrics.student_requests_total.load(Ordering::Relaxed),
+            staff: data.metrics.staff_requests_total.load(Ordering::Relaxed),
+            admin: data.metrics.admin_requests_total.load(Ordering::Relaxed),
+        },
     })
 }
 
+#[get("/api/personas")]
+pub async fn personas(data: web::Data<AppState>) -> impl Responder {
+    data.metrics.incr_requests();
+
+    HttpResponse::Ok().json(data.personas.list())
+}
+
+#[get("/api/tools")]
+pub async fn list_tools(data: web::Data<AppState>) -> impl Responder {
+    data.metrics.incr_requests();
+
+    HttpResponse::Ok().json(data.tools.list())
+}
+
 #[get("/api/utility/templates")]
 pub async fn utility_templates(data: web::Data<AppState>) -> impl Responder {
     data.metrics.incr_requests();
 
-    HttpResponse::Ok().json(vec![
-        UtilityTemplate {
-            id: "summary",
-            title: "Executive Summary",
-            description: "Create a concise summary.",
-        },
-        UtilityTemplate {
-            id: "email",
-            title: "Professional Email",
-            description: "Draft a polished email response.",
-        },
-        UtilityTemplate {
-            id: "plan",
-            title: "Action Plan",
-            description: "Generate a tactical execution plan.",
-        },
-    ])
+    HttpResponse::Ok().json(data.templates.list())
 }
 
-#[post("/api/utility/generate")]
-pub async fn utility_generate(
+#[post("/api/utility/templates")]
+pub async fn create_utility_template(
     data: web::Data<AppState>,
-    payload: web::Json<UtilityGenerateRequest>,
+    caller: Caller,
+    payload: web::Json<UtilityTemplate>,
 ) -> impl Responder {
-    data.metrics.incr_requests();
-
-    if payload.prompt.trim().is_empty() {
-        return HttpResponse::BadRequest().json(ErrorResponse {
-            error: "prompt cannot be empty".to_string(),
-        });
+    if let Err(denied) = require_role(&caller, Role::Staff) {
+        return denied;
     }
 
-    let template = payload
-        .template
-        .clone()
-        .unwrap_or_else(|| "generic".to_string());
-
-    let result = format!(
-        "Template: {template}\n\nInput:\n{}\n\nGenerated output (placeholder):\n- Point 1\n- Point 2\n- Point 3",
-        payload.prompt.trim()
-    );
-
-    HttpResponse::Ok().json(UtilityGenerateResponse { result })
+    let template = payload.into_inner();
+    let id = template.id.clone();
+    match data.templates.save(template.clone(), false) {
+        Ok(()) => {
+            info!("{} created utility template {}", caller.owner_key(), id);
+            HttpResponse::Created().json(template)
+        }
+        Err(err) => template_error(err, &id),
+    }
 }
 
-#[get("/api/ai/report")]
-pub async fn ai_report(data: web::Data<AppState>) -> impl Responder {
-    data.metrics.incr_requests();
+#[put("/api/utility/templates/{id}")]
+pub async fn update_utility_template(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+    payload: web::Json<UtilityTemplate>,
+) -> impl Responder {
+    if let Err(denied) = require_role(&caller, Role::Staff) {
+        return denied;
+    }
 
-    let last_query = data
-        .last_query
-        .lock()
-        .map(|v| v.clone())
-        .unwrap_or_default();
-    let last_route = data
-        .last_route
-        .lock()
-        .map(|v| v.clone())
-        .unwrap_or_default();
-    let cache_size = data.cache.lock().map(|v| v.len()).unwrap_or(0);
+    let id = path.into_inner();
+    let template = payload.into_inner();
+    if template.id != id {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!("template id '{}' does not match the path", template.id),
+        });
+    }
+    match data.templates.save(template.clone(), true) {
+        Ok(()) => {
+            info!("{} updated utility template {}", caller.owner_key(), id);
+            HttpResponse::Ok().json(template)
+        }
+        Err(err) => template_error(err, &id),
+    }
+}
 
-    let model_info = if data.cfg.mode == "local" {
-        format!("local:{}", data.cfg.ollama_model)
-    } else {
-        format!("cloud:{}", data.cfg.cloud_model)
-    };
+#[delete("/api/utility/templates/{id}")]
+pub async fn delete_utility_template(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(denied) = require_role(&caller, Role::Staff) {
+        return denied;
+    }
 
-    HttpResponse::Ok().json(AiReport {
-        confidence: 0.92,
-        bias_warnings: vec![],
-        transparency_score: 93,
-        model_info,
-        last_query,
-        route: last_route,
-        cache_size,
-    })
+    let id = path.into_inner();
+    match data.templates.delete(&id) {
+        Ok(()) => {
+            info!("{} deleted utility template {}", caller.owner_key(), id);
+            HttpResponse::NoContent().finish()
+        }
+        Err(err) => template_error(err, &id),
+    }
 }
 
-#[post("/api/chat")]
-pub async fn chat(
+/// Runs a template through the chat pipeline; JSON by default, streamed
+/// like `/api/chat` with `"stream": true`.
+#[post("/api/utility/generate")]
+pub async fn utility_generate(
     data: web::Data<AppState>,
-    payload: web::Json<ChatRequest>,
+    caller: Caller,
+    req: HttpRequest,
+    payload: web::Json<UtilityGenerateRequest>,
 ) -> actix_web::Result<HttpResponse> {
     data.metrics.incr_requests();
-    data.metrics.incr_chat();
+    data.metrics.incr_utility();
 
-    if payload.messages.is_empty() {
+    let Some(template) = payload
+        .template
+        .as_deref()
+        .and_then(|id| data.templates.get(id))
+    else {
         return Ok(HttpResponse::BadRequest().json(ErrorResponse {
-            error: "messages cannot be empty".to_string(),
+            error: match &payload.template {
+                Some(id) => format!("unknown template '{id}'"),
+                None => "template is required".to_string(),
+            },
         }));
-    }
-
-    let messages = trim_messages(
-        payload.messages.clone(),
-        data.cfg.max_input_chars,
-        &data.cfg.quality_system_prompt,
-    );
+    };
 
-    let latest = messages
-        .last()
-        .map(|m| m.content.clone())
-        .unwrap_or_default();
-    if let Ok(mut q) = data.last_query.lock() {
-        *q = latest.chars().take(120).collect();
+    let mut values = payload.variables.clone();
+    if let Some(prompt) = &payload.prompt {
+        values
+            .entry("input".to_string())
+            .or_insert_with(|| Value::String(prompt.clone()));
     }
+    let (persona, message) = match template.render(&values) {
+        Ok(rendered) => rendered,
+        Err(error) => return Ok(HttpResponse::BadRequest().json(ErrorResponse { error })),
+    };
 
-    let route = if data.cfg.mode == "cloud" {
-        RouteChoice {
-            provider: Provider::Cloud,
-            model: data.cfg.cloud_model.clone(),
-            tier: "forced-cloud".to_string(),
-            reason: "mode=cloud".to_string(),
-        }
-    } else {
-        choose_route(data.get_ref(), &messages)
+    let request = GenerationRequest {
+        system_prompt: persona.system_prompt.clone(),
+        persona,
+        messages: normalize_messages(vec![message], data.cfg.max_input_chars),
+        residency: payload.residency,
+        lane: payload.priority,
+        schema: template.response_schema(),
+        tools: Vec::new(),
+        collection: None,
+    };
+    let started = match start_generation(&data, &caller, &req, request).await {
+        Ok(started) => started,
+        Err(response) => return Ok(response),
     };
 
-    match route.provider {
-        Provider::Local => data.metrics.incr_local_route(),
-        Provider::Cloud => data.metrics.incr_cloud_route(),
+    if payload.stream {
+        let format = StreamFormat::from_accept(
+            req.headers()
+                .get(header::ACCEPT)
+                .and_then(|v| v.to_str().ok()),
+        );
+        return Ok(started.stream(data.get_ref(), &caller, format));
     }
 
-    if let Ok(mut r) = data.last_route.lock() {
-        *r = format!(
-            "{}:{}:{}:{}",
-            provider_label(&route.provider),
-            route.model,
-            route.tier,
-            route.reason
-        );
+    let mut response = generation_response(
+        data.get_ref(),
+        &caller,
+        "application/json",
+        &started.request_id,
+        &started.route,
+        started.residency,
+        started.cache_hit,
+    );
+    let (result, metadata) = started.collect().await;
+
+    let structured = metadata.as_ref().and_then(|m| m.structured_output.clone());
+    if let Some(structured) = structured.filter(|s| !s.valid) {
+        return Ok(HttpResponse::UnprocessableEntity()
+            .insert_header((SESSION_HEADER, caller.session_id.clone()))
+            .json(StructuredOutputFailedResponse {
+                error: format!(
+                    "the answer did not match the template schema after {} attempts",
+                    structured.attempts
+                ),
+                request_id: metadata.map(|m| m.request_id).unwrap_or_default(),
+                structured_output: structured,
+            }));
     }
+    let data = template
+        .schema
+        .as_ref()
+        .and_then(|_| serde_json::from_str(&result).ok());
+    Ok(response.json(UtilityGenerateResponse {
+        result,
+        data,
+        template: template.id,
+        metadata,
+    }))
+}
 
-    let cache_key = response_cache_key(&route.model, &messages);
-    if let Ok(mut cache) = data.cache.lock() {
-        if let Some(cached) = cache.get(&cache_key) {
-            data.metrics.incr_cache_hit();
-            let stream = futures_util::stream::once(async move {
-                Ok::<Bytes, actix_web::Error>(Bytes::from(cached))
-            });
-            return Ok(HttpResponse::Ok()
-                .content_type("text/plain; charset=utf-8")
-                .streaming(stream));
-        }
+#[get("/api/ai/report")]
+pub async fn ai_report(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    data.metrics.incr_requests();
+
+    let owner = visible_owner(&caller);
+    let latest = data
+        .activity
+        .lock()
+        .ok()
+        .and_then(|a| a.latest_report(owner.as_deref()));
+    match latest {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => HttpResponse::NotFound().json(ErrorResponse {
+            error: "no chat requests have been reported for this session yet".to_string(),
+        }),
     }
+}
 
-    data.metrics.incr_cache_miss();
+#[get("/api/ai/report/{request_id}")]
+pub async fn ai_report_by_id(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+) -> impl Responder {
+    data.metrics.incr_requests();
 
-    let client = data.client.clone();
-    let cfg = data.cfg.clone();
-    let app_state = data.clone();
-    let model = route.model.clone();
-    let provider = route.provider.clone();
-    let timeout_ms = cfg.upstream_timeout_ms;
+    let request_id = path.into_inner();
+    let owner = visible_owner(&caller);
+    let report = data
+        .activity
+        .lock()
+        .ok()
+        .and_then(|a| a.report(owner.as_deref(), &request_id));
+    match report {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => HttpResponse::NotFound().json(ErrorResponse {
+            error: format!("no report found for request {request_id}"),
+        }),
+    }
+}
 
-    let (tx, rx) = mpsc::channel::<Bytes>(64);
+#[get("/api/ai/activity")]
+pub async fn ai_activity(
+    data: web::Data<AppState>,
+    caller: Caller,
+    query: web::Query<ActivityQuery>,
+) -> impl Responder {
+    data.metrics.incr_requests();
 
-    tokio::spawn(async move {
-        let fut = async {
-            if provider == Provider::Cloud {
-                stream_cloud(client, cfg, model.clone(), messages.clone(), tx.clone()).await
-            } else {
-                stream_ollama(client, cfg, model.clone(), messages.clone(), tx.clone()).await
-            }
-        };
+    let owner = visible_owner(&caller);
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    let records = data
+        .activity
+        .lock()
+        .map(|a| a.records(owner.as_deref(), limit))
+        .unwrap_or_default();
 
-        let result = timeout(Duration::from_millis(timeout_ms), fut).await;
+    HttpResponse::Ok()
+        .insert_header((SESSION_HEADER, caller.session_id.clone()))
+        .json(records)
+}
 
-        match result {
-            Ok(Ok(full_text)) => {
-                if !full_text.is_empty() && full_text.len() < 8000 {
-                    if let Ok(mut cache) = app_state.cache.lock() {
-                        cache.put(cache_key, full_text);
-                    }
-                }
-            }
-            Ok(Err(err)) => {
-                app_state.metrics.incr_fallback();
-                let _ = tx
-                    .send(Bytes::from(format!(
-                        "Runtime fallback response: {}. Infrastructure is up; retry should recover.",
-                        err
-                    )))
-                    .await;
-            }
-            Err(_) => {
-                app_state.metrics.incr_fallback();
-                let _ = tx
-                    .send(Bytes::from(
-                        "Runtime fallback response: upstream timeout. Infrastructure is up; retry should recover."
-                            .to_string(),
-                    ))
-                    .await;
-            }
-        }
-    });
+#[get("/api/auth/whoami")]
+pub async fn whoami(caller: Caller) -> impl Responder {
+    HttpResponse::Ok().json(WhoAmIResponse {
+        authenticated: caller.identity.is_some(),
+        session_id: caller.session_id.clone(),
+        principal: caller.identity.as_ref().map(|i| i.principal.clone()),
+        name: caller.identity.as_ref().map(|i| i.name.clone()),
+        role: caller.role(),
+    })
+}
 
-    let stream = ReceiverStream::new(rx).map(Ok::<Bytes, actix_web::Error>);
+#[get("/api/usage")]
+pub async fn user_usage(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    let principal = caller.quota_key();
+    let (usage, limits) = data
+        .usage
+        .lock()
+        .map(|store| {
+            (
+                store.today(&principal),
+                store.limits_for(&principal, caller.role()),
+            )
+        })
+        .unwrap_or_default();
 
-    Ok(HttpResponse::Ok()
-        .content_type("text/plain; charset=utf-8")
-        .streaming(stream))
+    HttpResponse::Ok().json(UsageResponse {
+        principal,
+        date: utc_date(unix_seconds()),
+        usage,
+        limits,
+    })
 }
 
-fn provider_label(provider: &Provider) -> &'static str {
-    match provider {
-        Provider::Local => "local",
-        Provider::Cloud => "cloud",
+#[get("/api/admin/usage")]
+pub async fn admin_usage(
+    data: web::Data<AppState>,
+    caller: Caller,
+    query: web::Query<UsageQuery>,
+) -> impl Responder {
+    if let Err(denied) = require_role(&caller, Role::Staff) {
+        return denied;
     }
-}
 
-fn trim_messages(messages: Vec<ChatMessage>, max_chars: usize, system_prompt: &str) -> Vec<ChatMessage> {
-    let mut normalized: Vec<ChatMessage> = messages
+    let date = query
+        .date
+        .clone()
+        .unwrap_or_else(|| utc_date(unix_seconds()));
+    let mut principals: Vec<PrincipalUsage> = data
+        .usage
+        .lock()
+        .map(|store| store.day(&date))
+        .unwrap_or_default()
         .into_iter()
-        .filter(|m| !m.content.trim().is_empty())
-        .map(|m| ChatMessage {
-            role: if m.role.is_empty() {
-                "user".to_string()
-            } else {
-                m.role
-            },
-            content: m.content,
-        })
+        .map(|(principal, usage)| PrincipalUsage { principal, usage })
         .collect();
+    principals.sort_by(|a, b| b.usage.requests.cmp(&a.usage.requests));
 
-    let mut total = 0usize;
-    let mut kept: Vec<ChatMessage> = Vec::new();
+    HttpResponse::Ok().json(AdminUsageResponse { date, principals })
+}
 
-    while let Some(item) = normalized.pop() {
-        total += item.content.len();
-        if total > max_chars {
-            break;
+#[get("/api/auth/login")]
+pub async fn oidc_login(data: web::Data<AppState>) -> impl Responder {
+    let Some(oidc) = &data.oidc else {
+        return HttpResponse::NotFound().json(ErrorResponse {
+            error: "OIDC login is not configured".to_string(),
+        });
+    };
+
+    match oidc.begin_login(&data.client).await {
+        Ok(url) => HttpResponse::Found()
+            .insert_header((header::LOCATION, url))
+            .finish(),
+        Err(OidcError::Unavailable(error)) => {
+            warn!("OIDC login could not start: {}", error);
+            HttpResponse::ServiceUnavailable().json(ErrorResponse { error })
+        }
+        Err(OidcError::Rejected(error)) => {
+            warn!("OIDC login could not start: {}", error);
+            HttpResponse::BadGateway().json(ErrorResponse { error })
         }
-        kept.push(item);
     }
-
-    kept.reverse();
-
-    let mut with_system = vec![ChatMessage {
-        role: "system".to_string(),
-        content: system_prompt.to_string(),
-    }];
-
-    with_system.extend(kept);
-    with_system
 }
 
-fn score_query_complexity(messages: &[ChatMessage]) -> i32 {
-    let latest = messages.last().map(|m| m.content.as_str()).unwrap_or_default();
-    let history_count = messages.len();
-
-    let mut score = 0;
+#[get("/api/auth/callback")]
+pub async fn oidc_callback(
+    data: web::Data<AppState>,
+    query: web::Query<OidcCallbackQuery>,
+) -> impl Responder {
+    let Some(oidc) = &data.oidc else {
+        return HttpResponse::NotFound().json(ErrorResponse {
+            error: "OIDC login is not configured".to_string(),
+        });
+    };
 
-    if latest.len() > 400 {
-        score += 2;
-    }
-    if latest.len() > 900 {
-        score += 2;
-    }
-    if history_count > 8 {
-        score += 2;
+    if let Some(error) = &query.error {
+        data.metrics.incr_auth_failure();
+        return HttpResponse::Unauthorized().json(ErrorResponse {
+            error: format!(
+                "login failed at the identity provider: {} {}",
+                error,
+                query.error_description.as_deref().unwrap_or_default()
+            )
+            .trim_end()
+            .to_string(),
+        });
     }
+    let (Some(code), Some(state)) = (&query.code, &query.state) else {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "callback requires code and state".to_string(),
+        });
+    };
 
-    let lower = latest.to_lowercase();
-    for term in [
-        "architecture",
-        "optimize",
-        "benchmark",
-        "latency",
-        "throughput",
-        "algorithm",
-        "debug",
-        "refactor",
-        "rust",
-        "typescript",
-        "docker",
-        "api",
-        "stream",
-    ] {
-        if lower.contains(term) {
-            score += 1;
+    match oidc.complete_login(&data.client, code, state).await {
+        Ok((token, identity)) => {
+            data.metrics.incr_oidc_login();
+            info!(
+                "{} logged in via OIDC as {}",
+                identity.principal,
+                identity.role.label()
+            );
+            let cookie = Cookie::build(SESSION_COOKIE, token)
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .secure(data.cfg.oidc_redirect_uri.starts_with("https://"))
+                .max_age(CookieDuration::seconds(oidc.session_ttl.as_secs() as i64))
+                .finish();
+            HttpResponse::Found()
+                .cookie(cookie)
+                .insert_header((header::LOCATION, oidc.post_login_url.clone()))
+                .finish()
+        }
+        Err(OidcError::Unavailable(error)) => {
+            warn!("OIDC login could not finish: {}", error);
+            HttpResponse::ServiceUnavailable().json(ErrorResponse { error })
+        }
+        Err(OidcError::Rejected(error)) => {
+            data.metrics.incr_auth_failure();
+            warn!("OIDC login failed: {}", error);
+            HttpResponse::Unauthorized().json(ErrorResponse { error })
         }
     }
+}
 
-    if latest.contains("```") {
-        score += 2;
+#[post("/api/auth/logout")]
+pub async fn logout(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let (Some(oidc), Some(cookie)) = (&data.oidc, req.cookie(SESSION_COOKIE)) {
+        oidc.end_session(cookie.value());
     }
 
-    score
+    let mut removal = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    removal.make_removal();
+    HttpResponse::NoContent().cookie(removal).finish()
 }
 
-fn choose_route(data: &AppState, messages: &[ChatMessage]) -> RouteChoice {
-    if !data.cfg.smart_routing {
-        return RouteChoice {
-            provider: Provider::Local,
-            model: data.cfg.ollama_model.clone(),
-            tier: "default".to_string(),
-            reason: "smart-routing-disabled".to_string(),
-        };
+#[get("/api/admin/keys")]
+pub async fn list_api_keys(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    if let Err(denied) = require_role(&caller, Role::Admin) {
+        return denied;
     }
 
-    let score = score_query_complexity(messages);
+    let keys = data
+        .api_keys
+        .lock()
+        .map(|store| store.list())
+        .unwrap_or_default();
+    HttpResponse::Ok().json(keys)
+}
 
-    if score >= 10 && data.cfg.cloud_escalation && !data.cfg.cloud_api_key.is_empty() {
-        return RouteChoice {
-            provider: Provider::Cloud,
-            model: data.cfg.cloud_model.clone(),
-            tier: "escalated".to_string(),
-            reason: format!("complexity={score}"),
-        };
+#[post("/api/admin/keys")]
+pub async fn create_api_key(
+    data: web::Data<AppState>,
+    caller: Caller,
+    payload: web::Json<CreateApiKeyRequest>,
+) -> impl Responder {
+    if let Err(denied) = require_role(&caller, Role::Admin) {
+        return denied;
     }
 
-    if score >= 8 {
-        return RouteChoice {
-            provider: Provider::Local,
-            model: data.cfg.local_model_quality.clone(),
-            tier: "quality".to_string(),
-            reason: format!("complexity={score}"),
-        };
+    let name = payload.name.trim();
+    if name.is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "name cannot be empty".to_string(),
+        });
     }
 
-    if score >= 4 {
-        return RouteChoice {
-            provider: Provider::Local,
-            model: data.cfg.local_model_balanced.clone(),
-            tier: "balanced".to_string(),
-            reason: format!("complexity={score}"),
-        };
+    let created = match data.api_keys.lock() {
+        Ok(mut store) => {
+            let tenant = payload
+                .tenant
+                .as_deref()
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .map(str::to_string);
+            store.create(name, payload.role, tenant)
+        }
+        Err(_) => Err("API key store unavailable".to_string()),
+    };
+    match created {
+        Ok((record, key)) => {
+            info!(
+                "{} created API key {} ({}) for {}",
+                caller.owner_key(),
+                record.id,
+                record.role.label(),
+                record.name
+            );
+            HttpResponse::Created().json(CreateApiKeyResponse { record, key })
+        }
+        Err(error) => HttpResponse::InternalServerError().json(ErrorResponse { error }),
     }
+}
 
-    RouteChoice {
-        provider: Provider::Local,
-        model: data.cfg.local_model_fast.clone(),
-        tier: "fast".to_string(),
-        reason: format!("complexity={score}"),
+#[delete("/api/admin/keys/{id}")]
+pub async fn revoke_api_key(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(denied) = require_role(&caller, Role::Admin) {
+        return denied;
     }
-}
 
-fn response_cache_key(model: &str, messages: &[ChatMessage]) -> String {
-    let latest = messages.last().map(|m| m.content.as_str()).unwrap_or_default();
+    let id = path.into_inner();
+    let revoked = match data.api_keys.lock() {
+        Ok(mut store) => store.revoke(&id),
+        Err(_) => Err("API key store unavailable".to_string()),
+    };
+    match revoked {
+        Ok(true) => {
+            info!("{} revoked API key {}", caller.owner_key(), id);
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().json(ErrorResponse {
+            error: format!("no API key with id '{id}'"),
+        }),
+        Err(error) => HttpResponse::InternalServerError().json(ErrorResponse { error }),
+    }
+}
+
+#[post("/api/documents")]
+pub async fn upload_document(
+    data: web::Data<AppState>,
+    caller: Caller,
+    req: HttpRequest,
+    query: web::Query<DocumentUploadQuery>,
+    body: Bytes,
+) -> impl Responder {
+    if let Err(denied) = require_role(&caller, Role::Staff) {
+        return denied;
+    }
+
+    let collection = query.collection.trim().to_string();
+    if !valid_collection(&collection) {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "collection must be 1-64 lowercase letters, digits, '-' or '_'".to_string(),
+        });
+    }
+    let title = query.title.trim().to_string();
+    if title.is_empty() || title.chars().count() > 200 {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "title must be between 1 and 200 characters".to_string(),
+        });
+    }
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let Some(format) = DocumentFormat::detect(content_type, &title) else {
+        return HttpResponse::UnsupportedMediaType().json(ErrorResponse {
+            error: "upload plain text, Markdown or PDF".to_string(),
+        });
+    };
+    if body.is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "the document is empty".to_string(),
+        });
+    }
+
+    // Extraction and chunking are CPU-bound; keep them off the workers.
+    let bytes = body.len();
+    let (chunk_tokens, overlap_tokens) = (data.cfg.chunk_tokens, data.cfg.chunk_overlap_tokens);
+    let extracted = web::block(move || {
+        extract(format, &body).map(|sections| {
+            let pages = (format == DocumentFormat::Pdf).then_some(sections.len() as u32);
+            (
+                pages,
+                chunk_sections(&sections, chunk_tokens, overlap_tokens),
+            )
+        })
+    })
+    .await;
+    let (pages, passages) = match extracted {
+        Ok(Ok(extracted)) => extracted,
+        Ok(Err(error)) => return HttpResponse::UnprocessableEntity().json(ErrorResponse { error }),
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(ErrorResponse {
+                error: format!("document extraction failed: {err}"),
+            })
+        }
+    };
+
+    let texts: Vec<String> = passages.iter().map(|p| p.text.clone()).collect();
+    let embeddings =
+        match embed_ollama(&data.client, &data.cfg, &data.cfg.embedding_model, &texts).await {
+            Ok((embeddings, _)) => embeddings,
+            Err(err) => {
+                warn!(
+                    "embedding '{}' for collection {} failed: {}",
+                    title, collection, err
+                );
+                return HttpResponse::BadGateway().json(ErrorResponse {
+                    error: format!("embedding with {} failed: {err}", data.cfg.embedding_model),
+                });
+            }
+        };
+
+    let id = new_id("doc");
+    let chunks: Vec<Chunk> = passages
+        .into_iter()
+        .zip(embeddings)
+        .enumerate()
+        .map(|(index, (passage, mut embedding))| {
+            normalize(&mut embedding);
+            Chunk {
+                id: format!("{id}:{index}"),
+                document_id: id.clone(),
+                page: passage.page,
+                offset: passage.offset,
+                text: passage.text,
+                embedding,
+            }
+        })
+        .collect();
+    let record = DocumentRecord {
+        id,
+        collection,
+        title,
+        format,
+        bytes,
+        pages,
+        chunks: chunks.len(),
+        embedding_model: data.cfg.embedding_model.clone(),
+        uploaded_by: caller.owner_key(),
+        uploaded_at: unix_seconds(),
+    };
+    match data.documents.add(record.clone(), chunks).await {
+        Ok(()) => {
+            data.metrics.incr_document_ingested();
+            info!(
+                "{} added document {} '{}' to collection {} ({} chunks)",
+                record.uploaded_by, record.id, record.title, record.collection, record.chunks
+            );
+            HttpResponse::Created().json(record)
+        }
+        Err(error) => HttpResponse::InternalServerError().json(ErrorResponse { error }),
+    }
+}
+
+#[get("/api/documents")]
+pub async fn list_documents(
+    data: web::Data<AppState>,
+    query: web::Query<DocumentListQuery>,
+) -> impl Responder {
+    HttpResponse::Ok().json(data.documents.list(query.collection.as_deref()))
+}
+
+#[get("/api/documents/collections")]
+pub async fn document_collections(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.documents.collections())
+}
+
+#[delete("/api/documents/{id}")]
+pub async fn delete_document(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(denied) = require_role(&caller, Role::Staff) {
+        return denied;
+    }
+
+    let id = path.into_inner();
+    match data.documents.delete(&id).await {
+        Ok(Some(record)) => {
+            info!(
+                "{} deleted document {} '{}' from collection {}",
+                caller.owner_key(),
+                id,
+                record.title,
+                record.collection
+            );
+            HttpResponse::NoContent().finish()
+        }
+        Ok(None) => HttpResponse::NotFound().json(ErrorResponse {
+            error: format!("no document with id '{id}'"),
+        }),
+        Err(error) => HttpResponse::InternalServerError().json(ErrorResponse { error }),
+    }
+}
+
+#[get("/api/search")]
+pub async fn search(data: web::Data<AppState>, query: web::Query<SearchQuery>) -> impl Responder {
+    data.metrics.incr_requests();
+    data.metrics.incr_search();
+
+    let text = query.q.trim();
+    if text.is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "q cannot be empty".to_string(),
+        });
+    }
+    let limit = query.limit.unwrap_or(10);
+    if !(1..=50).contains(&limit) {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "limit must be between 1 and 50".to_string(),
+        });
+    }
+    let collections = match &query.collection {
+        Some(name) if !data.documents.contains(name) => {
+            return HttpResponse::NotFound().json(ErrorResponse {
+                error: format!("unknown document collection '{name}'"),
+            })
+        }
+        Some(name) => vec![name.clone()],
+        None => data
+            .documents
+            .collections()
+            .into_iter()
+            .map(|c| c.name)
+            .collect(),
+    };
+
+    let mut mode = query.mode.unwrap_or(SearchMode::Keyword);
+    let vector = if mode.uses_embeddings() {
+        match embed_query(data.get_ref(), text).await {
+            Ok(vector) => Some(vector),
+            Err(err) if mode == SearchMode::Hybrid => {
+                warn!("search uses keywords only: embedding failed: {}", err);
+                mode = SearchMode::Keyword;
+                None
+            }
+            Err(err) => {
+                return HttpResponse::BadGateway().json(ErrorResponse {
+                    error: format!("embedding with {} failed: {err}", data.cfg.embedding_model),
+                })
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut results: Vec<SearchResult> = collections
+        .iter()
+        .flat_map(|collection| {
+            rank(
+                data.get_ref(),
+                collection,
+                text,
+                vector.as_deref(),
+                mode,
+                limit,
+            )
+            .into_iter()
+            .map(|hit| SearchResult {
+                collection: collection.clone(),
+                chunk_id: hit.chunk.id.clone(),
+                citation_id: hit.chunk.citation_id(),
+                document_id: hit.chunk.document_id.clone(),
+                title: hit.title,
+                page: hit.chunk.page,
+                offset: hit.chunk.offset,
+                score: hit.score,
+                snippet: hit.chunk.snippet(),
+            })
+        })
+        .collect();
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    results.truncate(limit);
+
+    HttpResponse::Ok().json(SearchResponse {
+        query: text.to_string(),
+        mode,
+        results,
+    })
+}
+
+/// OpenAI-compatible embeddings from `EMBEDDING_MODEL` on the local
+/// runtime, or from `EMBEDDINGS_CLOUD_MODEL` when residency and the cloud
+/// budget allow. Vectors are cached per input, and the request only counts
+/// against the caller's quota when it succeeds.
+#[post("/api/embeddings")]
+pub async fn create_embeddings(
+    data: web::Data<AppState>,
+    caller: Caller,
+    req: HttpRequest,
+    payload: web::Json<EmbeddingsRequest>,
+) -> impl Responder {
+    data.metrics.incr_requests();
+    let EmbeddingsRequest {
+        input,
+        model,
+        encoding_format,
+        residency: requested_residency,
+    } = payload.into_inner();
+
+    let inputs = input.into_vec();
+    if inputs.is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "input cannot be empty".to_string(),
+        });
+    }
+    if inputs.len() > data.cfg.embeddings_max_inputs {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!(
+                "at most {} inputs are accepted per request",
+                data.cfg.embeddings_max_inputs
+            ),
+        });
+    }
+    if inputs.iter().any(|text| text.trim().is_empty()) {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "inputs cannot be empty strings".to_string(),
+        });
+    }
+    if inputs
+        .iter()
+        .any(|text| text.chars().count() > data.cfg.max_input_chars)
+    {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!(
+                "inputs are limited to {} characters",
+                data.cfg.max_input_chars
+            ),
+        });
+    }
+    data.metrics.incr_embeddings(inputs.len() as u64);
+
+    let model = model.unwrap_or_else(|| data.cfg.embedding_model.clone());
+    let provider = if model == data.cfg.embedding_model {
+        Provider::Local
+    } else if !data.cfg.embeddings_cloud_model.is_empty()
+        && model == data.cfg.embeddings_cloud_model
+    {
+        Provider::Cloud
+    } else {
+        let mut models = vec![data.cfg.embedding_model.as_str()];
+        if !data.cfg.embeddings_cloud_model.is_empty() {
+            models.push(&data.cfg.embeddings_cloud_model);
+        }
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!(
+                "unknown embedding model '{model}'; available: {}",
+                models.join(", ")
+            ),
+        });
+    };
+
+    let request_id = new_id("req");
+    let (residency, residency_reason) =
+        data.residency
+            .resolve(requested_residency, caller.tenant.as_deref(), req.path());
+    let guard = CloudGuard::new(residency);
+    if provider == Provider::Cloud {
+        // Vectors from another model are not interchangeable, so a blocked
+        // cloud request is refused rather than answered locally.
+        if !guard.allows_cloud() {
+            data.metrics.incr_residency_block();
+            return HttpResponse::Forbidden().json(ErrorResponse {
+                error: format!(
+                    "cloud embeddings blocked by local-only data residency ({residency_reason})"
+                ),
+            });
+        }
+        let exhausted = data.budget.lock().ok().and_then(|b| b.exhausted());
+        if let Some(limit) = exhausted {
+            data.metrics.incr_budget_cloud_block();
+            return HttpResponse::Forbidden().json(ErrorResponse {
+                error: format!("the {limit} cloud budget is used up"),
+            });
+        }
+    }
+
+    let quota_key = caller.quota_key();
+    let started = data
+        .usage
+        .lock()
+        .map(|mut usage| usage.begin_request(&quota_key, caller.role()));
+    if let Ok(Err(exceeded)) = started {
+        data.metrics.incr_quota_rejection();
+        info!(
+            "request {} rejected: {} quota used up for {}",
+            request_id, exceeded.quota, quota_key
+        );
+        return HttpResponse::TooManyRequests()
+            .insert_header((
+                header::RETRY_AFTER,
+                exceeded.retry_after_seconds.to_string(),
+            ))
+            .insert_header((SESSION_HEADER, caller.session_id.clone()))
+            .json(QuotaExceededResponse {
+                error: format!(
+                    "daily {} quota of {} used up; it resets at 00:00 UTC",
+                    exceeded.quota, exceeded.limit
+                ),
+                request_id,
+                quota: exceeded,
+            });
+    }
+
+    let keys: Vec<String> = inputs
+        .iter()
+        .map(|text| embedding_cache_key(&model, text))
+        .collect();
+    let mut vectors: Vec<Option<Vec<f32>>> = match data.embeddings_cache.lock() {
+        Ok(mut cache) => keys
+            .iter()
+            .map(|key| {
+                cache
+                    .get(key)
+                    .and_then(|cached| serde_json::from_str(&cached.text).ok())
+            })
+            .collect(),
+        Err(_) => vec![None; inputs.len()],
+    };
+    let missing: Vec<usize> = (0..inputs.len())
+        .filter(|&i| vectors[i].is_none())
+        .collect();
+    for _ in 0..inputs.len() - missing.len() {
+        data.metrics.incr_cache_hit();
+    }
+
+    let mut prompt_tokens = 0;
+    if !missing.is_empty() {
+        for _ in &missing {
+            data.metrics.incr_cache_miss();
+        }
+        match provider {
+            Provider::Local => data.metrics.incr_local_route(),
+            Provider::Cloud => data.metrics.incr_cloud_route(),
+        }
+
+        let texts: Vec<String> = missing.iter().map(|&i| inputs[i].clone()).collect();
+        let call = async {
+            match provider {
+                Provider::Local => embed_ollama(&data.client, &data.cfg, &model, &texts).await,
+                Provider::Cloud => {
+                    embed_cloud(&data.client, &data.cfg, &model, &texts, &guard).await
+                }
+            }
+        };
+        let embedded = timeout(Duration::from_millis(data.cfg.upstream_timeout_ms), call)
+            .await
+            .unwrap_or_else(|_| Err("timed out".to_string()));
+        let (embedded, reported) = match embedded {
+            Ok(embedded) => embedded,
+            Err(err) => {
+                warn!(
+                    "request {} embedding with {} failed: {}",
+                    request_id, model, err
+                );
+                if let Ok(mut quotas) = data.usage.lock() {
+                    quotas.refund_request(&quota_key);
+                }
+                return HttpResponse::BadGateway().json(ErrorResponse {
+                    error: format!("embedding with {model} failed: {err}"),
+                });
+            }
+        };
+
+        prompt_tokens = if reported > 0 {
+            reported
+        } else {
+            texts.iter().map(|text| estimate_tokens(text)).sum()
+        };
+        let usage = TokenUsage {
+            prompt_tokens,
+            ..TokenUsage::default()
+        };
+        data.metrics.record_token_usage(&provider, &usage);
+        if provider == Provider::Cloud {
+            if let Ok(mut budget) = data.budget.lock() {
+                budget.record(&model, &usage);
+            }
+        }
+        if let Ok(mut quotas) = data.usage.lock() {
+            quotas.record_tokens(&quota_key, prompt_tokens);
+        }
+
+        let mut cache = data.embeddings_cache.lock().ok();
+        for (&i, vector) in missing.iter().zip(embedded) {
+            if let (Some(cache), Ok(text)) = (cache.as_mut(), serde_json::to_string(&vector)) {
+                cache.put(keys[i].clone(), CachedAnswer { text, usage: None });
+            }
+            vectors[i] = Some(vector);
+        }
+    }
+    info!(
+        "request {} embedded {} inputs with {} ({} cached)",
+        request_id,
+        inputs.len(),
+        model,
+        inputs.len() - missing.len()
+    );
+
+    let data_items = vectors
+        .into_iter()
+        .enumerate()
+        .map(|(index, vector)| {
+            let vector = vector.unwrap_or_default();
+            let embedding = match encoding_format {
+                EncodingFormat::Float => EmbeddingVector::Float(vector),
+                EncodingFormat::Base64 => EmbeddingVector::Base64(
+                    BASE64.encode(
+                        vector
+                            .iter()
+                            .flat_map(|v| v.to_le_bytes())
+                            .collect::<Vec<u8>>(),
+                    ),
+                ),
+            };
+            EmbeddingData {
+                object: "embedding",
+                index,
+                embedding,
+            }
+        })
+        .collect();
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(("X-Request-Id", request_id))
+        .insert_header((SESSION_HEADER, caller.session_id.clone()))
+        .insert_header(("X-Data-Residency", residency.label()));
+    if data.cfg.route_disclosure {
+        response
+            .insert_header(("X-Route-Provider", provider.label()))
+            .insert_header(("X-Route-Model", model.clone()))
+            .insert_header(("X-Cache", if missing.is_empty() { "hit" } else { "miss" }));
+    }
+    response.json(EmbeddingsResponse {
+        object: "list",
+        data: data_items,
+        model,
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    })
+}
+
+#[post("/api/chat")]
+pub async fn chat(
+    data: web::Data<AppState>,
+    caller: Caller,
+    req: HttpRequest,
+    payload: web::Json<ChatRequest>,
+) -> actix_web::Result<HttpResponse> {
+    data.metrics.incr_requests();
+    data.metrics.incr_chat();
+
+    if payload.messages.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: "messages cannot be empty".to_string(),
+        }));
+    }
+
+    let Some(persona) = data.personas.get(payload.persona.as_deref()).cloned() else {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!(
+                "unknown persona '{}'",
+                payload.persona.as_deref().unwrap_or_default()
+            ),
+        }));
+    };
+
+    // Client system messages never reach the model on their own; the
+    // persona decides whether they are merged into its prompt.
+    let (client_system, messages): (Vec<ChatMessage>, Vec<ChatMessage>) =
+        normalize_messages(payload.messages.clone(), data.cfg.max_input_chars)
+            .into_iter()
+            .partition(|m| m.role == "system");
+    if messages.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: "messages must include at least one non-system message".to_string(),
+        }));
+    }
+    let system_prompt = persona.system_prompt_with(&client_system);
+
+    if let Some(Err(error)) = payload.response_schema.as_ref().map(ResponseSchema::check) {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!("invalid responseSchema: {error}"),
+        }));
+    }
+
+    let tool_names = match &payload.tools {
+        Some(requested) => {
+            if let Some(name) = requested
+                .iter()
+                .find(|name| !persona.allowed_tools.contains(name))
+            {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: format!("tool '{name}' is not available to persona '{}'", persona.id),
+                }));
+            }
+            requested.clone()
+        }
+        None => persona.allowed_tools.clone(),
+    };
+    let tools = if data.cfg.tools_enabled {
+        data.tools.select(&tool_names)
+    } else {
+        Vec::new()
+    };
+
+    if let Some(collection) = payload
+        .collection
+        .as_deref()
+        .filter(|name| !data.documents.contains(name))
+    {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!("unknown document collection '{collection}'"),
+        }));
+    }
+
+    let format = StreamFormat::from_accept(
+        req.headers()
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok()),
+    );
+    let request = GenerationRequest {
+        persona,
+        system_prompt,
+        messages,
+        residency: payload.residency,
+        lane: payload.priority,
+        schema: payload.response_schema.clone(),
+        tools,
+        collection: payload.collection.clone(),
+    };
+    match start_generation(&data, &caller, &req, request).await {
+        Ok(started) => Ok(started.stream(data.get_ref(), &caller, format)),
+        Err(response) => Ok(response),
+    }
+}
+
+/// A chat-shaped request, ready for moderation, routing and generation.
+struct GenerationRequest {
+    persona: Persona,
+    /// The persona's prompt with any client instructions merged in.
+    system_prompt: String,
+    /// Conversation without system messages.
+    messages: Vec<ChatMessage>,
+    residency: Option<Residency>,
+    lane: Lane,
+    schema: Option<ResponseSchema>,
+    /// Tools the model may call while answering.
+    tools: Vec<Arc<dyn Tool>>,
+    /// Document collection to retrieve context from.
+    collection: Option<String>,
+}
+
+/// An admitted request: answered from the cache or generating in the
+/// background, with its events still to be delivered.
+struct Started {
+    request_id: String,
+    route: RouteChoice,
+    residency: Residency,
+    cache_hit: bool,
+    events: BoxStream<'static, StreamEvent>,
+}
+
+impl Started {
+    fn stream(self, data: &AppState, caller: &Caller, format: StreamFormat) -> HttpResponse {
+        let stream = self.events.filter_map(move |ev| {
+            futures_util::future::ready(ev.encode(format).map(Ok::<Bytes, actix_web::Error>))
+        });
+        generation_response(
+            data,
+            caller,
+            format.content_type(),
+            &self.request_id,
+            &self.route,
+            self.residency,
+            self.cache_hit,
+        )
+        .streaming(stream)
+    }
+
+    /// Waits for the whole answer, for clients that want a single response.
+    async fn collect(self) -> (String, Option<ResponseMetadata>) {
+        let mut events = self.events;
+        let mut text = String::new();
+        let mut metadata = None;
+        while let Some(event) = events.next().await {
+            match event {
+                StreamEvent::Delta(delta) => text.push_str(&delta),
+                StreamEvent::Done(meta) => metadata = Some(*meta),
+                StreamEvent::Moderation(_)
+                | StreamEvent::Queue(_)
+                | StreamEvent::ToolCall(_)
+                | StreamEvent::ToolResult(_)
+                | StreamEvent::Citations(_) => {}
+            }
+        }
+        (text, metadata)
+    }
+}
+
+/// Moderates, admits, routes and fits a request to its model, then answers
+/// it from the cache or starts the generation. Rejections come back as the
+/// finished error response.
+async fn start_generation(
+    data: &web::Data<AppState>,
+    caller: &Caller,
+    req: &HttpRequest,
+    request: GenerationRequest,
+) -> Result<Started, HttpResponse> {
+    let GenerationRequest {
+        persona,
+        mut system_prompt,
+        mut messages,
+        residency: requested_residency,
+        lane,
+        schema,
+        tools,
+        collection,
+    } = request;
+    if let Some(schema) = &schema {
+        system_prompt = format!("{system_prompt}\n\n{}", schema.instruction());
+    }
+    let request_id = new_id("req");
+    let owner = caller.owner_key();
+
+    let mut input_events = Vec::new();
+    for message in messages.iter_mut().filter(|m| m.role == "user") {
+        let outcome = data
+            .moderation
+            .check(ModerationStage::Input, &message.content);
+        message.content = outcome.text;
+        input_events.extend(outcome.events);
+    }
+
+    let latest = messages
+        .last()
+        .map(|m| m.content.clone())
+        .unwrap_or_default();
+
+    if let Some(event) = data
+        .moderation
+        .classify(&data.client, &data.cfg, &latest)
+        .await
+    {
+        input_events.push(event);
+    }
+
+    if !input_events.is_empty() {
+        data.metrics
+            .incr_moderation_flags(input_events.len() as u64);
+    }
+    if input_events
+        .iter()
+        .any(|e| e.action == ModerationAction::Block)
+    {
+        data.metrics.incr_moderation_block();
+        info!(
+            "request {} blocked by input moderation for {}",
+            request_id, owner
+        );
+        return Err(HttpResponse::UnprocessableEntity()
+            .insert_header((SESSION_HEADER, caller.session_id.clone()))
+            .json(ModerationBlockedResponse {
+                error: "request blocked by the campus moderation policy".to_string(),
+                request_id,
+                moderation: input_events,
+            }));
+    }
+
+    // Taken before the quota so a busy server does not charge the caller.
+    // Cache hits drop the permit without using it.
+    let Ok(stream_slot) = data.stream_slots.clone().try_acquire_owned() else {
+        data.metrics.incr_stream_rejected();
+        warn!(
+            "request {} rejected: all {} generation slots busy",
+            request_id, data.cfg.max_concurrent_streams
+        );
+        return Err(HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, "5"))
+            .insert_header((SESSION_HEADER, caller.session_id.clone()))
+            .json(ErrorResponse {
+                error: "the assistant is busy; retry in a few seconds".to_string(),
+            }));
+    };
+
+    let quota_key = caller.quota_key();
+    let started = data
+        .usage
+        .lock()
+        .map(|mut usage| usage.begin_request(&quota_key, caller.role()));
+    if let Ok(Err(exceeded)) = started {
+        data.metrics.incr_quota_rejection();
+        info!(
+            "request {} rejected: {} quota used up for {}",
+            request_id, exceeded.quota, quota_key
+        );
+        return Err(HttpResponse::TooManyRequests()
+            .insert_header((
+                header::RETRY_AFTER,
+                exceeded.retry_after_seconds.to_string(),
+            ))
+            .insert_header((SESSION_HEADER, caller.session_id.clone()))
+            .json(QuotaExceededResponse {
+                error: format!(
+                    "daily {} quota of {} used up; it resets at 00:00 UTC",
+                    exceeded.quota, exceeded.limit
+                ),
+                request_id,
+                quota: exceeded,
+            }));
+    }
+
+    let (residency, residency_reason) =
+        data.residency
+            .resolve(requested_residency, caller.tenant.as_deref(), req.path());
+    let guard = CloudGuard::new(residency);
+
+    let cloud_block = if !guard.allows_cloud() {
+        Some(CloudBlock::Residency)
+    } else if !data
+        .usage
+        .lock()
+        .map(|usage| usage.cloud_escalation_available(&quota_key, caller.role()))
+        .unwrap_or(true)
+    {
+        Some(CloudBlock::Quota)
+    } else if !cloud_budget_allows(data.get_ref()) {
+        Some(CloudBlock::Budget)
+    } else {
+        None
+    };
+
+    let route = if data.cfg.mode == "cloud" && guard.allows_cloud() {
+        RouteChoice {
+            provider: Provider::Cloud,
+            model: data.cfg.cloud_model.clone(),
+            tier: "forced-cloud".to_string(),
+            reason: "mode=cloud".to_string(),
+        }
+    } else if data.cfg.mode == "cloud" {
+        data.metrics.incr_residency_block();
+        RouteChoice {
+            provider: Provider::Local,
+            model: data.cfg.ollama_model.clone(),
+            tier: "residency-local".to_string(),
+            reason: format!("residency=local-only:{residency_reason}"),
+        }
+    } else {
+        choose_route(data.get_ref(), &messages, &persona, cloud_block)
+    };
+
+    let budget = prompt_budget(&data.cfg, &route.provider);
+    let mut retrieval = None;
+    let mut sources = Vec::new();
+    if let Some(collection) = collection {
+        // Context gets at most half of the prompt, leaving the rest for the
+        // conversation.
+        let max_tokens = data.cfg.rag_context_tokens.min(budget / 2);
+        if let Some(retrieved) = retrieve(
+            data.get_ref(),
+            &request_id,
+            &collection,
+            &latest,
+            max_tokens,
+            (&quota_key, lane),
+        )
+        .await
+        {
+            if !retrieved.hits.is_empty() {
+                system_prompt = format!("{system_prompt}\n\n{}", retrieved.context);
+            }
+            retrieval = Some(retrieval_summary(
+                &collection,
+                retrieved.mode,
+                retrieved.reranker,
+                &retrieved.hits,
+            ));
+            sources = retrieved.hits;
+        }
+    }
+    let mut trimmed = trim_messages(messages, &system_prompt, &route.model, budget, &data.tokens);
+    if data.cfg.history_summary && !trimmed.dropped.is_empty() {
+        // Refit with room for the summary that will stand in for the
+        // dropped prefix.
+        let mut all = trimmed.dropped;
+        all.extend(trimmed.messages.into_iter().skip(1));
+        trimmed = trim_messages(
+            all,
+            &system_prompt,
+            &route.model,
+            budget.saturating_sub(data.cfg.summary_max_tokens),
+            &data.tokens,
+        );
+    }
+    if !trimmed.dropped.is_empty() {
+        data.metrics
+            .incr_context_trimmed(trimmed.dropped.len() as u64);
+        info!(
+            "request {} dropped {} older messages to fit {} ({} prompt tokens)",
+            request_id,
+            trimmed.dropped.len(),
+            route.model,
+            trimmed.prompt_tokens
+        );
+    }
+    let messages = trimmed.messages;
+    let dropped = if data.cfg.history_summary {
+        trimmed.dropped
+    } else {
+        Vec::new()
+    };
+
+    let cache_key = response_cache_key(&route.model, &persona.id, &tools, &messages);
+    if let Ok(mut cache) = data.cache.lock() {
+        if let Some(cached) = cache.get(&cache_key) {
+            data.metrics.incr_cache_hit();
+            let cache_size = cache.len();
+            drop(cache);
+            let citations = retrieval
+                .as_ref()
+                .map(|_| cite(data.get_ref(), &request_id, &cached.text, &sources));
+
+            let report = record_report(
+                data.get_ref(),
+                owner,
+                ReportInput {
+                    request_id: &request_id,
+                    prompt: &latest,
+                    answer: &cached.text,
+                    route: &route,
+                    cache_hit: true,
+                    fallback: false,
+                    route_disclosed: data.cfg.route_disclosure,
+                    cache_size,
+                    confidence: ConfidenceMetrics::unavailable(
+                        "answer served from cache; token log-probabilities are not kept with cached entries",
+                    ),
+                    moderation: &input_events,
+                    pii_redactions: 0,
+                    residency,
+                    residency_reason: &residency_reason,
+                    cloud_calls: 0,
+                    persona: &persona.id,
+                    queue_wait_ms: 0,
+                    // Only answers that validated are cached.
+                    structured_output: schema.as_ref().map(|schema| StructuredOutput {
+                        schema: schema.name.clone(),
+                        valid: true,
+                        attempts: 0,
+                        errors: Vec::new(),
+                    }),
+                    // Answers that used tools are not cached.
+                    tool_calls: Vec::new(),
+                    retrieval,
+                    citations: citations.clone().unwrap_or_default(),
+                    token_usage: cached.usage,
+                    cloud_cost_usd: 0.0,
+                    cloud_budget_remaining_usd: cloud_budget_remaining(data.get_ref()),
+                    identity: caller.identity.as_ref(),
+                },
+            );
+
+            let mut events: Vec<StreamEvent> = input_events
+                .into_iter()
+                .map(StreamEvent::Moderation)
+                .collect();
+            events.push(StreamEvent::Delta(cached.text));
+            events.extend(citations.map(StreamEvent::Citations));
+            events.push(StreamEvent::Done(Box::new(response_metadata(
+                &report, &route,
+            ))));
+            return Ok(Started {
+                request_id,
+                route,
+                residency,
+                cache_hit: true,
+                events: futures_util::stream::iter(events).boxed(),
+            });
+        }
+    }
+
+    data.metrics.incr_cache_miss();
+    match route.provider {
+        Provider::Local => data.metrics.incr_local_route(),
+        Provider::Cloud => data.metrics.incr_cloud_route(),
+    }
+
+    let (tx, rx) = mpsc::channel::<StreamEvent>(64);
+
+    tokio::spawn(run_generation(
+        data.clone(),
+        Generation {
+            _stream_slot: stream_slot,
+            request_id: request_id.clone(),
+            owner,
+            route: route.clone(),
+            messages,
+            latest,
+            cache_key,
+            input_events,
+            guard,
+            residency_reason,
+            identity: caller.identity.clone(),
+            quota_key,
+            lane,
+            persona,
+            schema,
+            tools,
+            retrieval,
+            sources,
+            dropped,
+        },
+        tx,
+    ));
+
+    Ok(Started {
+        request_id,
+        route,
+        residency,
+        cache_hit: false,
+        events: ReceiverStream::new(rx).boxed(),
+    })
+}
+
+/// Everything the background generation task needs once the handler has
+/// returned the streaming response.
+struct Generation {
+    request_id: String,
+    owner: String,
+    route: RouteChoice,
+    messages: Vec<ChatMessage>,
+    latest: String,
+    cache_key: String,
+    input_events: Vec<ModerationEvent>,
+    guard: CloudGuard,
+    residency_reason: String,
+    identity: Option<Identity>,
+    quota_key: String,
+    lane: Lane,
+    persona: Persona,
+    schema: Option<ResponseSchema>,
+    tools: Vec<Arc<dyn Tool>>,
+    retrieval: Option<Retrieval>,
+    /// Excerpts in the prompt, in the order the model cites them.
+    sources: Vec<Hit>,
+    /// History trimmed to fit the context, to be summarized when
+    /// `HISTORY_SUMMARY` is on.
+    dropped: Vec<ChatMessage>,
+    /// Released when the generation finishes, freeing a stream slot.
+    _stream_slot: OwnedSemaphorePermit,
+}
+
+/// Streams the provider's answer to `tx`, failing over to the cloud when
+/// allowed, then caches it and records the report.
+/// Counts an escalation or failover against the caller's cloud quota once
+/// the cloud was actually called, so cached answers and attempts refused
+/// before sending cost nothing.
+fn charge_escalation(
+    app_state: &AppState,
+    gen: &Generation,
+    route: &RouteChoice,
+    calls_before: u32,
+) {
+    let escalated = matches!(route.tier.as_str(), "escalated" | "failover");
+    if escalated && gen.guard.calls() > calls_before {
+        if let Ok(mut usage) = app_state.usage.lock() {
+            usage.record_cloud_escalation(&gen.quota_key);
+        }
+    }
+}
+
+async fn run_generation(
+    app_state: web::Data<AppState>,
+    mut gen: Generation,
+    tx: mpsc::Sender<StreamEvent>,
+) {
+    for event in &gen.input_events {
+        let _ = tx.send(StreamEvent::Moderation(event.clone())).await;
+    }
+
+    if !gen.dropped.is_empty() {
+        let summary = app_state
+            .summarizer
+            .summarize(
+                app_state.get_ref(),
+                &gen.dropped,
+                &gen.quota_key,
+                gen.lane,
+                &tx,
+            )
+            .await;
+        if let Some(summary) = summary {
+            gen.messages.insert(1, summary);
+        }
+    }
+
+    let prompt_tokens = app_state
+        .tokens
+        .count_messages(&gen.route.model, &gen.messages);
+
+    let mut route = gen.route.clone();
+    let calls_before = gen.guard.calls();
+    let mut attempt =
+        generate_answer(app_state.get_ref(), &route, gen.messages.clone(), &gen, &tx).await;
+    charge_escalation(app_state.get_ref(), &gen, &route, calls_before);
+    let mut queue_wait = attempt.queue_wait;
+
+    let local_failed = !matches!(attempt.result, Ok(Ok(_))) && attempt.emitted.is_empty();
+    if local_failed
+        && route.provider == Provider::Local
+        && app_state.cfg.cloud_failover
+        && !app_state.cfg.cloud_api_key.is_empty()
+    {
+        let role = gen.identity.as_ref().map(|i| i.role);
+        let quota_allows = app_state
+            .usage
+            .lock()
+            .map(|usage| usage.cloud_escalation_available(&gen.quota_key, role))
+            .unwrap_or(true);
+        let budget_allows = cloud_budget_allows(app_state.get_ref());
+        if gen.guard.allows_cloud() && quota_allows && budget_allows {
+            warn!(
+                "request {} failing over to cloud after local error",
+                gen.request_id
+            );
+            route = RouteChoice {
+                provider: Provider::Cloud,
+                model: app_state.cfg.cloud_model.clone(),
+                tier: "failover".to_string(),
+                reason: format!("local-failed:{}", route.tier),
+            };
+            app_state.metrics.incr_cloud_route();
+            let calls_before = gen.guard.calls();
+            attempt =
+                generate_answer(app_state.get_ref(), &route, gen.messages.clone(), &gen, &tx).await;
+            charge_escalation(app_state.get_ref(), &gen, &route, calls_before);
+            queue_wait += attempt.queue_wait;
+        } else if !gen.guard.allows_cloud() {
+            app_state.metrics.incr_residency_block();
+        } else if !quota_allows {
+            app_state.metrics.incr_quota_cloud_block();
+        } else {
+            app_state.metrics.incr_budget_cloud_block();
+        }
+    }
+
+    let Attempt {
+        result,
+        emitted,
+        moderation_events: output_events,
+        blocked,
+        pii_redactions,
+        queue_wait: _,
+        structured,
+    } = attempt;
+
+    if !output_events.is_empty() {
+        app_state
+            .metrics
+            .incr_moderation_flags(output_events.len() as u64);
+    }
+    if blocked {
+        app_state.metrics.incr_moderation_block();
+    }
+
+    let mut cloud_cost_usd = 0.0;
+    let mut token_usage = None;
+    let mut tool_calls = Vec::new();
+    let timed_out = matches!(result, Err(AttemptTimeout::Generation));
+    let (answer, fallback, confidence) = match result {
+        Ok(Ok(mut completion)) => {
+            token_usage = completion.usage;
+            tool_calls = std::mem::take(&mut completion.tool_results);
+            if let Some(usage) = &completion.usage {
+                app_state.metrics.record_token_usage(&route.provider, usage);
+                // Re-prompts sum several prompts, which would skew calibration.
+                if structured.as_ref().map_or(true, |s| s.attempts <= 1) {
+                    app_state
+                        .tokens
+                        .observe(&route.model, &gen.messages, usage.prompt_tokens);
+                }
+            }
+            // Counted from the provider's usage, estimated when it has none.
+            let usage = completion.usage.unwrap_or(TokenUsage {
+                prompt_tokens,
+                completion_tokens: estimate_tokens(&completion.text),
+                ..TokenUsage::default()
+            });
+            if route.provider == Provider::Cloud {
+                // Includes calls that failed before a structured retry.
+                let spent = gen.guard.take_usage().unwrap_or(usage);
+                if let Ok(mut budget) = app_state.budget.lock() {
+                    cloud_cost_usd = budget.record(&route.model, &spent);
+                }
+            }
+            // Failover answers came from the cloud, so they must not be cached
+            // under the local route's key where local-only requests could hit them.
+            let failed_over = route.provider != gen.route.provider;
+            // Tool results such as the current time go stale.
+            let cacheable = structured.as_ref().map_or(true, |s| s.valid) && tool_calls.is_empty();
+            if !blocked && !failed_over && cacheable && !emitted.is_empty() && emitted.len() < 8000
+            {
+                if let Ok(mut cache) = app_state.cache.lock() {
+                    cache.put(
+                        gen.cache_key,
+                        CachedAnswer {
+                            text: emitted.clone(),
+                            usage: completion.usage,
+                        },
+                    );
+                }
+            }
+            if let Ok(mut quotas) = app_state.usage.lock() {
+                quotas.record_tokens(
+                    &gen.quota_key,
+                    usage.prompt_tokens + usage.completion_tokens,
+                );
+            }
+            let note = if app_state.cfg.request_logprobs {
+                "provider did not return token log-probabilities"
+            } else {
+                "token log-probabilities were not requested (REQUEST_LOGPROBS=false)"
+            };
+            (emitted, false, completion.logprobs.metrics(note))
+        }
+        Ok(Err(err)) => {
+            app_state.metrics.incr_fallback();
+            let _ = tx
+                .send(StreamEvent::Delta(format!(
+                    "Runtime fallback response: {}. Infrastructure is up; retry should recover.",
+                    err
+                )))
+                .await;
+            (
+                emitted,
+                true,
+                ConfidenceMetrics::unavailable("runtime fallback; no model output"),
+            )
+        }
+        Err(AttemptTimeout::Queue) => {
+            app_state.metrics.incr_fallback();
+            let _ = tx
+                .send(StreamEvent::Delta(
+                    "Runtime fallback response: the model is busy and the queue timed out. Retry in a moment."
+                        .to_string(),
+                ))
+                .await;
+            (
+                emitted,
+                true,
+                ConfidenceMetrics::unavailable("runtime fallback; queue timeout before generation"),
+            )
+        }
+        Err(AttemptTimeout::Generation) => {
+            app_state.metrics.incr_fallback();
+            let _ = tx
+                .send(StreamEvent::Delta(
+                    "Runtime fallback response: upstream timeout. Infrastructure is up; retry should recover."
+                        .to_string(),
+                ))
+                .await;
+            (
+                emitted,
+                true,
+                ConfidenceMetrics::unavailable("runtime fallback; no model output"),
+            )
+        }
+    };
+
+    // A failed or timed-out cloud call still costs what it used: the usage it
+    // reported, or else an estimate once the provider started answering.
+    if fallback && route.provider == Provider::Cloud {
+        let started = !answer.is_empty() || timed_out;
+        let spent = gen.guard.take_usage().or_else(|| {
+            (gen.guard.calls() > 0 && started).then(|| TokenUsage {
+                prompt_tokens,
+                completion_tokens: estimate_tokens(&answer),
+                ..TokenUsage::default()
+            })
+        });
+        if let Some(spent) = spent {
+            if let Ok(mut budget) = app_state.budget.lock() {
+                cloud_cost_usd = budget.record(&route.model, &spent);
+            }
+        }
+    }
+
+    let mut moderation = gen.input_events;
+    moderation.extend(output_events);
+
+    let citations = match &gen.retrieval {
+        Some(_) if !fallback => Some(cite(
+            app_state.get_ref(),
+            &gen.request_id,
+            &answer,
+            &gen.sources,
+        )),
+        _ => None,
+    };
+    if let Some(citations) = &citations {
+        let _ = tx.send(StreamEvent::Citations(citations.clone())).await;
+    }
+
+    let cache_size = app_state.cache.lock().map(|c| c.len()).unwrap_or(0);
+    let report = record_report(
+        app_state.get_ref(),
+        gen.owner,
+        ReportInput {
+            request_id: &gen.request_id,
+            prompt: &gen.latest,
+            answer: &answer,
+            route: &route,
+            cache_hit: false,
+            fallback,
+            route_disclosed: app_state.cfg.route_disclosure,
+            cache_size,
+            confidence,
+            moderation: &moderation,
+            pii_redactions,
+            residency: gen.guard.residency(),
+            residency_reason: &gen.residency_reason,
+            cloud_calls: gen.guard.calls(),
+            persona: &gen.persona.id,
+            queue_wait_ms: queue_wait.as_millis() as u64,
+            structured_output: structured,
+            tool_calls,
+            retrieval: gen.retrieval,
+            citations: citations.unwrap_or_default(),
+            token_usage,
+            cloud_cost_usd,
+            cloud_budget_remaining_usd: cloud_budget_remaining(app_state.get_ref()),
+            identity: gen.identity.as_ref(),
+        },
+    );
+    let _ = tx
+        .send(StreamEvent::Done(Box::new(response_metadata(
+            &report, &route,
+        ))))
+        .await;
+}
+
+/// Which wait ran out: for a generation slot, or for the model itself.
+enum AttemptTimeout {
+    Queue,
+    Generation,
+}
+
+/// Outcome of streaming one provider call to the client.
+struct Attempt {
+    result: Result<Result<Completion, String>, AttemptTimeout>,
+    queue_wait: Duration,
+    emitted: String,
+    moderation_events: Vec<ModerationEvent>,
+    blocked: bool,
+    pii_redactions: usize,
+    /// Set for requests with a response schema.
+    structured: Option<StructuredOutput>,
+}
+
+/// One answer for `route`. With a response schema, drafts are held back,
+/// validated and re-prompted with their errors up to
+/// `STRUCTURED_OUTPUT_RETRIES` times; only JSON that validated is sent.
+async fn generate_answer(
+    app_state: &AppState,
+    route: &RouteChoice,
+    messages: Vec<ChatMessage>,
+    gen: &Generation,
+    tx: &mpsc::Sender<StreamEvent>,
+) -> Attempt {
+    let Some(schema) = &gen.schema else {
+        return stream_attempt(app_state, route, messages, gen, tx).await;
+    };
+
+    let (draft_tx, mut draft_rx) = mpsc::channel::<StreamEvent>(64);
+    let relay = async {
+        while let Some(event) = draft_rx.recv().await {
+            if !matches!(event, StreamEvent::Delta(_)) {
+                let _ = tx.send(event).await;
+            }
+        }
+    };
+    let attempts = async move {
+        let draft_tx = draft_tx;
+        let mut messages = messages;
+        let mut outcome = StructuredOutput {
+            schema: schema.name.clone(),
+            valid: false,
+            attempts: 0,
+            errors: Vec::new(),
+        };
+        let mut queue_wait = Duration::ZERO;
+        let mut pii_redactions = 0;
+        let mut earlier_events = Vec::new();
+        let mut usage: Option<TokenUsage> = None;
+
+        loop {
+            let mut attempt =
+                stream_attempt(app_state, route, messages.clone(), gen, &draft_tx).await;
+            outcome.attempts += 1;
+            queue_wait += attempt.queue_wait;
+            pii_redactions += attempt.pii_redactions;
+            earlier_events.append(&mut attempt.moderation_events);
+            attempt.moderation_events.clone_from(&earlier_events);
+            attempt.queue_wait = queue_wait;
+            attempt.pii_redactions = pii_redactions;
+
+            let Ok(Ok(completion)) = &mut attempt.result else {
+                attempt.structured = Some(outcome);
+                return attempt;
+            };
+            if let Some(reported) = completion.usage {
+                usage = Some(usage.map_or(reported, |u| u.combined(reported)));
+                completion.usage = usage;
+            }
+            if attempt.blocked {
+                attempt.structured = Some(outcome);
+                return attempt;
+            }
+
+            match schema.validate(&attempt.emitted) {
+                Ok(value) => {
+                    outcome.valid = true;
+                    outcome.errors.clear();
+                    attempt.emitted = value.to_string();
+                    attempt.structured = Some(outcome);
+                    return attempt;
+                }
+                Err(errors) => {
+                    warn!(
+                        "request {} answer failed schema '{}' on attempt {}: {}",
+                        gen.request_id,
+                        schema.name,
+                        outcome.attempts,
+                        errors.join("; ")
+                    );
+                    outcome.errors = errors;
+                    if outcome.attempts > app_state.cfg.structured_output_retries {
+                        app_state.metrics.incr_structured_failure();
+                        attempt.structured = Some(outcome);
+                        return attempt;
+                    }
+                    app_state.metrics.incr_structured_retry();
+                    messages.push(ChatMessage {
+                        role: "assistant".to_string(),
+                        content: attempt.emitted,
+                    });
+                    messages.push(ChatMessage {
+                        role: "user".to_string(),
+                        content: format!(
+                            "That answer does not match the JSON Schema:\n- {}\nReply again with only the corrected JSON.",
+                            outcome.errors.join("\n- ")
+                        ),
+                    });
+                }
+            }
+        }
+    };
+
+    let (attempt, ()) = tokio::join!(attempts, relay);
+    if attempt.structured.as_ref().is_some_and(|s| s.valid) {
+        let _ = tx.send(StreamEvent::Delta(attempt.emitted.clone())).await;
+    }
+    attempt
+}
+
+async fn stream_attempt(
+    app_state: &AppState,
+    route: &RouteChoice,
+    mut messages: Vec<ChatMessage>,
+    gen: &Generation,
+    tx: &mpsc::Sender<StreamEvent>,
+) -> Attempt {
+    // Generations for the same model wait here rather than piling up on
+    // the provider; the upstream timeout only starts once a slot is free.
+    let slot = app_state
+        .queue
+        .acquire(
+            &route.model,
+            &route.provider,
+            &gen.quota_key,
+            gen.lane,
+            tx,
+            Duration::from_millis(app_state.cfg.queue_timeout_ms),
+        )
+        .await;
+    let slot = match slot {
+        Ok(slot) => slot,
+        Err(QueueTimeout) => {
+            app_state.metrics.incr_queue_timeout();
+            warn!(
+                "request {} timed out waiting for {}",
+                gen.request_id, route.model
+            );
+            return Attempt {
+                result: Err(AttemptTimeout::Queue),
+                queue_wait: Duration::from_millis(app_state.cfg.queue_timeout_ms),
+                emitted: String::new(),
+                moderation_events: Vec::new(),
+                blocked: false,
+                pii_redactions: 0,
+                structured: None,
+            };
+        }
+    };
+    let guard = &gen.guard;
+
+    let pii = if route.provider == Provider::Cloud {
+        app_state
+            .pii
+            .redact(&mut messages, &gen.persona.system_prompt)
+    } else {
+        PiiMap::default()
+    };
+    if pii.redactions > 0 {
+        app_state.metrics.incr_pii_redactions(pii.redactions as u64);
+    }
+
+    let client = app_state.client.clone();
+    let mut cfg = app_state.cfg.clone();
+    gen.persona.apply(&mut cfg);
+    let timeout_ms = cfg.upstream_timeout_ms;
+    let model = route.model.clone();
+    let provider = route.provider.clone();
+    let schema = gen.schema.as_ref();
+    let tools = &gen.tools;
+    let limits = ToolLimits {
+        max_iterations: cfg.tool_max_iterations,
+        call_timeout: Duration::from_millis(cfg.tool_timeout_ms),
+    };
+
+    let (provider_tx, mut provider_rx) = mpsc::channel::<StreamEvent>(64);
+
+    // The timeout covers every turn of the tool loop, not just the first.
+    let generate = timeout(Duration::from_millis(timeout_ms), async move {
+        let call = |rounds: Vec<ToolRound>, offer_tools: bool| {
+            let (client, cfg, model, messages) =
+                (client.clone(), cfg.clone(), model.clone(), messages.clone());
+            let provider_tx = provider_tx.clone();
+            let provider = provider.clone();
+            async move {
+                let options = CallOptions {
+                    schema,
+                    tools: if offer_tools { tools } else { &[] },
+                    rounds: &rounds,
+                };
+                if provider == Provider::Cloud {
+                    stream_cloud(client, cfg, model, messages, options, provider_tx, guard).await
+                } else {
+                    stream_ollama(client, cfg, model, messages, options, provider_tx).await
+                }
+            }
+        };
+        run_tool_loop(tools, limits, &app_state.metrics, &provider_tx, call).await
+    });
+
+    let forward = async {
+        let mut restorer = app_state.pii.restores().then(|| PiiRestorer::new(&pii));
+        let mut output = OutputModeration::new(&app_state.moderation);
+        let mut emitted = String::new();
+        let mut sent_events = 0;
+
+        loop {
+            let (released, done) = match provider_rx.recv().await {
+                Some(StreamEvent::Delta(text)) => {
+                    let text = match restorer.as_mut() {
+                        Some(r) => r.push(&text),
+                        None => text,
+                    };
+                    (output.push(&text), false)
+                }
+                Some(other) => {
+                    let _ = tx.send(other).await;
+                    (None, false)
+                }
+                None => {
+                    let tail = restorer
+                        .as_mut()
+                        .map(PiiRestorer::finish)
+                        .unwrap_or_default();
+                    let mut released = output.push(&tail).unwrap_or_default();
+                    released.push_str(&output.finish().unwrap_or_default());
+                    (Some(released).filter(|r| !r.is_empty()), true)
+                }
+            };
+
+            for event in &output.events[sent_events..] {
+                let _ = tx.send(StreamEvent::Moderation(event.clone())).await;
+            }
+            sent_events = output.events.len();
+
+            if let Some(text) = released {
+                emitted.push_str(&text);
+                let _ = tx.send(StreamEvent::Delta(text)).await;
+            }
+
+            if done {
+                break;
+            }
+        }
+
+        (emitted, output.events, output.blocked)
+    };
+
+    let (result, (emitted, moderation_events, blocked)) = tokio::join!(generate, forward);
+    let queue_wait = slot.waited;
+    drop(slot);
+
+    let result = result.map_err(|_: Elapsed| {
+        app_state.metrics.incr_generation_timeout();
+        AttemptTimeout::Generation
+    });
+
+    Attempt {
+        result,
+        queue_wait,
+        emitted,
+        moderation_events,
+        blocked,
+        pii_redactions: pii.redactions,
+        structured: None,
+    }
+}
+
+fn template_error(err: TemplateError, id: &str) -> HttpResponse {
+    match err {
+        TemplateError::Invalid(error) => HttpResponse::BadRequest().json(ErrorResponse { error }),
+        TemplateError::NotFound => HttpResponse::NotFound().json(ErrorResponse {
+            error: format!("no utility template with id '{id}'"),
+        }),
+        TemplateError::Exists => HttpResponse::Conflict().json(ErrorResponse {
+            error: format!("utility template '{id}' already exists"),
+        }),
+        TemplateError::Storage(error) => {
+            HttpResponse::InternalServerError().json(ErrorResponse { error })
+        }
+    }
+}
+
+fn generation_response(
+    data: &AppState,
+    caller: &Caller,
+    content_type: &str,
+    request_id: &str,
+    route: &RouteChoice,
+    residency: Residency,
+    cache_hit: bool,
+) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::Ok();
+    builder
+        .content_type(content_type)
+        .insert_header(("X-Request-Id", request_id.to_string()))
+        .insert_header((SESSION_HEADER, caller.session_id.clone()))
+        .insert_header(("X-Data-Residency", residency.label()));
+
+    if data.cfg.route_disclosure {
+        builder
+            .insert_header(("X-Route-Provider", route.provider.label()))
+            .insert_header(("X-Route-Model", route.model.clone()))
+            .insert_header(("X-Route-Tier", route.tier.clone()))
+            .insert_header(("X-Cache", if cache_hit { "hit" } else { "miss" }));
+    }
+
+    builder
+}
+
+fn record_report(data: &AppState, owner: String, input: ReportInput<'_>) -> AiReport {
+    let report = build_report(input);
+    if let Ok(mut activity) = data.activity.lock() {
+        activity.push(owner, report.clone());
+    }
+    report
+}
+
+/// Hybrid ranking draws this many times `k` candidates from each ranking
+/// before fusing them.
+const HYBRID_CANDIDATES: usize = 3;
+
+/// The normalized embedding of a search query.
+async fn embed_query(data: &AppState, query: &str) -> Result<Vec<f32>, String> {
+    let embedded = timeout(
+        Duration::from_millis(data.cfg.upstream_timeout_ms),
+        embed_ollama(
+            &data.client,
+            &data.cfg,
+            &data.cfg.embedding_model,
+            &[query.to_string()],
+        ),
+    )
+    .await
+    .unwrap_or_else(|_| Err("timed out".to_string()))
+    .and_then(|(mut vectors, _)| vectors.pop().ok_or_else(|| "no embedding".to_string()));
+    let mut vector = embedded.inspect_err(|_| data.metrics.incr_retrieval_failure())?;
+    normalize(&mut vector);
+    Ok(vector)
+}
+
+/// The `k` best chunks of `collection` for `query`. Without a query
+/// embedding, vector ranking finds nothing and hybrid ranking uses keywords
+/// alone.
+fn rank(
+    data: &AppState,
+    collection: &str,
+    query: &str,
+    vector: Option<&[f32]>,
+    mode: SearchMode,
+    k: usize,
+) -> Vec<Hit> {
+    let min_score = data.cfg.rag_min_score;
+    match (mode, vector) {
+        (SearchMode::Vector, Some(vector)) => {
+            data.documents.search(collection, vector, k, min_score)
+        }
+        (SearchMode::Vector, None) => Vec::new(),
+        (SearchMode::Keyword, _) | (SearchMode::Hybrid, None) => {
+            data.documents.keyword_search(collection, query, k)
+        }
+        (SearchMode::Hybrid, Some(vector)) => {
+            let candidates = k * HYBRID_CANDIDATES;
+            fuse(
+                vec![
+                    data.documents
+                        .search(collection, vector, candidates, min_score),
+                    data.documents.keyword_search(collection, query, candidates),
+                ],
+                k,
+            )
+        }
+    }
+}
+
+/// Passages retrieved for a chat request and the prompt section holding them.
+struct Retrieved {
+    context: String,
+    hits: Vec<Hit>,
+    /// The ranking actually used; hybrid falls back to keywords when
+    /// embedding fails.
+    mode: SearchMode,
+    reranker: Option<String>,
+}
+
+/// Ranks `collection` for `query` with `RETRIEVAL_MODE`, reranks the
+/// candidates when `RERANK_PROVIDER` is set, and formats the best chunks as
+/// prompt context of at most `max_tokens`. `None` when vector ranking cannot
+/// embed the query; the answer then goes ahead without context. A local
+/// reranker queues for the model as the given user and lane.
+async fn retrieve(
+    data: &AppState,
+    request_id: &str,
+    collection: &str,
+    query: &str,
+    max_tokens: u64,
+    queue_as: (&str, Lane),
+) -> Option<Retrieved> {
+    let mut mode = retrieval_mode(&data.cfg);
+    let vector = if mode.uses_embeddings() {
+        match embed_query(data, query).await {
+            Ok(vector) => Some(vector),
+            Err(err) if mode == SearchMode::Hybrid => {
+                warn!(
+                    "retrieval from collection {} uses keywords only: embedding failed: {}",
+                    collection, err
+                );
+                mode = SearchMode::Keyword;
+                None
+            }
+            Err(err) => {
+                warn!(
+                    "retrieval from collection {} skipped: embedding failed: {}",
+                    collection, err
+                );
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+
+    data.metrics.incr_retrieval();
+    let k = data.cfg.rag_top_k;
+    let provider = RerankProvider::parse(&data.cfg.rerank_provider);
+    let candidates = if provider.is_some() {
+        data.cfg.rerank_candidates
+    } else {
+        k
+    };
+    let mut hits = rank(data, collection, query, vector.as_deref(), mode, candidates);
+    let mut reranker = None;
+    if let Some(provider) = provider.filter(|_| !hits.is_empty()) {
+        let reranked = rerank_hits(data, request_id, provider, query, hits.clone(), k, queue_as);
+        match reranked.await {
+            Some(kept) => {
+                hits = kept;
+                reranker = Some(provider.label(&data.cfg));
+            }
+            None => hits.truncate(k),
+        }
+    }
+    let (context, hits) = context_block(collection, hits, max_tokens);
+    Some(Retrieved {
+        context,
+        hits,
+        mode,
+        reranker,
+    })
+}
+
+/// Reranks retrieved passages, logging every score at debug level. `None`
+/// when the reranker fails, leaving the first-stage order in place.
+async fn rerank_hits(
+    data: &AppState,
+    request_id: &str,
+    provider: RerankProvider,
+    query: &str,
+    hits: Vec<Hit>,
+    k: usize,
+    (user, lane): (&str, Lane),
+) -> Option<Vec<Hit>> {
+    let limit = Duration::from_millis(data.cfg.rerank_timeout_ms);
+    let result = timeout(limit, rerank(data, provider, query, hits, k, user, lane))
+        .await
+        .unwrap_or_else(|_| Err("timed out".to_string()));
+    match result {
+        Ok(reranked) => {
+            data.metrics.incr_rerank();
+            let scores = |hits: &[Hit]| {
+                hits.iter()
+                    .map(|hit| format!("{}={:.3}", hit.chunk.citation_id(), hit.score))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+            debug!(
+                "request {} reranked with {}: kept [{}] dropped [{}]",
+                request_id,
+                provider.label(&data.cfg),
+                scores(&reranked.kept),
+                scores(&reranked.dropped)
+            );
+            Some(reranked.kept)
+        }
+        Err(err) => {
+            data.metrics.incr_rerank_failure();
+            warn!(
+                "request {} keeps first-stage ranking: rerank failed: {}",
+                request_id, err
+            );
+            None
+        }
+    }
+}
+
+fn retrieval_mode(cfg: &AppConfig) -> SearchMode {
+    SearchMode::parse(&cfg.retrieval_mode).unwrap_or(SearchMode::Hybrid)
+}
+
+fn retrieval_summary(
+    collection: &str,
+    mode: SearchMode,
+    reranker: Option<String>,
+    hits: &[Hit],
+) -> Retrieval {
+    Retrieval {
+        collection: collection.to_string(),
+        mode,
+        reranker,
+        chunks: hits
+            .iter()
+            .map(|hit| RetrievedChunk {
+                chunk_id: hit.chunk.id.clone(),
+                citation_id: hit.chunk.citation_id(),
+                document_id: hit.chunk.document_id.clone(),
+                title: hit.title.clone(),
+                page: hit.chunk.page,
+                score: hit.score,
+            })
+            .collect(),
+    }
+}
+
+/// Citations in an answer grounded on `sources`, counting the ones that
+/// point outside them.
+fn cite(data: &AppState, request_id: &str, answer: &str, sources: &[Hit]) -> Vec<Citation> {
+    let citations = extract_citations(answer, sources, &data.documents);
+    let flagged: Vec<&str> = citations
+        .iter()
+        .filter(|c| !c.retrieved)
+        .map(|c| c.marker.as_str())
+        .collect();
+    data.metrics
+        .incr_citations(citations.len() as u64, flagged.len() as u64);
+    if !flagged.is_empty() {
+        warn!(
+            "request {} cites sources that were not retrieved: {}",
+            request_id,
+            flagged.join(", ")
+        );
+    }
+    citations
+}
+
+fn response_metadata(report: &AiReport, route: &RouteChoice) -> ResponseMetadata {
+    ResponseMetadata {
+        request_id: report.request_id.clone(),
+        provider: route.provider.label().to_string(),
+        model: route.model.clone(),
+        tier: route.tier.clone(),
+        cache_hit: report.cache_hit,
+        fallback: report.fallback,
+        confidence: report.confidence_metrics.clone(),
+        pii_redactions: report.pii_redactions,
+        residency: report.residency.clone(),
+        cloud_calls: report.cloud_calls,
+        persona: report.persona.clone(),
+        queue_wait_ms: report.queue_wait_ms,
+        token_usage: report.token_usage,
+        structured_output: report.structured_output.clone(),
+        tool_calls: report.tool_calls.clone(),
+        retrieval: report.retrieval.clone(),
+        citations: report.citations.clone(),
+    }
+}
+
+/// 401 for anonymous callers, 403 when the role is too low.
+fn require_role(caller: &Caller, min: Role) -> Result<(), HttpResponse> {
+    match caller.role() {
+        Some(role) if role >= min => Ok(()),
+        Some(_) => Err(HttpResponse::Forbidden().json(ErrorResponse {
+            error: format!("requires the {} role", min.label()),
+        })),
+        None => Err(HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .json(ErrorResponse {
+                error: "authentication required".to_string(),
+            })),
+    }
+}
+
+/// Admins read the whole activity feed; everyone else only their own entries.
+fn visible_owner(caller: &Caller) -> Option<String> {
+    if caller.is_admin() {
+        None
+    } else {
+        Some(caller.owner_key())
+    }
+}
+
+/// Drops empty messages, fills in missing roles and caps the request at
+/// `MAX_INPUT_CHARS`, oldest first. The newest message is always kept.
+fn normalize_messages(messages: Vec<ChatMessage>, max_chars: usize) -> Vec<ChatMessage> {
+    let mut normalized: Vec<ChatMessage> = messages
+        .into_iter()
+        .filter(|m| !m.content.trim().is_empty())
+        .map(|m| ChatMessage {
+            role: if m.role.is_empty() {
+                "user".to_string()
+            } else {
+                m.role
+            },
+            content: m.content,
+        })
+        .collect();
+
+    let mut total = 0usize;
+    let mut kept: Vec<ChatMessage> = Vec::new();
+
+    while let Some(item) = normalized.pop() {
+        total += item.content.len();
+        if total > max_chars && !kept.is_empty() {
+            break;
+        }
+        kept.push(item);
+    }
+
+    kept.reverse();
+    kept
+}
+
+/// A conversation fitted to a model's context window.
+struct Trimmed {
+    /// System prompt followed by the history that fits.
+    messages: Vec<ChatMessage>,
+    /// Oldest messages that did not fit, in conversation order.
+    dropped: Vec<ChatMessage>,
+    prompt_tokens: u64,
+}
+
+/// Keeps the system prompt and as much recent history as fits in `budget`
+/// tokens. The latest user message and anything after it are never
+/// dropped, even when they alone exceed the budget.
+fn trim_messages(
+    mut messages: Vec<ChatMessage>,
+    system_prompt: &str,
+    model: &str,
+    budget: u64,
+    tokens: &TokenEstimators,
+) -> Trimmed {
+    let system = ChatMessage {
+        role: "system".to_string(),
+        content: system_prompt.to_string(),
+    };
+
+    let pinned_from = messages
+        .iter()
+        .rposition(|m| m.role == "user")
+        .unwrap_or(messages.len().saturating_sub(1));
+    let mut used = tokens.count_message(model, &system)
+        + tokens.count_messages(model, &messages[pinned_from..]);
+
+    let mut keep_from = pinned_from;
+    while keep_from > 0 {
+        let cost = tokens.count_message(model, &messages[keep_from - 1]);
+        if used + cost > budget {
+            break;
+        }
+        used += cost;
+        keep_from -= 1;
+    }
+
+    let kept = messages.split_off(keep_from);
+    let mut with_system = vec![system];
+    with_system.extend(kept);
+    Trimmed {
+        messages: with_system,
+        dropped: messages,
+        prompt_tokens: used,
+    }
+}
+
+fn score_query_complexity(messages: &[ChatMessage]) -> i32 {
+    let latest = messages.last().map(|m| m.content.as_str()).unwrap_or_default();
+    let history_count = messages.len();
+
+    let mut score = 0;
+
+    if latest.len() > 400 {
+        score += 2;
+    }
+    if latest.len() > 900 {
+        score += 2;
+    }
+    if history_count > 8 {
+        score += 2;
+    }
+
+    let lower = latest.to_lowercase();
+    for term in [
+        "architecture",
+        "optimize",
+        "benchmark",
+        "latency",
+        "throughput",
+        "algorithm",
+        "debug",
+        "refactor",
+        "rust",
+        "typescript",
+        "docker",
+        "api",
+        "stream",
+    ] {
+        if lower.contains(term) {
+            score += 1;
+        }
+    }
+
+    if latest.contains("```") {
+        score += 2;
+    }
+
+    score
+}
+
+/// Why a request may not escalate to the cloud.
+#[derive(Clone, Copy, Debug)]
+enum CloudBlock {
+    Residency,
+    Quota,
+    Budget,
+}
+
+impl CloudBlock {
+    fn label(self) -> &'static str {
+        match self {
+            CloudBlock::Residency => "residency",
+            CloudBlock::Quota => "quota",
+            CloudBlock::Budget => "budget",
+        }
+    }
+}
+
+fn cloud_budget_remaining(data: &AppState) -> Option<f64> {
+    data.budget
+        .lock()
+        .ok()
+        .and_then(|budget| budget.status().remaining_usd())
+}
+
+/// False once cloud spend has reached the budget cut-off.
+fn cloud_budget_allows(data: &AppState) -> bool {
+    data.budget
+        .lock()
+        .map(|budget| budget.escalation_allowed())
+        .unwrap_or(true)
+}
+
+/// With a `cloud_block`, escalation is replaced by the local quality tier.
+/// The persona's default tier is the lowest local tier chosen.
+fn choose_route(
+    data: &AppState,
+    messages: &[ChatMessage],
+    persona: &Persona,
+    cloud_block: Option<CloudBlock>,
+) -> RouteChoice {
+    if !data.cfg.smart_routing {
+        return RouteChoice {
+            provider: Provider::Local,
+            model: data.cfg.ollama_model.clone(),
+            tier: "default".to_string(),
+            reason: "smart-routing-disabled".to_string(),
+        };
+    }
+
+    let score = score_query_complexity(messages);
+
+    if score >= 10 && data.cfg.cloud_escalation && !data.cfg.cloud_api_key.is_empty() {
+        if let Some(block) = cloud_block {
+            match block {
+                CloudBlock::Residency => data.metrics.incr_residency_block(),
+                CloudBlock::Quota => data.metrics.incr_quota_cloud_block(),
+                CloudBlock::Budget => data.metrics.incr_budget_cloud_block(),
+            }
+            return RouteChoice {
+                provider: Provider::Local,
+                model: data.cfg.local_model_quality.clone(),
+                tier: "quality".to_string(),
+                reason: format!("complexity={score};escalation-blocked={}", block.label()),
+            };
+        }
+
+        return RouteChoice {
+            provider: Provider::Cloud,
+            model: data.cfg.cloud_model.clone(),
+            tier: "escalated".to_string(),
+            reason: format!("complexity={score}"),
+        };
+    }
+
+    let scored = if score >= 8 {
+        Tier::Quality
+    } else if score >= 4 {
+        Tier::Balanced
+    } else {
+        Tier::Fast
+    };
+    let mut reason = format!("complexity={score}");
+    let tier = match persona.default_tier {
+        Some(floor) if floor > scored => {
+            reason.push_str(&format!(";persona={}", persona.id));
+            floor
+        }
+        _ => scored,
+    };
+    let model = match tier {
+        Tier::Fast => &data.cfg.local_model_fast,
+        Tier::Balanced => &data.cfg.local_model_balanced,
+        Tier::Quality => &data.cfg.local_model_quality,
+    };
+
+    RouteChoice {
+        provider: Provider::Local,
+        model: model.clone(),
+        tier: tier.label().to_string(),
+        reason,
+    }
+}
+
+/// Key in `embeddings_cache`. Vectors have a cache of their own because one
+/// batch of up to `EMBEDDINGS_MAX_INPUTS` inputs would otherwise evict every
+/// cached chat answer.
+fn embedding_cache_key(model: &str, text: &str) -> String {
+    format!(
+        "embedding::{model}::{}",
+        to_hex(&Sha256::digest(text.as_bytes()))
+    )
+}
+
+/// Keyed on the model, the persona, the system prompt it produced (which
+/// may carry merged client instructions) and the latest message.
+fn response_cache_key(
+    model: &str,
+    persona: &str,
+    tools: &[Arc<dyn Tool>],
+    messages: &[ChatMessage],
+) -> String {
+    let system = messages
+        .first()
+        .filter(|m| m.role == "system")
+        .map(|m| m.content.as_str())
+        .unwrap_or_default();
+    let system_hash = to_hex(&Sha256::digest(system.as_bytes()));
+    let latest = messages.last().map(|m| m.content.as_str()).unwrap_or_default();
     let trimmed: String = latest.chars().take(500).collect();
-    format!("{model}::{trimmed}")
+    let tools: Vec<&str> = tools.iter().map(|tool| tool.name()).collect();
+    format!(
+        "{model}::{persona}::{}::{}::{trimmed}",
+        tools.join(","),
+        &system_hash[..16]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    use actix_web::test as actix_test;
+    use actix_web::{App, HttpServer};
+
+    use super::*;
+    use crate::api_keys::random_hex;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    fn tokens() -> TokenEstimators {
+        let mut cfg = AppConfig::from_env();
+        cfg.token_chars_per_token = "default=4.0".to_string();
+        TokenEstimators::from_config(&cfg).unwrap()
+    }
+
+    #[test]
+    fn trimming_keeps_recent_history_that_fits_the_budget() {
+        let tokens = tokens();
+        // 40 characters: 10 tokens plus 4 of overhead per message.
+        let turn = "t".repeat(40);
+        let history = vec![
+            message("user", &turn),
+            message("assistant", &turn),
+            message("user", &turn),
+            message("assistant", &turn),
+            message("user", "latest question"),
+        ];
+
+        // System (1 + 4) and the latest message (4 + 4) use 13 of 45.
+        let trimmed = trim_messages(history.clone(), "sys", "m", 45, &tokens);
+        assert_eq!(trimmed.messages.len(), 1 + 3);
+        assert_eq!(trimmed.messages[0].role, "system");
+        assert_eq!(trimmed.messages.last().unwrap().content, "latest question");
+        assert_eq!(trimmed.dropped.len(), 2);
+        assert_eq!(trimmed.prompt_tokens, 13 + 2 * 14);
+
+        let everything = trim_messages(history.clone(), "sys", "m", 10_000, &tokens);
+        assert!(everything.dropped.is_empty());
+        assert_eq!(everything.messages.len(), 6);
+    }
+
+    #[test]
+    fn the_latest_user_turn_is_kept_even_over_budget() {
+        let tokens = tokens();
+        let history = vec![
+            message("assistant", "earlier answer"),
+            message("user", &"q".repeat(400)),
+            message("tool", "tool output after the question"),
+        ];
+        let trimmed = trim_messages(history, "sys", "m", 10, &tokens);
+        assert_eq!(trimmed.dropped.len(), 1);
+        assert_eq!(trimmed.messages.len(), 3);
+        assert_eq!(trimmed.messages[2].role, "tool");
+        assert!(trimmed.prompt_tokens > 10);
+    }
+
+    /// A local model that streams a canned answer, a quiz for the JSON
+    /// template, and records the messages of every request. Quizzes on
+    /// "Broken" material are empty until re-prompted, on "Hopeless" always.
+    /// Texts mentioning "parking" embed along one axis, the rest the other.
+    fn local_model() -> (web::Data<AppState>, Arc<Mutex<Vec<Value>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = HttpServer::new(move || {
+            let seen = seen.clone();
+            App::new()
+                .route(
+                    "/api/embed",
+                    web::post().to(|body: web::Json<Value>| async move {
+                        let embeddings: Vec<Value> = body["input"]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .map(|text| {
+                                if text.to_string().contains("parking") {
+                                    json!([1.0, 0.0])
+                                } else {
+                                    json!([0.0, 1.0])
+                                }
+                            })
+                            .collect();
+                        HttpResponse::Ok().json(json!({"embeddings": embeddings}))
+                    }),
+                )
+                .route(
+                    "/api/chat",
+                    web::post().to(move |body: web::Json<Value>| {
+                        let seen = seen.clone();
+                        async move {
+                            let messages = body["messages"].clone();
+                            seen.lock().unwrap().push(messages.clone());
+                            let prompt = messages.to_string();
+                            let reprompted = prompt.contains("does not match the JSON Schema");
+                            let answer = if prompt.contains("Hopeless")
+                                || (prompt.contains("Broken") && !reprompted)
+                            {
+                                json!({"questions": []}).to_string()
+                            } else if prompt.contains("quiz questions") {
+                                json!({"questions": [{
+                                    "question": "Q?", "options": ["a", "b", "c", "d"],
+                                    "answer": 1, "explanation": "Because."
+                                }]})
+                                .to_string()
+                            } else {
+                                "Dear Sam, see you Friday.".to_string()
+                            };
+                            let lines = [
+                                json!({"message": {"content": answer}, "done": false}),
+                                json!({"message": {"content": ""}, "done": true,
+                                   "prompt_eval_count": 30, "eval_count": 8}),
+                            ];
+                            HttpResponse::Ok().body(lines.map(|l| format!("{l}\n")).concat())
+                        }
+                    }),
+                )
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+
+        let mut cfg = AppConfig::from_env();
+        cfg.data_dir = std::env::temp_dir()
+            .join(format!("campus-routes-{}", random_hex(8)))
+            .display()
+            .to_string();
+        cfg.mode = "local".to_string();
+        cfg.local_model_base_url = base;
+        (web::Data::new(AppState::for_tests(cfg)), requests)
+    }
+
+    #[actix_web::test]
+    async fn utility_templates_generate_through_the_local_model() {
+        let (data, requests) = local_model();
+        let app =
+            actix_test::init_service(App::new().app_data(data.clone()).service(utility_generate))
+                .await;
+        let generate = |body: Value| {
+            actix_test::TestRequest::post()
+                .uri("/api/utility/generate")
+                .set_json(body)
+                .to_request()
+        };
+
+        let email = json!({"template": "email", "prompt": "Meet on Friday", "variables": {"recipient": "Sam"}});
+        let answer: Value =
+            actix_test::call_and_read_body_json(&app, generate(email.clone())).await;
+        assert_eq!(answer["result"], "Dear Sam, see you Friday.");
+        assert_eq!(answer["template"], "email");
+        let sent = requests.lock().unwrap()[0].clone();
+        assert_eq!(sent[0]["role"], "system");
+        assert!(sent[0]["content"].as_str().unwrap().contains("formal tone"));
+        assert_eq!(
+            sent[1]["content"],
+            "Recipient: Sam\n\nWhat the email should say:\nMeet on Friday"
+        );
+
+        // The same request is answered from the cache.
+        let again: Value = actix_test::call_and_read_body_json(&app, generate(email)).await;
+        assert_eq!(again["result"], answer["result"]);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        let quiz =
+            json!({"template": "quiz", "prompt": "Cells divide.", "variables": {"questions": 1}});
+        let answer: Value = actix_test::call_and_read_body_json(&app, generate(quiz)).await;
+        assert_eq!(answer["data"]["questions"][0]["answer"], 1);
+
+        let streamed = json!({"template": "email", "prompt": "Cancel the trip", "stream": true});
+        let body = actix_test::call_and_read_body(&app, generate(streamed)).await;
+        assert!(String::from_utf8_lossy(&body).contains("see you Friday"));
+
+        for (body, error) in [
+            (json!({"template": "nope"}), "unknown template 'nope'"),
+            (json!({"template": "email"}), "variable 'input' is required"),
+            (
+                json!({"template": "email", "prompt": "x", "variables": {"tone": "rude"}}),
+                "variable 'tone' must be one of formal, friendly, firm",
+            ),
+        ] {
+            let response = actix_test::call_service(&app, generate(body)).await;
+            assert_eq!(response.status(), 400);
+            let answer: Value = actix_test::read_body_json(response).await;
+            assert_eq!(answer["error"], error);
+        }
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        std::fs::remove_dir_all(&data.cfg.data_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn invalid_structured_answers_are_re_prompted() {
+        let (data, requests) = local_model();
+        let retries = data.cfg.structured_output_retries;
+        let app =
+            actix_test::init_service(App::new().app_data(data.clone()).service(utility_generate))
+                .await;
+        let quiz = |material: &str| {
+            actix_test::TestRequest::post()
+                .uri("/api/utility/generate")
+                .set_json(json!({"template": "quiz", "prompt": material}))
+                .to_request()
+        };
+
+        let answer: Value = actix_test::call_and_read_body_json(&app, quiz("Broken")).await;
+        let structured = &answer["metadata"]["structuredOutput"];
+        assert_eq!(structured["valid"], true);
+        assert_eq!(structured["attempts"], 2);
+        assert_eq!(answer["data"]["questions"][0]["options"][3], "d");
+        let retry = requests.lock().unwrap()[1].clone();
+        let retry = retry.as_array().unwrap();
+        assert_eq!(retry[retry.len() - 2]["content"], r#"{"questions":[]}"#);
+        assert!(retry[retry.len() - 1]["content"]
+            .as_str()
+            .unwrap()
+            .contains("/questions: needs at least 1 items, got 0"));
+
+        let response = actix_test::call_service(&app, quiz("Hopeless")).await;
+        assert_eq!(response.status(), 422);
+        let failed: Value = actix_test::read_body_json(response).await;
+        assert_eq!(failed["structuredOutput"]["valid"], false);
+        assert_eq!(failed["structuredOutput"]["attempts"], retries + 1);
+        assert_eq!(requests.lock().unwrap().len() as u32, 2 + retries + 1);
+        std::fs::remove_dir_all(&data.cfg.data_dir).unwrap();
+    }
+
+    async fn add_policies(data: &AppState) {
+        let texts = [
+            ("Parking permits renew in August.", [1.0, 0.0]),
+            ("CS-2041 meets in room B12.104.", [0.0, 1.0]),
+            ("Bike permits are free.", [0.8, 0.6]),
+        ];
+        let record = DocumentRecord {
+            id: "doc-00aa-11bb".to_string(),
+            collection: "policies".to_string(),
+            title: "Handbook".to_string(),
+            format: DocumentFormat::Text,
+            bytes: 90,
+            pages: None,
+            chunks: texts.len(),
+            embedding_model: data.cfg.embedding_model.clone(),
+            uploaded_by: "key:test".to_string(),
+            uploaded_at: 0,
+        };
+        let chunks = texts
+            .iter()
+            .enumerate()
+            .map(|(i, (text, embedding))| Chunk {
+                id: format!("doc-00aa-11bb:{i}"),
+                document_id: record.id.clone(),
+                page: None,
+                offset: i * 40,
+                text: text.to_string(),
+                embedding: embedding.to_vec(),
+            })
+            .collect();
+        data.documents.add(record, chunks).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn search_ranks_by_keywords_vectors_or_both() {
+        let (data, _) = local_model();
+        add_policies(&data).await;
+        let app = actix_test::init_service(App::new().app_data(data.clone()).service(search)).await;
+        let get = |query: &str| {
+            actix_test::TestRequest::get()
+                .uri(&format!("/api/search?{query}"))
+                .to_request()
+        };
+        let ranked = |answer: &Value| -> Vec<String> {
+            answer["results"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|r| r["chunkId"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let answer: Value = actix_test::call_and_read_body_json(&app, get("q=cs2041")).await;
+        assert_eq!(answer["mode"], "keyword");
+        assert_eq!(ranked(&answer), ["doc-00aa-11bb:1"]);
+        assert_eq!(answer["results"][0]["citationId"], "doc-00aa-11bb#40");
+        assert_eq!(answer["results"][0]["collection"], "policies");
+
+        let answer: Value =
+            actix_test::call_and_read_body_json(&app, get("q=parking+spots&mode=vector")).await;
+        assert_eq!(ranked(&answer), ["doc-00aa-11bb:0", "doc-00aa-11bb:2"]);
+
+        // Only the keyword ranking finds the course; only the vectors find
+        // the bike permits; the parking chunk is first in both.
+        let answer: Value = actix_test::call_and_read_body_json(
+            &app,
+            get("q=parking+CS-2041&mode=hybrid&collection=policies"),
+        )
+        .await;
+        assert_eq!(answer["mode"], "hybrid");
+        let hybrid = ranked(&answer);
+        assert_eq!(hybrid[0], "doc-00aa-11bb:0");
+        assert_eq!(hybrid.len(), 3);
+
+        for (query, status) in [
+            ("q=+", 400),
+            ("q=x&limit=0", 400),
+            ("q=x&limit=51", 400),
+            ("q=x&collection=courses", 404),
+            ("q=x&mode=bm25", 400),
+        ] {
+            let response = actix_test::call_service(&app, get(query)).await;
+            assert_eq!(response.status(), status, "{query}");
+        }
+
+        // Without an embedder, hybrid search falls back to keywords.
+        let mut cfg = data.cfg.clone();
+        cfg.local_model_base_url = "http://127.0.0.1:1".to_string();
+        let offline = web::Data::new(AppState::for_tests(cfg));
+        let app = actix_test::init_service(App::new().app_data(offline).service(search)).await;
+        let answer: Value =
+            actix_test::call_and_read_body_json(&app, get("q=parking+CS-2041&mode=hybrid")).await;
+        assert_eq!(answer["mode"], "keyword");
+        assert_eq!(ranked(&answer).len(), 2);
+        let response = actix_test::call_service(&app, get("q=parking&mode=vector")).await;
+        assert_eq!(response.status(), 502);
+        std::fs::remove_dir_all(&data.cfg.data_dir).unwrap();
+    }
 }
@@ -0,0 +1,657 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Errors past this many are summarized; the model only needs the first few.
+const MAX_ERRORS: usize = 10;
+
+/// Subschemas entered for one value before validation gives up. Recursive
+/// schemas only go deeper as the value does, and parsed JSON is shallower.
+const MAX_DEPTH: usize = 256;
+
+/// Keywords this validator understands. Schemas using anything else are
+/// rejected up front rather than silently half-checked.
+const SUPPORTED_KEYWORDS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$defs",
+    "definitions",
+    "$ref",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "format",
+    "type",
+    "enum",
+    "const",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "uniqueItems",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "anyOf",
+    "oneOf",
+    "allOf",
+];
+
+const TYPE_NAMES: &[&str] = &[
+    "null", "boolean", "integer", "number", "string", "array", "object",
+];
+
+/// A JSON Schema the answer must satisfy, as attached to a request.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ResponseSchema {
+    #[serde(default = "default_schema_name")]
+    pub name: String,
+    pub schema: Value,
+}
+
+fn default_schema_name() -> String {
+    "response".to_string()
+}
+
+impl ResponseSchema {
+    pub fn check(&self) -> Result<(), String> {
+        let valid_name = !self.name.is_empty()
+            && self.name.len() <= 64
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_name {
+            return Err("schema name must be 1-64 letters, digits, '_' or '-'".to_string());
+        }
+        check_schema(&self.schema, &self.schema, "#")
+    }
+
+    /// Appended to the system prompt so models without native structured
+    /// output still know what to produce.
+    pub fn instruction(&self) -> String {
+        format!(
+            "Reply with a single JSON value and nothing else: no prose, no code fences. It must match this JSON Schema:\n{}",
+            self.schema
+        )
+    }
+
+    /// Parses the model's answer and returns the value, or every mismatch
+    /// found (capped at a readable number).
+    pub fn validate(&self, answer: &str) -> Result<Value, Vec<String>> {
+        let value: Value = serde_json::from_str(extract_json(answer))
+            .map_err(|e| vec![format!("the answer is not valid JSON: {e}")])?;
+        let errors = schema_errors(&self.schema, &value);
+        if errors.is_empty() {
+            Ok(value)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Every mismatch between `value` and `schema`, capped at a readable number.
+/// The schema is assumed to have passed [`ResponseSchema::check`].
+pub fn schema_errors(schema: &Value, value: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    validate_at(schema, schema, value, "", 0, &mut errors);
+    if errors.len() > MAX_ERRORS {
+        let more = errors.len() - MAX_ERRORS;
+        errors.truncate(MAX_ERRORS);
+        errors.push(format!("...and {more} more"));
+    }
+    errors
+}
+
+/// The JSON inside an answer, without surrounding prose or code fences.
+fn extract_json(answer: &str) -> &str {
+    let trimmed = answer.trim();
+    let start = trimmed.find(['{', '[']);
+    let end = trimmed.rfind(['}', ']']);
+    match (start, end) {
+        (Some(start), Some(end)) if start < end => &trimmed[start..=end],
+        _ => trimmed,
+    }
+}
+
+fn check_schema(root: &Value, schema: &Value, at: &str) -> Result<(), String> {
+    let object = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(object) => object,
+        _ => return Err(format!("{at}: a schema must be an object or a boolean")),
+    };
+
+    for key in object.keys() {
+        if !SUPPORTED_KEYWORDS.contains(&key.as_str()) {
+            return Err(format!("{at}: unsupported keyword '{key}'"));
+        }
+    }
+    if let Some(types) = object.get("type") {
+        let types = match types {
+            Value::Array(types) => types.iter().collect(),
+            single => vec![single],
+        };
+        if let Some(unknown) = types
+            .iter()
+            .find(|t| !t.as_str().is_some_and(|t| TYPE_NAMES.contains(&t)))
+        {
+            return Err(format!("{at}: unknown type {unknown}"));
+        }
+    }
+    if let Some(reference) = object.get("$ref") {
+        let reference = reference
+            .as_str()
+            .ok_or_else(|| format!("{at}: $ref must be a string"))?;
+        let target = resolve(root, reference)
+            .ok_or_else(|| format!("{at}: cannot resolve $ref '{reference}'"))?;
+        if loops_in_place(root, target, &mut vec![schema], &mut Vec::new()) {
+            return Err(format!(
+                "{at}: $ref '{reference}' refers back to itself without descending into the value"
+            ));
+        }
+    }
+    if let Some(pattern) = object.get("pattern").and_then(Value::as_str) {
+        Regex::new(pattern).map_err(|e| format!("{at}: invalid pattern: {e}"))?;
+    }
+
+    for key in ["$defs", "definitions", "properties"] {
+        if let Some(children) = object.get(key).and_then(Value::as_object) {
+            for (name, child) in children {
+                check_schema(root, child, &format!("{at}/{key}/{name}"))?;
+            }
+        }
+    }
+    for key in ["items", "additionalProperties"] {
+        if let Some(child) = object.get(key) {
+            check_schema(root, child, &format!("{at}/{key}"))?;
+        }
+    }
+    for key in ["anyOf", "oneOf", "allOf"] {
+        if let Some(children) = object.get(key) {
+            let children = children
+                .as_array()
+                .ok_or_else(|| format!("{at}: {key} must be an array"))?;
+            for (i, child) in children.iter().enumerate() {
+                check_schema(root, child, &format!("{at}/{key}/{i}"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether following `$ref`, `allOf`, `anyOf` and `oneOf` from `schema`
+/// reaches a schema already on `path`. Such a cycle never moves on to a
+/// property or item, so validating against it would recurse forever.
+fn loops_in_place<'a>(
+    root: &'a Value,
+    schema: &'a Value,
+    path: &mut Vec<&'a Value>,
+    cleared: &mut Vec<&'a Value>,
+) -> bool {
+    if path.iter().any(|s| std::ptr::eq(*s, schema)) {
+        return true;
+    }
+    if cleared.iter().any(|s| std::ptr::eq(*s, schema)) {
+        return false;
+    }
+    let Some(object) = schema.as_object() else {
+        return false;
+    };
+    let target = object
+        .get("$ref")
+        .and_then(Value::as_str)
+        .and_then(|r| resolve(root, r));
+    let combined = ["allOf", "anyOf", "oneOf"]
+        .iter()
+        .filter_map(|key| object.get(*key).and_then(Value::as_array))
+        .flatten();
+    path.push(schema);
+    let found = target
+        .into_iter()
+        .chain(combined)
+        .any(|next| loops_in_place(root, next, path, cleared));
+    path.pop();
+    if !found {
+        cleared.push(schema);
+    }
+    found
+}
+
+/// Local references only: `#`, `#/$defs/...` and other JSON pointers.
+fn resolve<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    let pointer = reference.strip_prefix('#')?;
+    if pointer.is_empty() {
+        Some(root)
+    } else {
+        root.pointer(pointer)
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn matches_type(expected: &str, value: &Value) -> bool {
+    match expected {
+        "integer" => match value {
+            Value::Number(n) => n.as_f64().is_some_and(|f| f.fract() == 0.0),
+            _ => false,
+        },
+        "number" => value.is_number(),
+        other => type_name(value) == other,
+    }
+}
+
+fn validate_at(
+    root: &Value,
+    schema: &Value,
+    value: &Value,
+    path: &str,
+    depth: usize,
+    errors: &mut Vec<String>,
+) {
+    let location = if path.is_empty() { "/" } else { path };
+    if depth > MAX_DEPTH {
+        errors.push(format!("{location}: the schema nests too deeply"));
+        return;
+    }
+    let depth = depth + 1;
+    let object = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            errors.push(format!("{location}: no value is allowed here"));
+            return;
+        }
+        Value::Object(object) => object,
+        _ => return,
+    };
+
+    if let Some(target) = object
+        .get("$ref")
+        .and_then(Value::as_str)
+        .and_then(|r| resolve(root, r))
+    {
+        validate_at(root, target, value, path, depth, errors);
+    }
+
+    if let Some(expected) = object.get("type") {
+        let allowed: Vec<&str> = match expected {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|t| matches_type(t, value)) {
+            errors.push(format!(
+                "{location}: expected {}, got {}",
+                allowed.join(" or "),
+                type_name(value)
+            ));
+            return;
+        }
+    }
+
+    if let Some(options) = object.get("enum").and_then(Value::as_array) {
+        if !options.contains(value) {
+            let listed: Vec<String> = options.iter().map(Value::to_string).collect();
+            errors.push(format!("{location}: must be one of {}", listed.join(", ")));
+        }
+    }
+    if let Some(expected) = object.get("const") {
+        if expected != value {
+            errors.push(format!("{location}: must be {expected}"));
+        }
+    }
+
+    match value {
+        Value::Object(fields) => validate_object(root, object, fields, path, depth, errors),
+        Value::Array(items) => {
+            let count = items.len() as u64;
+            if let Some(min) = object.get("minItems").and_then(Value::as_u64) {
+                if count < min {
+                    errors.push(format!(
+                        "{location}: needs at least {min} items, got {count}"
+                    ));
+                }
+            }
+            if let Some(max) = object.get("maxItems").and_then(Value::as_u64) {
+                if count > max {
+                    errors.push(format!(
+                        "{location}: allows at most {max} items, got {count}"
+                    ));
+                }
+            }
+            if object.get("uniqueItems") == Some(&Value::Bool(true)) {
+                let duplicate = items
+                    .iter()
+                    .enumerate()
+                    .any(|(i, item)| items[..i].contains(item));
+                if duplicate {
+                    errors.push(format!("{location}: items must be unique"));
+                }
+            }
+            if let Some(item_schema) = object.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate_at(
+                        root,
+                        item_schema,
+                        item,
+                        &format!("{path}/{i}"),
+                        depth,
+                        errors,
+                    );
+                }
+            }
+        }
+        Value::String(text) => {
+            let length = text.chars().count() as u64;
+            if let Some(min) = object.get("minLength").and_then(Value::as_u64) {
+                if length < min {
+                    errors.push(format!("{location}: must be at least {min} characters"));
+                }
+            }
+            if let Some(max) = object.get("maxLength").and_then(Value::as_u64) {
+                if length > max {
+                    errors.push(format!("{location}: must be at most {max} characters"));
+                }
+            }
+            if let Some(pattern) = object.get("pattern").and_then(Value::as_str) {
+                if Regex::new(pattern).is_ok_and(|re| !re.is_match(text)) {
+                    errors.push(format!("{location}: must match the pattern {pattern}"));
+                }
+            }
+        }
+        Value::Number(number) => {
+            let n = number.as_f64().unwrap_or_default();
+            let bound = |key: &str| object.get(key).and_then(Value::as_f64);
+            if bound("minimum").is_some_and(|min| n < min) {
+                errors.push(format!("{location}: must be >= {}", object["minimum"]));
+            }
+            if bound("maximum").is_some_and(|max| n > max) {
+                errors.push(format!("{location}: must be <= {}", object["maximum"]));
+            }
+            if bound("exclusiveMinimum").is_some_and(|min| n <= min) {
+                errors.push(format!(
+                    "{location}: must be > {}",
+                    object["exclusiveMinimum"]
+                ));
+            }
+            if bound("exclusiveMaximum").is_some_and(|max| n >= max) {
+                errors.push(format!(
+                    "{location}: must be < {}",
+                    object["exclusiveMaximum"]
+                ));
+            }
+        }
+        _ => {}
+    }
+
+    if let Some(all) = object.get("allOf").and_then(Value::as_array) {
+        for sub in all {
+            validate_at(root, sub, value, path, depth, errors);
+        }
+    }
+    for key in ["anyOf", "oneOf"] {
+        let Some(options) = object.get(key).and_then(Value::as_array) else {
+            continue;
+        };
+        let passing = options
+            .iter()
+            .filter(|sub| {
+                let mut sub_errors = Vec::new();
+                validate_at(root, sub, value, path, depth, &mut sub_errors);
+                sub_errors.is_empty()
+            })
+            .count();
+        if passing == 0 {
+            errors.push(format!("{location}: matches none of the {key} options"));
+        } else if key == "oneOf" && passing > 1 {
+            errors.push(format!("{location}: matches more than one oneOf option"));
+        }
+    }
+}
+
+fn validate_object(
+    root: &Value,
+    schema: &Map<String, Value>,
+    fields: &Map<String, Value>,
+    path: &str,
+    depth: usize,
+    errors: &mut Vec<String>,
+) {
+    let location = if path.is_empty() { "/" } else { path };
+    if let Some(required) = schema.get("required").and_then(Value::as_array) {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !fields.contains_key(name) {
+                errors.push(format!("{location}: missing required property '{name}'"));
+            }
+        }
+    }
+
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (name, field) in fields {
+        let field_path = format!("{path}/{name}");
+        match properties.and_then(|p| p.get(name)) {
+            Some(field_schema) => {
+                validate_at(root, field_schema, field, &field_path, depth, errors)
+            }
+            None => match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => {
+                    errors.push(format!("{location}: unexpected property '{name}'"));
+                }
+                Some(extra) => validate_at(root, extra, field, &field_path, depth, errors),
+                None => {}
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema(schema: Value) -> ResponseSchema {
+        ResponseSchema {
+            name: "x".to_string(),
+            schema,
+        }
+    }
+
+    #[test]
+    fn self_referencing_schemas_are_rejected() {
+        let direct = schema(json!({"$ref": "#"}));
+        assert!(direct.check().is_err());
+
+        let indirect = schema(json!({
+            "$defs": {"a": {"$ref": "#/$defs/b"}, "b": {"allOf": [{"$ref": "#/$defs/a"}]}},
+            "$ref": "#/$defs/a"
+        }));
+        assert!(indirect.check().is_err());
+
+        let via_any_of = schema(json!({"anyOf": [{"type": "string"}, {"$ref": "#"}]}));
+        assert!(via_any_of.check().is_err());
+    }
+
+    #[test]
+    fn recursion_through_values_is_allowed() {
+        let tree = schema(json!({
+            "type": "object",
+            "required": ["name"],
+            "properties": {
+                "name": {"type": "string"},
+                "children": {"type": "array", "items": {"$ref": "#"}}
+            }
+        }));
+        tree.check().unwrap();
+        assert!(tree
+            .validate(r#"{"name": "a", "children": [{"name": "b", "children": []}]}"#)
+            .is_ok());
+        let errors = tree
+            .validate(r#"{"name": "a", "children": [{}]}"#)
+            .unwrap_err();
+        assert_eq!(errors, ["/children/0: missing required property 'name'"]);
+    }
+
+    #[test]
+    fn unchecked_cycles_stop_at_the_depth_limit() {
+        let errors = schema_errors(&json!({"$ref": "#"}), &json!({}));
+        assert_eq!(errors, ["/: the schema nests too deeply"]);
+    }
+
+    #[test]
+    fn answers_are_checked_against_the_schema() {
+        let answer = schema(json!({
+            "type": "object",
+            "additionalProperties": false,
+            "properties": {
+                "grade": {"enum": ["A", "B", "C"]},
+                "score": {"type": "integer", "minimum": 0, "maximum": 100}
+            }
+        }));
+        answer.check().unwrap();
+        assert!(answer
+            .validate("Here you go:\n```json\n{\"grade\": \"A\", \"score\": 91}\n```")
+            .is_ok());
+        let errors = answer
+            .validate(r#"{"grade": "E", "score": 101.5, "extra": 1}"#)
+            .unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(schema(json!({"type": "string", "format2": 1}))
+            .check()
+            .is_err());
+        for bad in [json!("text"), json!(["string", 1]), json!(null)] {
+            let err = schema(json!({"type": bad})).check().unwrap_err();
+            assert!(err.starts_with("#: unknown type"), "{err}");
+        }
+    }
+
+    #[test]
+    fn keywords_report_where_the_answer_differs() {
+        let errors = |schema: Value, value: Value| schema_errors(&schema, &value);
+        let list = json!({"type": "array", "items": {"type": "string", "minLength": 2, "pattern": "^[a-z]+$"},
+                          "minItems": 2, "maxItems": 3, "uniqueItems": true});
+        assert!(errors(list.clone(), json!(["ab", "cd"])).is_empty());
+        assert_eq!(
+            errors(list.clone(), json!(["x"])),
+            [
+                "/: needs at least 2 items, got 1",
+                "/0: must be at least 2 characters"
+            ]
+        );
+        assert_eq!(
+            errors(list, json!(["ab", "ab", "A1", "cd"])),
+            [
+                "/: allows at most 3 items, got 4",
+                "/: items must be unique",
+                "/2: must match the pattern ^[a-z]+$",
+            ]
+        );
+
+        let bounded = json!({"type": "number", "exclusiveMinimum": 0, "exclusiveMaximum": 1});
+        assert!(errors(bounded.clone(), json!(0.5)).is_empty());
+        assert_eq!(errors(bounded.clone(), json!(0)), ["/: must be > 0"]);
+        assert_eq!(
+            errors(bounded, json!("1")),
+            ["/: expected number, got string"]
+        );
+        assert_eq!(errors(json!({"const": 3}), json!(4)), ["/: must be 3"]);
+        assert!(errors(json!({"type": ["integer", "null"]}), json!(null)).is_empty());
+        assert!(errors(json!({"type": "integer"}), json!(2.0)).is_empty());
+
+        let either = json!({"anyOf": [{"type": "string"}, {"type": "integer"}]});
+        assert!(errors(either.clone(), json!(1)).is_empty());
+        assert_eq!(
+            errors(either, json!(true)),
+            ["/: matches none of the anyOf options"]
+        );
+        let exactly = json!({"oneOf": [{"type": "number"}, {"type": "integer"}]});
+        assert!(errors(exactly.clone(), json!(1.5)).is_empty());
+        assert_eq!(
+            errors(exactly, json!(1)),
+            ["/: matches more than one oneOf option"]
+        );
+        let both = json!({"allOf": [{"minimum": 1}, {"maximum": 2}]});
+        assert_eq!(errors(both, json!(3)), ["/: must be <= 2"]);
+
+        let defs = json!({
+            "$defs": {"id": {"type": "string", "maxLength": 3}},
+            "type": "object",
+            "properties": {"ids": {"type": "array", "items": {"$ref": "#/$defs/id"}}},
+            "additionalProperties": {"type": "boolean"}
+        });
+        assert_eq!(
+            errors(defs, json!({"ids": ["abcd"], "flag": 1})),
+            [
+                "/flag: expected boolean, got integer",
+                "/ids/0: must be at most 3 characters"
+            ]
+        );
+    }
+
+    #[test]
+    fn long_error_lists_are_capped() {
+        let value = Value::Array((0..15).map(|i| json!(i)).collect());
+        let errors = schema_errors(&json!({"items": {"type": "string"}}), &value);
+        assert_eq!(errors.len(), MAX_ERRORS + 1);
+        assert_eq!(errors[MAX_ERRORS], "...and 5 more");
+    }
+
+    #[test]
+    fn schemas_are_checked_up_front() {
+        let bad = [
+            (
+                json!({"type": "string", "pattern": "("}),
+                "#: invalid pattern",
+            ),
+            (
+                json!({"properties": {"a": 3}}),
+                "#/properties/a: a schema must be",
+            ),
+            (
+                json!({"items": {"if": {}}}),
+                "#/items: unsupported keyword 'if'",
+            ),
+            (json!({"oneOf": {}}), "#: oneOf must be an array"),
+            (json!({"$ref": "#/$defs/missing"}), "#: cannot resolve $ref"),
+            (json!({"$ref": 1}), "#: $ref must be a string"),
+        ];
+        for (raw, expected) in bad {
+            let err = schema(raw).check().unwrap_err();
+            assert!(err.starts_with(expected), "{err}");
+        }
+
+        let mut named = schema(json!(true));
+        named.check().unwrap();
+        named.name = "has space".to_string();
+        assert!(named.check().is_err());
+        named.name = String::new();
+        assert!(named.check().is_err());
+    }
+
+    #[test]
+    fn json_is_found_inside_prose_and_fences() {
+        assert_eq!(extract_json("Sure!\n```json\n[1, 2]\n```\nDone."), "[1, 2]");
+        assert_eq!(extract_json(" {\"a\": {}} "), "{\"a\": {}}");
+        assert_eq!(extract_json("no json here"), "no json here");
+        let answer = schema(json!({"type": "object"}));
+        assert_eq!(
+            answer.validate("{oops}").unwrap_err()[0].split(':').next(),
+            Some("the answer is not valid JSON")
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::documents::Hit;
+
+/// Term frequency saturation.
+const K1: f32 = 1.2;
+/// Document length normalization.
+const B: f32 = 0.75;
+/// Damping constant of reciprocal rank fusion.
+const RRF_K: f32 = 60.0;
+
+/// How chunks are ranked against a query.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    /// Cosine similarity of embeddings.
+    Vector,
+    /// BM25 over the chunk text.
+    Keyword,
+    /// Both rankings, merged with reciprocal rank fusion.
+    Hybrid,
+}
+
+impl SearchMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "vector" => Some(SearchMode::Vector),
+            "keyword" => Some(SearchMode::Keyword),
+            "hybrid" => Some(SearchMode::Hybrid),
+            _ => None,
+        }
+    }
+
+    pub fn uses_embeddings(self) -> bool {
+        self != SearchMode::Keyword
+    }
+}
+
+/// Lowercased search terms. Words joined by punctuation, such as course
+/// codes (`CS-2041`) and rooms (`B12.104`), are indexed whole, run together
+/// (`cs2041`) and as their parts, so any way of writing them matches.
+pub fn terms(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    for word in text.split_whitespace() {
+        let word = word
+            .trim_matches(|c: char| !c.is_alphanumeric())
+            .to_lowercase();
+        let parts: Vec<&str> = word
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|p| !p.is_empty())
+            .collect();
+        match parts.as_slice() {
+            [] => {}
+            [single] => terms.push(single.to_string()),
+            _ => {
+                terms.push(parts.concat());
+                terms.push(word.clone());
+                terms.extend(parts.iter().map(|p| p.to_string()));
+            }
+        }
+    }
+    terms
+}
+
+/// An inverted index over one collection's chunks, scored with BM25.
+#[derive(Default)]
+pub struct Bm25Index {
+    /// Term to `(chunk position, term frequency)`.
+    postings: HashMap<String, Vec<(usize, u32)>>,
+    lengths: Vec<u32>,
+    average_length: f32,
+}
+
+impl Bm25Index {
+    pub fn build<'a>(texts: impl Iterator<Item = &'a str>) -> Self {
+        let mut index = Bm25Index::default();
+        for (position, text) in texts.enumerate() {
+            let terms = terms(text);
+            index.lengths.push(terms.len() as u32);
+            let mut counts: HashMap<String, u32> = HashMap::new();
+            for term in terms {
+                *counts.entry(term).or_default() += 1;
+            }
+            for (term, count) in counts {
+                index
+                    .postings
+                    .entry(term)
+                    .or_default()
+                    .push((position, count));
+            }
+        }
+        let total: u64 = index.lengths.iter().map(|&l| u64::from(l)).sum();
+        index.average_length = total as f32 / index.lengths.len().max(1) as f32;
+        index
+    }
+
+    /// Positions of the `k` best-scoring chunks, best first. Chunks sharing
+    /// no term with the query are left out.
+    pub fn search(&self, query: &str, k: usize) -> Vec<(usize, f32)> {
+        let chunks = self.lengths.len() as f32;
+        let mut query_terms = terms(query);
+        query_terms.sort();
+        query_terms.dedup();
+
+        let mut scores: HashMap<usize, f32> = HashMap::new();
+        for term in &query_terms {
+            let Some(postings) = self.postings.get(term) else {
+                continue;
+            };
+            let frequency = postings.len() as f32;
+            let idf = (1.0 + (chunks - frequency + 0.5) / (frequency + 0.5)).ln();
+            for &(position, count) in postings {
+                let count = count as f32;
+                let length = self.lengths[position] as f32 / self.average_length.max(1.0);
+                let score = idf * count * (K1 + 1.0) / (count + K1 * (1.0 - B + B * length));
+                *scores.entry(position).or_default() += score;
+            }
+        }
+
+        let mut ranked: Vec<(usize, f32)> = scores.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked.truncate(k);
+        ranked
+    }
+}
+
+/// Merges rankings of the same chunks with reciprocal rank fusion, keeping
+/// the best `k`. The fused score replaces each hit's own.
+pub fn fuse(rankings: Vec<Vec<Hit>>, k: usize) -> Vec<Hit> {
+    let mut fused: Vec<Hit> = Vec::new();
+    for ranking in rankings {
+        for (rank, mut hit) in ranking.into_iter().enumerate() {
+            let score = 1.0 / (RRF_K + rank as f32 + 1.0);
+            match fused.iter_mut().find(|f| f.chunk.id == hit.chunk.id) {
+                Some(existing) => existing.score += score,
+                None => {
+                    hit.score = score;
+                    fused.push(hit);
+                }
+            }
+        }
+    }
+    fused.sort_by(|a, b| b.score.total_cmp(&a.score));
+    fused.truncate(k);
+    fused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::documents::Chunk;
+
+    fn hit(id: &str) -> Hit {
+        Hit {
+            chunk: Chunk {
+                id: id.to_string(),
+                document_id: "doc".to_string(),
+                page: None,
+                offset: 0,
+                text: String::new(),
+                embedding: Vec::new(),
+            },
+            title: String::new(),
+            score: 0.0,
+        }
+    }
+
+    #[test]
+    fn codes_and_rooms_are_indexed_every_way_they_are_written() {
+        assert_eq!(
+            terms("See CS-2041, in (B12.104)!"),
+            ["see", "cs2041", "cs-2041", "cs", "2041", "in", "b12104", "b12.104", "b12", "104"]
+        );
+        assert_eq!(terms("Ünïcode -- ok"), ["ünïcode", "ok"]);
+        assert!(terms(" ... ").is_empty());
+    }
+
+    #[test]
+    fn bm25_favours_rare_terms_and_short_chunks() {
+        let texts = [
+            "CS-2041 meets in room B12.104 on Mondays.",
+            "The course meets on Mondays and Wednesdays.",
+            "The course course course is offered every term, and the course is popular with many students on campus.",
+            "Parking near the campus.",
+        ];
+        let index = Bm25Index::build(texts.iter().copied());
+
+        for query in ["cs-2041", "CS2041", "cs 2041", "b12.104"] {
+            let ranked = index.search(query, 10);
+            assert_eq!(ranked.len(), 1, "{query}");
+            assert_eq!(ranked[0].0, 0, "{query}");
+        }
+
+        // "mondays" is rarer than "the", so it decides the order.
+        let ranked = index.search("the mondays", 10);
+        assert_eq!(ranked.iter().map(|r| r.0).collect::<Vec<_>>(), [1, 0, 3, 2]);
+        assert!(ranked.windows(2).all(|w| w[0].1 >= w[1].1));
+
+        // Repeats saturate, so a long chunk stuffed with the term does not
+        // outscore a short one by the repeat count.
+        let ranked = index.search("course", 10);
+        assert_eq!(ranked[0].0, 2);
+        assert!(ranked[0].1 < 2.0 * ranked[1].1);
+
+        assert_eq!(index.search("campus", 1).len(), 1);
+        assert!(index.search("library", 10).is_empty());
+        assert!(Bm25Index::build(std::iter::empty())
+            .search("x", 5)
+            .is_empty());
+    }
+
+    #[test]
+    fn fusion_rewards_chunks_found_by_both_rankings() {
+        let vector = vec![hit("a"), hit("b"), hit("c")];
+        let keyword = vec![hit("c"), hit("d")];
+        let fused = fuse(vec![vector, keyword], 2);
+
+        let ids: Vec<&str> = fused.iter().map(|h| h.chunk.id.as_str()).collect();
+        assert_eq!(ids, ["c", "a"]);
+        assert_eq!(fused[0].score, 1.0 / 63.0 + 1.0 / 61.0);
+        assert_eq!(fused[1].score, 1.0 / 61.0);
+    }
+
+    #[test]
+    fn modes_parse_from_their_names() {
+        assert_eq!(SearchMode::parse("hybrid"), Some(SearchMode::Hybrid));
+        assert_eq!(SearchMode::parse("bm25"), None);
+        assert!(!SearchMode::Keyword.uses_embeddings());
+        assert!(SearchMode::Vector.uses_embeddings() && SearchMode::Hybrid.uses_embeddings());
+    }
+}
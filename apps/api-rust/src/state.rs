@@ -1,19 +1,99 @@
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use reqwest::Client;
+use tokio::sync::Semaphore;
 
+use crate::activity::ActivityLog;
+use crate::api_keys::ApiKeyStore;
+use crate::auth::Role;
+use crate::budget::CloudBudget;
 use crate::cache::LruTtlCache;
+use crate::client_ip::TrustedProxies;
 use crate::config::AppConfig;
+use crate::documents::DocumentStore;
+use crate::models::{Provider, TokenTotals};
+use crate::moderation::Moderator;
+use crate::oidc::OidcClient;
+use crate::persona::PersonaRegistry;
+use crate::providers::TokenUsage;
+use crate::queue::GenerationQueue;
+use crate::ratelimit::RateLimiter;
+use crate::redaction::PiiRedactor;
+use crate::residency::ResidencyPolicy;
+use crate::summary::HistorySummarizer;
+use crate::tokens::TokenEstimators;
+use crate::tools::ToolRegistry;
+use crate::usage::UsageStore;
+use crate::utility::TemplateLibrary;
+
+/// Provider-reported token usage, summed per provider.
+#[derive(Default)]
+pub struct TokenCounters {
+    pub generations: AtomicU64,
+    pub prompt_tokens: AtomicU64,
+    pub completion_tokens: AtomicU64,
+    pub generation_ms: AtomicU64,
+}
+
+impl TokenCounters {
+    pub fn snapshot(&self) -> TokenTotals {
+        TokenTotals {
+            generations: self.generations.load(Ordering::Relaxed),
+            prompt_tokens: self.prompt_tokens.load(Ordering::Relaxed),
+            completion_tokens: self.completion_tokens.load(Ordering::Relaxed),
+            generation_ms: self.generation_ms.load(Ordering::Relaxed),
+        }
+    }
+}
 
 pub struct RuntimeMetrics {
     pub requests_total: AtomicU64,
     pub chat_requests_total: AtomicU64,
+    pub utility_requests_total: AtomicU64,
+    pub structured_retries_total: AtomicU64,
+    pub structured_failures_total: AtomicU64,
+    pub tool_calls_total: AtomicU64,
+    pub tool_failures_total: AtomicU64,
+    pub documents_ingested_total: AtomicU64,
+    pub retrievals_total: AtomicU64,
+    pub retrieval_failures_total: AtomicU64,
+    pub searches_total: AtomicU64,
+    pub embedding_requests_total: AtomicU64,
+    pub embedding_inputs_total: AtomicU64,
+    pub reranks_total: AtomicU64,
+    pub rerank_failures_total: AtomicU64,
+    pub citations_total: AtomicU64,
+    pub citations_flagged_total: AtomicU64,
     pub cache_hits_total: AtomicU64,
     pub cache_misses_total: AtomicU64,
     pub local_routes_total: AtomicU64,
     pub cloud_routes_total: AtomicU64,
     pub fallback_responses_total: AtomicU64,
+    pub moderation_flags_total: AtomicU64,
+    pub moderation_blocks_total: AtomicU64,
+    pub pii_redactions_total: AtomicU64,
+    pub residency_blocks_total: AtomicU64,
+    pub auth_failures_total: AtomicU64,
+    pub oidc_logins_total: AtomicU64,
+    pub quota_rejections_total: AtomicU64,
+    pub quota_cloud_blocks_total: AtomicU64,
+    pub rate_limited_total: AtomicU64,
+    pub streams_rejected_total: AtomicU64,
+    pub queue_timeouts_total: AtomicU64,
+    pub budget_cloud_blocks_total: AtomicU64,
+    pub context_trimmed_total: AtomicU64,
+    pub context_messages_dropped_total: AtomicU64,
+    pub summaries_generated_total: AtomicU64,
+    pub summary_cache_hits_total: AtomicU64,
+    pub summary_failures_total: AtomicU64,
+    pub local_tokens: TokenCounters,
+    pub cloud_tokens: TokenCounters,
+    pub generation_timeouts_total: AtomicU64,
+    pub anonymous_requests_total: AtomicU64,
+    pub student_requests_total: AtomicU64,
+    pub staff_requests_total: AtomicU64,
+    pub admin_requests_total: AtomicU64,
 }
 
 impl RuntimeMetrics {
@@ -21,11 +101,50 @@ impl RuntimeMetrics {
         Self {
             requests_total: AtomicU64::new(0),
             chat_requests_total: AtomicU64::new(0),
+            utility_requests_total: AtomicU64::new(0),
+            structured_retries_total: AtomicU64::new(0),
+            structured_failures_total: AtomicU64::new(0),
+            tool_calls_total: AtomicU64::new(0),
+            tool_failures_total: AtomicU64::new(0),
+            documents_ingested_total: AtomicU64::new(0),
+            retrievals_total: AtomicU64::new(0),
+            retrieval_failures_total: AtomicU64::new(0),
+            searches_total: AtomicU64::new(0),
+            embedding_requests_total: AtomicU64::new(0),
+            embedding_inputs_total: AtomicU64::new(0),
+            reranks_total: AtomicU64::new(0),
+            rerank_failures_total: AtomicU64::new(0),
+            citations_total: AtomicU64::new(0),
+            citations_flagged_total: AtomicU64::new(0),
             cache_hits_total: AtomicU64::new(0),
             cache_misses_total: AtomicU64::new(0),
             local_routes_total: AtomicU64::new(0),
             cloud_routes_total: AtomicU64::new(0),
             fallback_responses_total: AtomicU64::new(0),
+            moderation_flags_total: AtomicU64::new(0),
+            moderation_blocks_total: AtomicU64::new(0),
+            pii_redactions_total: AtomicU64::new(0),
+            residency_blocks_total: AtomicU64::new(0),
+            auth_failures_total: AtomicU64::new(0),
+            oidc_logins_total: AtomicU64::new(0),
+            quota_rejections_total: AtomicU64::new(0),
+            quota_cloud_blocks_total: AtomicU64::new(0),
+            rate_limited_total: AtomicU64::new(0),
+            streams_rejected_total: AtomicU64::new(0),
+            queue_timeouts_total: AtomicU64::new(0),
+            budget_cloud_blocks_total: AtomicU64::new(0),
+            context_trimmed_total: AtomicU64::new(0),
+            context_messages_dropped_total: AtomicU64::new(0),
+            summaries_generated_total: AtomicU64::new(0),
+            summary_cache_hits_total: AtomicU64::new(0),
+            summary_failures_total: AtomicU64::new(0),
+            local_tokens: TokenCounters::default(),
+            cloud_tokens: TokenCounters::default(),
+            generation_timeouts_total: AtomicU64::new(0),
+            anonymous_requests_total: AtomicU64::new(0),
+            student_requests_total: AtomicU64::new(0),
+            staff_requests_total: AtomicU64::new(0),
+            admin_requests_total: AtomicU64::new(0),
         }
     }
 
@@ -37,6 +156,67 @@ impl RuntimeMetrics {
         self.chat_requests_total.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn incr_utility(&self) {
+        self.utility_requests_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_structured_retry(&self) {
+        self.structured_retries_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_structured_failure(&self) {
+        self.structured_failures_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_tool_call(&self) {
+        self.tool_calls_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_tool_failure(&self) {
+        self.tool_failures_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_document_ingested(&self) {
+        self.documents_ingested_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_retrieval(&self) {
+        self.retrievals_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_retrieval_failure(&self) {
+        self.retrieval_failures_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_search(&self) {
+        self.searches_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_embeddings(&self, inputs: u64) {
+        self.embedding_requests_total
+            .fetch_add(1, Ordering::Relaxed);
+        self.embedding_inputs_total
+            .fetch_add(inputs, Ordering::Relaxed);
+    }
+
+    pub fn incr_rerank(&self) {
+        self.reranks_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_rerank_failure(&self) {
+        self.rerank_failures_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_citations(&self, total: u64, flagged: u64) {
+        self.citations_total.fetch_add(total, Ordering::Relaxed);
+        self.citations_flagged_total
+            .fetch_add(flagged, Ordering::Relaxed);
+    }
+
     pub fn incr_cache_hit(&self) {
         self.cache_hits_total.fetch_add(1, Ordering::Relaxed);
     }
@@ -56,13 +236,185 @@ impl RuntimeMetrics {
     pub fn incr_fallback(&self) {
         self.fallback_responses_total.fetch_add(1, Ordering::Relaxed);
     }
+
+    pub fn incr_moderation_flags(&self, count: u64) {
+        self.moderation_flags_total
+            .fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn incr_moderation_block(&self) {
+        self.moderation_blocks_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_pii_redactions(&self, count: u64) {
+        self.pii_redactions_total
+            .fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn incr_residency_block(&self) {
+        self.residency_blocks_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_auth_failure(&self) {
+        self.auth_failures_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_quota_rejection(&self) {
+        self.quota_rejections_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_quota_cloud_block(&self) {
+        self.quota_cloud_blocks_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_rate_limited(&self) {
+        self.rate_limited_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_stream_rejected(&self) {
+        self.streams_rejected_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_token_usage(&self, provider: &Provider, usage: &TokenUsage) {
+        let counters = match provider {
+            Provider::Local => &self.local_tokens,
+            Provider::Cloud => &self.cloud_tokens,
+        };
+        counters.generations.fetch_add(1, Ordering::Relaxed);
+        counters
+            .prompt_tokens
+            .fetch_add(usage.prompt_tokens, Ordering::Relaxed);
+        counters
+            .completion_tokens
+            .fetch_add(usage.completion_tokens, Ordering::Relaxed);
+        counters
+            .generation_ms
+            .fetch_add(usage.total_ms.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn incr_context_trimmed(&self, dropped: u64) {
+        self.context_trimmed_total.fetch_add(1, Ordering::Relaxed);
+        self.context_messages_dropped_total
+            .fetch_add(dropped, Ordering::Relaxed);
+    }
+
+    pub fn incr_summary_generated(&self) {
+        self.summaries_generated_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_summary_cache_hit(&self) {
+        self.summary_cache_hits_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_summary_failure(&self) {
+        self.summary_failures_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_budget_cloud_block(&self) {
+        self.budget_cloud_blocks_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_queue_timeout(&self) {
+        self.queue_timeouts_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_generation_timeout(&self) {
+        self.generation_timeouts_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_oidc_login(&self) {
+        self.oidc_logins_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_requests_by_role(&self, role: Option<Role>) {
+        let counter = match role {
+            None => &self.anonymous_requests_total,
+            Some(Role::Student) => &self.student_requests_total,
+            Some(Role::Staff) => &self.staff_requests_total,
+            Some(Role::Admin) => &self.admin_requests_total,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 pub struct AppState {
     pub cfg: AppConfig,
     pub client: Client,
     pub cache: Mutex<LruTtlCache>,
-    pub last_query: Mutex<String>,
-    pub last_route: Mutex<String>,
+    /// Embedding vectors, kept apart so they never evict chat answers.
+    pub embeddings_cache: Mutex<LruTtlCache>,
+    pub activity: Mutex<ActivityLog>,
+    pub moderation: Moderator,
+    pub personas: PersonaRegistry,
+    pub templates: TemplateLibrary,
+    pub tools: ToolRegistry,
+    pub documents: DocumentStore,
+    pub pii: PiiRedactor,
+    pub residency: ResidencyPolicy,
+    pub api_keys: Mutex<ApiKeyStore>,
+    pub oidc: Option<OidcClient>,
+    pub usage: Mutex<UsageStore>,
+    pub budget: Mutex<CloudBudget>,
+    pub rate_limiter: RateLimiter,
+    pub trusted_proxies: TrustedProxies,
+    /// One permit per streaming generation, `MAX_CONCURRENT_STREAMS` in total.
+    pub stream_slots: Arc<Semaphore>,
+    pub queue: GenerationQueue,
+    pub tokens: TokenEstimators,
+    pub summarizer: HistorySummarizer,
     pub metrics: RuntimeMetrics,
 }
+
+#[cfg(test)]
+impl AppState {
+    /// The state `main` builds, with everything it persists kept under
+    /// `cfg.data_dir`.
+    pub fn for_tests(mut cfg: AppConfig) -> Self {
+        let data_dir = std::path::Path::new(&cfg.data_dir).to_path_buf();
+        cfg.personas_dir = data_dir.join("personas").display().to_string();
+        let tools = ToolRegistry::builtin();
+        Self {
+            client: Client::new(),
+            cache: Mutex::new(LruTtlCache::new(
+                cfg.response_cache_size,
+                cfg.response_cache_ttl_seconds,
+            )),
+            embeddings_cache: Mutex::new(LruTtlCache::new(
+                cfg.embeddings_cache_size,
+                cfg.embeddings_cache_ttl_seconds,
+            )),
+            activity: Mutex::new(ActivityLog::new(cfg.activity_log_size)),
+            moderation: Moderator::load(&cfg).unwrap(),
+            personas: PersonaRegistry::load(&cfg, &tools).unwrap(),
+            templates: TemplateLibrary::load(&cfg).unwrap(),
+            tools,
+            documents: DocumentStore::load(&cfg).unwrap(),
+            pii: PiiRedactor::load(&cfg).unwrap(),
+            residency: ResidencyPolicy::from_config(&cfg),
+            api_keys: Mutex::new(ApiKeyStore::load(data_dir.join("api_keys.json")).unwrap()),
+            oidc: None,
+            usage: Mutex::new(
+                UsageStore::load(
+                    &cfg,
+                    data_dir.join("usage.json"),
+                    data_dir.join("quota_overrides.json"),
+                )
+                .unwrap(),
+            ),
+            budget: Mutex::new(CloudBudget::load(&cfg, data_dir.join("cloud_spend.json")).unwrap()),
+            rate_limiter: RateLimiter::from_config(&cfg).unwrap(),
+            trusted_proxies: TrustedProxies::from_config(&cfg).unwrap(),
+            stream_slots: Arc::new(Semaphore::new(cfg.max_concurrent_streams)),
+            queue: GenerationQueue::from_config(&cfg),
+            tokens: TokenEstimators::from_config(&cfg).unwrap(),
+            summarizer: HistorySummarizer::new(&cfg),
+            metrics: RuntimeMetrics::new(),
+            cfg,
+        }
+    }
+}
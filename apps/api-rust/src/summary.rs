@@ -0,0 +1,340 @@
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::api_keys::to_hex;
+use crate::cache::{CachedAnswer, LruTtlCache};
+use crate::config::AppConfig;
+use crate::events::StreamEvent;
+use crate::models::{ChatMessage, Provider};
+use crate::providers::complete_ollama;
+use crate::queue::Lane;
+use crate::state::AppState;
+
+const SUMMARY_PREFIX: &str = "Conversation summary of earlier messages:";
+
+/// Summaries of dropped conversation prefixes, made with the fast local
+/// model and cached so a long conversation is not re-summarized every turn.
+pub struct HistorySummarizer {
+    cache: Mutex<LruTtlCache>,
+}
+
+impl HistorySummarizer {
+    pub fn new(cfg: &AppConfig) -> Self {
+        Self {
+            cache: Mutex::new(LruTtlCache::new(
+                cfg.summary_cache_size,
+                cfg.summary_cache_ttl_seconds,
+            )),
+        }
+    }
+
+    /// Returns the synthetic system message standing in for `dropped`, or
+    /// `None` when the fast model could not produce one in time.
+    pub async fn summarize(
+        &self,
+        app_state: &AppState,
+        dropped: &[ChatMessage],
+        user: &str,
+        lane: Lane,
+        tx: &mpsc::Sender<StreamEvent>,
+    ) -> Option<ChatMessage> {
+        let model = &app_state.cfg.local_model_fast;
+        let keys = prefix_keys(model, dropped);
+
+        // A summary of a shorter prefix is extended instead of starting over.
+        let cached = self.cache.lock().ok().and_then(|mut cache| {
+            keys.iter()
+                .enumerate()
+                .rev()
+                .find_map(|(i, key)| cache.get(key).map(|hit| (i + 1, hit.text)))
+        });
+        let (covered, previous) = match cached {
+            Some((covered, summary)) if covered == dropped.len() => {
+                app_state.metrics.incr_summary_cache_hit();
+                return Some(summary_message(summary));
+            }
+            Some((covered, summary)) => (covered, Some(summary)),
+            None => (0, None),
+        };
+
+        let slot = app_state
+            .queue
+            .acquire(
+                model,
+                &Provider::Local,
+                user,
+                lane,
+                tx,
+                Duration::from_millis(app_state.cfg.queue_timeout_ms),
+            )
+            .await;
+        let Ok(_slot) = slot else {
+            app_state.metrics.incr_summary_failure();
+            warn!("history summary skipped: queue timeout for {}", model);
+            return None;
+        };
+
+        let prompt = summary_prompt(
+            previous.as_deref(),
+            &dropped[covered..],
+            app_state.cfg.summary_max_tokens,
+        );
+        let limit = Duration::from_millis(app_state.cfg.upstream_timeout_ms);
+        let request = complete_ollama(
+            &app_state.client,
+            &app_state.cfg,
+            model,
+            &prompt,
+            app_state.cfg.summary_max_tokens,
+        );
+        let completion = match timeout(limit, request).await {
+            Ok(Ok(completion)) if !completion.text.is_empty() => completion,
+            Ok(Ok(_)) => {
+                app_state.metrics.incr_summary_failure();
+                warn!("history summary skipped: empty answer from {}", model);
+                return None;
+            }
+            Ok(Err(err)) => {
+                app_state.metrics.incr_summary_failure();
+                warn!("history summary failed: {}", err);
+                return None;
+            }
+            Err(_) => {
+                app_state.metrics.incr_summary_failure();
+                warn!("history summary timed out");
+                return None;
+            }
+        };
+
+        app_state.metrics.incr_summary_generated();
+        if let Some(usage) = &completion.usage {
+            app_state
+                .metrics
+                .record_token_usage(&Provider::Local, usage);
+        }
+        info!(
+            "summarized {} earlier messages with {}",
+            dropped.len() - covered,
+            model
+        );
+
+        let summary = completion.text;
+        if let (Ok(mut cache), Some(key)) = (self.cache.lock(), keys.last()) {
+            cache.put(
+                key.clone(),
+                CachedAnswer {
+                    text: summary.clone(),
+                    usage: completion.usage,
+                },
+            );
+        }
+        Some(summary_message(summary))
+    }
+}
+
+/// One key per prefix length, each chained from the previous one, so any
+/// earlier prefix of the same conversation can be looked up cheaply.
+fn prefix_keys(model: &str, messages: &[ChatMessage]) -> Vec<String> {
+    let mut key = to_hex(&Sha256::digest(model.as_bytes()));
+    messages
+        .iter()
+        .map(|m| {
+            let mut hasher = Sha256::new();
+            hasher.update(key.as_bytes());
+            hasher.update(m.role.as_bytes());
+            hasher.update([0]);
+            hasher.update(m.content.as_bytes());
+            key = to_hex(&hasher.finalize());
+            key.clone()
+        })
+        .collect()
+}
+
+fn summary_prompt(
+    previous: Option<&str>,
+    messages: &[ChatMessage],
+    max_tokens: u64,
+) -> Vec<ChatMessage> {
+    let mut transcript = String::new();
+    if let Some(previous) = previous {
+        transcript.push_str("Summary so far:\n");
+        transcript.push_str(previous);
+        transcript.push_str("\n\nLater messages:\n");
+    }
+    for message in messages {
+        let _ = writeln!(transcript, "{}: {}", message.role, message.content);
+    }
+
+    vec![
+        ChatMessage {
+            role: "system".to_string(),
+            content: format!(
+                "You condense the earlier part of a conversation between a student and a campus assistant. \
+                 Write a summary of at most {} words that keeps names, facts, decisions and open questions. \
+                 Reply with the summary only.",
+                max_tokens * 3 / 4
+            ),
+        },
+        ChatMessage {
+            role: "user".to_string(),
+            content: transcript,
+        },
+    ]
+}
+
+pub fn summary_message(summary: String) -> ChatMessage {
+    ChatMessage {
+        role: "system".to_string(),
+        content: format!("{SUMMARY_PREFIX}\n{summary}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::api_keys::random_hex;
+
+    /// A local model that answers `summary <n>`, or nothing once `n`
+    /// reaches `empty_after`, and records every prompt it was sent.
+    fn summarizer_model(empty_after: usize) -> (AppState, Arc<Mutex<Vec<Value>>>) {
+        let prompts = Arc::new(Mutex::new(Vec::new()));
+        let seen = prompts.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = HttpServer::new(move || {
+            let seen = seen.clone();
+            App::new().route(
+                "/api/chat",
+                web::post().to(move |body: web::Json<Value>| {
+                    let seen = seen.clone();
+                    async move {
+                        let mut seen = seen.lock().unwrap();
+                        seen.push(body.into_inner());
+                        let n = seen.len();
+                        let text = if n > empty_after {
+                            String::new()
+                        } else {
+                            format!("summary {n}")
+                        };
+                        HttpResponse::Ok().json(json!({
+                            "message": {"content": text}, "done": true,
+                            "prompt_eval_count": 40, "eval_count": 5,
+                        }))
+                    }
+                }),
+            )
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+
+        let mut cfg = AppConfig::from_env();
+        cfg.data_dir = std::env::temp_dir()
+            .join(format!("campus-summary-{}", random_hex(8)))
+            .display()
+            .to_string();
+        cfg.local_model_base_url = base;
+        cfg.local_model_fast = "fast".to_string();
+        (AppState::for_tests(cfg), prompts)
+    }
+
+    fn turns(n: usize) -> Vec<ChatMessage> {
+        (0..n)
+            .map(|i| ChatMessage {
+                role: if i % 2 == 0 { "user" } else { "assistant" }.to_string(),
+                content: format!("turn {i}"),
+            })
+            .collect()
+    }
+
+    fn transcript(prompt: &Value) -> String {
+        prompt["messages"][1]["content"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    async fn summarize(state: &AppState, dropped: &[ChatMessage]) -> String {
+        let (tx, _rx) = mpsc::channel(16);
+        let summary = state
+            .summarizer
+            .summarize(state, dropped, "u", Lane::Interactive, &tx)
+            .await;
+        summary.unwrap().content
+    }
+
+    #[actix_web::test]
+    async fn longer_prefixes_extend_the_cached_summary() {
+        let (state, prompts) = summarizer_model(usize::MAX);
+        let history = turns(6);
+
+        let first = summarize(&state, &history[..2]).await;
+        assert_eq!(first, format!("{SUMMARY_PREFIX}\nsummary 1"));
+        assert!(transcript(&prompts.lock().unwrap()[0]).contains("user: turn 0"));
+
+        // The same prefix is served from the cache.
+        assert_eq!(summarize(&state, &history[..2]).await, first);
+        assert_eq!(prompts.lock().unwrap().len(), 1);
+
+        // A longer one sends only the new messages along with the old summary.
+        let second = summarize(&state, &history[..4]).await;
+        assert_eq!(second, format!("{SUMMARY_PREFIX}\nsummary 2"));
+        let extended = transcript(&prompts.lock().unwrap()[1]);
+        assert!(extended.starts_with("Summary so far:\nsummary 1\n"));
+        assert!(extended.contains("user: turn 2\nassistant: turn 3"));
+        assert!(!extended.contains("turn 1"));
+
+        let metrics = &state.metrics;
+        assert_eq!(metrics.summaries_generated_total.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.summary_cache_hits_total.load(Ordering::Relaxed), 1);
+        std::fs::remove_dir_all(&state.cfg.data_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn an_empty_summary_is_not_used_or_cached() {
+        let (state, prompts) = summarizer_model(0);
+        let (tx, _rx) = mpsc::channel(16);
+        let history = turns(2);
+
+        for _ in 0..2 {
+            let summary = state
+                .summarizer
+                .summarize(&state, &history, "u", Lane::Interactive, &tx)
+                .await;
+            assert!(summary.is_none());
+        }
+        assert_eq!(prompts.lock().unwrap().len(), 2);
+        let failures = state.metrics.summary_failures_total.load(Ordering::Relaxed);
+        assert_eq!(failures, 2);
+        std::fs::remove_dir_all(&state.cfg.data_dir).unwrap();
+    }
+
+    #[test]
+    fn prefix_keys_depend_on_the_model_and_every_earlier_message() {
+        let history = turns(3);
+        let keys = prefix_keys("fast", &history);
+        assert_eq!(keys.len(), 3);
+        assert_eq!(prefix_keys("fast", &history[..2]), keys[..2]);
+        assert_ne!(prefix_keys("other", &history)[0], keys[0]);
+
+        let mut edited = history.clone();
+        edited[0].content.push('!');
+        let edited = prefix_keys("fast", &edited);
+        assert!(edited.iter().zip(&keys).all(|(a, b)| a != b));
+    }
+}
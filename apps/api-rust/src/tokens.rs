@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::AppConfig;
+use crate::models::{ChatMessage, Provider};
+
+/// Chat templates add a few tokens of role markup around every message.
+const MESSAGE_OVERHEAD_TOKENS: u64 = 4;
+
+/// Counts tokens for one model family. A real tokenizer can be plugged in
+/// with [`TokenEstimators::register`].
+pub trait TokenEstimator: Send + Sync {
+    fn count(&self, text: &str) -> u64;
+
+    /// Feedback from a provider-reported prompt token count; estimators that
+    /// are exact can ignore it.
+    fn observe(&self, _chars: u64, _tokens: u64) {}
+}
+
+/// Characters-per-token estimate that drifts towards the ratio the provider
+/// actually reports, so it stays calibrated for the model in use.
+pub struct CalibratedEstimator {
+    /// `f64` bits; updated lock-free from finished generations.
+    chars_per_token: AtomicU64,
+    calibrate: bool,
+}
+
+impl CalibratedEstimator {
+    pub fn new(chars_per_token: f64, calibrate: bool) -> Self {
+        Self {
+            chars_per_token: AtomicU64::new(chars_per_token.to_bits()),
+            calibrate,
+        }
+    }
+
+    pub fn chars_per_token(&self) -> f64 {
+        f64::from_bits(self.chars_per_token.load(Ordering::Relaxed))
+    }
+}
+
+impl TokenEstimator for CalibratedEstimator {
+    fn count(&self, text: &str) -> u64 {
+        (text.chars().count() as f64 / self.chars_per_token()).ceil() as u64
+    }
+
+    fn observe(&self, chars: u64, tokens: u64) {
+        // Tiny prompts are dominated by template tokens and say little.
+        if !self.calibrate || tokens < 32 {
+            return;
+        }
+        let observed = (chars as f64 / tokens as f64).clamp(1.5, 8.0);
+        let blended = self.chars_per_token() * 0.8 + observed * 0.2;
+        self.chars_per_token
+            .store(blended.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Estimators keyed by model name prefix; the longest matching prefix wins.
+pub struct TokenEstimators {
+    by_prefix: Vec<(String, Box<dyn TokenEstimator>)>,
+    calibrate: bool,
+    default_ratio: f64,
+    /// Models seen without a configured prefix get their own estimator so
+    /// calibration for one does not skew another.
+    learned: Mutex<HashMap<String, Arc<CalibratedEstimator>>>,
+}
+
+impl TokenEstimators {
+    /// Parses `TOKEN_CHARS_PER_TOKEN`, e.g. `default=4.0,qwen2.5=3.4`.
+    pub fn from_config(cfg: &AppConfig) -> Result<Self, String> {
+        let mut estimators = Self {
+            by_prefix: Vec::new(),
+            calibrate: cfg.token_calibration,
+            default_ratio: 4.0,
+            learned: Default::default(),
+        };
+        for entry in cfg
+            .token_chars_per_token
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let (prefix, ratio) = entry
+                .split_once('=')
+                .and_then(|(prefix, ratio)| {
+                    let ratio: f64 = ratio.trim().parse().ok()?;
+                    (ratio > 0.0).then(|| (prefix.trim(), ratio))
+                })
+                .ok_or_else(|| {
+                    format!("TOKEN_CHARS_PER_TOKEN entry '{entry}' must look like model=3.5")
+                })?;
+            if prefix == "default" {
+                estimators.default_ratio = ratio;
+            } else {
+                let estimator = CalibratedEstimator::new(ratio, cfg.token_calibration);
+                estimators.register(prefix, Box::new(estimator));
+            }
+        }
+        Ok(estimators)
+    }
+
+    pub fn register(&mut self, prefix: &str, estimator: Box<dyn TokenEstimator>) {
+        self.by_prefix.retain(|(p, _)| p != prefix);
+        self.by_prefix.push((prefix.to_string(), estimator));
+        self.by_prefix.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    }
+
+    fn with_estimator<T>(&self, model: &str, f: impl FnOnce(&dyn TokenEstimator) -> T) -> T {
+        if let Some((_, estimator)) = self.by_prefix.iter().find(|(p, _)| model.starts_with(p)) {
+            return f(estimator.as_ref());
+        }
+        let learned = self.learned.lock().ok().map(|mut learned| {
+            learned
+                .entry(model.to_string())
+                .or_insert_with(|| {
+                    Arc::new(CalibratedEstimator::new(self.default_ratio, self.calibrate))
+                })
+                .clone()
+        });
+        match learned {
+            Some(estimator) => f(estimator.as_ref()),
+            None => f(&CalibratedEstimator::new(self.default_ratio, false)),
+        }
+    }
+
+    pub fn count_message(&self, model: &str, message: &ChatMessage) -> u64 {
+        self.with_estimator(model, |e| e.count(&message.content)) + MESSAGE_OVERHEAD_TOKENS
+    }
+
+    pub fn count_messages(&self, model: &str, messages: &[ChatMessage]) -> u64 {
+        messages.iter().map(|m| self.count_message(model, m)).sum()
+    }
+
+    /// Calibrates against the prompt token count a provider reported for
+    /// `messages`.
+    pub fn observe(&self, model: &str, messages: &[ChatMessage], prompt_tokens: u64) {
+        let chars: u64 = messages
+            .iter()
+            .map(|m| m.content.chars().count() as u64)
+            .sum();
+        let overhead = MESSAGE_OVERHEAD_TOKENS * messages.len() as u64;
+        self.with_estimator(model, |e| {
+            e.observe(chars, prompt_tokens.saturating_sub(overhead))
+        });
+    }
+}
+
+/// Prompt token budget for one model: its context window minus the tokens
+/// kept free for the answer.
+pub fn prompt_budget(cfg: &AppConfig, provider: &Provider) -> u64 {
+    let window = match provider {
+        Provider::Local => u64::from(cfg.local_num_ctx),
+        Provider::Cloud => cfg.cloud_context_tokens,
+    };
+    window.saturating_sub(cfg.reserved_completion_tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimators(ratios: &str, calibrate: bool) -> TokenEstimators {
+        let mut cfg = AppConfig::from_env();
+        cfg.token_chars_per_token = ratios.to_string();
+        cfg.token_calibration = calibrate;
+        TokenEstimators::from_config(&cfg).unwrap()
+    }
+
+    fn message(content: &str) -> ChatMessage {
+        ChatMessage {
+            role: "user".to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn the_longest_matching_prefix_sets_the_ratio() {
+        let tokens = estimators("default=4.0,qwen=2.0,qwen2.5=5.0", false);
+        let text = "x".repeat(100);
+        assert_eq!(tokens.count_message("qwen2.5:7b", &message(&text)), 20 + 4);
+        assert_eq!(tokens.count_message("qwen3:8b", &message(&text)), 50 + 4);
+        assert_eq!(tokens.count_message("llama3", &message(&text)), 25 + 4);
+        assert_eq!(
+            tokens.count_messages("llama3", &[message("abc"), message("abcde")]),
+            1 + 2 + 2 * MESSAGE_OVERHEAD_TOKENS
+        );
+    }
+
+    #[test]
+    fn reported_prompt_counts_calibrate_each_model_separately() {
+        let tokens = estimators("default=4.0", true);
+        let prompt = [message(&"y".repeat(400))];
+        // The provider saw 200 tokens: two characters per token.
+        tokens.observe("gemma", &prompt, 200 + MESSAGE_OVERHEAD_TOKENS);
+        let after = tokens.count_messages("gemma", &prompt);
+        assert!(after > 100 + MESSAGE_OVERHEAD_TOKENS, "{after}");
+        assert_eq!(
+            tokens.count_messages("llama3", &prompt),
+            100 + MESSAGE_OVERHEAD_TOKENS
+        );
+
+        // Tiny prompts and disabled calibration leave the ratio alone.
+        let estimator = CalibratedEstimator::new(4.0, true);
+        estimator.observe(40, 10);
+        assert_eq!(estimator.chars_per_token(), 4.0);
+        let fixed = CalibratedEstimator::new(4.0, false);
+        fixed.observe(4000, 2000);
+        assert_eq!(fixed.chars_per_token(), 4.0);
+    }
+
+    #[test]
+    fn bad_ratios_are_rejected() {
+        let mut cfg = AppConfig::from_env();
+        for raw in ["qwen", "qwen=abc", "qwen=0", "default=-1"] {
+            cfg.token_chars_per_token = raw.to_string();
+            assert!(TokenEstimators::from_config(&cfg).is_err(), "{raw}");
+        }
+    }
+
+    #[test]
+    fn the_prompt_budget_leaves_room_for_the_answer() {
+        let mut cfg = AppConfig::from_env();
+        cfg.local_num_ctx = 4096;
+        cfg.cloud_context_tokens = 128_000;
+        cfg.reserved_completion_tokens = 512;
+        assert_eq!(prompt_budget(&cfg, &Provider::Local), 3584);
+        assert_eq!(prompt_budget(&cfg, &Provider::Cloud), 127_488);
+        cfg.reserved_completion_tokens = 10_000;
+        assert_eq!(prompt_budget(&cfg, &Provider::Local), 0);
+    }
+}
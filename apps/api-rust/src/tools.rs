@@ -0,0 +1,952 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+use tracing::info;
+
+use crate::events::StreamEvent;
+use crate::providers::{Completion, TokenUsage};
+use crate::schema::schema_errors;
+use crate::state::RuntimeMetrics;
+
+/// Longest tool output handed back to the model, in characters.
+const MAX_OUTPUT_CHARS: usize = 4000;
+/// Calls past this many in one model turn are answered with an error.
+const MAX_CALLS_PER_TURN: usize = 8;
+
+/// A function the model may call while answering. Arguments are checked
+/// against `parameters` before `execute` runs.
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    /// JSON Schema of the arguments object.
+    fn parameters(&self) -> Value;
+    fn execute(&self, args: Value) -> BoxFuture<'_, Result<Value, String>>;
+}
+
+/// Every tool the server can offer; personas pick from it by name.
+pub struct ToolRegistry {
+    tools: BTreeMap<String, Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn builtin() -> Self {
+        let tools: [Arc<dyn Tool>; 3] = [
+            Arc::new(Calculator),
+            Arc::new(DateTime),
+            Arc::new(UnitConvert),
+        ];
+        Self {
+            tools: tools
+                .into_iter()
+                .map(|tool| (tool.name().to_string(), tool))
+                .collect(),
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tools.contains_key(name)
+    }
+
+    /// The named tools, skipping names that are not registered.
+    pub fn select(&self, names: &[String]) -> Vec<Arc<dyn Tool>> {
+        names
+            .iter()
+            .filter_map(|name| self.tools.get(name).cloned())
+            .collect()
+    }
+
+    pub fn list(&self) -> Vec<ToolInfo> {
+        self.tools
+            .values()
+            .map(|tool| ToolInfo {
+                name: tool.name().to_string(),
+                description: tool.description().to_string(),
+                parameters: tool.parameters(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ToolInfo {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
+/// A call the model asked for.
+#[derive(Serialize, Clone, Debug)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+}
+
+/// One model turn that ended in tool calls, with what each call returned.
+#[derive(Clone, Debug)]
+pub struct ToolRound {
+    pub calls: Vec<ToolCall>,
+    /// Serialized results, in the order of `calls`.
+    pub outputs: Vec<String>,
+}
+
+/// Sent as a `tool_result` event and kept in the report.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolResult {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+    pub ok: bool,
+    pub output: Value,
+    pub duration_ms: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ToolLimits {
+    /// Model turns that may end in tool calls; the turn after the last one
+    /// is offered no tools, so the model has to answer.
+    pub max_iterations: u32,
+    pub call_timeout: Duration,
+}
+
+/// Calls the model until it answers without asking for a tool, running the
+/// calls it makes in between. `call` makes one provider call given the
+/// rounds so far and whether tools are still offered. The completion holds
+/// the final turn's text and the usage of every turn.
+pub async fn run_tool_loop<F, Fut>(
+    tools: &[Arc<dyn Tool>],
+    limits: ToolLimits,
+    metrics: &RuntimeMetrics,
+    tx: &mpsc::Sender<StreamEvent>,
+    mut call: F,
+) -> Result<Completion, String>
+where
+    F: FnMut(Vec<ToolRound>, bool) -> Fut,
+    Fut: Future<Output = Result<Completion, String>>,
+{
+    let mut rounds: Vec<ToolRound> = Vec::new();
+    let mut results = Vec::new();
+    let mut usage: Option<TokenUsage> = None;
+
+    loop {
+        let offer = !tools.is_empty() && (rounds.len() as u32) < limits.max_iterations;
+        let mut completion = call(rounds.clone(), offer).await?;
+        if let Some(reported) = completion.usage {
+            usage = Some(usage.map_or(reported, |u| u.combined(reported)));
+        }
+        let calls = std::mem::take(&mut completion.tool_calls);
+        if !offer || calls.is_empty() {
+            if !calls.is_empty() {
+                info!(
+                    "ignoring {} tool calls after {} iterations",
+                    calls.len(),
+                    rounds.len()
+                );
+            }
+            completion.usage = usage;
+            completion.tool_results = results;
+            return Ok(completion);
+        }
+
+        let mut outputs = Vec::new();
+        for (i, call) in calls.iter().enumerate() {
+            let _ = tx.send(StreamEvent::ToolCall(call.clone())).await;
+            let started = Instant::now();
+            let outcome = if i < MAX_CALLS_PER_TURN {
+                execute(tools, call, limits.call_timeout).await
+            } else {
+                Err(format!(
+                    "at most {MAX_CALLS_PER_TURN} tool calls are allowed per turn"
+                ))
+            };
+            metrics.incr_tool_call();
+            let (ok, output) = match outcome {
+                Ok(value) => (true, value),
+                Err(error) => {
+                    metrics.incr_tool_failure();
+                    (false, json!({ "error": error }))
+                }
+            };
+            let result = ToolResult {
+                id: call.id.clone(),
+                name: call.name.clone(),
+                arguments: call.arguments.clone(),
+                ok,
+                output,
+                duration_ms: started.elapsed().as_millis() as u64,
+            };
+            outputs.push(
+                result
+                    .output
+                    .to_string()
+                    .chars()
+                    .take(MAX_OUTPUT_CHARS)
+                    .collect(),
+            );
+            let _ = tx.send(StreamEvent::ToolResult(result.clone())).await;
+            results.push(result);
+        }
+        rounds.push(ToolRound { calls, outputs });
+    }
+}
+
+async fn execute(
+    tools: &[Arc<dyn Tool>],
+    call: &ToolCall,
+    limit: Duration,
+) -> Result<Value, String> {
+    let tool = tools
+        .iter()
+        .find(|tool| tool.name() == call.name)
+        .ok_or_else(|| format!("unknown tool '{}'", call.name))?;
+    let errors = schema_errors(&tool.parameters(), &call.arguments);
+    if !errors.is_empty() {
+        return Err(format!("invalid arguments: {}", errors.join("; ")));
+    }
+    timeout(limit, tool.execute(call.arguments.clone()))
+        .await
+        .map_err(|_| format!("timed out after {} ms", limit.as_millis()))?
+}
+
+struct Calculator;
+
+impl Tool for Calculator {
+    fn name(&self) -> &str {
+        "calculator"
+    }
+
+    fn description(&self) -> &str {
+        "Evaluates an arithmetic expression. Supports + - * / % ^, parentheses, pi, e and the functions sqrt, abs, exp, ln, log10, log2, sin, cos, tan, asin, acos, atan, floor, ceil, round, min and max."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "required": ["expression"],
+            "additionalProperties": false,
+            "properties": {
+                "expression": { "type": "string", "minLength": 1, "maxLength": 500 }
+            }
+        })
+    }
+
+    fn execute(&self, args: Value) -> BoxFuture<'_, Result<Value, String>> {
+        Box::pin(async move {
+            let expression = args["expression"].as_str().unwrap_or_default();
+            let result = Parser::new(expression).evaluate()?;
+            Ok(json!({ "expression": expression, "result": result }))
+        })
+    }
+}
+
+/// Recursive-descent evaluator for the calculator tool.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(input: &str) -> Self {
+        Self {
+            chars: input.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn evaluate(mut self) -> Result<f64, String> {
+        let value = self.expression()?;
+        self.skip_spaces();
+        if let Some(c) = self.peek() {
+            return Err(format!("unexpected '{c}' at position {}", self.pos + 1));
+        }
+        if !value.is_finite() {
+            return Err("the result is not a finite number".to_string());
+        }
+        Ok(value)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        self.skip_spaces();
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expression(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value += self.term()?;
+            } else if self.eat('-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value *= self.unary()?;
+            } else if self.eat('/') {
+                let divisor = self.unary()?;
+                if divisor == 0.0 {
+                    return Err("division by zero".to_string());
+                }
+                value /= divisor;
+            } else if self.eat('%') {
+                let divisor = self.unary()?;
+                if divisor == 0.0 {
+                    return Err("division by zero".to_string());
+                }
+                value %= divisor;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.eat('-') {
+            return Ok(-self.unary()?);
+        }
+        if self.eat('+') {
+            return self.unary();
+        }
+        let base = self.atom()?;
+        if self.eat('^') {
+            // Right-associative, and binds tighter than a leading minus.
+            return Ok(base.powf(self.unary()?));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64, String> {
+        self.skip_spaces();
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let value = self.expression()?;
+                if !self.eat(')') {
+                    return Err("missing ')'".to_string());
+                }
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => self.identifier(),
+            Some(c) => Err(format!("unexpected '{c}' at position {}", self.pos + 1)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_digit() || c == '.' || c == '_')
+        {
+            self.pos += 1;
+        }
+        if matches!(self.peek(), Some('e' | 'E'))
+            && self
+                .chars
+                .get(self.pos + 1)
+                .is_some_and(|c| c.is_ascii_digit() || *c == '-' || *c == '+')
+        {
+            self.pos += 2;
+            while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                self.pos += 1;
+            }
+        }
+        let text: String = self.chars[start..self.pos]
+            .iter()
+            .filter(|c| **c != '_')
+            .collect();
+        text.parse().map_err(|_| format!("invalid number '{text}'"))
+    }
+
+    fn identifier(&mut self) -> Result<f64, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric()) {
+            self.pos += 1;
+        }
+        let name: String = self.chars[start..self.pos].iter().collect();
+        match name.as_str() {
+            "pi" => return Ok(std::f64::consts::PI),
+            "e" => return Ok(std::f64::consts::E),
+            _ => {}
+        }
+
+        if !self.eat('(') {
+            return Err(format!("unknown name '{name}'"));
+        }
+        let mut args = vec![self.expression()?];
+        while self.eat(',') {
+            args.push(self.expression()?);
+        }
+        if !self.eat(')') {
+            return Err(format!("missing ')' after the arguments of {name}"));
+        }
+
+        let one = |f: fn(f64) -> f64| match args.as_slice() {
+            [x] => Ok(f(*x)),
+            _ => Err(format!("{name} takes one argument")),
+        };
+        match name.as_str() {
+            "sqrt" => one(f64::sqrt),
+            "abs" => one(f64::abs),
+            "exp" => one(f64::exp),
+            "ln" => one(f64::ln),
+            "log10" | "log" => one(f64::log10),
+            "log2" => one(f64::log2),
+            "sin" => one(f64::sin),
+            "cos" => one(f64::cos),
+            "tan" => one(f64::tan),
+            "asin" => one(f64::asin),
+            "acos" => one(f64::acos),
+            "atan" => one(f64::atan),
+            "floor" => one(f64::floor),
+            "ceil" => one(f64::ceil),
+            "round" => one(f64::round),
+            "min" => Ok(args.iter().copied().fold(f64::INFINITY, f64::min)),
+            "max" => Ok(args.iter().copied().fold(f64::NEG_INFINITY, f64::max)),
+            _ => Err(format!("unknown function '{name}'")),
+        }
+    }
+}
+
+struct DateTime;
+
+impl Tool for DateTime {
+    fn name(&self) -> &str {
+        "datetime"
+    }
+
+    fn description(&self) -> &str {
+        "Date and time helper. 'now' gives the current date and time, optionally at a UTC offset; 'add_days' shifts a date; 'days_between' counts days from date to other; 'weekday' names the day of a date. Dates are YYYY-MM-DD."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "required": ["operation"],
+            "additionalProperties": false,
+            "properties": {
+                "operation": { "enum": ["now", "add_days", "days_between", "weekday"] },
+                "date": { "type": "string", "pattern": "^[0-9]{4}-[0-9]{2}-[0-9]{2}$" },
+                "other": { "type": "string", "pattern": "^[0-9]{4}-[0-9]{2}-[0-9]{2}$" },
+                "days": { "type": "integer", "minimum": -100000, "maximum": 100000 },
+                "utcOffset": { "type": "string", "pattern": "^[+-][0-9]{2}:[0-9]{2}$" }
+            }
+        })
+    }
+
+    fn execute(&self, args: Value) -> BoxFuture<'_, Result<Value, String>> {
+        Box::pin(async move {
+            let date = |key: &str| match args.get(key).and_then(Value::as_str) {
+                Some(text) => parse_date(text),
+                None => Err(format!("'{key}' is required for this operation")),
+            };
+            match args["operation"].as_str().unwrap_or_default() {
+                "now" => {
+                    let offset_minutes = match args.get("utcOffset").and_then(Value::as_str) {
+                        Some(offset) => parse_offset(offset)?,
+                        None => 0,
+                    };
+                    let seconds = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs() as i64)
+                        .unwrap_or(0)
+                        + offset_minutes * 60;
+                    let days = seconds.div_euclid(86_400);
+                    let time = seconds.rem_euclid(86_400);
+                    Ok(json!({
+                        "date": format_date(days),
+                        "time": format!("{:02}:{:02}:{:02}", time / 3600, time / 60 % 60, time % 60),
+                        "weekday": weekday(days),
+                        "utcOffset": format!(
+                            "{}{:02}:{:02}",
+                            if offset_minutes < 0 { '-' } else { '+' },
+                            offset_minutes.abs() / 60,
+                            offset_minutes.abs() % 60
+                        ),
+                    }))
+                }
+                "add_days" => {
+                    let days = args
+                        .get("days")
+                        .and_then(Value::as_i64)
+                        .ok_or("'days' is required for add_days")?;
+                    let result = date("date")? + days;
+                    Ok(json!({ "date": format_date(result), "weekday": weekday(result) }))
+                }
+                "days_between" => Ok(json!({ "days": date("other")? - date("date")? })),
+                "weekday" => Ok(json!({ "weekday": weekday(date("date")?) })),
+                other => Err(format!("unknown operation '{other}'")),
+            }
+        })
+    }
+}
+
+/// Days since 1970-01-01 for a `YYYY-MM-DD` date.
+fn parse_date(text: &str) -> Result<i64, String> {
+    let invalid = || format!("invalid date '{text}'");
+    let mut parts = text.split('-');
+    let mut next = |width: usize| digits(parts.next(), width).ok_or_else(invalid);
+    let (year, month, day) = (next(4)?, next(2)?, next(2)?);
+    if parts.next().is_some()
+        || !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month)
+    {
+        return Err(invalid());
+    }
+    Ok(days_from_civil(year, month, day))
+}
+
+/// Minutes east of UTC for a `+HH:MM` or `-HH:MM` offset.
+fn parse_offset(text: &str) -> Result<i64, String> {
+    let invalid = || format!("invalid utcOffset '{text}'");
+    let (sign, rest) = match text.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, text.strip_prefix('+').ok_or_else(invalid)?),
+    };
+    let (hours, minutes) = rest.split_once(':').ok_or_else(invalid)?;
+    let hours = digits(Some(hours), 2).ok_or_else(invalid)?;
+    let minutes = digits(Some(minutes), 2).ok_or_else(invalid)?;
+    if hours > 14 || minutes > 59 {
+        return Err(format!("utcOffset '{text}' is out of range"));
+    }
+    Ok(sign * (hours * 60 + minutes))
+}
+
+/// Exactly `width` ASCII digits.
+fn digits(part: Option<&str>, width: usize) -> Option<i64> {
+    part.filter(|p| p.len() == width && p.bytes().all(|b| b.is_ascii_digit()))?
+        .parse()
+        .ok()
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Proleptic Gregorian conversions after Howard Hinnant's `days_from_civil`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn format_date(days: i64) -> String {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn weekday(days: i64) -> &'static str {
+    const NAMES: [&str; 7] = [
+        "Thursday",
+        "Friday",
+        "Saturday",
+        "Sunday",
+        "Monday",
+        "Tuesday",
+        "Wednesday",
+    ];
+    NAMES[days.rem_euclid(7) as usize]
+}
+
+struct UnitConvert;
+
+/// Unit names, the quantity they measure and their size in that quantity's
+/// base unit. Temperatures are converted separately.
+const UNITS: &[(&[&str], &str, f64)] = &[
+    (&["m", "meter", "meters", "metre", "metres"], "length", 1.0),
+    (
+        &["km", "kilometer", "kilometers", "kilometre", "kilometres"],
+        "length",
+        1000.0,
+    ),
+    (
+        &[
+            "cm",
+            "centimeter",
+            "centimeters",
+            "centimetre",
+            "centimetres",
+        ],
+        "length",
+        0.01,
+    ),
+    (
+        &[
+            "mm",
+            "millimeter",
+            "millimeters",
+            "millimetre",
+            "millimetres",
+        ],
+        "length",
+        0.001,
+    ),
+    (&["mi", "mile", "miles"], "length", 1609.344),
+    (&["yd", "yard", "yards"], "length", 0.9144),
+    (&["ft", "foot", "feet"], "length", 0.3048),
+    (&["in", "inch", "inches"], "length", 0.0254),
+    (&["kg", "kilogram", "kilograms"], "mass", 1.0),
+    (&["g", "gram", "grams"], "mass", 0.001),
+    (&["mg", "milligram", "milligrams"], "mass", 0.000_001),
+    (&["t", "tonne", "tonnes"], "mass", 1000.0),
+    (&["lb", "lbs", "pound", "pounds"], "mass", 0.453_592_37),
+    (&["oz", "ounce", "ounces"], "mass", 0.028_349_523_125),
+    (&["l", "liter", "liters", "litre", "litres"], "volume", 1.0),
+    (
+        &[
+            "ml",
+            "milliliter",
+            "milliliters",
+            "millilitre",
+            "millilitres",
+        ],
+        "volume",
+        0.001,
+    ),
+    (&["gal", "gallon", "gallons"], "volume", 3.785_411_784),
+    (&["qt", "quart", "quarts"], "volume", 0.946_352_946),
+    (&["pt", "pint", "pints"], "volume", 0.473_176_473),
+    (&["cup", "cups"], "volume", 0.236_588_236_5),
+    (
+        &["floz", "fl oz", "fluid ounce", "fluid ounces"],
+        "volume",
+        0.029_573_529_562_5,
+    ),
+    (&["s", "sec", "second", "seconds"], "time", 1.0),
+    (&["ms", "millisecond", "milliseconds"], "time", 0.001),
+    (&["min", "minute", "minutes"], "time", 60.0),
+    (&["h", "hr", "hour", "hours"], "time", 3600.0),
+    (&["d", "day", "days"], "time", 86_400.0),
+    (&["week", "weeks"], "time", 604_800.0),
+    (&["m/s", "mps"], "speed", 1.0),
+    (&["km/h", "kph", "kmh"], "speed", 1.0 / 3.6),
+    (&["mph"], "speed", 0.447_04),
+    (&["kn", "knot", "knots"], "speed", 0.514_444),
+    (&["b", "byte", "bytes"], "data", 1.0),
+    (&["kb", "kilobyte", "kilobytes"], "data", 1e3),
+    (&["mb", "megabyte", "megabytes"], "data", 1e6),
+    (&["gb", "gigabyte", "gigabytes"], "data", 1e9),
+    (&["tb", "terabyte", "terabytes"], "data", 1e12),
+    (&["kib", "kibibyte", "kibibytes"], "data", 1024.0),
+    (&["mib", "mebibyte", "mebibytes"], "data", 1_048_576.0),
+    (&["gib", "gibibyte", "gibibytes"], "data", 1_073_741_824.0),
+];
+
+#[derive(Clone, Copy)]
+enum Unit {
+    Scaled(&'static str, f64),
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl Unit {
+    fn parse(name: &str) -> Result<Unit, String> {
+        let normalized = name.trim().to_lowercase();
+        match normalized.as_str() {
+            "c" | "°c" | "celsius" => return Ok(Unit::Celsius),
+            "f" | "°f" | "fahrenheit" => return Ok(Unit::Fahrenheit),
+            "k" | "kelvin" => return Ok(Unit::Kelvin),
+            _ => {}
+        }
+        UNITS
+            .iter()
+            .find(|(names, _, _)| names.contains(&normalized.as_str()))
+            .map(|(_, quantity, factor)| Unit::Scaled(quantity, *factor))
+            .ok_or_else(|| format!("unknown unit '{name}'"))
+    }
+
+    fn quantity(self) -> &'static str {
+        match self {
+            Unit::Scaled(quantity, _) => quantity,
+            _ => "temperature",
+        }
+    }
+
+    /// In the base unit: meters, kilograms, liters, seconds, m/s, bytes or
+    /// kelvin.
+    fn base_value(self, value: f64) -> f64 {
+        match self {
+            Unit::Scaled(_, factor) => value * factor,
+            Unit::Celsius => value + 273.15,
+            Unit::Fahrenheit => (value - 32.0) * 5.0 / 9.0 + 273.15,
+            Unit::Kelvin => value,
+        }
+    }
+
+    fn value_of(self, base: f64) -> f64 {
+        match self {
+            Unit::Scaled(_, factor) => base / factor,
+            Unit::Celsius => base - 273.15,
+            Unit::Fahrenheit => (base - 273.15) * 9.0 / 5.0 + 32.0,
+            Unit::Kelvin => base,
+        }
+    }
+}
+
+impl Tool for UnitConvert {
+    fn name(&self) -> &str {
+        "unit_convert"
+    }
+
+    fn description(&self) -> &str {
+        "Converts a value between units of length, mass, volume, time, speed, data size or temperature, for example km to mi, lb to kg, F to C or GiB to GB."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "required": ["value", "from", "to"],
+            "additionalProperties": false,
+            "properties": {
+                "value": { "type": "number" },
+                "from": { "type": "string", "minLength": 1, "maxLength": 32 },
+                "to": { "type": "string", "minLength": 1, "maxLength": 32 }
+            }
+        })
+    }
+
+    fn execute(&self, args: Value) -> BoxFuture<'_, Result<Value, String>> {
+        Box::pin(async move {
+            let value = args["value"].as_f64().unwrap_or_default();
+            let from = Unit::parse(args["from"].as_str().unwrap_or_default())?;
+            let to = Unit::parse(args["to"].as_str().unwrap_or_default())?;
+            if from.quantity() != to.quantity() {
+                return Err(format!(
+                    "cannot convert {} to {}",
+                    from.quantity(),
+                    to.quantity()
+                ));
+            }
+            let result = to.value_of(from.base_value(value));
+            // Twelve significant digits hide float noise such as
+            // 0.30000000000000004.
+            let result: f64 = format!("{result:.11e}").parse().unwrap_or(result);
+            Ok(json!({
+                "value": value,
+                "from": args["from"],
+                "to": args["to"],
+                "result": result,
+                "quantity": from.quantity(),
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calculate(expression: &str) -> Result<f64, String> {
+        Parser::new(expression).evaluate()
+    }
+
+    async fn call(name: &str, arguments: Value) -> Result<Value, String> {
+        let registry = ToolRegistry::builtin();
+        let tools = registry.select(&[name.to_string()]);
+        let call = ToolCall {
+            id: "call-1".to_string(),
+            name: name.to_string(),
+            arguments,
+        };
+        execute(&tools, &call, Duration::from_secs(1)).await
+    }
+
+    #[test]
+    fn calculator_follows_precedence() {
+        assert_eq!(calculate("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(calculate("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(calculate("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(calculate("2 ^ 3 ^ 2"), Ok(512.0));
+        assert_eq!(calculate("-2 ^ 2"), Ok(-4.0));
+        assert_eq!(calculate("7 % 4 + 1_000"), Ok(1003.0));
+        assert_eq!(calculate("max(1, sqrt(16), 2) * 1.5e1"), Ok(60.0));
+        assert_eq!(calculate("round(pi * 100)"), Ok(314.0));
+    }
+
+    #[test]
+    fn calculator_reports_errors() {
+        assert_eq!(calculate("1 / 0"), Err("division by zero".to_string()));
+        assert_eq!(
+            calculate("5 % (2 - 2)"),
+            Err("division by zero".to_string())
+        );
+        assert_eq!(calculate("(1 + 2"), Err("missing ')'".to_string()));
+        assert_eq!(
+            calculate("1 +"),
+            Err("unexpected end of expression".to_string())
+        );
+        assert_eq!(
+            calculate("2 3"),
+            Err("unexpected '3' at position 3".to_string())
+        );
+        assert_eq!(calculate("foo"), Err("unknown name 'foo'".to_string()));
+        assert_eq!(
+            calculate("sqrt(1, 2)"),
+            Err("sqrt takes one argument".to_string())
+        );
+        assert_eq!(
+            calculate("1.2.3"),
+            Err("invalid number '1.2.3'".to_string())
+        );
+        assert_eq!(
+            calculate("10 ^ 400"),
+            Err("the result is not a finite number".to_string())
+        );
+    }
+
+    #[test]
+    fn dates_round_trip_across_leap_years() {
+        assert_eq!(parse_date("1970-01-01"), Ok(0));
+        assert_eq!(format_date(parse_date("2024-02-29").unwrap()), "2024-02-29");
+        assert_eq!(
+            format_date(parse_date("2024-02-28").unwrap() + 1),
+            "2024-02-29"
+        );
+        assert_eq!(
+            format_date(parse_date("2023-02-28").unwrap() + 1),
+            "2023-03-01"
+        );
+        assert_eq!(
+            format_date(parse_date("1999-12-31").unwrap() + 366),
+            "2000-12-31"
+        );
+        assert!(parse_date("2023-02-29").is_err());
+        assert!(parse_date("1900-02-29").is_err());
+        assert!(parse_date("2000-02-29").is_ok());
+        assert_eq!(weekday(parse_date("2000-01-01").unwrap()), "Saturday");
+        assert_eq!(format_date(-1), "1969-12-31");
+    }
+
+    #[test]
+    fn malformed_dates_and_offsets_are_errors() {
+        for date in [
+            "2024-1-01",
+            "2024-01-01-01",
+            "+024-01-01",
+            "2024-13-01",
+            "٢٠٢٤-01-01",
+        ] {
+            assert!(parse_date(date).is_err(), "{date}");
+        }
+        assert_eq!(parse_offset("+05:30"), Ok(330));
+        assert_eq!(parse_offset("-03:00"), Ok(-180));
+        assert_eq!(parse_offset("+00:00"), Ok(0));
+        assert!(parse_offset("+15:00").is_err());
+        assert!(parse_offset("+01:60").is_err());
+        for offset in ["+0١:00", "+1:00", "05:00", "+", "", "-0530", "+05:30:00"] {
+            assert!(parse_offset(offset).is_err(), "{offset}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn datetime_tool_does_date_math() {
+        let added = call(
+            "datetime",
+            json!({"operation": "add_days", "date": "2024-02-28", "days": 2}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(added, json!({"date": "2024-03-01", "weekday": "Friday"}));
+
+        let between = call(
+            "datetime",
+            json!({"operation": "days_between", "date": "2024-01-01", "other": "2025-01-01"}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(between, json!({"days": 366}));
+
+        let now = call(
+            "datetime",
+            json!({"operation": "now", "utcOffset": "-09:30"}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(now["utcOffset"], "-09:30");
+
+        let rejected = call(
+            "datetime",
+            json!({"operation": "now", "utcOffset": "+0١:00"}),
+        )
+        .await;
+        assert!(rejected.unwrap_err().starts_with("invalid arguments"));
+    }
+
+    #[actix_web::test]
+    async fn unit_convert_handles_scales_and_temperatures() {
+        let convert = |value: f64, from: &str, to: &str| {
+            call(
+                "unit_convert",
+                json!({"value": value, "from": from, "to": to}),
+            )
+        };
+        assert_eq!(convert(1.0, "mi", "km").await.unwrap()["result"], 1.609344);
+        assert_eq!(
+            convert(2.0, "GiB", "MB").await.unwrap()["result"],
+            2147.483648
+        );
+        assert_eq!(convert(0.1, "l", "ml").await.unwrap()["result"], 100.0);
+        assert_eq!(convert(212.0, "F", "C").await.unwrap()["result"], 100.0);
+        assert_eq!(
+            convert(0.0, "celsius", "K").await.unwrap()["result"],
+            273.15
+        );
+        assert_eq!(
+            convert(1.0, "kg", "km").await,
+            Err("cannot convert mass to length".to_string())
+        );
+        assert_eq!(
+            convert(1.0, "parsec", "m").await,
+            Err("unknown unit 'parsec'".to_string())
+        );
+    }
+}
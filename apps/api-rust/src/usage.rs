@@ -0,0 +1,306 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::api_keys::write_atomic;
+use crate::auth::Role;
+use crate::budget;
+use crate::config::AppConfig;
+use crate::report::unix_seconds;
+use crate::state::AppState;
+
+/// Days of history kept in the usage file.
+const RETAINED_DAYS: usize = 31;
+
+/// How often changed counters are written to disk.
+const SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Daily limits; `None` means unlimited.
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaLimits {
+    pub requests_per_day: Option<u64>,
+    pub tokens_per_day: Option<u64>,
+    pub cloud_escalations_per_day: Option<u64>,
+}
+
+impl QuotaLimits {
+    /// Parses `requests=200,tokens=200000,cloud=5`. Omitted fields stay
+    /// unlimited.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let mut limits = QuotaLimits::default();
+        limits.merge(raw)?;
+        Ok(limits)
+    }
+
+    fn merge(&mut self, raw: &str) -> Result<(), String> {
+        for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("quota entry '{entry}' must look like name=value"))?;
+            let value: u64 = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("quota entry '{entry}' needs a whole number"))?;
+            match name.trim() {
+                "requests" => self.requests_per_day = Some(value),
+                "tokens" => self.tokens_per_day = Some(value),
+                "cloud" => self.cloud_escalations_per_day = Some(value),
+                other => {
+                    return Err(format!(
+                        "unknown quota '{other}'; expected requests, tokens or cloud"
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageCounters {
+    pub requests: u64,
+    pub tokens: u64,
+    pub cloud_escalations: u64,
+}
+
+/// Which daily limit a request ran into.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaExceeded {
+    pub quota: &'static str,
+    pub limit: u64,
+    pub used: u64,
+    /// Seconds until the counters reset at 00:00 UTC.
+    pub retry_after_seconds: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct UsageFile {
+    days: BTreeMap<String, HashMap<String, UsageCounters>>,
+}
+
+/// Per-principal daily usage, saved to `DATA_DIR/usage.json` by
+/// [`save_periodically`] so restarts do not reset anyone's quota.
+pub struct UsageStore {
+    path: PathBuf,
+    file: UsageFile,
+    unsaved: bool,
+    role_limits: HashMap<Option<Role>, QuotaLimits>,
+    overrides: HashMap<String, String>,
+}
+
+impl UsageStore {
+    pub fn load(cfg: &AppConfig, path: PathBuf, overrides_path: PathBuf) -> Result<Self, String> {
+        let file = match fs::read_to_string(&path) {
+            Ok(raw) => serde_json::from_str(&raw)
+                .map_err(|e| format!("invalid usage store {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => UsageFile::default(),
+            Err(e) => return Err(format!("cannot read usage store {}: {e}", path.display())),
+        };
+
+        let overrides: HashMap<String, String> = match fs::read_to_string(&overrides_path) {
+            Ok(raw) => serde_json::from_str(&raw).map_err(|e| {
+                format!("invalid quota overrides {}: {e}", overrides_path.display())
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                return Err(format!(
+                    "cannot read quota overrides {}: {e}",
+                    overrides_path.display()
+                ))
+            }
+        };
+        for (principal, raw) in &overrides {
+            QuotaLimits::parse(raw).map_err(|e| format!("quota override for {principal}: {e}"))?;
+        }
+
+        let mut role_limits = HashMap::new();
+        for (role, name, raw) in [
+            (None, "QUOTA_ANONYMOUS", &cfg.quota_anonymous),
+            (Some(Role::Student), "QUOTA_STUDENT", &cfg.quota_student),
+            (Some(Role::Staff), "QUOTA_STAFF", &cfg.quota_staff),
+            (Some(Role::Admin), "QUOTA_ADMIN", &cfg.quota_admin),
+        ] {
+            role_limits.insert(
+                role,
+                QuotaLimits::parse(raw).map_err(|e| format!("{name}: {e}"))?,
+            );
+        }
+
+        Ok(Self {
+            path,
+            file,
+            unsaved: false,
+            role_limits,
+            overrides,
+        })
+    }
+
+    /// Role limits with any per-principal override applied on top.
+    pub fn limits_for(&self, principal: &str, role: Option<Role>) -> QuotaLimits {
+        let mut limits = self.role_limits.get(&role).copied().unwrap_or_default();
+        if let Some(raw) = self.overrides.get(principal) {
+            // Validated at load time.
+            let _ = limits.merge(raw);
+        }
+        limits
+    }
+
+    pub fn today(&self, principal: &str) -> UsageCounters {
+        self.file
+            .days
+            .get(&utc_date(unix_seconds()))
+            .and_then(|day| day.get(principal))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn day(&self, date: &str) -> HashMap<String, UsageCounters> {
+        self.file.days.get(date).cloned().unwrap_or_default()
+    }
+
+    /// Counts a new request, or refuses it when the request or token quota
+    /// for today is used up.
+    pub fn begin_request(
+        &mut self,
+        principal: &str,
+        role: Option<Role>,
+    ) -> Result<(), QuotaExceeded> {
+        let limits = self.limits_for(principal, role);
+        let used = self.today(principal);
+
+        let exceeded = |quota, limit: Option<u64>, used: u64| {
+            limit.filter(|l| used >= *l).map(|limit| QuotaExceeded {
+                quota,
+                limit,
+                used,
+                retry_after_seconds: seconds_until_midnight(),
+            })
+        };
+        if let Some(e) = exceeded("requests", limits.requests_per_day, used.requests)
+            .or_else(|| exceeded("tokens", limits.tokens_per_day, used.tokens))
+        {
+            return Err(e);
+        }
+
+        self.update(principal, |c| c.requests += 1);
+        Ok(())
+    }
+
+    /// Gives back a request counted by `begin_request` that was not served.
+    pub fn refund_request(&mut self, principal: &str) {
+        self.update(principal, |c| c.requests = c.requests.saturating_sub(1));
+    }
+
+    pub fn cloud_escalation_available(&self, principal: &str, role: Option<Role>) -> bool {
+        let limit = self.limits_for(principal, role).cloud_escalations_per_day;
+        limit.map_or(true, |l| self.today(principal).cloud_escalations < l)
+    }
+
+    pub fn record_tokens(&mut self, principal: &str, tokens: u64) {
+        if tokens > 0 {
+            self.update(principal, |c| c.tokens += tokens);
+        }
+    }
+
+    pub fn record_cloud_escalation(&mut self, principal: &str) {
+        self.update(principal, |c| c.cloud_escalations += 1);
+    }
+
+    fn update(&mut self, principal: &str, change: impl FnOnce(&mut UsageCounters)) {
+        let today = utc_date(unix_seconds());
+        change(
+            self.file
+                .days
+                .entry(today)
+                .or_default()
+                .entry(principal.to_string())
+                .or_default(),
+        );
+        while self.file.days.len() > RETAINED_DAYS {
+            self.file.days.pop_first();
+        }
+        self.unsaved = true;
+    }
+
+    /// The file contents to write, when counters changed since the last call.
+    fn take_unsaved(&mut self) -> Option<(PathBuf, String)> {
+        if !std::mem::take(&mut self.unsaved) {
+            return None;
+        }
+        match serde_json::to_string(&self.file) {
+            Ok(raw) => Some((self.path.clone(), raw)),
+            Err(err) => {
+                warn!("usage store not saved: {}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Writes changed counters and cloud spend every `SAVE_INTERVAL`, off the
+/// request path and outside the store locks, and once more when `stop` is
+/// notified.
+pub async fn save_periodically(data: web::Data<AppState>, stop: Arc<Notify>) {
+    let mut ticks = tokio::time::interval(SAVE_INTERVAL);
+    loop {
+        let stopping = tokio::select! {
+            _ = ticks.tick() => false,
+            _ = stop.notified() => true,
+        };
+        save(&data).await;
+        budget::save(&data).await;
+        if stopping {
+            return;
+        }
+    }
+}
+
+async fn save(data: &AppState) {
+    let Some((path, raw)) = data.usage.lock().ok().and_then(|mut u| u.take_unsaved()) else {
+        return;
+    };
+    let result = tokio::task::spawn_blocking(move || write_atomic(&path, &raw))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    if let Err(err) = result {
+        warn!("usage store not saved: {}", err);
+        // Retried on the next tick.
+        if let Ok(mut usage) = data.usage.lock() {
+            usage.unsaved = true;
+        }
+    }
+}
+
+/// Rough token count for text the providers did not count for us.
+pub fn estimate_tokens(text: &str) -> u64 {
+    (text.chars().count() as u64).div_ceil(4)
+}
+
+fn seconds_until_midnight() -> u64 {
+    86_400 - unix_seconds() % 86_400
+}
+
+/// `YYYY-MM-DD` for a unix timestamp, in UTC.
+pub fn utc_date(secs: u64) -> String {
+    // Civil-from-days conversion (Howard Hinnant's algorithm).
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
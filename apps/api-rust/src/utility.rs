@@ -0,0 +1,793 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::api_keys::write_atomic;
+use crate::config::AppConfig;
+use crate::models::ChatMessage;
+use crate::persona::{ClientSystem, Persona, Tier};
+use crate::schema::ResponseSchema;
+
+/// Seeded into an empty template directory on first start.
+const BUILTIN_TEMPLATES: &str = r#"[
+  {
+    "id": "summary",
+    "title": "Executive Summary",
+    "description": "Create a concise summary.",
+    "systemPrompt": "You write executive summaries for {{audience}}. Lead with the single most important point, then give at most {{bullets}} short bullet points covering decisions, figures and open questions. Use only facts from the input and never add new ones.",
+    "prompt": "Input:\n{{input}}",
+    "outputFormat": "markdown",
+    "preferredTier": "fast",
+    "variables": [
+      { "name": "input", "label": "Text to summarize", "type": "text", "required": true, "multiline": true },
+      { "name": "audience", "label": "Audience", "type": "enum", "options": ["students", "staff", "leadership"], "default": "staff" },
+      { "name": "bullets", "label": "Bullet points", "type": "number", "integer": true, "min": 1, "max": 10, "default": 5 }
+    ]
+  },
+  {
+    "id": "email",
+    "title": "Professional Email",
+    "description": "Draft a polished email response.",
+    "systemPrompt": "You draft professional emails for a university setting in a {{tone}} tone. Write a subject line, a greeting, a short, direct body and a closing. Keep placeholders such as [Name] where details are missing instead of inventing them.",
+    "prompt": "Recipient: {{recipient}}\n\nWhat the email should say:\n{{input}}",
+    "outputFormat": "text",
+    "preferredTier": "balanced",
+    "variables": [
+      { "name": "input", "label": "Points to cover", "type": "text", "required": true, "multiline": true },
+      { "name": "recipient", "label": "Recipient", "type": "text", "maxLength": 200, "default": "unspecified" },
+      { "name": "tone", "label": "Tone", "type": "enum", "options": ["formal", "friendly", "firm"], "default": "formal" }
+    ]
+  },
+  {
+    "id": "plan",
+    "title": "Action Plan",
+    "description": "Generate a tactical execution plan.",
+    "systemPrompt": "You turn goals into tactical action plans that fit in {{weeks}} weeks. Give a one-sentence objective, then numbered steps, each with an owner placeholder, a rough duration and a done criterion. Finish with the main risks and how to mitigate them.",
+    "prompt": "Goal:\n{{input}}",
+    "outputFormat": "markdown",
+    "preferredTier": "quality",
+    "variables": [
+      { "name": "input", "label": "Goal", "type": "text", "required": true, "multiline": true },
+      { "name": "weeks", "label": "Timeframe (weeks)", "type": "number", "integer": true, "min": 1, "max": 52, "default": 4 }
+    ]
+  },
+  {
+    "id": "quiz",
+    "title": "Quiz Generator",
+    "description": "Turn course material into multiple-choice questions.",
+    "systemPrompt": "You write {{difficulty}} multiple-choice quiz questions from course material. Write exactly {{questions}} questions, each with four options, the index of the correct option and a one-sentence explanation. Only ask about facts in the material.",
+    "prompt": "Course material:\n{{input}}",
+    "outputFormat": "json",
+    "schema": {
+      "type": "object",
+      "required": ["questions"],
+      "additionalProperties": false,
+      "properties": {
+        "questions": {
+          "type": "array",
+          "minItems": 1,
+          "items": {
+            "type": "object",
+            "required": ["question", "options", "answer", "explanation"],
+            "additionalProperties": false,
+            "properties": {
+              "question": { "type": "string", "minLength": 1 },
+              "options": { "type": "array", "items": { "type": "string" }, "minItems": 4, "maxItems": 4 },
+              "answer": { "type": "integer", "minimum": 0, "maximum": 3 },
+              "explanation": { "type": "string" }
+            }
+          }
+        }
+      }
+    },
+    "preferredTier": "balanced",
+    "variables": [
+      { "name": "input", "label": "Course material", "type": "text", "required": true, "multiline": true },
+      { "name": "questions", "label": "Questions", "type": "number", "integer": true, "min": 1, "max": 20, "default": 5 },
+      { "name": "difficulty", "label": "Difficulty", "type": "enum", "options": ["introductory", "intermediate", "advanced"], "default": "introductory" }
+    ]
+  }
+]"#;
+
+/// Shape of a utility's answer, enforced through its system prompt.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Markdown,
+    Text,
+    /// JSON matching the template's `schema`, validated server-side.
+    Json,
+}
+
+impl OutputFormat {
+    fn instruction(self) -> &'static str {
+        match self {
+            OutputFormat::Markdown => "Format the answer as Markdown.",
+            OutputFormat::Text => "Reply in plain text without Markdown.",
+            OutputFormat::Json => "Reply with JSON only.",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum VariableKind {
+    Text {
+        #[serde(default, rename = "maxLength", skip_serializing_if = "Option::is_none")]
+        max_length: Option<usize>,
+        /// Rendered as a text area rather than a single line.
+        #[serde(default)]
+        multiline: bool,
+    },
+    Enum {
+        options: Vec<String>,
+    },
+    Number {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max: Option<f64>,
+        #[serde(default)]
+        integer: bool,
+    },
+}
+
+/// One typed input of a template, referenced as `{{name}}` in its prompts.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TemplateVariable {
+    pub name: String,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+    #[serde(flatten)]
+    pub kind: VariableKind,
+}
+
+impl TemplateVariable {
+    /// Checks one value and returns the text substituted for it.
+    fn render_value(&self, value: &Value) -> Result<String, String> {
+        match &self.kind {
+            VariableKind::Text { max_length, .. } => {
+                let text = value
+                    .as_str()
+                    .ok_or_else(|| format!("variable '{}' must be a string", self.name))?;
+                if let Some(max) = max_length.filter(|max| text.chars().count() > *max) {
+                    return Err(format!(
+                        "variable '{}' is longer than {max} characters",
+                        self.name
+                    ));
+                }
+                Ok(text.trim().to_string())
+            }
+            VariableKind::Enum { options } => {
+                let choice = value.as_str().filter(|v| options.iter().any(|o| o == v));
+                choice.map(str::to_string).ok_or_else(|| {
+                    format!(
+                        "variable '{}' must be one of {}",
+                        self.name,
+                        options.join(", ")
+                    )
+                })
+            }
+            VariableKind::Number { min, max, integer } => {
+                let number = match value {
+                    Value::Number(n) => n.as_f64(),
+                    Value::String(s) => s.trim().parse().ok(),
+                    _ => None,
+                }
+                .filter(|n: &f64| n.is_finite())
+                .ok_or_else(|| format!("variable '{}' must be a number", self.name))?;
+                if *integer && number.fract() != 0.0 {
+                    return Err(format!("variable '{}' must be a whole number", self.name));
+                }
+                if min.is_some_and(|min| number < min) || max.is_some_and(|max| number > max) {
+                    return Err(format!(
+                        "variable '{}' must be between {} and {}",
+                        self.name,
+                        min.map_or("-inf".to_string(), |v| v.to_string()),
+                        max.map_or("inf".to_string(), |v| v.to_string())
+                    ));
+                }
+                Ok(number.to_string())
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let valid_name = self
+            .name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic())
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(format!(
+                "variable name '{}' must start with a letter and use letters, digits and underscores",
+                self.name
+            ));
+        }
+        match &self.kind {
+            VariableKind::Enum { options } if options.is_empty() => {
+                return Err(format!("enum variable '{}' needs options", self.name));
+            }
+            VariableKind::Number {
+                min: Some(min),
+                max: Some(max),
+                ..
+            } if min > max => {
+                return Err(format!("variable '{}' has min above max", self.name));
+            }
+            _ => {}
+        }
+        if let Some(default) = &self.default {
+            self.render_value(default)
+                .map_err(|e| format!("default for {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// A prompt recipe: system prompt and user prompt with `{{variable}}`
+/// placeholders, the output format and the preferred routing tier.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct UtilityTemplate {
+    pub id: String,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    pub system_prompt: String,
+    pub prompt: String,
+    pub output_format: OutputFormat,
+    /// JSON Schema for the `json` output format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<Value>,
+    pub preferred_tier: Tier,
+    #[serde(default)]
+    pub variables: Vec<TemplateVariable>,
+}
+
+impl UtilityTemplate {
+    pub fn validate(&self) -> Result<(), String> {
+        let valid_id = !self.id.is_empty()
+            && self.id.len() <= 64
+            && self
+                .id
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !valid_id {
+            return Err(format!(
+                "template id '{}' must be lowercase letters, digits and dashes",
+                self.id
+            ));
+        }
+        if self.title.trim().is_empty() {
+            return Err("title cannot be empty".to_string());
+        }
+        if self.prompt.trim().is_empty() || self.system_prompt.trim().is_empty() {
+            return Err("systemPrompt and prompt cannot be empty".to_string());
+        }
+        match (self.output_format, self.response_schema()) {
+            (OutputFormat::Json, Some(schema)) => {
+                schema.check().map_err(|e| format!("invalid schema: {e}"))?;
+            }
+            (OutputFormat::Json, None) => {
+                return Err("the json output format needs a schema".to_string());
+            }
+            (_, Some(_)) => {
+                return Err("schema is only used with the json output format".to_string());
+            }
+            (_, None) => {}
+        }
+
+        let mut names = HashSet::new();
+        for variable in &self.variables {
+            variable.validate()?;
+            if !names.insert(variable.name.as_str()) {
+                return Err(format!("variable '{}' is declared twice", variable.name));
+            }
+        }
+        for text in [&self.system_prompt, &self.prompt] {
+            for placeholder in placeholders(text) {
+                if !names.contains(placeholder) {
+                    return Err(format!(
+                        "placeholder {{{{{placeholder}}}}} has no matching variable"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The schema answers must match, named after the template.
+    pub fn response_schema(&self) -> Option<ResponseSchema> {
+        self.schema.clone().map(|schema| ResponseSchema {
+            name: self.id.clone(),
+            schema,
+        })
+    }
+
+    /// The template as a persona, so generation takes the same path as
+    /// chat: its prompt is the system message and its tier the routing floor.
+    fn persona(&self, system_prompt: String) -> Persona {
+        Persona {
+            id: format!("utility:{}", self.id),
+            name: self.title.clone(),
+            description: self.description.clone(),
+            system_prompt: format!("{} {}", system_prompt, self.output_format.instruction()),
+            default_tier: Some(self.preferred_tier),
+            temperature: None,
+            top_p: None,
+            allowed_tools: Vec::new(),
+            client_system: ClientSystem::Ignore,
+        }
+    }
+
+    /// Validates `values` against the variables, fills in defaults and
+    /// returns the persona and the user message to generate with.
+    pub fn render(
+        &self,
+        values: &BTreeMap<String, Value>,
+    ) -> Result<(Persona, ChatMessage), String> {
+        if let Some(unknown) = values
+            .keys()
+            .find(|k| !self.variables.iter().any(|v| &v.name == *k))
+        {
+            return Err(format!(
+                "template '{}' has no variable '{unknown}'",
+                self.id
+            ));
+        }
+
+        let mut rendered = BTreeMap::new();
+        for variable in &self.variables {
+            let value = values
+                .get(&variable.name)
+                .filter(|v| !v.is_null() && v.as_str().map_or(true, |s| !s.trim().is_empty()))
+                .or(variable.default.as_ref());
+            let text = match value {
+                Some(value) => variable.render_value(value)?,
+                None if variable.required => {
+                    return Err(format!("variable '{}' is required", variable.name));
+                }
+                None => String::new(),
+            };
+            rendered.insert(variable.name.as_str(), text);
+        }
+
+        let fill = |text: &str| {
+            let mut out = text.to_string();
+            for (name, value) in &rendered {
+                out = out.replace(&format!("{{{{{name}}}}}"), value);
+            }
+            out
+        };
+        let message = ChatMessage {
+            role: "user".to_string(),
+            content: fill(&self.prompt),
+        };
+        Ok((self.persona(fill(&self.system_prompt)), message))
+    }
+}
+
+/// Names inside `{{...}}` placeholders.
+fn placeholders(text: &str) -> impl Iterator<Item = &str> {
+    text.split("{{")
+        .skip(1)
+        .filter_map(|part| part.split_once("}}").map(|(name, _)| name.trim()))
+}
+
+/// Why a template change was refused.
+#[derive(Debug)]
+pub enum TemplateError {
+    Invalid(String),
+    NotFound,
+    Exists,
+    Storage(String),
+}
+
+type FileStamp = (PathBuf, Option<SystemTime>, u64);
+
+struct Loaded {
+    templates: BTreeMap<String, UtilityTemplate>,
+    stamps: Vec<FileStamp>,
+    checked: Instant,
+}
+
+/// Templates stored as `<id>.json` in `UTILITY_TEMPLATES_DIR`. Edits made
+/// on disk are picked up within `UTILITY_TEMPLATES_RELOAD_SECONDS`; edits
+/// made through the API apply at once.
+pub struct TemplateLibrary {
+    dir: PathBuf,
+    reload_interval: Option<Duration>,
+    loaded: Mutex<Loaded>,
+}
+
+impl TemplateLibrary {
+    pub fn load(cfg: &AppConfig) -> Result<Self, String> {
+        let dir = if cfg.utility_templates_dir.is_empty() {
+            Path::new(&cfg.data_dir).join("utility_templates")
+        } else {
+            PathBuf::from(&cfg.utility_templates_dir)
+        };
+
+        if !dir.exists() {
+            let builtins: Vec<UtilityTemplate> = serde_json::from_str(BUILTIN_TEMPLATES)
+                .map_err(|e| format!("invalid built-in templates: {e}"))?;
+            for template in &builtins {
+                write_template(&dir, template)?;
+            }
+            info!(
+                "seeded {} utility templates into {}",
+                builtins.len(),
+                dir.display()
+            );
+        }
+
+        let (templates, stamps) = scan(&dir)?;
+        info!(
+            "loaded {} utility templates from {}",
+            templates.len(),
+            dir.display()
+        );
+        Ok(Self {
+            dir,
+            reload_interval: (cfg.utility_templates_reload_seconds > 0)
+                .then(|| Duration::from_secs(cfg.utility_templates_reload_seconds)),
+            loaded: Mutex::new(Loaded {
+                templates,
+                stamps,
+                checked: Instant::now(),
+            }),
+        })
+    }
+
+    /// Rescans the directory when the reload interval has passed and any
+    /// file was added, removed or modified since the last scan.
+    fn refresh(&self, loaded: &mut Loaded) {
+        let Some(interval) = self.reload_interval else {
+            return;
+        };
+        if loaded.checked.elapsed() < interval {
+            return;
+        }
+        loaded.checked = Instant::now();
+        match stamps(&self.dir) {
+            Ok(stamps) if stamps == loaded.stamps => {}
+            Ok(_) => self.rescan(loaded),
+            Err(err) => warn!("utility templates not reloaded: {}", err),
+        }
+    }
+
+    fn rescan(&self, loaded: &mut Loaded) {
+        match scan(&self.dir) {
+            Ok((templates, stamps)) => {
+                info!(
+                    "reloaded {} utility templates from {}",
+                    templates.len(),
+                    self.dir.display()
+                );
+                loaded.templates = templates;
+                loaded.stamps = stamps;
+            }
+            Err(err) => warn!("utility templates not reloaded: {}", err),
+        }
+    }
+
+    pub fn list(&self) -> Vec<UtilityTemplate> {
+        let Ok(mut loaded) = self.loaded.lock() else {
+            return Vec::new();
+        };
+        self.refresh(&mut loaded);
+        loaded.templates.values().cloned().collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<UtilityTemplate> {
+        let mut loaded = self.loaded.lock().ok()?;
+        self.refresh(&mut loaded);
+        loaded.templates.get(id).cloned()
+    }
+
+    /// Creates a template, or replaces an existing one when `replace` is set.
+    pub fn save(&self, template: UtilityTemplate, replace: bool) -> Result<(), TemplateError> {
+        template.validate().map_err(TemplateError::Invalid)?;
+        let mut loaded = self
+            .loaded
+            .lock()
+            .map_err(|_| TemplateError::Storage("template library unavailable".to_string()))?;
+        self.refresh(&mut loaded);
+        match (loaded.templates.contains_key(&template.id), replace) {
+            (true, false) => return Err(TemplateError::Exists),
+            (false, true) => return Err(TemplateError::NotFound),
+            _ => {}
+        }
+        write_template(&self.dir, &template).map_err(TemplateError::Storage)?;
+        self.rescan(&mut loaded);
+        Ok(())
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), TemplateError> {
+        let mut loaded = self
+            .loaded
+            .lock()
+            .map_err(|_| TemplateError::Storage("template library unavailable".to_string()))?;
+        self.refresh(&mut loaded);
+        if !loaded.templates.contains_key(id) {
+            return Err(TemplateError::NotFound);
+        }
+        let path = self.dir.join(format!("{id}.json"));
+        fs::remove_file(&path).map_err(|e| {
+            TemplateError::Storage(format!("cannot remove {}: {e}", path.display()))
+        })?;
+        self.rescan(&mut loaded);
+        Ok(())
+    }
+}
+
+fn write_template(dir: &Path, template: &UtilityTemplate) -> Result<(), String> {
+    let raw = serde_json::to_string_pretty(template)
+        .map_err(|e| format!("cannot encode template '{}': {e}", template.id))?;
+    write_atomic(&dir.join(format!("{}.json", template.id)), &raw)
+}
+
+fn stamps(dir: &Path) -> Result<Vec<FileStamp>, String> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format!("cannot read UTILITY_TEMPLATES_DIR {}: {e}", dir.display()))?;
+    let mut stamps: Vec<FileStamp> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .map(|path| {
+            let meta = fs::metadata(&path).ok();
+            let modified = meta.as_ref().and_then(|m| m.modified().ok());
+            let len = meta.map_or(0, |m| m.len());
+            (path, modified, len)
+        })
+        .collect();
+    stamps.sort();
+    Ok(stamps)
+}
+
+/// Loads every valid template; broken files are skipped with a warning so
+/// one bad edit does not take the others down.
+fn scan(dir: &Path) -> Result<(BTreeMap<String, UtilityTemplate>, Vec<FileStamp>), String> {
+    let stamps = stamps(dir)?;
+    let mut templates = BTreeMap::new();
+    for (path, _, _) in &stamps {
+        let parsed = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|raw| {
+                serde_json::from_str::<UtilityTemplate>(&raw).map_err(|e| e.to_string())
+            })
+            .and_then(|template| template.validate().map(|_| template));
+        match parsed {
+            Ok(template) if path.file_stem().is_some_and(|stem| *stem == *template.id) => {
+                templates.insert(template.id.clone(), template);
+            }
+            Ok(template) => warn!(
+                "skipping utility template {}: id '{}' does not match the file name",
+                path.display(),
+                template.id
+            ),
+            Err(err) => warn!("skipping utility template {}: {}", path.display(), err),
+        }
+    }
+    Ok((templates, stamps))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::api_keys::random_hex;
+
+    fn builtin(id: &str) -> UtilityTemplate {
+        let builtins: Vec<UtilityTemplate> = serde_json::from_str(BUILTIN_TEMPLATES).unwrap();
+        builtins.into_iter().find(|t| t.id == id).unwrap()
+    }
+
+    fn values(pairs: &[(&str, Value)]) -> BTreeMap<String, Value> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn builtin_templates_are_valid() {
+        let builtins: Vec<UtilityTemplate> = serde_json::from_str(BUILTIN_TEMPLATES).unwrap();
+        for template in &builtins {
+            template.validate().unwrap();
+        }
+        assert!(builtin("quiz").response_schema().is_some());
+        assert!(builtin("email").response_schema().is_none());
+    }
+
+    #[test]
+    fn rendering_fills_the_prompt_recipe() {
+        let email = builtin("email");
+        let (persona, message) = email
+            .render(&values(&[
+                ("input", json!("  Move the meeting to Friday. ")),
+                ("tone", json!("friendly")),
+            ]))
+            .unwrap();
+
+        assert_eq!(persona.id, "utility:email");
+        assert_eq!(persona.default_tier, Some(Tier::Balanced));
+        assert!(persona.allowed_tools.is_empty());
+        assert!(persona.system_prompt.contains("in a friendly tone"));
+        assert!(persona
+            .system_prompt
+            .ends_with(OutputFormat::Text.instruction()));
+        assert_eq!(message.role, "user");
+        assert_eq!(
+            message.content,
+            "Recipient: unspecified\n\nWhat the email should say:\nMove the meeting to Friday."
+        );
+
+        let (persona, _) = builtin("summary")
+            .render(&values(&[("input", json!("x")), ("bullets", json!("3"))]))
+            .unwrap();
+        assert!(persona.system_prompt.contains("for staff"));
+        assert!(persona.system_prompt.contains("at most 3 short bullet"));
+        assert_eq!(persona.default_tier, Some(Tier::Fast));
+    }
+
+    #[test]
+    fn variables_are_type_checked() {
+        let variable = |kind: Value| -> TemplateVariable {
+            let mut raw = json!({"name": "v"});
+            raw.as_object_mut()
+                .unwrap()
+                .extend(kind.as_object().unwrap().clone());
+            serde_json::from_value(raw).unwrap()
+        };
+        let text = variable(json!({"type": "text", "maxLength": 5}));
+        assert_eq!(text.render_value(&json!(" abc ")).unwrap(), "abc");
+        assert!(text.render_value(&json!("abcdef")).is_err());
+        assert!(text.render_value(&json!(3)).is_err());
+
+        let choice = variable(json!({"type": "enum", "options": ["a", "b"]}));
+        assert_eq!(choice.render_value(&json!("b")).unwrap(), "b");
+        assert!(choice.render_value(&json!("c")).is_err());
+
+        let count = variable(json!({"type": "number", "integer": true, "min": 1, "max": 10}));
+        assert_eq!(count.render_value(&json!(" 7 ")).unwrap(), "7");
+        assert_eq!(count.render_value(&json!(10)).unwrap(), "10");
+        for bad in [json!(2.5), json!(0), json!(11), json!("many"), json!(null)] {
+            assert!(count.render_value(&bad).is_err(), "{bad}");
+        }
+
+        for (bad, expected) in [
+            (
+                json!({"name": "1st", "type": "text"}),
+                "must start with a letter",
+            ),
+            (
+                json!({"name": "v", "type": "enum", "options": []}),
+                "needs options",
+            ),
+            (
+                json!({"name": "v", "type": "number", "min": 3, "max": 1}),
+                "min above max",
+            ),
+            (
+                json!({"name": "v", "type": "enum", "options": ["a"], "default": "b"}),
+                "default for variable 'v'",
+            ),
+        ] {
+            let variable: TemplateVariable = serde_json::from_value(bad).unwrap();
+            let err = variable.validate().unwrap_err();
+            assert!(err.contains(expected), "{err}");
+        }
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        let rejects = |edit: fn(&mut UtilityTemplate), expected: &str| {
+            let mut template = builtin("email");
+            edit(&mut template);
+            let err = template.validate().unwrap_err();
+            assert!(err.contains(expected), "{err}");
+        };
+        rejects(|t| t.id = "Email".to_string(), "lowercase letters");
+        rejects(|t| t.title = " ".to_string(), "title cannot be empty");
+        rejects(|t| t.prompt = String::new(), "cannot be empty");
+        rejects(|t| t.prompt.push_str("{{ cc }}"), "{{cc}} has no matching");
+        rejects(
+            |t| t.variables.push(t.variables[0].clone()),
+            "'input' is declared twice",
+        );
+        rejects(|t| t.output_format = OutputFormat::Json, "needs a schema");
+        rejects(
+            |t| t.schema = Some(json!({"type": "object"})),
+            "only used with the json output format",
+        );
+        rejects(
+            |t| {
+                t.output_format = OutputFormat::Json;
+                t.schema = Some(json!({"type": "object", "if": true}));
+            },
+            "invalid schema",
+        );
+
+        let err = builtin("email")
+            .render(&values(&[("input", json!("x")), ("cc", json!("y"))]))
+            .unwrap_err();
+        assert_eq!(err, "template 'email' has no variable 'cc'");
+    }
+
+    #[test]
+    fn the_library_seeds_saves_deletes_and_reloads_files() {
+        let mut cfg = AppConfig::from_env();
+        let dir = std::env::temp_dir().join(format!("campus-templates-{}", random_hex(8)));
+        cfg.utility_templates_dir = dir.display().to_string();
+        let mut library = TemplateLibrary::load(&cfg).unwrap();
+        library.reload_interval = Some(Duration::ZERO);
+        let ids = |library: &TemplateLibrary| -> Vec<String> {
+            library.list().into_iter().map(|t| t.id).collect()
+        };
+        assert_eq!(ids(&library), ["email", "plan", "quiz", "summary"]);
+
+        let mut memo = builtin("email");
+        memo.id = "memo".to_string();
+        assert!(matches!(
+            library.save(memo.clone(), true),
+            Err(TemplateError::NotFound)
+        ));
+        library.save(memo.clone(), false).unwrap();
+        assert!(matches!(
+            library.save(memo.clone(), false),
+            Err(TemplateError::Exists)
+        ));
+        memo.title = "Memo".to_string();
+        library.save(memo, true).unwrap();
+        assert_eq!(library.get("memo").unwrap().title, "Memo");
+        let mut broken = builtin("email");
+        broken.id = "bad id".to_string();
+        assert!(matches!(
+            library.save(broken, false),
+            Err(TemplateError::Invalid(_))
+        ));
+
+        library.delete("email").unwrap();
+        assert!(matches!(
+            library.delete("email"),
+            Err(TemplateError::NotFound)
+        ));
+        assert!(!dir.join("email.json").exists());
+
+        // Edits made on disk show up on the next read; broken files are skipped.
+        fs::remove_file(dir.join("plan.json")).unwrap();
+        fs::write(dir.join("quiz.json"), "{").unwrap();
+        let mut renamed = builtin("summary");
+        renamed.id = "other".to_string();
+        write_atomic(
+            &dir.join("digest.json"),
+            &serde_json::to_string(&renamed).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(ids(&library), ["memo", "summary"]);
+
+        let reopened = TemplateLibrary::load(&cfg).unwrap();
+        assert_eq!(ids(&reopened), ["memo", "summary"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
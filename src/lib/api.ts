@@ -17,10 +17,36 @@ interface ChatMessage {
   content: string;
 }
 
+const SESSION_STORAGE_KEY = "campus-ai-session-id";
+
 function trimTrailingSlash(url: string): string {
   return url.replace(/\/+$/, "");
 }
 
+// The backend mints the session id and scopes reports and activity to it, so
+// keep the one it hands out; ids it did not issue are replaced.
+function getSessionId(): string | null {
+  if (typeof window === "undefined") return null;
+  return window.localStorage.getItem(SESSION_STORAGE_KEY);
+}
+
+function rememberSession(res: Response): void {
+  const id = res.headers.get("X-Session-Id");
+  if (id && typeof window !== "undefined") {
+    window.localStorage.setItem(SESSION_STORAGE_KEY, id);
+  }
+}
+
+// Headers every request to the campus API carries.
+function campusHeaders(): Record<string, string> {
+  const headers: Record<string, string> = {};
+  const sessionId = getSessionId();
+  if (sessionId) headers["X-Session-Id"] = sessionId;
+  const apiKey = getProviderConfig().campusApiKey;
+  if (apiKey) headers.Authorization = `Bearer ${apiKey}`;
+  return headers;
+}
+
 function endpointSupportsCloud(endpoint: string): boolean {
   return endpoint === "/chat";
 }
@@ -35,9 +61,10 @@ async function streamLocal(
 
   const res = await fetch(`${baseUrl}${endpoint}`, {
     method: "POST",
-    headers: { "Content-Type": "application/json" },
+    headers: { "Content-Type": "application/json", ...campusHeaders() },
     body: JSON.stringify(body),
   });
+  rememberSession(res);
 
   if (!res.ok) {
     callbacks.onError(`Error ${res.status}`);
@@ -157,12 +184,14 @@ export async function apiFetch<T>(
     const baseUrl = trimTrailingSlash(cfg.localBaseUrl);
 
     const res = await fetch(`${baseUrl}${endpoint}`, {
+      ...options,
       headers: {
         "Content-Type": "application/json",
+        ...campusHeaders(),
         ...options?.headers,
       },
-      ...options,
     });
+    rememberSession(res);
 
     if (!res.ok) {
       const errorText = await res.text();
@@ -3,6 +3,7 @@ export type ProviderMode = "local" | "cloud";
 export interface ProviderConfig {
   mode: ProviderMode;
   localBaseUrl: string;
+  campusApiKey: string;
   cloudBaseUrl: string;
   cloudApiKey: string;
   cloudModel: string;
@@ -13,6 +14,7 @@ const STORAGE_KEY = "campus-ai-provider-config";
 const DEFAULT_CONFIG: ProviderConfig = {
   mode: "local",
   localBaseUrl: "http://localhost:8000/api",
+  campusApiKey: "",
   cloudBaseUrl: import.meta.env.VITE_OPENAI_BASE_URL || "https://api.openai.com/v1",
   cloudApiKey: import.meta.env.VITE_OPENAI_API_KEY || "",
   cloudModel: import.meta.env.VITE_OPENAI_MODEL || "gpt-4.1-mini",
@@ -30,6 +32,7 @@ function parseConfig(raw: string | null): ProviderConfig | null {
     return {
       mode: parsed.mode,
       localBaseUrl: parsed.localBaseUrl || DEFAULT_CONFIG.localBaseUrl,
+      campusApiKey: parsed.campusApiKey || DEFAULT_CONFIG.campusApiKey,
       cloudBaseUrl: parsed.cloudBaseUrl || DEFAULT_CONFIG.cloudBaseUrl,
       cloudApiKey: parsed.cloudApiKey || DEFAULT_CONFIG.cloudApiKey,
       cloudModel: parsed.cloudModel || DEFAULT_CONFIG.cloudModel,
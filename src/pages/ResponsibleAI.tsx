@@ -3,9 +3,20 @@ import { Shield, AlertTriangle, Eye, BarChart3, RefreshCw, Loader2 } from "lucid
 import { apiFetch } from "@/lib/api";
 import { LoadingState } from "@/components/StateIndicators";
 
+interface ConfidenceMetrics {
+  available: boolean;
+  tokenCount: number;
+  meanTokenProb: number | null;
+  minTokenProb: number | null;
+  note: string | null;
+}
+
 interface AiReport {
   confidence: number;
+  confidenceSource: string;
+  confidenceMetrics: ConfidenceMetrics;
   biasWarnings: string[];
+  moderationWarnings: string[];
   transparencyScore: number;
   modelInfo: string;
   lastQuery: string;
@@ -102,6 +113,11 @@ export default function ResponsibleAI() {
               <h3 className="font-semibold text-sm">Confidence Score</h3>
             </div>
             <AnimatedScoreBar value={report.confidence * 100} label="Model Confidence" />
+            <p className="text-xs text-muted-foreground mt-3">
+              {report.confidenceMetrics.available
+                ? `From token log-probabilities over ${report.confidenceMetrics.tokenCount} tokens (min ${Math.round((report.confidenceMetrics.minTokenProb ?? 0) * 100)}%).`
+                : `Heuristic estimate: ${report.confidenceMetrics.note ?? "log-probabilities unavailable"}.`}
+            </p>
           </div>
 
           <div className="rounded-xl border border-border bg-card p-5 sm:p-6 opacity-0 animate-stagger-in hover-lift" style={{ animationDelay: "0.2s" }}>
@@ -115,13 +131,13 @@ export default function ResponsibleAI() {
           <div className="rounded-xl border border-border bg-card p-5 sm:p-6 sm:col-span-2 opacity-0 animate-stagger-in" style={{ animationDelay: "0.3s" }}>
             <div className="flex items-center gap-2 mb-4">
               <AlertTriangle className="w-4 h-4 text-warning" />
-              <h3 className="font-semibold text-sm">Bias Warnings</h3>
+              <h3 className="font-semibold text-sm">Bias &amp; Moderation Warnings</h3>
             </div>
-            {report.biasWarnings.length === 0 ? (
-              <p className="text-sm text-muted-foreground">No bias warnings reported.</p>
+            {report.biasWarnings.length + report.moderationWarnings.length === 0 ? (
+              <p className="text-sm text-muted-foreground">No warnings reported.</p>
             ) : (
               <ul className="space-y-2">
-                {report.biasWarnings.map((warning, i) => (
+                {[...report.biasWarnings, ...report.moderationWarnings].map((warning, i) => (
                   <li
                     key={i}
                     className="flex items-start gap-2 text-sm bg-warning/5 border border-warning/20 rounded-lg px-4 py-3 opacity-0 animate-stagger-in"
@@ -103,6 +103,27 @@ export default function RuntimeSettings() {
             className="w-full rounded-lg border border-border bg-background px-3 py-2.5 text-sm"
             placeholder="http://localhost:8000/api"
           />
+
+          <label className="text-sm font-medium block" htmlFor="campusApiKey">
+            Campus API Key
+          </label>
+          <input
+            id="campusApiKey"
+            type="password"
+            value={config.campusApiKey}
+            onChange={(e) => setConfig((prev) => ({ ...prev, campusApiKey: e.target.value }))}
+            className="w-full rounded-lg border border-border bg-background px-3 py-2.5 text-sm"
+            placeholder="cak_..."
+          />
+          <p className="text-xs text-muted-foreground">
+            Required when the campus API enforces authentication, unless you sign in with campus SSO.
+          </p>
+          <a
+            href={`${config.localBaseUrl.replace(/\/+$/, "")}/auth/login`}
+            className="inline-flex items-center gap-2 rounded-lg border border-border px-4 py-2 text-sm hover:bg-secondary"
+          >
+            Sign in with campus SSO
+          </a>
         </div>
 
         <div className="rounded-xl border border-border bg-card p-5 space-y-4">
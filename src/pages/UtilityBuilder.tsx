@@ -3,12 +3,34 @@ import { Wrench, Send, Loader2 } from "lucide-react";
 import { apiFetch } from "@/lib/api";
 import { ErrorState } from "@/components/StateIndicators";
 
+interface TemplateVariable {
+  name: string;
+  label?: string;
+  description?: string;
+  type: "text" | "enum" | "number";
+  required?: boolean;
+  default?: string | number;
+  options?: string[];
+  min?: number;
+  max?: number;
+  integer?: boolean;
+  multiline?: boolean;
+  maxLength?: number;
+}
+
 interface UtilityTemplate {
   id: string;
   title: string;
   description?: string;
+  variables?: TemplateVariable[];
 }
 
+// The main prompt box fills the `input` variable; the rest get their own fields.
+const PROMPT_VARIABLE = "input";
+
+const fieldClass =
+  "w-full rounded-xl border border-border bg-background px-4 py-3 text-sm transition-all duration-300 focus:outline-none focus:ring-2 focus:ring-primary/30 focus:border-primary/40 placeholder:text-muted-foreground";
+
 export default function UtilityBuilder() {
   const [templates, setTemplates] = useState<UtilityTemplate[]>([]);
   const [templatesLoading, setTemplatesLoading] = useState(false);
@@ -16,6 +38,7 @@ export default function UtilityBuilder() {
 
   const [selectedTemplate, setSelectedTemplate] = useState<string | null>(null);
   const [prompt, setPrompt] = useState("");
+  const [values, setValues] = useState<Record<string, string>>({});
   const [output, setOutput] = useState<string | null>(null);
   const [loading, setLoading] = useState(false);
   const [error, setError] = useState<string | null>(null);
@@ -46,8 +69,21 @@ export default function UtilityBuilder() {
     loadTemplates();
   }, []);
 
+  const template = templates.find((t) => t.id === selectedTemplate);
+  const extraVariables = (template?.variables ?? []).filter((v) => v.name !== PROMPT_VARIABLE);
+  const promptVariable = template?.variables?.find((v) => v.name === PROMPT_VARIABLE);
+  const usesPrompt = !template?.variables || promptVariable !== undefined;
+
+  useEffect(() => {
+    const defaults: Record<string, string> = {};
+    for (const variable of template?.variables ?? []) {
+      if (variable.default !== undefined) defaults[variable.name] = String(variable.default);
+    }
+    setValues(defaults);
+  }, [template]);
+
   const handleGenerate = async () => {
-    if (!prompt.trim()) return;
+    if (usesPrompt && !prompt.trim()) return;
 
     setLoading(true);
     setError(null);
@@ -55,7 +91,7 @@ export default function UtilityBuilder() {
 
     const { data, error: err } = await apiFetch<{ result: string }>("/utility/generate", {
       method: "POST",
-      body: JSON.stringify({ template: selectedTemplate, prompt }),
+      body: JSON.stringify({ template: selectedTemplate, prompt: usesPrompt ? prompt : undefined, variables: values }),
     });
 
     setLoading(false);
@@ -113,20 +149,78 @@ export default function UtilityBuilder() {
             </select>
           )}
 
-          <label className="text-sm font-medium" htmlFor="prompt">
-            Prompt
-          </label>
-          <textarea
-            id="prompt"
-            value={prompt}
-            onChange={(e) => setPrompt(e.target.value)}
-            placeholder="Describe the output you need..."
-            rows={8}
-            className="w-full rounded-xl border border-border bg-background px-4 py-3 text-sm transition-all duration-300 focus:outline-none focus:ring-2 focus:ring-primary/30 focus:border-primary/40 resize-none placeholder:text-muted-foreground"
-          />
+          {extraVariables.map((variable) => {
+            const id = `var-${variable.name}`;
+            const value = values[variable.name] ?? "";
+            const setValue = (next: string) => setValues((prev) => ({ ...prev, [variable.name]: next }));
+            return (
+              <div key={variable.name} className="space-y-2">
+                <label className="text-sm font-medium block" htmlFor={id}>
+                  {variable.label || variable.name}
+                  {variable.required && <span className="text-destructive"> *</span>}
+                </label>
+                {variable.type === "enum" ? (
+                  <select id={id} value={value} onChange={(e) => setValue(e.target.value)} className={fieldClass}>
+                    {!variable.required && variable.default === undefined && <option value="" />}
+                    {(variable.options ?? []).map((option) => (
+                      <option key={option} value={option}>
+                        {option}
+                      </option>
+                    ))}
+                  </select>
+                ) : variable.type === "number" ? (
+                  <input
+                    id={id}
+                    type="number"
+                    value={value}
+                    min={variable.min}
+                    max={variable.max}
+                    step={variable.integer ? 1 : "any"}
+                    onChange={(e) => setValue(e.target.value)}
+                    className={fieldClass}
+                  />
+                ) : variable.multiline ? (
+                  <textarea
+                    id={id}
+                    value={value}
+                    maxLength={variable.maxLength}
+                    onChange={(e) => setValue(e.target.value)}
+                    rows={4}
+                    className={`${fieldClass} resize-none`}
+                  />
+                ) : (
+                  <input
+                    id={id}
+                    type="text"
+                    value={value}
+                    maxLength={variable.maxLength}
+                    onChange={(e) => setValue(e.target.value)}
+                    className={fieldClass}
+                  />
+                )}
+                {variable.description && <p className="text-xs text-muted-foreground">{variable.description}</p>}
+              </div>
+            );
+          })}
+
+          {usesPrompt && (
+            <>
+              <label className="text-sm font-medium" htmlFor="prompt">
+                {promptVariable?.label || "Prompt"}
+              </label>
+              <textarea
+                id="prompt"
+                value={prompt}
+                onChange={(e) => setPrompt(e.target.value)}
+                placeholder="Describe the output you need..."
+                rows={8}
+                className="w-full rounded-xl border border-border bg-background px-4 py-3 text-sm transition-all duration-300 focus:outline-none focus:ring-2 focus:ring-primary/30 focus:border-primary/40 resize-none placeholder:text-muted-foreground"
+              />
+            </>
+          )}
           <button
             onClick={handleGenerate}
-            disabled={!prompt.trim() || loading || templates.length === 0}
+            disabled={(usesPrompt && !prompt.trim()) || loading || templates.length === 0}
             className="group inline-flex w-full sm:w-auto justify-center items-center gap-2 bg-primary text-primary-foreground px-5 py-2.5 rounded-lg text-sm font-medium transition-all duration-300 hover:opacity-90 hover:shadow-lg hover:shadow-primary/25 disabled:opacity-40 disabled:shadow-none hover-scale"
           >
             {loading ? <Loader2 className="w-4 h-4 animate-spin" /> : <Send className="w-4 h-4 transition-transform duration-300 group-hover:translate-x-0.5" />}